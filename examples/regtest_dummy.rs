@@ -43,6 +43,25 @@ const UTXO_SCRIPT_PUBKEY: &str =
 const UTXO_PUBKEY: &str = "a6ac32163539c16b6b5dbbca01b725b8e8acaa5f821ba42c80e7940062140d19";
 const UTXO_MASTER_FINGERPRINT: &str = "e61b318f";
 const ABSOLUTE_FEES_IN_SATS: Amount = Amount::from_sat(1_000);
+// Step 3 leaves the chain at height 103, inheritance outputs are locked 900 blocks later.
+const TIP_AFTER_STEP_3: ChainTip = ChainTip {
+    height: 103,
+    median_time_past: 0,
+};
+// The block mined to confirm an inheritance funding transaction.
+const TIP_AFTER_FUNDING: ChainTip = ChainTip {
+    height: 104,
+    median_time_past: 0,
+};
+const FUNDING_CONFIRMATION: Confirmation = Confirmation {
+    height: 104,
+    prev_median_time_past: 0,
+};
+const INHERITANCE_CONFIG: InheritanceConfig = InheritanceConfig {
+    lock_blocks: 900,
+    refresh_interval: 450,
+    fee: ABSOLUTE_FEES_IN_SATS,
+};
 
 // UTXO_1 will be used for spending example 1
 const UTXO_1: P2trUtxo = P2trUtxo {
@@ -80,17 +99,22 @@ const UTXO_3: P2trUtxo = P2trUtxo {
 use std::collections::BTreeMap;
 use std::str::FromStr;
 
-use bitcoin::bip32::{ChildNumber, DerivationPath, Fingerprint, Xpriv, Xpub};
+use bitcoin_taproot_transaction::bitcoin_node::signer::InMemorySigner;
+use bitcoin_taproot_transaction::bitcoin_node::timelock::{ChainTip, Confirmation};
+use bitcoin_taproot_transaction::contract::inheritance::{
+    BenefactorWallet, BeneficiaryWallet, InheritanceConfig,
+};
+
+use bitcoin::bip32::{DerivationPath, Fingerprint, Xpriv};
 use bitcoin::consensus::encode;
 use bitcoin::key::{TapTweak, XOnlyPublicKey};
-use bitcoin::opcodes::all::{OP_CHECKSIG, OP_CLTV, OP_DROP};
-use bitcoin::psbt::{self, Input, Output, Psbt, PsbtSighashType};
+use bitcoin::psbt::{self, Input, Psbt, PsbtSighashType};
 use bitcoin::secp256k1::Secp256k1;
 use bitcoin::sighash::{self, SighashCache, TapSighash, TapSighashType};
-use bitcoin::taproot::{self, LeafVersion, TapLeafHash, TaprootBuilder, TaprootSpendInfo};
+use bitcoin::taproot::{self, TapLeafHash};
 use bitcoin::{
-    absolute, script, transaction, Address, Amount, Network, OutPoint, ScriptBuf, Transaction,
-    TxIn, TxOut, Witness,
+    absolute, transaction, Address, Amount, Network, OutPoint, ScriptBuf, Transaction, TxIn, TxOut,
+    Witness,
 };
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -141,15 +165,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("START EXAMPLE 2 - Script path spending of inheritance UTXO\n");
 
     {
-        let beneficiary = BeneficiaryWallet::new(Xpriv::from_str(BENEFICIARY_XPRIV_STR)?)?;
+        let beneficiary = BeneficiaryWallet::new(Xpriv::from_str(BENEFICIARY_XPRIV_STR)?);
 
         let mut benefactor = BenefactorWallet::new(
             Xpriv::from_str(BENEFACTOR_XPRIV_STR)?,
             beneficiary.master_xpub(),
+            Network::Regtest,
+            INHERITANCE_CONFIG,
         )?;
-        let (tx, psbt) = benefactor.create_inheritance_funding_tx(
-            absolute::LockTime::from_height(1000).unwrap(),
-            UTXO_2,
+        let tx = benefactor.create_funding_tx(
+            &TIP_AFTER_STEP_3,
+            &[p2tr_utxo_prevout(&UTXO_2)?],
+            &bip86_signer(&secp)?,
         )?;
         let tx_hex = encode::serialize_hex(&tx);
 
@@ -159,16 +186,26 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         //
         // And mine a block to confirm the transaction:
         // bt generatetoaddress 1 $(bt-benefactor getnewaddress '' 'bech32m')
-
+        //
+        // The funding output stays pending until the wallet is told it is confirmed.
+        benefactor.poll(&TIP_AFTER_FUNDING, Some(FUNDING_CONFIRMATION))?;
+
+        // The spend is refused before the lock time (height 1003), so build it for a later tip.
+        let output = benefactor.current().ok_or("Missing inheritance output")?;
+        let unlocked_tip = ChainTip {
+            height: output.lock_time.to_consensus_u32(),
+            median_time_past: 0,
+        };
         let spending_tx = beneficiary.spend_inheritance(
-            psbt,
-            absolute::LockTime::from_height(1000).unwrap(),
-            to_address,
+            output,
+            to_address.script_pubkey(),
+            ABSOLUTE_FEES_IN_SATS,
+            &unlocked_tip,
         )?;
         let spending_tx_hex = encode::serialize_hex(&spending_tx);
         println!("\nInheritance spending tx hex:\n\n{}", spending_tx_hex);
         // If you try to broadcast now, the transaction will be rejected as it is timelocked.
-        // First mine 900 blocks so we're sure we are over the 1000 block locktime:
+        // First mine 900 blocks so we're sure we are over the 1003 block locktime:
         // bt generatetoaddress 900 $(bt-benefactor getnewaddress '' 'bech32m')
         // Then broadcast the transaction with `bt sendrawtransaction ...`
     }
@@ -179,15 +216,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("START EXAMPLE 3 - Key path spending of inheritance UTXO\n");
 
     {
-        let beneficiary = BeneficiaryWallet::new(Xpriv::from_str(BENEFICIARY_XPRIV_STR)?)?;
+        let beneficiary = BeneficiaryWallet::new(Xpriv::from_str(BENEFICIARY_XPRIV_STR)?);
 
         let mut benefactor = BenefactorWallet::new(
             Xpriv::from_str(BENEFACTOR_XPRIV_STR)?,
             beneficiary.master_xpub(),
+            Network::Regtest,
+            INHERITANCE_CONFIG,
         )?;
-        let (tx, _) = benefactor.create_inheritance_funding_tx(
-            absolute::LockTime::from_height(2000).unwrap(),
-            UTXO_3,
+        let tx = benefactor.create_funding_tx(
+            &TIP_AFTER_STEP_3,
+            &[p2tr_utxo_prevout(&UTXO_3)?],
+            &bip86_signer(&secp)?,
         )?;
         let tx_hex = encode::serialize_hex(&tx);
 
@@ -197,13 +237,17 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         //
         // And mine a block to confirm the transaction:
         // bt generatetoaddress 1 $(bt-benefactor getnewaddress '' 'bech32m')
+        //
+        // The funding output can only be refreshed once the wallet is told it is confirmed.
+        benefactor.poll(&TIP_AFTER_FUNDING, Some(FUNDING_CONFIRMATION))?;
 
         // At some point we may want to extend the locktime further into the future for the beneficiary.
         // We can do this by "refreshing" the inheritance transaction as the benefactor. This effectively
         // spends the inheritance transaction via the key path of the taproot output, and is not encumbered
         // by the timelock so we can spend it immediately. We set up a new output similar to the first with
-        // a locktime that is 'locktime_delta' blocks greater.
-        let (tx, _) = benefactor.refresh_tx(1000)?;
+        // a locktime that is `lock_blocks` after the tip. `BenefactorWallet::poll` does this on its own
+        // every `refresh_interval` blocks.
+        let tx = benefactor.refresh_tx(&TIP_AFTER_FUNDING)?;
         let tx_hex = encode::serialize_hex(&tx);
 
         println!("\nRefreshed inheritance tx hex:\n\n{}\n", tx_hex);
@@ -215,6 +259,28 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+// The benefactor key of the UTXOs above.
+fn bip86_signer(
+    secp: &Secp256k1<secp256k1::All>,
+) -> Result<InMemorySigner, Box<dyn std::error::Error>> {
+    let xpriv = Xpriv::from_str(BENEFACTOR_XPRIV_STR)?
+        .derive_priv(secp, &DerivationPath::from_str(BIP86_DERIVATION_PATH)?)?;
+    Ok(InMemorySigner::from_private_key(&xpriv.to_priv()))
+}
+
+fn p2tr_utxo_prevout(utxo: &P2trUtxo) -> Result<(OutPoint, TxOut), Box<dyn std::error::Error>> {
+    Ok((
+        OutPoint {
+            txid: utxo.txid.parse()?,
+            vout: utxo.vout,
+        },
+        TxOut {
+            value: utxo.amount_in_sats,
+            script_pubkey: ScriptBuf::from_hex(utxo.script_pubkey)?,
+        },
+    ))
+}
+
 struct P2trUtxo<'a> {
     txid: &'a str,
     vout: u32,
@@ -317,7 +383,7 @@ fn generate_bip86_key_spend_tx(
                 .ok_or("Missing taproot key origin")?;
 
             let secret_key = master_xpriv
-                .derive_priv(secp, &derivation_path)
+                .derive_priv(secp, &derivation_path)?
                 .to_priv()
                 .inner;
             sign_psbt_taproot(
@@ -360,438 +426,6 @@ fn generate_bip86_key_spend_tx(
     Ok(tx)
 }
 
-/// A wallet that allows creating and spending from an inheritance directly via the key path for purposes
-/// of refreshing the inheritance timelock or changing other spending conditions.
-struct BenefactorWallet {
-    master_xpriv: Xpriv,
-    beneficiary_xpub: Xpub,
-    current_spend_info: Option<TaprootSpendInfo>,
-    next_psbt: Option<Psbt>,
-    secp: Secp256k1<secp256k1::All>,
-    next: ChildNumber,
-}
-
-impl BenefactorWallet {
-    fn new(
-        master_xpriv: Xpriv,
-        beneficiary_xpub: Xpub,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
-        Ok(Self {
-            master_xpriv,
-            beneficiary_xpub,
-            current_spend_info: None,
-            next_psbt: None,
-            secp: Secp256k1::new(),
-            next: ChildNumber::ZERO_NORMAL,
-        })
-    }
-
-    fn time_lock_script(
-        locktime: absolute::LockTime,
-        beneficiary_key: XOnlyPublicKey,
-    ) -> ScriptBuf {
-        script::Builder::new()
-            .push_int(locktime.to_consensus_u32() as i64)
-            .push_opcode(OP_CLTV)
-            .push_opcode(OP_DROP)
-            .push_x_only_key(beneficiary_key)
-            .push_opcode(OP_CHECKSIG)
-            .into_script()
-    }
-
-    fn create_inheritance_funding_tx(
-        &mut self,
-        lock_time: absolute::LockTime,
-        input_utxo: P2trUtxo,
-    ) -> Result<(Transaction, Psbt), Box<dyn std::error::Error>> {
-        if let ChildNumber::Normal { index } = self.next {
-            if index > 0 && self.current_spend_info.is_some() {
-                return Err("Transaction already exists, use refresh_inheritance_timelock to refresh the timelock".into());
-            }
-        }
-        // We use some other derivation path in this example for our inheritance protocol. The important thing is to ensure
-        // that we use an unhardened path so we can make use of xpubs.
-        let derivation_path = DerivationPath::from_str(&format!("101/1/0/0/{}", self.next))?;
-        let internal_keypair = self
-            .master_xpriv
-            .derive_priv(&self.secp, &derivation_path)
-            .to_keypair(&self.secp);
-        let beneficiary_key = self
-            .beneficiary_xpub
-            .derive_pub(&self.secp, &derivation_path)?
-            .to_x_only_pub();
-
-        // Build up the leaf script and combine with internal key into a taproot commitment
-        let script = Self::time_lock_script(lock_time, beneficiary_key);
-        let leaf_hash = script.tapscript_leaf_hash();
-
-        let taproot_spend_info = TaprootBuilder::new()
-            .add_leaf(0, script.clone())?
-            .finalize(&self.secp, internal_keypair.x_only_public_key().0)
-            .expect("Should be finalizable");
-        self.current_spend_info = Some(taproot_spend_info.clone());
-        let script_pubkey = ScriptBuf::new_p2tr(
-            &self.secp,
-            taproot_spend_info.internal_key(),
-            taproot_spend_info.merkle_root(),
-        );
-        let value = input_utxo.amount_in_sats - ABSOLUTE_FEES_IN_SATS;
-
-        // Spend a normal BIP86-like output as an input in our inheritance funding transaction
-        let tx = generate_bip86_key_spend_tx(
-            &self.secp,
-            self.master_xpriv,
-            input_utxo,
-            vec![TxOut {
-                script_pubkey: script_pubkey.clone(),
-                value,
-            }],
-        )?;
-
-        // CREATOR + UPDATER
-        let next_tx = Transaction {
-            version: transaction::Version::TWO,
-            lock_time,
-            input: vec![TxIn {
-                previous_output: OutPoint {
-                    txid: tx.compute_txid(),
-                    vout: 0,
-                },
-                script_sig: ScriptBuf::new(),
-                sequence: bitcoin::Sequence(0xFFFFFFFD), // enable locktime and opt-in RBF
-                witness: Witness::default(),
-            }],
-            output: vec![],
-        };
-        let mut next_psbt = Psbt::from_unsigned_tx(next_tx)?;
-        let mut origins = BTreeMap::new();
-        origins.insert(
-            beneficiary_key,
-            (
-                vec![leaf_hash],
-                (self.beneficiary_xpub.fingerprint(), derivation_path.clone()),
-            ),
-        );
-        origins.insert(
-            internal_keypair.x_only_public_key().0,
-            (
-                vec![],
-                (self.master_xpriv.fingerprint(&self.secp), derivation_path),
-            ),
-        );
-        let ty = PsbtSighashType::from_str("SIGHASH_ALL")?;
-        let mut tap_scripts = BTreeMap::new();
-        tap_scripts.insert(
-            taproot_spend_info
-                .control_block(&(script.clone(), LeafVersion::TapScript))
-                .unwrap(),
-            (script, LeafVersion::TapScript),
-        );
-
-        let input = Input {
-            witness_utxo: {
-                Some(TxOut {
-                    value,
-                    script_pubkey,
-                })
-            },
-            tap_key_origins: origins,
-            tap_merkle_root: taproot_spend_info.merkle_root(),
-            sighash_type: Some(ty),
-            tap_internal_key: Some(internal_keypair.x_only_public_key().0),
-            tap_scripts,
-            ..Default::default()
-        };
-
-        next_psbt.inputs = vec![input];
-        self.next_psbt = Some(next_psbt.clone());
-
-        self.next.increment()?;
-        Ok((tx, next_psbt))
-    }
-
-    fn refresh_tx(
-        &mut self,
-        lock_time_delta: u32,
-    ) -> Result<(Transaction, Psbt), Box<dyn std::error::Error>> {
-        if let Some(ref spend_info) = self.current_spend_info.clone() {
-            let mut psbt = self.next_psbt.clone().expect("Should have next_psbt");
-            let input = &mut psbt.inputs[0];
-            let input_value = input.witness_utxo.as_ref().unwrap().value;
-            let output_value = input_value - ABSOLUTE_FEES_IN_SATS;
-
-            // We use some other derivation path in this example for our inheritance protocol. The important thing is to ensure
-            // that we use an unhardened path so we can make use of xpubs.
-            let new_derivation_path =
-                DerivationPath::from_str(&format!("101/1/0/0/{}", self.next))?;
-            let new_internal_keypair = self
-                .master_xpriv
-                .derive_priv(&self.secp, &new_derivation_path)
-                .to_keypair(&self.secp);
-            let beneficiary_key = self
-                .beneficiary_xpub
-                .derive_pub(&self.secp, &new_derivation_path)?
-                .to_x_only_pub();
-
-            // Build up the leaf script and combine with internal key into a taproot commitment
-            let lock_time = absolute::LockTime::from_height(
-                psbt.unsigned_tx.lock_time.to_consensus_u32() + lock_time_delta,
-            )
-            .unwrap();
-            let script = Self::time_lock_script(lock_time, beneficiary_key);
-            let leaf_hash = script.tapscript_leaf_hash();
-
-            let taproot_spend_info = TaprootBuilder::new()
-                .add_leaf(0, script.clone())?
-                .finalize(&self.secp, new_internal_keypair.x_only_public_key().0)
-                .expect("Should be finalizable");
-            self.current_spend_info = Some(taproot_spend_info.clone());
-            let prevout_script_pubkey = input.witness_utxo.as_ref().unwrap().script_pubkey.clone();
-            let output_script_pubkey = ScriptBuf::new_p2tr(
-                &self.secp,
-                taproot_spend_info.internal_key(),
-                taproot_spend_info.merkle_root(),
-            );
-
-            psbt.unsigned_tx.output = vec![TxOut {
-                script_pubkey: output_script_pubkey.clone(),
-                value: output_value,
-            }];
-            psbt.outputs = vec![Output::default()];
-            psbt.unsigned_tx.lock_time = absolute::LockTime::ZERO;
-
-            let sighash_type = input
-                .sighash_type
-                .and_then(|psbt_sighash_type| psbt_sighash_type.taproot_hash_ty().ok())
-                .unwrap_or(TapSighashType::All);
-            let hash = SighashCache::new(&psbt.unsigned_tx).taproot_key_spend_signature_hash(
-                0,
-                &sighash::Prevouts::All(&[TxOut {
-                    value: input_value,
-                    script_pubkey: prevout_script_pubkey,
-                }]),
-                sighash_type,
-            )?;
-
-            {
-                let (_, (_, derivation_path)) = input
-                    .tap_key_origins
-                    .get(
-                        &input
-                            .tap_internal_key
-                            .ok_or("Internal key missing in PSBT")?,
-                    )
-                    .ok_or("Missing taproot key origin")?;
-                let secret_key = self
-                    .master_xpriv
-                    .derive_priv(&self.secp, &derivation_path)
-                    .to_priv()
-                    .inner;
-                sign_psbt_taproot(
-                    secret_key,
-                    spend_info.internal_key(),
-                    None,
-                    input,
-                    hash,
-                    sighash_type,
-                    &self.secp,
-                );
-            }
-
-            // FINALIZER
-            psbt.inputs.iter_mut().for_each(|input| {
-                let mut script_witness: Witness = Witness::new();
-                script_witness.push(input.tap_key_sig.unwrap().to_vec());
-                input.final_script_witness = Some(script_witness);
-
-                // Clear all the data fields as per the spec.
-                input.partial_sigs = BTreeMap::new();
-                input.sighash_type = None;
-                input.redeem_script = None;
-                input.witness_script = None;
-                input.bip32_derivation = BTreeMap::new();
-            });
-
-            // EXTRACTOR
-            let tx = psbt.extract_tx_unchecked_fee_rate();
-            tx.verify(|_| {
-                Some(TxOut {
-                    value: input_value,
-                    script_pubkey: output_script_pubkey.clone(),
-                })
-            })
-            .expect("failed to verify transaction");
-
-            let next_tx = Transaction {
-                version: transaction::Version::TWO,
-                lock_time,
-                input: vec![TxIn {
-                    previous_output: OutPoint {
-                        txid: tx.compute_txid(),
-                        vout: 0,
-                    },
-                    script_sig: ScriptBuf::new(),
-                    sequence: bitcoin::Sequence(0xFFFFFFFD), // enable locktime and opt-in RBF
-                    witness: Witness::default(),
-                }],
-                output: vec![],
-            };
-            let mut next_psbt = Psbt::from_unsigned_tx(next_tx)?;
-            let mut origins = BTreeMap::new();
-            origins.insert(
-                beneficiary_key,
-                (
-                    vec![leaf_hash],
-                    (self.beneficiary_xpub.fingerprint(), new_derivation_path),
-                ),
-            );
-            let ty = PsbtSighashType::from_str("SIGHASH_ALL")?;
-            let mut tap_scripts = BTreeMap::new();
-            tap_scripts.insert(
-                taproot_spend_info
-                    .control_block(&(script.clone(), LeafVersion::TapScript))
-                    .unwrap(),
-                (script, LeafVersion::TapScript),
-            );
-
-            let input = Input {
-                witness_utxo: {
-                    let script_pubkey = output_script_pubkey;
-                    let amount = output_value;
-
-                    Some(TxOut {
-                        value: amount,
-                        script_pubkey,
-                    })
-                },
-                tap_key_origins: origins,
-                tap_merkle_root: taproot_spend_info.merkle_root(),
-                sighash_type: Some(ty),
-                tap_internal_key: Some(new_internal_keypair.x_only_public_key().0),
-                tap_scripts,
-                ..Default::default()
-            };
-
-            next_psbt.inputs = vec![input];
-            self.next_psbt = Some(next_psbt.clone());
-
-            self.next.increment()?;
-            Ok((tx, next_psbt))
-        } else {
-            Err("No current_spend_info available. Create an inheritance tx first.".into())
-        }
-    }
-}
-
-/// A wallet that allows spending from an inheritance locked to a P2TR UTXO via a script path
-/// after some expiry using CLTV.
-struct BeneficiaryWallet {
-    master_xpriv: Xpriv,
-    secp: secp256k1::Secp256k1<secp256k1::All>,
-}
-
-impl BeneficiaryWallet {
-    fn new(master_xpriv: Xpriv) -> Result<Self, Box<dyn std::error::Error>> {
-        Ok(Self {
-            master_xpriv,
-            secp: Secp256k1::new(),
-        })
-    }
-
-    fn master_xpub(&self) -> Xpub {
-        Xpub::from_priv(&self.secp, &self.master_xpriv)
-    }
-
-    fn spend_inheritance(
-        &self,
-        mut psbt: Psbt,
-        lock_time: absolute::LockTime,
-        to_address: Address,
-    ) -> Result<Transaction, Box<dyn std::error::Error>> {
-        let input_value = psbt.inputs[0].witness_utxo.as_ref().unwrap().value;
-        let input_script_pubkey = psbt.inputs[0]
-            .witness_utxo
-            .as_ref()
-            .unwrap()
-            .script_pubkey
-            .clone();
-        psbt.unsigned_tx.lock_time = lock_time;
-        psbt.unsigned_tx.output = vec![TxOut {
-            script_pubkey: to_address.script_pubkey(),
-            value: input_value - ABSOLUTE_FEES_IN_SATS,
-        }];
-        psbt.outputs = vec![Output::default()];
-        let unsigned_tx = psbt.unsigned_tx.clone();
-
-        // SIGNER
-        for (x_only_pubkey, (leaf_hashes, (_, derivation_path))) in
-            &psbt.inputs[0].tap_key_origins.clone()
-        {
-            let secret_key = self
-                .master_xpriv
-                .derive_priv(&self.secp, &derivation_path)
-                .to_priv()
-                .inner;
-            for lh in leaf_hashes {
-                let sighash_type = TapSighashType::All;
-                let hash = SighashCache::new(&unsigned_tx).taproot_script_spend_signature_hash(
-                    0,
-                    &sighash::Prevouts::All(&[TxOut {
-                        value: input_value,
-                        script_pubkey: input_script_pubkey.clone(),
-                    }]),
-                    *lh,
-                    sighash_type,
-                )?;
-                sign_psbt_taproot(
-                    secret_key,
-                    *x_only_pubkey,
-                    Some(*lh),
-                    &mut psbt.inputs[0],
-                    hash,
-                    sighash_type,
-                    &self.secp,
-                );
-            }
-        }
-
-        // FINALIZER
-        psbt.inputs.iter_mut().for_each(|input| {
-            let mut script_witness: Witness = Witness::new();
-            for (_, signature) in input.tap_script_sigs.iter() {
-                script_witness.push(signature.to_vec());
-            }
-            for (control_block, (script, _)) in input.tap_scripts.iter() {
-                script_witness.push(script.to_bytes());
-                script_witness.push(control_block.serialize());
-            }
-            input.final_script_witness = Some(script_witness);
-
-            // Clear all the data fields as per the spec.
-            input.partial_sigs = BTreeMap::new();
-            input.sighash_type = None;
-            input.redeem_script = None;
-            input.witness_script = None;
-            input.bip32_derivation = BTreeMap::new();
-            input.tap_script_sigs = BTreeMap::new();
-            input.tap_scripts = BTreeMap::new();
-            input.tap_key_sig = None;
-        });
-
-        // EXTRACTOR
-        let tx = psbt.extract_tx_unchecked_fee_rate();
-        tx.verify(|_| {
-            Some(TxOut {
-                value: input_value,
-                script_pubkey: input_script_pubkey.clone(),
-            })
-        })
-        .expect("failed to verify transaction");
-
-        Ok(tx)
-    }
-}
-
 // Lifted and modified from BDK at https://github.com/bitcoindevkit/bdk/blob/8fbe40a9181cc9e22cabfc04d57dac5d459da87d/src/wallet/signer.rs#L469-L503
 
 // Bitcoin Dev Kit
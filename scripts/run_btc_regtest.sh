@@ -5,6 +5,7 @@ docker run \
   --name bitcoin-regtest \
   -p 18443:18443 \
   -p 18444:18444 \
+  -p 28332:28332 \
   bitcoin/bitcoin:28.0 \
   -printtoconsole \
   -regtest=1 \
@@ -16,4 +17,8 @@ docker run \
   -rpcpassword=userpswd \
   -server \
   -txindex=1 \
+  -blockfilterindex=1 \
+  -zmqpubrawtx=tcp://0.0.0.0:28332 \
+  -zmqpubrawblock=tcp://0.0.0.0:28332 \
+  -zmqpubsequence=tcp://0.0.0.0:28332 \
   -rpcauth='username:5cab6e9e4fe9282621ef9d351c0710b7$4a2f92ba573a0f79085013ebee0fbf48e1567428c572a5642afdbe4836232b70'
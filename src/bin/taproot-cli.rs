@@ -0,0 +1,523 @@
+//! Command line front end of the library, driven by the `BITCOIN_PROFILE` config profile.
+use anyhow::{anyhow, bail};
+use bitcoin::consensus::encode;
+use bitcoin::hashes::sha256;
+use bitcoin::taproot::{LeafVersion, TaprootBuilder};
+use bitcoin::{
+    absolute, relative, transaction, Address, Amount, Network, OutPoint, PrivateKey, Psbt,
+    ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness, XOnlyPublicKey,
+};
+use bitcoin_taproot_transaction::bitcoin_node::account::keystore::Keystore;
+use bitcoin_taproot_transaction::bitcoin_node::address::AddressInfo;
+use bitcoin_taproot_transaction::bitcoin_node::config::Profile;
+use bitcoin_taproot_transaction::bitcoin_node::decode::decode;
+use bitcoin_taproot_transaction::bitcoin_node::psbt::{finalize_psbt, sign_psbt};
+#[cfg(unix)]
+use bitcoin_taproot_transaction::bitcoin_node::signer::RemoteSigner;
+use bitcoin_taproot_transaction::bitcoin_node::signer::{InMemorySigner, Signer};
+use bitcoin_taproot_transaction::bitcoin_node::taproot_tree_tx::template::LeafTemplate;
+use bitcoin_taproot_transaction::keygen::Keygen;
+use bitcoin_taproot_transaction::mempool::faucet::FaucetClient;
+use bitcoincore_rpc::RpcApi;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+const USAGE: &str = "\
+Usage: taproot-cli [--profile <name>] <command>
+
+Commands:
+  keygen [--type p2tr|p2wpkh|p2sh-p2wpkh|p2pkh]
+  address info <address>
+  address tree --internal-key <key|nums> --leaf <spec> [--leaf <spec>...]
+  tx build --input <txid:vout>... --output <address:sats>...
+  tx sign --psbt <psbt> --key <key>
+  tx broadcast <raw tx hex>
+  psbt sign <psbt> --key <key>
+  psbt combine <psbt> <psbt>...
+  psbt finalize <psbt>
+  decode <raw tx hex|psbt>
+  faucet claim <address> <sats>
+  regtest fund <address> <btc> [--wallet <name>]
+  regtest mine <blocks> [--address <address>] [--wallet <name>]
+
+Leaf specs:
+  pk:<key>                      single signature
+  multi:<k>:<key>,<key>,...     k-of-n OP_CHECKSIGADD
+  hash:<sha256>:<key>           preimage of <sha256> and signature
+  after:<height|time>:<key>     OP_CHECKLOCKTIMEVERIFY and signature
+  older:<blocks>:<key>          OP_CHECKSEQUENCEVERIFY and signature
+
+Keys are x-only or compressed public keys in hex. Signing keys are a WIF, an xprv,
+keystore:<name> (TAPROOT_KEYSTORE dir, passphrase from TAPROOT_PASSPHRASE or stdin)
+or remote:<signer socket path>.
+
+The profile defaults to BITCOIN_PROFILE, then regtest.";
+
+// BIP-341 NUMS point `H`, an internal key without known secret: disables the key path.
+const UNSPENDABLE_KEY: &str = "50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0";
+
+/// `--name value` options, in order, and the remaining positional arguments.
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, Vec<String>>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+        let mut positional = vec![];
+        let mut options: HashMap<String, Vec<String>> = HashMap::new();
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some(name) => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow!("Missing value of --{}", name))?;
+                    options.entry(name.to_string()).or_default().push(value);
+                }
+                None => positional.push(arg),
+            }
+        }
+        Ok(Self {
+            positional,
+            options,
+        })
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options
+            .get(name)
+            .and_then(|values| values.last())
+            .map(String::as_str)
+    }
+
+    fn required(&self, name: &str) -> anyhow::Result<&str> {
+        self.option(name)
+            .ok_or_else(|| anyhow!("Missing --{}", name))
+    }
+
+    fn all(&self, name: &str) -> &[String] {
+        self.options
+            .get(name)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    fn arg(&self, index: usize, name: &str) -> anyhow::Result<&str> {
+        self.positional
+            .get(index)
+            .map(String::as_str)
+            .ok_or_else(|| anyhow!("Missing <{}>", name))
+    }
+}
+
+/// Where transactions are looked up and broadcast: the node if the profile has one,
+/// otherwise its Esplora server.
+enum Chain {
+    Rpc(bitcoincore_rpc::Client),
+    Esplora(esplora_client::AsyncClient, tokio::runtime::Runtime),
+}
+
+impl Chain {
+    fn new(profile: &Profile) -> anyhow::Result<Self> {
+        if profile.rpc_url.is_some() {
+            return Ok(Chain::Rpc(profile.rpc_client()?));
+        }
+        let url = profile
+            .esplora_url
+            .as_ref()
+            .ok_or_else(|| anyhow!("Profile {} has no RPC nor Esplora url", profile.name))?;
+        Ok(Chain::Esplora(
+            esplora_client::Builder::new(url).build_async()?,
+            tokio::runtime::Runtime::new()?,
+        ))
+    }
+
+    fn transaction(&self, txid: &Txid) -> anyhow::Result<Transaction> {
+        match self {
+            Chain::Rpc(rpc) => Ok(rpc.get_raw_transaction(txid, None)?),
+            Chain::Esplora(client, runtime) => runtime
+                .block_on(client.get_tx(txid))?
+                .ok_or_else(|| anyhow!("Transaction {} not found", txid)),
+        }
+    }
+
+    fn prevout(&self, out_point: &OutPoint) -> anyhow::Result<TxOut> {
+        self.transaction(&out_point.txid)?
+            .output
+            .get(out_point.vout as usize)
+            .cloned()
+            .ok_or_else(|| anyhow!("Output {} not found", out_point))
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> anyhow::Result<Txid> {
+        match self {
+            Chain::Rpc(rpc) => Ok(rpc.send_raw_transaction(tx)?),
+            Chain::Esplora(client, runtime) => {
+                runtime.block_on(client.broadcast(tx))?;
+                Ok(tx.compute_txid())
+            }
+        }
+    }
+}
+
+fn main() {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => exit_with_usage(&e),
+    };
+    if args.positional.is_empty() {
+        println!("{}", USAGE);
+        return;
+    }
+    if let Err(e) = run(&args) {
+        exit_with_usage(&e);
+    }
+}
+
+fn exit_with_usage(e: &anyhow::Error) -> ! {
+    eprintln!("error: {}\n\n{}", e, USAGE);
+    std::process::exit(1);
+}
+
+fn run(args: &Args) -> anyhow::Result<()> {
+    let profile = match args.option("profile") {
+        Some(name) => Profile::load(name)?,
+        None => Profile::from_env()?,
+    };
+    let command: Vec<&str> = args.positional.iter().take(2).map(String::as_str).collect();
+    // positional arguments after the (sub)command.
+    let rest = |skip: usize| Args {
+        positional: args.positional[skip..].to_vec(),
+        options: args.options.clone(),
+    };
+    match command.as_slice() {
+        ["keygen", ..] => keygen(args, &profile),
+        ["address", "info", ..] => address_info(&rest(2), &profile),
+        ["address", "tree", ..] => address_tree(args, &profile),
+        ["tx", "build", ..] => tx_build(args, &profile),
+        ["tx", "sign", ..] => tx_sign(args),
+        ["tx", "broadcast", ..] => tx_broadcast(&rest(2), &profile),
+        ["psbt", "sign", ..] => psbt_sign(&rest(2)),
+        ["psbt", "combine", ..] => psbt_combine(&rest(2)),
+        ["psbt", "finalize", ..] => psbt_finalize(&rest(2)),
+        ["decode", ..] => decode_tx(&rest(1), &profile),
+        ["faucet", "claim", ..] => faucet_claim(&rest(2), &profile),
+        ["regtest", "fund", ..] => regtest_fund(&rest(2), &profile),
+        ["regtest", "mine", ..] => regtest_mine(&rest(2), &profile),
+        _ => bail!("Unknown command: {}", args.positional.join(" ")),
+    }
+}
+
+fn keygen(args: &Args, profile: &Profile) -> anyhow::Result<()> {
+    let sk = Keygen::gen_sk(profile.network);
+    let pk = Keygen::pk_from_sk(&sk);
+    println!("network: {}", profile.network);
+    println!("private_key: {}", sk.to_wif());
+    println!("public_key: {}", pk);
+    let address_type = args.option("type");
+    if matches!(address_type, None | Some("p2tr")) {
+        println!("p2tr: {}", Keygen::p2tr_addr_from_pk(pk, profile.network)?);
+    }
+    if matches!(address_type, None | Some("p2wpkh")) {
+        println!(
+            "p2wpkh: {}",
+            Keygen::p2wpkh_addr_from_pk(&pk, profile.network)?
+        );
+    }
+    if matches!(address_type, None | Some("p2sh-p2wpkh")) {
+        println!(
+            "p2sh-p2wpkh: {}",
+            Keygen::p2sh_p2wpkh_addr_from_pk(&pk, profile.network)?
+        );
+    }
+    if matches!(address_type, None | Some("p2pkh")) {
+        println!(
+            "p2pkh: {}",
+            Keygen::p2pkh_addr_from_pk(pk, profile.network)?
+        );
+    }
+    Ok(())
+}
+
+fn address_info(args: &Args, profile: &Profile) -> anyhow::Result<()> {
+    let info = AddressInfo::parse(args.arg(0, "address")?)?;
+    println!(
+        "type: {}",
+        info.address_type
+            .map_or("unknown".to_string(), |t| t.to_string())
+    );
+    println!("network: {}", info.network);
+    println!(
+        "profile {} ({}): {}",
+        profile.name,
+        profile.network,
+        if info.network.contains(profile.network) {
+            "ok"
+        } else {
+            "wrong network"
+        }
+    );
+    Ok(())
+}
+
+fn parse_key(key: &str) -> anyhow::Result<XOnlyPublicKey> {
+    if key == "nums" {
+        return Ok(XOnlyPublicKey::from_str(UNSPENDABLE_KEY)?);
+    }
+    match key.len() {
+        64 => Ok(XOnlyPublicKey::from_str(key)?),
+        _ => Ok(bitcoin::PublicKey::from_str(key)?
+            .inner
+            .x_only_public_key()
+            .0),
+    }
+}
+
+fn parse_leaf(spec: &str) -> anyhow::Result<LeafTemplate> {
+    let parts: Vec<&str> = spec.split(':').collect();
+    let leaf = match parts.as_slice() {
+        ["pk", key] => LeafTemplate::single_sig(parse_key(key)?),
+        ["multi", threshold, keys] => LeafTemplate::multi_a(
+            keys.split(',')
+                .map(parse_key)
+                .collect::<anyhow::Result<_>>()?,
+            threshold.parse()?,
+        )?,
+        ["hash", hash, key] => {
+            LeafTemplate::hash_lock(sha256::Hash::from_str(hash)?, parse_key(key)?)
+        }
+        ["after", lock_time, key] => LeafTemplate::abs_time_lock(
+            absolute::LockTime::from_consensus(lock_time.parse()?),
+            parse_key(key)?,
+        ),
+        ["older", blocks, key] => LeafTemplate::rel_time_lock(
+            relative::LockTime::from_height(blocks.parse()?),
+            parse_key(key)?,
+        ),
+        _ => bail!("Invalid leaf spec: {}", spec),
+    };
+    Ok(leaf)
+}
+
+fn address_tree(args: &Args, profile: &Profile) -> anyhow::Result<()> {
+    let internal_key = parse_key(args.required("internal-key")?)?;
+    let leaves = args
+        .all("leaf")
+        .iter()
+        .map(|spec| parse_leaf(spec))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if leaves.is_empty() {
+        bail!("Missing --leaf");
+    }
+    let scripts: Vec<ScriptBuf> = leaves.iter().map(LeafTemplate::to_script).collect();
+    let spend_info = TaprootBuilder::with_huffman_tree(scripts.iter().map(|s| (1, s.clone())))?
+        .finalize(
+            &bitcoin::secp256k1::Secp256k1::verification_only(),
+            internal_key,
+        )
+        .map_err(|_| anyhow!("Fail to finalize taproot tree"))?;
+
+    println!(
+        "address: {}",
+        Address::p2tr_tweaked(spend_info.output_key(), profile.network)
+    );
+    println!("internal_key: {}", internal_key);
+    println!("output_key: {}", spend_info.output_key());
+    if let Some(merkle_root) = spend_info.merkle_root() {
+        println!("merkle_root: {}", merkle_root);
+    }
+    for (spec, script) in args.all("leaf").iter().zip(&scripts) {
+        let control_block = spend_info
+            .control_block(&(script.clone(), LeafVersion::TapScript))
+            .ok_or_else(|| anyhow!("Missing control block of {}", spec))?;
+        println!("leaf {}:", spec);
+        println!("  script: {}", script.to_asm_string());
+        println!("  script_hex: {}", script.to_hex_string());
+        println!(
+            "  control_block: {}",
+            bitcoin::hex::DisplayHex::to_lower_hex_string(&control_block.serialize())
+        );
+    }
+    Ok(())
+}
+
+fn parse_psbt(psbt: &str) -> anyhow::Result<Psbt> {
+    Psbt::from_str(psbt.trim()).map_err(|e| anyhow!("Invalid base64 PSBT: {}", e))
+}
+
+fn load_signer(key: &str) -> anyhow::Result<Box<dyn Signer>> {
+    if let Some(name) = key.strip_prefix("keystore:") {
+        let dir = std::env::var("TAPROOT_KEYSTORE").unwrap_or_else(|_| "keystore".to_string());
+        let passphrase = match std::env::var("TAPROOT_PASSPHRASE") {
+            Ok(passphrase) => passphrase,
+            Err(_) => {
+                eprint!("passphrase of {}: ", name);
+                let mut line = String::new();
+                std::io::stdin().read_line(&mut line)?;
+                line.trim_end_matches(['\r', '\n']).to_string()
+            }
+        };
+        let account = Keystore::open(dir)?.unlock(name, &passphrase)?;
+        return Ok(Box::new(InMemorySigner::from_account(&account)));
+    }
+    #[cfg(unix)]
+    if let Some(path) = key.strip_prefix("remote:") {
+        return Ok(Box::new(RemoteSigner::connect(path)?));
+    }
+    let private_key = match PrivateKey::from_wif(key) {
+        Ok(private_key) => private_key,
+        Err(_) => bitcoin::bip32::Xpriv::from_str(key)
+            .map_err(|_| {
+                anyhow!("Invalid signing key, expect a WIF, an xprv, keystore: or remote:")
+            })?
+            .to_priv(),
+    };
+    Ok(Box::new(InMemorySigner::from_private_key(&private_key)))
+}
+
+fn tx_build(args: &Args, profile: &Profile) -> anyhow::Result<()> {
+    let chain = Chain::new(profile)?;
+    let mut input = vec![];
+    let mut prevouts = vec![];
+    for out_point in args.all("input") {
+        let out_point = OutPoint::from_str(out_point)?;
+        prevouts.push(chain.prevout(&out_point)?);
+        input.push(TxIn {
+            previous_output: out_point,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::default(),
+        });
+    }
+    let mut output = vec![];
+    for spec in args.all("output") {
+        let (address, sats) = spec
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("Invalid output {}, expect <address:sats>", spec))?;
+        let address = profile.parse_address(address)?;
+        output.push(TxOut {
+            value: Amount::from_sat(sats.parse()?),
+            script_pubkey: address.script_pubkey(),
+        });
+    }
+    if input.is_empty() || output.is_empty() {
+        bail!("Need at least one --input and one --output");
+    }
+
+    let input_value = prevouts.iter().map(|prevout| prevout.value).sum::<Amount>();
+    let output_value = output.iter().map(|out| out.value).sum::<Amount>();
+    let fee = input_value
+        .checked_sub(output_value)
+        .ok_or_else(|| anyhow!("Outputs {} exceed inputs {}", output_value, input_value))?;
+    let mut psbt = Psbt::from_unsigned_tx(Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input,
+        output,
+    })?;
+    for (psbt_input, prevout) in psbt.inputs.iter_mut().zip(prevouts) {
+        psbt_input.witness_utxo = Some(prevout);
+    }
+    eprintln!("fee: {} sat", fee.to_sat());
+    println!("{}", psbt);
+    Ok(())
+}
+
+fn tx_sign(args: &Args) -> anyhow::Result<()> {
+    let mut psbt = parse_psbt(args.required("psbt")?)?;
+    let signer = load_signer(args.required("key")?)?;
+    if sign_psbt(&mut psbt, signer.as_ref())? == 0 {
+        bail!("Nothing to sign with this key");
+    }
+    finalize_psbt(&mut psbt)?;
+    println!("{}", encode::serialize_hex(&psbt.extract_tx()?));
+    Ok(())
+}
+
+fn tx_broadcast(args: &Args, profile: &Profile) -> anyhow::Result<()> {
+    let tx: Transaction = encode::deserialize_hex(args.arg(0, "raw tx hex")?.trim())?;
+    println!("{}", Chain::new(profile)?.broadcast(&tx)?);
+    Ok(())
+}
+
+fn psbt_sign(args: &Args) -> anyhow::Result<()> {
+    let mut psbt = parse_psbt(args.arg(0, "psbt")?)?;
+    let signer = load_signer(args.required("key")?)?;
+    let count = sign_psbt(&mut psbt, signer.as_ref())?;
+    eprintln!("signatures added: {}", count);
+    println!("{}", psbt);
+    Ok(())
+}
+
+fn psbt_combine(args: &Args) -> anyhow::Result<()> {
+    let mut psbts = args.positional.iter().map(|psbt| parse_psbt(psbt));
+    let mut combined = psbts.next().ok_or_else(|| anyhow!("Missing <psbt>"))??;
+    for psbt in psbts {
+        combined.combine(psbt?)?;
+    }
+    println!("{}", combined);
+    Ok(())
+}
+
+fn psbt_finalize(args: &Args) -> anyhow::Result<()> {
+    let mut psbt = parse_psbt(args.arg(0, "psbt")?)?;
+    finalize_psbt(&mut psbt)?;
+    println!("{}", encode::serialize_hex(&psbt.extract_tx()?));
+    Ok(())
+}
+
+fn decode_tx(args: &Args, profile: &Profile) -> anyhow::Result<()> {
+    // Prevouts are best effort, the report works without them.
+    let chain = Chain::new(profile).ok();
+    let resolve = |out_point: &OutPoint| chain.as_ref()?.prevout(out_point).ok();
+    let report = decode(args.arg(0, "raw tx hex|psbt")?, profile.network, &resolve)?;
+    print!("{}", report);
+    Ok(())
+}
+
+fn faucet_claim(args: &Args, profile: &Profile) -> anyhow::Result<()> {
+    let url = profile
+        .faucet_url
+        .as_ref()
+        .ok_or_else(|| anyhow!("Profile {} has no faucet", profile.name))?;
+    let address = profile.parse_address(args.arg(0, "address")?)?;
+    let sats: u32 = args.arg(1, "sats")?.parse()?;
+    let response = tokio::runtime::Runtime::new()?
+        .block_on(FaucetClient::new(url).claim_tokens(&address.to_string(), sats))?;
+    println!("{}", response.txid);
+    Ok(())
+}
+
+fn regtest_rpc(args: &Args, profile: &Profile) -> anyhow::Result<bitcoincore_rpc::Client> {
+    if profile.network != Network::Regtest {
+        bail!("Profile {} is not a regtest profile", profile.name);
+    }
+    match args.option("wallet") {
+        Some(wallet) => profile.wallet_rpc_client(wallet),
+        None => profile.rpc_client(),
+    }
+}
+
+fn regtest_fund(args: &Args, profile: &Profile) -> anyhow::Result<()> {
+    let rpc = regtest_rpc(args, profile)?;
+    let address = profile.parse_address(args.arg(0, "address")?)?;
+    let amount = Amount::from_str_in(args.arg(1, "btc")?, bitcoin::Denomination::Bitcoin)?;
+    let txid = rpc.send_to_address(&address, amount, None, None, None, None, None, None)?;
+    println!("{}", txid);
+    Ok(())
+}
+
+fn regtest_mine(args: &Args, profile: &Profile) -> anyhow::Result<()> {
+    let rpc = regtest_rpc(args, profile)?;
+    let blocks: u64 = args.arg(0, "blocks")?.parse()?;
+    let address = match args.option("address") {
+        Some(address) => profile.parse_address(address)?,
+        None => rpc
+            .get_new_address(None, None)?
+            .require_network(profile.network)?,
+    };
+    for hash in rpc.generate_to_address(blocks, &address)? {
+        println!("{}", hash);
+    }
+    Ok(())
+}
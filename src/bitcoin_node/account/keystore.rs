@@ -0,0 +1,479 @@
+//! Encrypted on-disk keystore for `BitcoinAccount`.
+//!
+//! Every account is stored as one json file `<dir>/<name>.json`. The secret (a WIF, or an xprv
+//! with the derivation path of the account key) is encrypted with ChaCha20-Poly1305 under a key
+//! derived from the passphrase with scrypt. The account name and network are bound to the
+//! ciphertext as associated data, so a file can't be renamed or retargeted without detection.
+use crate::bitcoin_node::account::BitcoinAccount;
+use crate::keygen::Keygen;
+use anyhow::{anyhow, bail};
+use bitcoin::bip32::{DerivationPath, Xpriv};
+use bitcoin::hex::{DisplayHex, FromHex};
+use bitcoin::{Network, NetworkKind, PrivateKey, PublicKey};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use zeroize::{Zeroize, Zeroizing};
+
+const KEYSTORE_VERSION: u32 = 1;
+const SALT_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+
+/// The secret material of an account, as it is stored inside the encrypted payload.
+#[derive(Serialize, Deserialize)]
+pub enum KeySecret {
+    Wif(String),
+    Xpriv {
+        xpriv: String,
+        derivation_path: String,
+    },
+}
+
+impl Drop for KeySecret {
+    fn drop(&mut self) {
+        match self {
+            KeySecret::Wif(wif) => wif.zeroize(),
+            KeySecret::Xpriv {
+                xpriv,
+                derivation_path,
+            } => {
+                xpriv.zeroize();
+                derivation_path.zeroize();
+            }
+        }
+    }
+}
+
+impl KeySecret {
+    pub fn from_account(account: &BitcoinAccount) -> Self {
+        match &account.derivation {
+            Some(derivation) => KeySecret::Xpriv {
+                xpriv: derivation.master.to_string(),
+                derivation_path: derivation.path.to_string(),
+            },
+            None => KeySecret::Wif(account.private_key.to_wif()),
+        }
+    }
+
+    pub fn to_account(&self) -> anyhow::Result<BitcoinAccount> {
+        match self {
+            KeySecret::Wif(wif) => Ok(BitcoinAccount::from_private_key(PrivateKey::from_wif(wif)?)),
+            KeySecret::Xpriv {
+                xpriv,
+                derivation_path,
+            } => BitcoinAccount::from_xpriv(
+                Xpriv::from_str(xpriv)?,
+                DerivationPath::from_str(derivation_path)?,
+            ),
+        }
+    }
+}
+
+/// scrypt cost parameters, persisted next to every entry so they can be raised later.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KdfParams {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            log_n: scrypt::Params::RECOMMENDED_LOG_N,
+            r: scrypt::Params::RECOMMENDED_R,
+            p: scrypt::Params::RECOMMENDED_P,
+        }
+    }
+}
+
+/// The on-disk representation of an account. Everything but `ciphertext` is public.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeystoreEntry {
+    pub version: u32,
+    pub name: String,
+    pub network: Network,
+    pub public_key: PublicKey,
+    pub derivation_path: Option<String>,
+    pub kdf: KdfParams,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+impl KeystoreEntry {
+    fn associated_data(name: &str, network: Network) -> Vec<u8> {
+        format!("{}:{}:{}", KEYSTORE_VERSION, name, network).into_bytes()
+    }
+
+    fn derive_key(
+        passphrase: &str,
+        salt: &[u8],
+        kdf: &KdfParams,
+    ) -> anyhow::Result<Zeroizing<[u8; KEY_LEN]>> {
+        let params = scrypt::Params::new(kdf.log_n, kdf.r, kdf.p, KEY_LEN)
+            .map_err(|e| anyhow!("Invalid scrypt params: {}", e))?;
+        let mut key = Zeroizing::new([0u8; KEY_LEN]);
+        scrypt::scrypt(passphrase.as_bytes(), salt, &params, key.as_mut())
+            .map_err(|e| anyhow!("scrypt failed: {}", e))?;
+        Ok(key)
+    }
+
+    fn seal(
+        name: &str,
+        account: &BitcoinAccount,
+        network: Network,
+        passphrase: &str,
+        kdf: KdfParams,
+    ) -> anyhow::Result<Self> {
+        if account.private_key.network != NetworkKind::from(network) {
+            bail!("Account key doesn't belong to network: {}", network);
+        }
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let key = Self::derive_key(passphrase, &salt, &kdf)?;
+        let plaintext = Zeroizing::new(serde_json::to_vec(&KeySecret::from_account(account))?);
+        let aad = Self::associated_data(name, network);
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(key.as_ref()))
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| anyhow!("Fail to encrypt account: {}", name))?;
+
+        Ok(Self {
+            version: KEYSTORE_VERSION,
+            name: name.to_string(),
+            network,
+            public_key: account.public_key,
+            derivation_path: account.derivation.as_ref().map(|d| d.path.to_string()),
+            kdf,
+            salt: salt.to_lower_hex_string(),
+            nonce: nonce.to_lower_hex_string(),
+            ciphertext: ciphertext.to_lower_hex_string(),
+        })
+    }
+
+    fn open(&self, passphrase: &str) -> anyhow::Result<BitcoinAccount> {
+        if self.version != KEYSTORE_VERSION {
+            bail!("Unsupported keystore version: {}", self.version);
+        }
+        let salt = Vec::<u8>::from_hex(&self.salt)?;
+        let nonce = Vec::<u8>::from_hex(&self.nonce)?;
+        let ciphertext = Vec::<u8>::from_hex(&self.ciphertext)?;
+        if nonce.len() != NONCE_LEN {
+            bail!("Invalid nonce length: {}", nonce.len());
+        }
+
+        let key = Self::derive_key(passphrase, &salt, &self.kdf)?;
+        let aad = Self::associated_data(&self.name, self.network);
+        let plaintext = ChaCha20Poly1305::new(Key::from_slice(key.as_ref()))
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map(Zeroizing::new)
+            .map_err(|_| anyhow!("Wrong passphrase or corrupted keystore: {}", self.name))?;
+
+        let secret: KeySecret = serde_json::from_slice(&plaintext)?;
+        let account = secret.to_account()?;
+        if account.public_key != self.public_key {
+            bail!("Public key mismatch for account: {}", self.name);
+        }
+        Ok(account)
+    }
+}
+
+/// The accounts of a keystore directory, and the json files in it that aren't one.
+#[derive(Debug, Default)]
+pub struct KeystoreListing {
+    pub entries: Vec<KeystoreEntry>,
+    pub invalid: Vec<(PathBuf, anyhow::Error)>,
+}
+
+/// A directory of encrypted accounts.
+pub struct Keystore {
+    dir: PathBuf,
+    kdf: KdfParams,
+}
+
+impl Keystore {
+    pub fn open<P: AsRef<Path>>(dir: P) -> anyhow::Result<Self> {
+        Self::open_with_kdf(dir, KdfParams::default())
+    }
+
+    /// Open a keystore that seals new or rotated entries with the given scrypt parameters.
+    pub fn open_with_kdf<P: AsRef<Path>>(dir: P, kdf: KdfParams) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| anyhow!("Fail to create keystore dir: {:?}, error: {}", dir, e))?;
+        Ok(Self { dir, kdf })
+    }
+
+    fn entry_path(&self, name: &str) -> anyhow::Result<PathBuf> {
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            bail!("Invalid account name: {:?}", name);
+        }
+        Ok(self.dir.join(format!("{}.json", name)))
+    }
+
+    // Owner only files.
+    fn file_options() -> std::fs::OpenOptions {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options
+    }
+
+    fn write_file(mut file: std::fs::File, entry: &KeystoreEntry) -> anyhow::Result<()> {
+        std::io::Write::write_all(&mut file, &serde_json::to_vec_pretty(entry)?)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Replace the file of `entry` atomically.
+    fn write_entry(&self, entry: &KeystoreEntry) -> anyhow::Result<()> {
+        let path = self.entry_path(&entry.name)?;
+        let tmp_path = path.with_extension("json.tmp");
+        let file = Self::file_options()
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        Self::write_file(file, entry)?;
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// Write the file of `entry`, failing if it exists, even when created concurrently.
+    fn create_entry(&self, entry: &KeystoreEntry) -> anyhow::Result<()> {
+        let path = self.entry_path(&entry.name)?;
+        let file = match Self::file_options().create_new(true).open(&path) {
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                bail!("Account already exists: {}", entry.name)
+            }
+            file => file?,
+        };
+        Self::write_file(file, entry).inspect_err(|_| {
+            let _ = std::fs::remove_file(&path);
+        })
+    }
+
+    fn read_entry(&self, name: &str) -> anyhow::Result<KeystoreEntry> {
+        let path = self.entry_path(name)?;
+        let data = std::fs::read(&path)
+            .map_err(|e| anyhow!("Fail to read account: {}, error: {}", name, e))?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Encrypt and persist `account` as `name`. Fails if the name is taken.
+    pub fn import(
+        &self,
+        name: &str,
+        account: &BitcoinAccount,
+        network: Network,
+        passphrase: &str,
+    ) -> anyhow::Result<KeystoreEntry> {
+        let entry = KeystoreEntry::seal(name, account, network, passphrase, self.kdf)?;
+        self.create_entry(&entry)?;
+        Ok(entry)
+    }
+
+    /// List the public metadata of all stored accounts, without decrypting anything. A file
+    /// that can't be read doesn't hide the others, it's reported in `invalid`.
+    pub fn list(&self) -> anyhow::Result<KeystoreListing> {
+        let mut listing = KeystoreListing::default();
+        for dir_entry in std::fs::read_dir(&self.dir)? {
+            let path = dir_entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let entry = std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| Ok(serde_json::from_slice::<KeystoreEntry>(&data)?));
+            match entry {
+                Ok(entry) => listing.entries.push(entry),
+                Err(e) => listing.invalid.push((path, e)),
+            }
+        }
+        listing.entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(listing)
+    }
+
+    pub fn unlock(&self, name: &str, passphrase: &str) -> anyhow::Result<BitcoinAccount> {
+        self.read_entry(name)?.open(passphrase)
+    }
+
+    /// Re-encrypt an account under a new passphrase, with a fresh salt and nonce.
+    pub fn rotate(
+        &self,
+        name: &str,
+        old_passphrase: &str,
+        new_passphrase: &str,
+    ) -> anyhow::Result<KeystoreEntry> {
+        let network = self.read_entry(name)?.network;
+        let account = self.unlock(name, old_passphrase)?;
+        let entry = KeystoreEntry::seal(name, &account, network, new_passphrase, self.kdf)?;
+        self.write_entry(&entry)?;
+        Ok(entry)
+    }
+
+    /// Delete an account. The passphrase is required so a typo can't drop the wrong key.
+    pub fn remove(&self, name: &str, passphrase: &str) -> anyhow::Result<()> {
+        self.unlock(name, passphrase)?;
+        std::fs::remove_file(self.entry_path(name)?)?;
+        Ok(())
+    }
+}
+
+/// Generate a fresh account straight into the keystore.
+pub fn gen_into_keystore(
+    keystore: &Keystore,
+    name: &str,
+    network: Network,
+    passphrase: &str,
+) -> anyhow::Result<KeystoreEntry> {
+    let account = BitcoinAccount::from_private_key(Keygen::gen_sk(network));
+    keystore.import(name, &account, network, passphrase)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::tx::USER_B_PRIVATE_KEY;
+
+    // Cheap scrypt params, the default ones take seconds in debug builds.
+    const TEST_KDF: KdfParams = KdfParams {
+        log_n: 4,
+        r: 8,
+        p: 1,
+    };
+
+    /// A keystore in a fresh temp dir, removed on drop.
+    struct TestKeystore(Keystore);
+
+    impl std::ops::Deref for TestKeystore {
+        type Target = Keystore;
+
+        fn deref(&self) -> &Keystore {
+            &self.0
+        }
+    }
+
+    impl Drop for TestKeystore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0.dir);
+        }
+    }
+
+    fn test_keystore(tag: &str) -> TestKeystore {
+        let dir = std::env::temp_dir().join(format!(
+            "keystore-{}-{}",
+            tag,
+            rand::thread_rng().next_u64()
+        ));
+        TestKeystore(Keystore::open_with_kdf(dir, TEST_KDF).unwrap())
+    }
+
+    #[test]
+    fn test_import_and_unlock_wif() -> anyhow::Result<()> {
+        let keystore = test_keystore("wif");
+        let account = BitcoinAccount::gen(Network::Regtest)?;
+        keystore.import("alice", &account, Network::Regtest, "pswd")?;
+
+        let unlocked = keystore.unlock("alice", "pswd")?;
+        assert_eq!(unlocked.private_key, account.private_key);
+        assert_eq!(unlocked.public_key, account.public_key);
+        assert!(unlocked.derivation.is_none());
+
+        assert!(keystore.unlock("alice", "wrong").is_err());
+        let error = keystore
+            .import("alice", &account, Network::Regtest, "pswd")
+            .unwrap_err();
+        assert!(error.to_string().contains("already exists"));
+        assert_eq!(
+            keystore.unlock("alice", "pswd")?.public_key,
+            account.public_key
+        );
+        assert!(keystore
+            .import("alice2", &account, Network::Bitcoin, "pswd")
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_and_unlock_xpriv() -> anyhow::Result<()> {
+        let keystore = test_keystore("xpriv");
+        let account = BitcoinAccount::from_xpriv(
+            Xpriv::from_str(USER_B_PRIVATE_KEY)?,
+            DerivationPath::from_str("m/86'/1'/0'/0/0")?,
+        )?;
+        let entry = keystore.import("bob", &account, Network::Regtest, "pswd")?;
+        assert_eq!(entry.derivation_path.as_deref(), Some("86'/1'/0'/0/0"));
+
+        let unlocked = keystore.unlock("bob", "pswd")?;
+        assert_eq!(unlocked.public_key, account.public_key);
+        let derivation = unlocked.derivation.as_ref().unwrap();
+        assert_eq!(derivation.path, account.derivation.as_ref().unwrap().path);
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_rotate_and_remove() -> anyhow::Result<()> {
+        let keystore = test_keystore("rotate");
+        gen_into_keystore(&keystore, "b", Network::Regtest, "old")?;
+        gen_into_keystore(&keystore, "a", Network::Testnet, "old")?;
+
+        // a broken file is reported next to the accounts.
+        std::fs::write(keystore.dir.join("broken.json"), b"{")?;
+        let listing = keystore.list()?;
+        let names: Vec<_> = listing.entries.into_iter().map(|e| e.name).collect();
+        assert_eq!(names, vec!["a", "b"]);
+        assert_eq!(listing.invalid.len(), 1);
+        assert_eq!(listing.invalid[0].0, keystore.dir.join("broken.json"));
+
+        let before = keystore.unlock("a", "old")?;
+        keystore.rotate("a", "old", "new")?;
+        assert!(keystore.unlock("a", "old").is_err());
+        assert_eq!(keystore.unlock("a", "new")?.private_key, before.private_key);
+
+        assert!(keystore.remove("b", "wrong").is_err());
+        keystore.remove("b", "old")?;
+        assert_eq!(keystore.list()?.entries.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tampered_entry_is_rejected() -> anyhow::Result<()> {
+        let keystore = test_keystore("tamper");
+        gen_into_keystore(&keystore, "carol", Network::Regtest, "pswd")?;
+
+        let mut entry = keystore.read_entry("carol")?;
+        entry.network = Network::Bitcoin;
+        keystore.write_entry(&entry)?;
+        assert!(keystore.unlock("carol", "pswd").is_err());
+
+        assert!(keystore.entry_path("../carol").is_err());
+        Ok(())
+    }
+}
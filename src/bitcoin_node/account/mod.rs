@@ -1,21 +1,70 @@
+pub mod keystore;
 mod test;
 
 use crate::keygen::Keygen;
-use bitcoin::{Address, Network, PrivateKey, PublicKey};
+use crate::silent_payments::{SilentPaymentAddress, SilentPaymentKeys};
+use crate::SECP;
+use anyhow::anyhow;
+use bitcoin::bip32::{DerivationPath, Xpriv};
+use bitcoin::{Network, PrivateKey, PublicKey};
+
+/// Where an account key comes from when it was derived from an extended private key.
+pub struct AccountDerivation {
+    pub master: Xpriv,
+    pub path: DerivationPath,
+}
 
 pub struct BitcoinAccount {
     pub private_key: PrivateKey,
     pub public_key: PublicKey,
+    pub derivation: Option<AccountDerivation>,
 }
 
 impl BitcoinAccount {
     pub fn gen(network: Network) -> anyhow::Result<BitcoinAccount> {
         let sk = Keygen::gen_sk(network);
-        let pk = Keygen::pk_from_sk(&sk);
+        Ok(Self::from_private_key(sk))
+    }
 
+    pub fn from_private_key(private_key: PrivateKey) -> BitcoinAccount {
+        let public_key = Keygen::pk_from_sk(&private_key);
+        BitcoinAccount {
+            private_key,
+            public_key,
+            derivation: None,
+        }
+    }
+
+    pub fn from_xpriv(master: Xpriv, path: DerivationPath) -> anyhow::Result<BitcoinAccount> {
+        let private_key = master.derive_priv(&SECP, &path)?.to_priv();
+        let public_key = Keygen::pk_from_sk(&private_key);
         Ok(BitcoinAccount {
-            private_key: sk,
-            public_key: pk,
+            private_key,
+            public_key,
+            derivation: Some(AccountDerivation { master, path }),
         })
     }
+
+    /// BIP-352 scan and spend keys of account 0, derived from the master key.
+    pub fn silent_payment_keys(&self) -> anyhow::Result<SilentPaymentKeys> {
+        let derivation = self
+            .derivation
+            .as_ref()
+            .ok_or_else(|| anyhow!("Fail to derive silent payment keys: no master key"))?;
+        SilentPaymentKeys::from_xpriv(&derivation.master, 0)
+    }
+
+    pub fn silent_payment_address(&self, network: Network) -> anyhow::Result<SilentPaymentAddress> {
+        Ok(self.silent_payment_keys()?.receiver(network).address())
+    }
+}
+
+// Wipe the secrets once the account goes out of scope.
+impl Drop for BitcoinAccount {
+    fn drop(&mut self) {
+        self.private_key.inner.non_secure_erase();
+        if let Some(derivation) = self.derivation.as_mut() {
+            derivation.master.private_key.non_secure_erase();
+        }
+    }
 }
@@ -0,0 +1,187 @@
+//! Address parsing and network validation.
+//!
+//! `Address::from_str` accepts an address of any network, `assume_checked` then silently sends
+//! to it. `AddressInfo` reports what an address is before it gets used and `require_network`
+//! refuses addresses of another network, e.g. a mainnet `bc1..` address under a signet profile.
+//!
+//! Segwit addresses tell the networks apart by their human readable part: `bc` mainnet, `tb`
+//! testnet and signet, `bcrt` regtest. Base58 (p2pkh/p2sh) addresses only distinguish mainnet
+//! from the test networks, regtest included.
+//!
+//! Reference: https://github.com/bitcoin/bips/blob/master/bip-0173.mediawiki#segwit-address-format
+use anyhow::{anyhow, bail};
+use bitcoin::address::NetworkUnchecked;
+use bitcoin::{Address, AddressType, Network};
+use std::fmt;
+use std::str::FromStr;
+
+/// Networks an address can be used on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressNetwork {
+    /// `bc` or a mainnet base58 prefix.
+    Mainnet,
+    /// `tb`, testnet, testnet4 and signet.
+    Testnet,
+    /// `bcrt`.
+    Regtest,
+    /// A test network base58 prefix, valid on testnet, signet and regtest alike.
+    AnyTest,
+}
+
+impl AddressNetwork {
+    pub fn of(address: &Address<NetworkUnchecked>) -> Self {
+        let testnet = address.is_valid_for_network(Network::Testnet);
+        let regtest = address.is_valid_for_network(Network::Regtest);
+        match (testnet, regtest) {
+            (true, true) => Self::AnyTest,
+            (true, false) => Self::Testnet,
+            (false, true) => Self::Regtest,
+            (false, false) => Self::Mainnet,
+        }
+    }
+
+    pub fn contains(&self, network: Network) -> bool {
+        match self {
+            Self::Mainnet => network == Network::Bitcoin,
+            Self::Testnet => matches!(
+                network,
+                Network::Testnet | Network::Testnet4 | Network::Signet
+            ),
+            Self::Regtest => network == Network::Regtest,
+            Self::AnyTest => network != Network::Bitcoin,
+        }
+    }
+}
+
+impl From<Network> for AddressNetwork {
+    fn from(network: Network) -> Self {
+        match network {
+            Network::Bitcoin => Self::Mainnet,
+            Network::Regtest => Self::Regtest,
+            _ => Self::Testnet,
+        }
+    }
+}
+
+impl fmt::Display for AddressNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Mainnet => write!(f, "mainnet"),
+            Self::Testnet => write!(f, "testnet/signet"),
+            Self::Regtest => write!(f, "regtest"),
+            Self::AnyTest => write!(f, "testnet/signet/regtest"),
+        }
+    }
+}
+
+/// A parsed address with its type and network, not yet checked against any network.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddressInfo {
+    pub address: Address<NetworkUnchecked>,
+    /// `None` for segwit versions without a standard type (v2 and up).
+    pub address_type: Option<AddressType>,
+    pub network: AddressNetwork,
+}
+
+impl AddressInfo {
+    pub fn parse(address: &str) -> anyhow::Result<Self> {
+        let address = Address::from_str(address.trim())
+            .map_err(|e| anyhow!("Invalid address {}: {}", address, e))?;
+        Ok(Self {
+            // The type doesn't depend on the network.
+            address_type: address.clone().assume_checked().address_type(),
+            network: AddressNetwork::of(&address),
+            address,
+        })
+    }
+
+    /// The address, if it can be used on `network`.
+    pub fn require_network(self, network: Network) -> anyhow::Result<Address> {
+        if !self.network.contains(network) {
+            bail!(
+                "Refusing {} address {} on {}",
+                self.network,
+                self.address.assume_checked_ref(),
+                network
+            );
+        }
+        Ok(self.address.assume_checked())
+    }
+}
+
+impl fmt::Display for AddressInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let address_type = self
+            .address_type
+            .map_or("unknown".to_string(), |t| t.to_string());
+        write!(
+            f,
+            "{} ({}, {})",
+            self.address.assume_checked_ref(),
+            address_type,
+            self.network
+        )
+    }
+}
+
+/// Parse `address` and check that it belongs to `network`.
+pub fn parse_address(address: &str, network: Network) -> anyhow::Result<Address> {
+    AddressInfo::parse(address)?.require_network(network)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_address_info() -> anyhow::Result<()> {
+        let cases = [
+            (
+                "bc1p0dq0tzg2r780hldthn5mrznmpxsxc0jux5f20fwj0z3wqxxk6fpqm7q0va",
+                AddressType::P2tr,
+                AddressNetwork::Mainnet,
+            ),
+            (
+                "tb1ql9mjwcp9swms3hm6kyvp832myv4ujmqcpmn7az",
+                AddressType::P2wpkh,
+                AddressNetwork::Testnet,
+            ),
+            (
+                "bcrt1p3ndyc0r2s4khcqka5vpeyt00ky378nk7mr6tmmja7yu6jg24uscq064uer",
+                AddressType::P2tr,
+                AddressNetwork::Regtest,
+            ),
+            (
+                "3JvL6Ymt8MVWiCNHC7oWU6nLeHNJKLZGLN",
+                AddressType::P2sh,
+                AddressNetwork::Mainnet,
+            ),
+            (
+                "2N83imGV3gPwBzKJQvWJ7cRUY2SpUyU6A5e",
+                AddressType::P2sh,
+                AddressNetwork::AnyTest,
+            ),
+        ];
+        for (address, address_type, network) in cases {
+            let info = AddressInfo::parse(address)?;
+            assert_eq!(info.address_type, Some(address_type), "{}", address);
+            assert_eq!(info.network, network, "{}", address);
+        }
+
+        // cross network sends are refused
+        let mainnet = "bc1p0dq0tzg2r780hldthn5mrznmpxsxc0jux5f20fwj0z3wqxxk6fpqm7q0va";
+        assert!(parse_address(mainnet, Network::Bitcoin).is_ok());
+        let error = parse_address(mainnet, Network::Signet).unwrap_err();
+        assert!(error.to_string().contains("Refusing mainnet address"));
+        let regtest = "bcrt1p3ndyc0r2s4khcqka5vpeyt00ky378nk7mr6tmmja7yu6jg24uscq064uer";
+        assert!(parse_address(regtest, Network::Testnet).is_err());
+        assert!(parse_address(
+            "tb1ql9mjwcp9swms3hm6kyvp832myv4ujmqcpmn7az",
+            Network::Regtest
+        )
+        .is_err());
+        assert!(parse_address("2N83imGV3gPwBzKJQvWJ7cRUY2SpUyU6A5e", Network::Regtest).is_ok());
+        assert!(parse_address("not an address", Network::Regtest).is_err());
+        Ok(())
+    }
+}
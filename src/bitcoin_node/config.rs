@@ -1,7 +1,11 @@
+use crate::bitcoin_node::address::AddressInfo;
 use crate::bitcoin_node::wallet::BitcoinWallet;
 use crate::bitcoin_node::BitcoinClient;
+use anyhow::{anyhow, bail};
+use bitcoin::{Address, Network};
 use bitcoincore_rpc::Auth;
 use dotenv::dotenv;
+use std::str::FromStr;
 
 pub struct BitcoinConfig;
 
@@ -30,3 +34,181 @@ impl BitcoinConfig {
         }
     }
 }
+
+/// Builtin profiles: name, network, Bitcoin Core RPC url, Esplora url and faucet url.
+type BuiltinProfile = (
+    &'static str,
+    Network,
+    Option<&'static str>,
+    Option<&'static str>,
+    Option<&'static str>,
+);
+
+const BUILTIN_PROFILES: [BuiltinProfile; 5] = [
+    (
+        "regtest",
+        Network::Regtest,
+        Some("http://localhost:18443"),
+        None,
+        None,
+    ),
+    (
+        "mutinynet",
+        Network::Signet,
+        None,
+        Some("https://mutinynet.com/api"),
+        Some("https://faucet.mutinynet.com"),
+    ),
+    (
+        "signet",
+        Network::Signet,
+        None,
+        Some("https://mempool.space/signet/api"),
+        None,
+    ),
+    (
+        "testnet",
+        Network::Testnet,
+        None,
+        Some("https://mempool.space/testnet/api"),
+        None,
+    ),
+    (
+        "mainnet",
+        Network::Bitcoin,
+        None,
+        Some("https://mempool.space/api"),
+        None,
+    ),
+];
+
+/// The network, node and services one environment talks to.
+///
+/// Selected with `BITCOIN_PROFILE` (default `regtest`). Each field can be overridden with
+/// `<PROFILE>_NETWORK`, `<PROFILE>_RPC_URL`, `<PROFILE>_ESPLORA_URL` or `<PROFILE>_FAUCET_URL`
+/// (e.g. `MUTINYNET_ESPLORA_URL`), which is also how new profiles are defined. The regtest
+/// RPC url falls back to `BITCOIN_NETWORK`, RPC credentials come from `bitcoin_auth`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Profile {
+    pub name: String,
+    pub network: Network,
+    pub rpc_url: Option<String>,
+    pub esplora_url: Option<String>,
+    pub faucet_url: Option<String>,
+}
+
+impl Profile {
+    pub fn from_env() -> anyhow::Result<Self> {
+        dotenv().ok();
+        let name = std::env::var("BITCOIN_PROFILE").unwrap_or_else(|_| "regtest".to_string());
+        Self::load(&name)
+    }
+
+    pub fn load(name: &str) -> anyhow::Result<Self> {
+        dotenv().ok();
+        Self::from_vars(name, |key| std::env::var(key).ok())
+    }
+
+    fn from_vars(name: &str, var: impl Fn(&str) -> Option<String>) -> anyhow::Result<Self> {
+        let prefix = name.to_uppercase().replace('-', "_");
+        let profile_var = |key: &str| var(&format!("{}_{}", prefix, key));
+        let builtin = BUILTIN_PROFILES.iter().find(|profile| profile.0 == name);
+
+        let network = match (profile_var("NETWORK"), builtin) {
+            (Some(network), _) => Network::from_str(&network)
+                .map_err(|e| anyhow!("Invalid network of profile {}: {}", name, e))?,
+            (None, Some(builtin)) => builtin.1,
+            (None, None) => bail!(
+                "Unknown profile {}, set {}_NETWORK to define it",
+                name,
+                prefix
+            ),
+        };
+        let builtin_url = |url: fn(&BuiltinProfile) -> Option<&'static str>| {
+            builtin.and_then(url).map(str::to_string)
+        };
+        let mut rpc_url = profile_var("RPC_URL");
+        if rpc_url.is_none() && name == "regtest" {
+            rpc_url = var("BITCOIN_NETWORK");
+        }
+        Ok(Self {
+            name: name.to_string(),
+            network,
+            rpc_url: rpc_url.or_else(|| builtin_url(|b| b.2)),
+            esplora_url: profile_var("ESPLORA_URL").or_else(|| builtin_url(|b| b.3)),
+            faucet_url: profile_var("FAUCET_URL").or_else(|| builtin_url(|b| b.4)),
+        })
+    }
+
+    /// Parse `address`, refusing addresses of another network than this profile's.
+    pub fn parse_address(&self, address: &str) -> anyhow::Result<Address> {
+        AddressInfo::parse(address)?
+            .require_network(self.network)
+            .map_err(|e| anyhow!("{} (profile {})", e, self.name))
+    }
+
+    pub fn rpc_client(&self) -> anyhow::Result<bitcoincore_rpc::Client> {
+        let url = self
+            .rpc_url
+            .as_ref()
+            .ok_or_else(|| anyhow!("Profile {} has no RPC url", self.name))?;
+        BitcoinClient::init_client_with_url(url)
+    }
+
+    /// RPC client of the node wallet `wallet_name`.
+    pub fn wallet_rpc_client(&self, wallet_name: &str) -> anyhow::Result<bitcoincore_rpc::Client> {
+        let url = self
+            .rpc_url
+            .as_ref()
+            .ok_or_else(|| anyhow!("Profile {} has no RPC url", self.name))?;
+        BitcoinClient::init_client_with_url(&format!("{}/wallet/{}", url, wallet_name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_profile_builtin_and_overrides() -> anyhow::Result<()> {
+        let vars: HashMap<&str, &str> = [
+            ("BITCOIN_NETWORK", "http://127.0.0.1:18443"),
+            ("MUTINYNET_ESPLORA_URL", "http://localhost:3002"),
+            ("MY_SIGNET_NETWORK", "signet"),
+            ("MY_SIGNET_RPC_URL", "http://localhost:38332"),
+        ]
+        .into_iter()
+        .collect();
+        let var = |key: &str| vars.get(key).map(|v| v.to_string());
+
+        let regtest = Profile::from_vars("regtest", var)?;
+        assert_eq!(regtest.network, Network::Regtest);
+        assert_eq!(regtest.rpc_url.as_deref(), Some("http://127.0.0.1:18443"));
+
+        let mutinynet = Profile::from_vars("mutinynet", var)?;
+        assert_eq!(mutinynet.network, Network::Signet);
+        assert_eq!(
+            mutinynet.esplora_url.as_deref(),
+            Some("http://localhost:3002")
+        );
+        assert_eq!(
+            mutinynet.faucet_url.as_deref(),
+            Some("https://faucet.mutinynet.com")
+        );
+        assert!(mutinynet.rpc_client().is_err());
+
+        let custom = Profile::from_vars("my-signet", var)?;
+        assert_eq!(custom.network, Network::Signet);
+        assert_eq!(custom.rpc_url.as_deref(), Some("http://localhost:38332"));
+        assert_eq!(custom.esplora_url, None);
+
+        assert!(Profile::from_vars("unknown", var).is_err());
+
+        let tb = "tb1ql9mjwcp9swms3hm6kyvp832myv4ujmqcpmn7az";
+        assert!(mutinynet.parse_address(tb).is_ok());
+        let error = regtest.parse_address(tb).unwrap_err().to_string();
+        assert!(error.contains("testnet/signet") && error.contains("profile regtest"));
+        Ok(())
+    }
+}
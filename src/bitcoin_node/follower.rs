@@ -0,0 +1,539 @@
+//! Chain follower over bitcoind RPC: walks the blocks from a checkpoint and reports the
+//! outputs paid to our scripts and their spends.
+//!
+//! Blocks are checked against their BIP-158 basic filter (`getblockfilter`, bitcoind with
+//! `-blockfilterindex`) and only downloaded on a match. The filter holds the output scripts
+//! and the scripts of the spent outputs, our scripts match both what we receive and what we
+//! spend. Without the index every block is downloaded.
+//!
+//! Each poll first checks the block hashes it followed against the node: the blocks no longer
+//! in the best chain are disconnected, their outputs dropped and their spends reverted.
+//!
+//! Scripts watched after the follower went past a block don't rescan it, start from an older
+//! checkpoint instead.
+//!
+//! Reference: https://github.com/bitcoin/bips/blob/master/bip-0158.mediawiki
+use anyhow::bail;
+use bitcoin::bip158::BlockFilter;
+use bitcoin::taproot::TaprootSpendInfo;
+use bitcoin::{Block, BlockHash, OutPoint, ScriptBuf, TxOut, Txid};
+use bitcoincore_rpc::jsonrpc::error::Error as JsonRpcError;
+use bitcoincore_rpc::{Error, RpcApi};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// `getblockfilter` without `-blockfilterindex`.
+const RPC_MISC_ERROR: i32 = -1;
+// bitcoind before `getblockfilter`.
+const RPC_METHOD_NOT_FOUND: i32 = -32601;
+/// Block hashes kept to find the fork point of a reorg.
+pub const MAX_REORG_DEPTH: u32 = 100;
+
+/// Where blocks come from, bitcoind or a test double.
+pub trait BlockSource {
+    fn tip_height(&self) -> anyhow::Result<u32>;
+    fn block_hash(&self, height: u32) -> anyhow::Result<BlockHash>;
+    fn block(&self, hash: &BlockHash) -> anyhow::Result<Block>;
+    /// BIP-158 basic filter of the block, `None` if the source has none.
+    fn block_filter(&self, hash: &BlockHash) -> anyhow::Result<Option<BlockFilter>>;
+}
+
+impl BlockSource for bitcoincore_rpc::Client {
+    fn tip_height(&self) -> anyhow::Result<u32> {
+        Ok(self.get_block_count()? as u32)
+    }
+
+    fn block_hash(&self, height: u32) -> anyhow::Result<BlockHash> {
+        Ok(self.get_block_hash(height as u64)?)
+    }
+
+    fn block(&self, hash: &BlockHash) -> anyhow::Result<Block> {
+        Ok(self.get_block(hash)?)
+    }
+
+    fn block_filter(&self, hash: &BlockHash) -> anyhow::Result<Option<BlockFilter>> {
+        match self.get_block_filter(hash) {
+            Ok(result) => Ok(Some(result.into_filter())),
+            Err(Error::JsonRpc(JsonRpcError::Rpc(ref e)))
+                if e.code == RPC_MISC_ERROR || e.code == RPC_METHOD_NOT_FOUND =>
+            {
+                Ok(None)
+            }
+            Err(e) => bail!("Fail to getblockfilter {}: {}", hash, e),
+        }
+    }
+}
+
+/// A block the follower starts after.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub height: u32,
+    pub hash: BlockHash,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FollowedUtxo {
+    pub txout: TxOut,
+    pub height: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChainEvent {
+    Received {
+        out_point: OutPoint,
+        txout: TxOut,
+        height: u32,
+    },
+    Spent {
+        out_point: OutPoint,
+        txout: TxOut,
+        spending_txid: Txid,
+        height: u32,
+    },
+    /// The block left the best chain, what it received and spent is reverted.
+    Disconnected { height: u32, hash: BlockHash },
+}
+
+#[derive(Clone, Debug)]
+struct Spend {
+    out_point: OutPoint,
+    utxo: FollowedUtxo,
+    height: u32,
+}
+
+pub struct ChainFollower {
+    scripts: HashSet<ScriptBuf>,
+    /// Hashes of the last `MAX_REORG_DEPTH` blocks followed, the checkpoint first.
+    hashes: BTreeMap<u32, BlockHash>,
+    utxos: HashMap<OutPoint, FollowedUtxo>,
+    spends: Vec<Spend>,
+    use_filters: bool,
+}
+
+impl ChainFollower {
+    pub fn new(checkpoint: Checkpoint) -> Self {
+        Self {
+            scripts: HashSet::new(),
+            hashes: BTreeMap::from([(checkpoint.height, checkpoint.hash)]),
+            utxos: HashMap::new(),
+            spends: vec![],
+            use_filters: true,
+        }
+    }
+
+    pub fn watch_script(&mut self, script_pubkey: ScriptBuf) {
+        self.scripts.insert(script_pubkey);
+    }
+
+    /// Watch the output key of a taproot tree, e.g. from `LeafTemplate`s.
+    pub fn watch_taproot(&mut self, spend_info: &TaprootSpendInfo) {
+        self.watch_script(ScriptBuf::new_p2tr_tweaked(spend_info.output_key()));
+    }
+
+    pub fn tip(&self) -> Checkpoint {
+        let (height, hash) = self.hashes.last_key_value().unwrap();
+        Checkpoint {
+            height: *height,
+            hash: *hash,
+        }
+    }
+
+    pub fn utxos(&self) -> &HashMap<OutPoint, FollowedUtxo> {
+        &self.utxos
+    }
+
+    /// Follow `source` up to its tip, returns what happened to our scripts since last time.
+    pub fn poll<S: BlockSource>(&mut self, source: &S) -> anyhow::Result<Vec<ChainEvent>> {
+        let mut events = self.disconnect_stale(source)?;
+        let tip_height = source.tip_height()?;
+        for height in self.tip().height + 1..=tip_height {
+            let hash = source.block_hash(height)?;
+            if self.may_match(source, &hash)? {
+                let block = source.block(&hash)?;
+                if block.header.prev_blockhash != self.tip().hash {
+                    // reorg while we were polling, catch up on the next poll.
+                    break;
+                }
+                events.extend(self.connect(&block, height));
+            }
+            self.hashes.insert(height, hash);
+            while self.hashes.len() > MAX_REORG_DEPTH as usize {
+                self.hashes.pop_first();
+            }
+            // the oldest block followed can't be disconnected, nor can its spends be reverted.
+            let (&oldest, _) = self.hashes.first_key_value().unwrap();
+            self.spends.retain(|spend| spend.height > oldest);
+        }
+        Ok(events)
+    }
+
+    fn may_match<S: BlockSource>(&mut self, source: &S, hash: &BlockHash) -> anyhow::Result<bool> {
+        if self.scripts.is_empty() {
+            return Ok(false);
+        }
+        if !self.use_filters {
+            return Ok(true);
+        }
+        match source.block_filter(hash)? {
+            Some(filter) => Ok(filter.match_any(hash, self.scripts.iter().map(|s| s.as_bytes()))?),
+            None => {
+                self.use_filters = false;
+                Ok(true)
+            }
+        }
+    }
+
+    fn connect(&mut self, block: &Block, height: u32) -> Vec<ChainEvent> {
+        let mut events = vec![];
+        for tx in &block.txdata {
+            let txid = tx.compute_txid();
+            for input in &tx.input {
+                if let Some(utxo) = self.utxos.remove(&input.previous_output) {
+                    events.push(ChainEvent::Spent {
+                        out_point: input.previous_output,
+                        txout: utxo.txout.clone(),
+                        spending_txid: txid,
+                        height,
+                    });
+                    self.spends.push(Spend {
+                        out_point: input.previous_output,
+                        utxo,
+                        height,
+                    });
+                }
+            }
+            for (vout, txout) in tx.output.iter().enumerate() {
+                if self.scripts.contains(&txout.script_pubkey) {
+                    let out_point = OutPoint::new(txid, vout as u32);
+                    let utxo = FollowedUtxo {
+                        txout: txout.clone(),
+                        height,
+                    };
+                    self.utxos.insert(out_point, utxo);
+                    events.push(ChainEvent::Received {
+                        out_point,
+                        txout: txout.clone(),
+                        height,
+                    });
+                }
+            }
+        }
+        events
+    }
+
+    /// Disconnect the blocks followed that are no longer in the best chain of `source`.
+    fn disconnect_stale<S: BlockSource>(&mut self, source: &S) -> anyhow::Result<Vec<ChainEvent>> {
+        let tip_height = source.tip_height()?;
+        let mut events = vec![];
+        while let Some((&height, &hash)) = self.hashes.last_key_value() {
+            if height <= tip_height && source.block_hash(height)? == hash {
+                return Ok(events);
+            }
+            if self.hashes.len() == 1 {
+                bail!(
+                    "Reorg deeper than the {} blocks followed, at height {}",
+                    MAX_REORG_DEPTH,
+                    height
+                );
+            }
+            self.hashes.pop_last();
+            // revert the spends first, an output may be received and spent in the same block.
+            while self
+                .spends
+                .last()
+                .is_some_and(|spend| spend.height == height)
+            {
+                let spend = self.spends.pop().unwrap();
+                self.utxos.insert(spend.out_point, spend.utxo);
+            }
+            self.utxos.retain(|_, utxo| utxo.height != height);
+            events.push(ChainEvent::Disconnected { height, hash });
+        }
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::tx::taproot_tree_tx::template::LeafTemplate;
+    use crate::bitcoin_node::tx::USER_A_PRIVATE_KEY;
+    use crate::SECP;
+    use bitcoin::bip32::Xpriv;
+    use bitcoin::block::{Header, Version};
+    use bitcoin::hashes::Hash;
+    use bitcoin::taproot::TaprootBuilder;
+    use bitcoin::{
+        absolute, transaction, Amount, CompactTarget, Sequence, Transaction, TxIn, TxMerkleNode,
+        Witness,
+    };
+    use std::cell::RefCell;
+    use std::str::FromStr;
+
+    /// A best chain of blocks, with or without filters, recording the blocks downloaded.
+    struct FakeNode {
+        blocks: Vec<Block>,
+        filters: bool,
+        downloaded: RefCell<Vec<u32>>,
+    }
+
+    impl FakeNode {
+        fn new(filters: bool) -> Self {
+            let mut node = Self {
+                blocks: vec![],
+                filters,
+                downloaded: RefCell::new(vec![]),
+            };
+            node.mine(vec![]);
+            node
+        }
+
+        fn mine(&mut self, txdata: Vec<Transaction>) -> BlockHash {
+            let height = self.blocks.len() as u32;
+            let coinbase = Transaction {
+                version: transaction::Version::TWO,
+                lock_time: absolute::LockTime::ZERO,
+                input: vec![TxIn {
+                    previous_output: OutPoint::null(),
+                    script_sig: ScriptBuf::from_bytes(height.to_le_bytes().to_vec()),
+                    sequence: Sequence::MAX,
+                    witness: Witness::default(),
+                }],
+                output: vec![TxOut {
+                    value: Amount::from_sat(50_000),
+                    script_pubkey: ScriptBuf::new_op_return([]),
+                }],
+            };
+            let mut block = Block {
+                header: Header {
+                    version: Version::ONE,
+                    prev_blockhash: self
+                        .blocks
+                        .last()
+                        .map_or(BlockHash::all_zeros(), |b| b.block_hash()),
+                    merkle_root: TxMerkleNode::all_zeros(),
+                    time: height,
+                    bits: CompactTarget::from_consensus(0x207fffff),
+                    nonce: self.blocks.len() as u32,
+                },
+                txdata: [vec![coinbase], txdata].concat(),
+            };
+            block.header.merkle_root = block.compute_merkle_root().unwrap();
+            self.blocks.push(block);
+            self.blocks.last().unwrap().block_hash()
+        }
+
+        fn height(&self, hash: &BlockHash) -> anyhow::Result<usize> {
+            match self.blocks.iter().position(|b| b.block_hash() == *hash) {
+                Some(height) => Ok(height),
+                None => bail!("Unknown block {}", hash),
+            }
+        }
+    }
+
+    impl BlockSource for FakeNode {
+        fn tip_height(&self) -> anyhow::Result<u32> {
+            Ok(self.blocks.len() as u32 - 1)
+        }
+
+        fn block_hash(&self, height: u32) -> anyhow::Result<BlockHash> {
+            Ok(self.blocks[height as usize].block_hash())
+        }
+
+        fn block(&self, hash: &BlockHash) -> anyhow::Result<Block> {
+            let height = self.height(hash)?;
+            self.downloaded.borrow_mut().push(height as u32);
+            Ok(self.blocks[height].clone())
+        }
+
+        fn block_filter(&self, hash: &BlockHash) -> anyhow::Result<Option<BlockFilter>> {
+            if !self.filters {
+                return Ok(None);
+            }
+            let block = &self.blocks[self.height(hash)?];
+            let outputs: HashMap<OutPoint, ScriptBuf> = self
+                .blocks
+                .iter()
+                .flat_map(|b| &b.txdata)
+                .flat_map(|tx| {
+                    let txid = tx.compute_txid();
+                    tx.output.iter().enumerate().map(move |(vout, txout)| {
+                        (
+                            OutPoint::new(txid, vout as u32),
+                            txout.script_pubkey.clone(),
+                        )
+                    })
+                })
+                .collect();
+            let filter = BlockFilter::new_script_filter(block, |out_point| {
+                outputs
+                    .get(out_point)
+                    .cloned()
+                    .ok_or(bitcoin::bip158::Error::UtxoMissing(*out_point))
+            })?;
+            Ok(Some(filter))
+        }
+    }
+
+    fn spend(previous_output: OutPoint, script_pubkey: ScriptBuf, sats: u64) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::default(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(sats),
+                script_pubkey,
+            }],
+        }
+    }
+
+    fn follow(filters: bool) -> anyhow::Result<()> {
+        let key = Xpriv::from_str(USER_A_PRIVATE_KEY)?
+            .to_priv()
+            .public_key(&SECP)
+            .inner
+            .x_only_public_key()
+            .0;
+        let tree = TaprootBuilder::new()
+            .add_leaf(0, LeafTemplate::single_sig(key).to_script())?
+            .finalize(&SECP, key)
+            .unwrap();
+        let ours = ScriptBuf::new_p2tr_tweaked(tree.output_key());
+        let theirs = ScriptBuf::new_op_return([1]);
+
+        let mut node = FakeNode::new(filters);
+        let mut follower = ChainFollower::new(Checkpoint {
+            height: 0,
+            hash: node.block_hash(0)?,
+        });
+        follower.watch_taproot(&tree);
+
+        let coinbase = |node: &FakeNode, height: usize| {
+            OutPoint::new(node.blocks[height].txdata[0].compute_txid(), 0)
+        };
+        let funding = spend(coinbase(&node, 0), ours.clone(), 10_000);
+        let out_point = OutPoint::new(funding.compute_txid(), 0);
+        node.mine(vec![funding.clone()]);
+        node.mine(vec![spend(coinbase(&node, 1), theirs.clone(), 1)]);
+        let spending = spend(out_point, theirs.clone(), 9_000);
+        node.mine(vec![spending.clone()]);
+
+        let events = follower.poll(&node)?;
+        assert_eq!(
+            events,
+            vec![
+                ChainEvent::Received {
+                    out_point,
+                    txout: funding.output[0].clone(),
+                    height: 1,
+                },
+                ChainEvent::Spent {
+                    out_point,
+                    txout: funding.output[0].clone(),
+                    spending_txid: spending.compute_txid(),
+                    height: 3,
+                },
+            ]
+        );
+        assert!(follower.utxos().is_empty());
+        let downloaded = if filters { vec![1, 3] } else { vec![1, 2, 3] };
+        assert_eq!(*node.downloaded.borrow(), downloaded);
+        assert!(follower.poll(&node)?.is_empty());
+
+        // block 3 is replaced by two blocks without the spend.
+        let stale = node.blocks.pop().unwrap().block_hash();
+        node.mine(vec![spend(coinbase(&node, 2), theirs, 2)]);
+        node.mine(vec![]);
+        let events = follower.poll(&node)?;
+        assert_eq!(
+            events,
+            vec![ChainEvent::Disconnected {
+                height: 3,
+                hash: stale
+            }]
+        );
+        assert_eq!(follower.utxos()[&out_point].height, 1);
+        assert_eq!(follower.tip().height, 4);
+        assert_eq!(follower.tip().hash, node.block_hash(4)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_follow_with_block_filters() -> anyhow::Result<()> {
+        follow(true)
+    }
+
+    #[test]
+    fn test_follow_without_block_filters() -> anyhow::Result<()> {
+        follow(false)
+    }
+
+    #[test]
+    fn test_reorg_parent_and_child_in_same_block() -> anyhow::Result<()> {
+        let mut node = FakeNode::new(false);
+        let mut follower = ChainFollower::new(Checkpoint {
+            height: 0,
+            hash: node.block_hash(0)?,
+        });
+        let ours = ScriptBuf::new_op_return([2]);
+        follower.watch_script(ours.clone());
+
+        // a CPFP parent mined with its child.
+        let mine_package = |node: &mut FakeNode| {
+            let coinbase = OutPoint::new(node.blocks[0].txdata[0].compute_txid(), 0);
+            let parent = spend(coinbase, ours.clone(), 10_000);
+            let child = spend(OutPoint::new(parent.compute_txid(), 0), ours.clone(), 9_000);
+            let child_out_point = OutPoint::new(child.compute_txid(), 0);
+            (node.mine(vec![parent, child]), child_out_point)
+        };
+        let (stale, child_out_point) = mine_package(&mut node);
+        assert_eq!(follower.poll(&node)?.len(), 3);
+        assert_eq!(
+            follower.utxos().keys().collect::<Vec<_>>(),
+            vec![&child_out_point]
+        );
+
+        node.blocks.pop();
+        node.mine(vec![spend(OutPoint::null(), ScriptBuf::new(), 0)]);
+        let events = follower.poll(&node)?;
+        assert_eq!(
+            events,
+            vec![ChainEvent::Disconnected {
+                height: 1,
+                hash: stale
+            }]
+        );
+        assert!(follower.utxos().is_empty());
+        assert!(follower.spends.is_empty());
+
+        // spends are forgotten once their block is out of the reorg depth.
+        mine_package(&mut node);
+        follower.poll(&node)?;
+        assert_eq!(follower.spends.len(), 1);
+        for _ in 0..MAX_REORG_DEPTH {
+            node.mine(vec![]);
+        }
+        follower.poll(&node)?;
+        assert!(follower.spends.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reorg_past_followed_blocks() -> anyhow::Result<()> {
+        let mut node = FakeNode::new(true);
+        node.mine(vec![]);
+        let mut follower = ChainFollower::new(Checkpoint {
+            height: 1,
+            hash: node.block_hash(1)?,
+        });
+        node.blocks.pop();
+        node.mine(vec![spend(OutPoint::null(), ScriptBuf::new(), 0)]);
+        assert!(follower.poll(&node).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,352 @@
+//! An in-memory chain to exercise contracts without a running node.
+//!
+//! `LocalLedger` keeps a utxo set and a mempool, mines blocks on demand and enforces what a
+//! node would on broadcast: inputs exist and are unspent, outputs don't exceed inputs and the
+//! transaction is final (lock time and BIP-68 sequence locks). Of the node policy, it enforces
+//! the TRUC (v3) topology limits and ephemeral dust, see `submit_package`. Scripts and
+//! signatures are not checked here.
+use crate::bitcoin_node::tx::anchor::{TRUC_CHILD_MAX_VSIZE, TRUC_MAX_VSIZE, TRUC_VERSION};
+use crate::bitcoin_node::tx::timelock::{check_final, ChainTip, Confirmation};
+use anyhow::{anyhow, bail};
+use bitcoin::{
+    absolute, script, transaction, Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut,
+    Txid, Witness,
+};
+use std::collections::HashMap;
+
+// One block every ten minutes.
+const BLOCK_INTERVAL: u32 = 600;
+
+#[derive(Clone)]
+struct LedgerUtxo {
+    txout: TxOut,
+    confirmation: Option<Confirmation>,
+}
+
+#[derive(Clone)]
+pub struct LocalLedger {
+    tip: ChainTip,
+    utxos: HashMap<OutPoint, LedgerUtxo>,
+    mempool: Vec<Transaction>,
+    transactions: HashMap<Txid, Transaction>,
+    funding_count: u32,
+}
+
+impl LocalLedger {
+    pub fn new(tip: ChainTip) -> Self {
+        Self {
+            tip,
+            utxos: HashMap::new(),
+            mempool: vec![],
+            transactions: HashMap::new(),
+            funding_count: 0,
+        }
+    }
+
+    pub fn tip(&self) -> ChainTip {
+        self.tip
+    }
+
+    /// Create an output out of thin air, confirmed in the tip block like a matured coinbase.
+    pub fn fund(&mut self, script_pubkey: ScriptBuf, value: Amount) -> (OutPoint, TxOut) {
+        self.funding_count += 1;
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: script::Builder::new()
+                    .push_int(self.funding_count as i64)
+                    .into_script(),
+                sequence: Sequence::MAX,
+                witness: Witness::default(),
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey,
+            }],
+        };
+        let out_point = OutPoint::new(tx.compute_txid(), 0);
+        let txout = tx.output[0].clone();
+        self.utxos.insert(
+            out_point,
+            LedgerUtxo {
+                txout: txout.clone(),
+                confirmation: Some(Confirmation {
+                    height: self.tip.height,
+                    prev_median_time_past: self.tip.median_time_past.saturating_sub(BLOCK_INTERVAL),
+                }),
+            },
+        );
+        self.transactions.insert(out_point.txid, tx);
+        (out_point, txout)
+    }
+
+    /// Accept `tx` into the mempool.
+    pub fn broadcast(&mut self, tx: &Transaction) -> anyhow::Result<Txid> {
+        Ok(self.submit_package(std::slice::from_ref(tx))?[0])
+    }
+
+    /// Accept `txs`, parents first, all or none of them.
+    ///
+    /// A transaction with a dust output (e.g. a P2A anchor of 0 sat) pays no fee and comes
+    /// with the child spending its dust in the same package (ephemeral dust).
+    pub fn submit_package(&mut self, txs: &[Transaction]) -> anyhow::Result<Vec<Txid>> {
+        let mut staged = self.clone();
+        let txids = txs
+            .iter()
+            .map(|tx| staged.accept(tx, txs))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        *self = staged;
+        Ok(txids)
+    }
+
+    fn accept(&mut self, tx: &Transaction, package: &[Transaction]) -> anyhow::Result<Txid> {
+        let txid = tx.compute_txid();
+        if self.transactions.contains_key(&txid) {
+            bail!("Transaction {} already known", txid);
+        }
+
+        let mut input_value = Amount::ZERO;
+        let mut confirmations = vec![];
+        for input in &tx.input {
+            let utxo = self
+                .utxos
+                .get(&input.previous_output)
+                .ok_or_else(|| anyhow!("Missing or spent input {}", input.previous_output))?;
+            input_value += utxo.txout.value;
+            confirmations.push(utxo.confirmation);
+        }
+        let output_value = tx.output.iter().map(|out| out.value).sum::<Amount>();
+        if output_value > input_value {
+            bail!(
+                "Outputs {} exceed inputs {} in {}",
+                output_value,
+                input_value,
+                txid
+            );
+        }
+        check_final(tx, &self.tip, &confirmations)?;
+        self.check_truc(tx)?;
+        check_ephemeral_dust(tx, input_value - output_value, package)?;
+
+        for input in &tx.input {
+            self.utxos.remove(&input.previous_output);
+        }
+        for (vout, txout) in tx.output.iter().enumerate() {
+            self.utxos.insert(
+                OutPoint::new(txid, vout as u32),
+                LedgerUtxo {
+                    txout: txout.clone(),
+                    confirmation: None,
+                },
+            );
+        }
+        self.mempool.push(tx.clone());
+        self.transactions.insert(txid, tx.clone());
+        Ok(txid)
+    }
+
+    /// BIP-431: a TRUC transaction has at most one unconfirmed parent, itself TRUC without
+    /// unconfirmed parent nor other child, and non TRUC ones don't spend unconfirmed TRUC.
+    fn check_truc(&self, tx: &Transaction) -> anyhow::Result<()> {
+        let txid = tx.compute_txid();
+        let mut parents: Vec<&Transaction> = self
+            .mempool
+            .iter()
+            .filter(|parent| {
+                let parent_txid = parent.compute_txid();
+                tx.input
+                    .iter()
+                    .any(|input| input.previous_output.txid == parent_txid)
+            })
+            .collect();
+
+        if tx.version != TRUC_VERSION {
+            if let Some(parent) = parents.iter().find(|p| p.version == TRUC_VERSION) {
+                bail!(
+                    "Non TRUC {} spends unconfirmed TRUC {}",
+                    txid,
+                    parent.compute_txid()
+                );
+            }
+            return Ok(());
+        }
+
+        if tx.vsize() > TRUC_MAX_VSIZE {
+            bail!(
+                "TRUC {} of {} vB over {} vB",
+                txid,
+                tx.vsize(),
+                TRUC_MAX_VSIZE
+            );
+        }
+        let Some(parent) = parents.pop() else {
+            return Ok(());
+        };
+        let parent_txid = parent.compute_txid();
+        if !parents.is_empty() {
+            bail!("TRUC {} has more than one unconfirmed parent", txid);
+        }
+        if parent.version != TRUC_VERSION {
+            bail!("TRUC {} spends unconfirmed non TRUC {}", txid, parent_txid);
+        }
+        if tx.vsize() > TRUC_CHILD_MAX_VSIZE {
+            bail!(
+                "TRUC child {} of {} vB over {} vB",
+                txid,
+                tx.vsize(),
+                TRUC_CHILD_MAX_VSIZE
+            );
+        }
+        if parent
+            .input
+            .iter()
+            .any(|input| self.in_mempool(&input.previous_output.txid))
+        {
+            bail!("TRUC {} would have an unconfirmed grandparent", txid);
+        }
+        let sibling = self.mempool.iter().find(|other| {
+            other
+                .input
+                .iter()
+                .any(|input| input.previous_output.txid == parent_txid)
+        });
+        if let Some(sibling) = sibling {
+            bail!(
+                "TRUC {} already has the unconfirmed child {}",
+                parent_txid,
+                sibling.compute_txid()
+            );
+        }
+        Ok(())
+    }
+
+    /// Mine `blocks` blocks, the first one confirming the whole mempool.
+    pub fn mine(&mut self, blocks: u32) {
+        for _ in 0..blocks {
+            let confirmation = Confirmation {
+                height: self.tip.height + 1,
+                prev_median_time_past: self.tip.median_time_past,
+            };
+            for tx in self.mempool.drain(..) {
+                let txid = tx.compute_txid();
+                for vout in 0..tx.output.len() {
+                    if let Some(utxo) = self.utxos.get_mut(&OutPoint::new(txid, vout as u32)) {
+                        utxo.confirmation = Some(confirmation);
+                    }
+                }
+            }
+            self.tip.height += 1;
+            self.tip.median_time_past += BLOCK_INTERVAL;
+        }
+    }
+
+    pub fn utxo(&self, out_point: &OutPoint) -> Option<&TxOut> {
+        self.utxos.get(out_point).map(|utxo| &utxo.txout)
+    }
+
+    /// `None` if the output is unknown, spent or unconfirmed.
+    pub fn confirmation(&self, out_point: &OutPoint) -> Option<Confirmation> {
+        self.utxos.get(out_point).and_then(|utxo| utxo.confirmation)
+    }
+
+    pub fn transaction(&self, txid: &Txid) -> Option<&Transaction> {
+        self.transactions.get(txid)
+    }
+
+    pub fn in_mempool(&self, txid: &Txid) -> bool {
+        self.mempool.iter().any(|tx| tx.compute_txid() == *txid)
+    }
+}
+
+/// Ephemeral dust: one dust output at most, in a transaction paying no fee, spent by a later
+/// transaction of `package`.
+fn check_ephemeral_dust(
+    tx: &Transaction,
+    fee: Amount,
+    package: &[Transaction],
+) -> anyhow::Result<()> {
+    let txid = tx.compute_txid();
+    let dust: Vec<u32> = tx
+        .output
+        .iter()
+        .enumerate()
+        .filter(|(_, txout)| txout.value < txout.script_pubkey.minimal_non_dust())
+        .map(|(vout, _)| vout as u32)
+        .collect();
+    match dust.as_slice() {
+        [] => return Ok(()),
+        [_] => {}
+        _ => bail!("{} has {} dust outputs", txid, dust.len()),
+    }
+    if fee != Amount::ZERO {
+        bail!("{} has a dust output and pays a fee of {}", txid, fee);
+    }
+    let dust = OutPoint::new(txid, dust[0]);
+    let spent = package.iter().any(|child| {
+        child
+            .input
+            .iter()
+            .any(|input| input.previous_output == dust)
+    });
+    if !spent {
+        bail!("Dust output {} isn't spent in the package", dust);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn spend(out_point: OutPoint, value: Amount, sequence: Sequence) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: out_point,
+                script_sig: ScriptBuf::new(),
+                sequence,
+                witness: Witness::default(),
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: ScriptBuf::new_op_return([]),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_ledger_spend_and_relative_lock() -> anyhow::Result<()> {
+        let mut ledger = LocalLedger::new(ChainTip {
+            height: 100,
+            median_time_past: 1_700_000_000,
+        });
+        let (out_point, txout) = ledger.fund(ScriptBuf::new(), Amount::from_sat(10_000));
+        assert_eq!(ledger.confirmation(&out_point).unwrap().height, 100);
+
+        assert!(ledger
+            .broadcast(&spend(out_point, Amount::from_sat(20_000), Sequence::MAX))
+            .is_err());
+
+        // needs 3 confirmations: mined at 100, can be spent in block 103.
+        let tx = spend(out_point, txout.value, Sequence::from_height(3));
+        assert!(ledger.broadcast(&tx).is_err());
+        ledger.mine(1);
+        assert!(ledger.broadcast(&tx).is_err());
+        ledger.mine(1);
+        let txid = ledger.broadcast(&tx)?;
+        assert!(ledger.in_mempool(&txid));
+        assert!(ledger.utxo(&out_point).is_none());
+
+        // double spend
+        assert!(ledger
+            .broadcast(&spend(out_point, txout.value, Sequence::ZERO))
+            .is_err());
+        ledger.mine(1);
+        assert!(!ledger.in_mempool(&txid));
+        assert!(ledger.confirmation(&OutPoint::new(txid, 0)).is_some());
+        Ok(())
+    }
+}
@@ -1,11 +1,23 @@
 use crate::bitcoin_node::config::BitcoinConfig;
 
 pub mod account;
+pub mod address;
 pub mod config;
+pub mod follower;
+pub mod ledger;
 pub mod regtest;
+pub mod signer;
+pub mod store;
 mod test;
-mod tx;
+pub(crate) mod tx;
 pub mod wallet;
+pub mod zmq;
+
+// The transaction modules without the regtest keys `tx` keeps for its tests and examples.
+pub use tx::{
+    anchor, cpfp, decode, inscription, interpreter, pay_to_contract, psbt, rbf, taproot_tree_tx,
+    timelock,
+};
 
 pub struct BitcoinClient;
 
@@ -0,0 +1,288 @@
+use crate::bitcoin_node::account::BitcoinAccount;
+use crate::bitcoin_node::signer::Signer;
+use crate::SECP;
+use bitcoin::bip32::Xpriv;
+use bitcoin::key::TapTweak;
+use bitcoin::secp256k1::{ecdsa, schnorr, Keypair, Message};
+use bitcoin::taproot::{TapLeafHash, TapNodeHash};
+use bitcoin::{LegacySighash, PrivateKey, PublicKey, SegwitV0Sighash, TapSighash};
+use std::str::FromStr;
+
+/// A signer holding the secret key in this process.
+pub struct InMemorySigner {
+    keypair: Keypair,
+}
+
+impl InMemorySigner {
+    pub fn new(keypair: Keypair) -> Self {
+        Self { keypair }
+    }
+
+    pub fn from_private_key(private_key: &PrivateKey) -> Self {
+        Self::new(Keypair::from_secret_key(&SECP, &private_key.inner))
+    }
+
+    pub fn from_account(account: &BitcoinAccount) -> Self {
+        Self::from_private_key(&account.private_key)
+    }
+
+    /// Same key as `senders_keys`, e.g. one of the `USER_*_PRIVATE_KEY` test constants.
+    pub fn from_xpriv_str(xpriv: &str) -> anyhow::Result<Self> {
+        let xpriv = Xpriv::from_str(xpriv)?;
+        Ok(Self::from_private_key(&xpriv.to_priv()))
+    }
+}
+
+impl Drop for InMemorySigner {
+    fn drop(&mut self) {
+        self.keypair.non_secure_erase();
+    }
+}
+
+impl Signer for InMemorySigner {
+    fn public_key(&self) -> anyhow::Result<PublicKey> {
+        Ok(PublicKey::new(self.keypair.public_key()))
+    }
+
+    fn sign_schnorr_key_spend(
+        &self,
+        sighash: TapSighash,
+        merkle_root: Option<TapNodeHash>,
+    ) -> anyhow::Result<schnorr::Signature> {
+        let tweaked = self.keypair.tap_tweak(&SECP, merkle_root);
+        let msg = Message::from(sighash);
+        Ok(SECP.sign_schnorr(&msg, &tweaked.to_inner()))
+    }
+
+    fn sign_schnorr_script_spend(
+        &self,
+        sighash: TapSighash,
+        _leaf_hash: TapLeafHash,
+    ) -> anyhow::Result<schnorr::Signature> {
+        let msg = Message::from(sighash);
+        Ok(SECP.sign_schnorr(&msg, &self.keypair))
+    }
+
+    fn sign_ecdsa(&self, sighash: SegwitV0Sighash) -> anyhow::Result<ecdsa::Signature> {
+        let msg = Message::from(sighash);
+        Ok(SECP.sign_ecdsa(&msg, &self.keypair.secret_key()))
+    }
+
+    fn sign_ecdsa_legacy(&self, sighash: LegacySighash) -> anyhow::Result<ecdsa::Signature> {
+        let msg = Message::from(sighash);
+        Ok(SECP.sign_ecdsa(&msg, &self.keypair.secret_key()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::signer::{
+        p2sh_multisig_script_sig, sign_key_spend_input, sign_p2sh_input, sign_p2sh_p2wpkh_input,
+        sign_p2wpkh_input, sign_p2wsh_input,
+    };
+    use crate::bitcoin_node::tx::taproot_tree_tx::create_taproot_tree;
+    use crate::bitcoin_node::tx::{
+        dummy_unspent_transaction_output, senders_keys, USER_A_PRIVATE_KEY,
+    };
+    use crate::bitcoin_node::tx::{USER_B_PRIVATE_KEY, USER_C_PRIVATE_KEY};
+    use crate::keygen::Keygen;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{
+        absolute, transaction, Amount, CompressedPublicKey, EcdsaSighashType, Network, ScriptBuf,
+        Sequence, TapSighashType, Transaction, TxIn, TxOut, Witness,
+    };
+
+    fn spend_tx(prevout: bitcoin::OutPoint, script_pubkey: ScriptBuf) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: prevout,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::default(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(99_000),
+                script_pubkey,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_in_memory_key_spend_matches_senders_keys() -> anyhow::Result<()> {
+        let signer = InMemorySigner::from_xpriv_str(USER_A_PRIVATE_KEY)?;
+        let keypair = senders_keys(&SECP, USER_A_PRIVATE_KEY);
+        assert_eq!(signer.x_only_public_key()?, keypair.x_only_public_key().0);
+
+        // key path spend of the taproot tree output, tweaked with its merkle root.
+        let tree = create_taproot_tree(&SECP);
+        let prevout = TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: ScriptBuf::new_p2tr_tweaked(tree.output_key()),
+        };
+        let (out_point, _) = dummy_unspent_transaction_output(
+            &SECP,
+            signer.x_only_public_key()?,
+            "a3bb137d668556d9ccbb01e1dd6216ba139aba3c3866f0c891d4ba794537d271",
+            0,
+            prevout.value,
+        );
+        let mut tx = spend_tx(out_point, prevout.script_pubkey.clone());
+        let signature = sign_key_spend_input(
+            &signer,
+            &mut tx,
+            0,
+            std::slice::from_ref(&prevout),
+            TapSighashType::All,
+            tree.merkle_root(),
+        )?;
+
+        let sighash = bitcoin::sighash::SighashCache::new(&tx).taproot_key_spend_signature_hash(
+            0,
+            &bitcoin::sighash::Prevouts::All(&[prevout]),
+            TapSighashType::All,
+        )?;
+        SECP.verify_schnorr(
+            &signature.signature,
+            &Message::from(sighash),
+            &tree.output_key().to_inner(),
+        )?;
+        assert_eq!(tx.input[0].witness.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory_p2wpkh() -> anyhow::Result<()> {
+        let signer = InMemorySigner::from_xpriv_str(USER_A_PRIVATE_KEY)?;
+        let pk = CompressedPublicKey::try_from(signer.public_key()?)?;
+        let prevout = TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: ScriptBuf::new_p2wpkh(&pk.wpubkey_hash()),
+        };
+        let out_point = bitcoin::OutPoint {
+            txid: bitcoin::Txid::all_zeros(),
+            vout: 0,
+        };
+        let mut tx = spend_tx(out_point, prevout.script_pubkey.clone());
+        let signature = sign_p2wpkh_input(&signer, &mut tx, 0, &prevout, EcdsaSighashType::All)?;
+
+        let sighash = bitcoin::sighash::SighashCache::new(&tx).p2wpkh_signature_hash(
+            0,
+            &prevout.script_pubkey,
+            prevout.value,
+            EcdsaSighashType::All,
+        )?;
+        SECP.verify_ecdsa(&Message::from(sighash), &signature.signature, &pk.0)?;
+        assert_eq!(tx.input[0].witness.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory_p2sh_p2wpkh() -> anyhow::Result<()> {
+        let signer = InMemorySigner::from_xpriv_str(USER_A_PRIVATE_KEY)?;
+        let pk = CompressedPublicKey::try_from(signer.public_key()?)?;
+        let address = Keygen::p2sh_p2wpkh_addr_from_pk(&signer.public_key()?, Network::Regtest)?;
+        let prevout = TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: address.script_pubkey(),
+        };
+        let mut tx = spend_tx(bitcoin::OutPoint::null(), prevout.script_pubkey.clone());
+        let signature =
+            sign_p2sh_p2wpkh_input(&signer, &mut tx, 0, &prevout, EcdsaSighashType::All)?;
+
+        // The script sig is the single push of the p2wpkh redeem script, which the sighash commits to.
+        let redeem_script = ScriptBuf::new_p2wpkh(&pk.wpubkey_hash());
+        let pushes: Vec<_> = tx.input[0]
+            .script_sig
+            .instructions()
+            .collect::<Result<_, _>>()?;
+        assert_eq!(pushes.len(), 1);
+        assert_eq!(
+            pushes[0].push_bytes().map(|bytes| bytes.as_bytes()),
+            Some(redeem_script.as_bytes())
+        );
+        assert_eq!(
+            ScriptBuf::new_p2sh(&redeem_script.script_hash()),
+            prevout.script_pubkey
+        );
+        let sighash = bitcoin::sighash::SighashCache::new(&tx).p2wpkh_signature_hash(
+            0,
+            &redeem_script,
+            prevout.value,
+            EcdsaSighashType::All,
+        )?;
+        SECP.verify_ecdsa(&Message::from(sighash), &signature.signature, &pk.0)?;
+        assert_eq!(tx.input[0].witness.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory_p2wsh_and_p2sh_multisig() -> anyhow::Result<()> {
+        let signers = [USER_A_PRIVATE_KEY, USER_B_PRIVATE_KEY, USER_C_PRIVATE_KEY]
+            .iter()
+            .map(|key| InMemorySigner::from_xpriv_str(key))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let keys = signers
+            .iter()
+            .map(|signer| signer.public_key())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        // p2wsh of `<pk> OP_CHECKSIG`
+        let witness_script = Keygen::p2pk_script(&keys[0]);
+        let value = Amount::from_sat(100_000);
+        let address = Keygen::p2wsh_addr_from_script(&witness_script, Network::Regtest);
+        let mut tx = spend_tx(bitcoin::OutPoint::null(), address.script_pubkey());
+        let signature = sign_p2wsh_input(
+            &signers[0],
+            &tx,
+            0,
+            &witness_script,
+            value,
+            EcdsaSighashType::All,
+        )?;
+        tx.input[0].witness =
+            Witness::from_slice(&[signature.serialize().to_vec(), witness_script.to_bytes()]);
+        let sighash = bitcoin::sighash::SighashCache::new(&tx).p2wsh_signature_hash(
+            0,
+            &witness_script,
+            value,
+            EcdsaSighashType::All,
+        )?;
+        SECP.verify_ecdsa(
+            &Message::from(sighash),
+            &signature.signature,
+            &keys[0].inner,
+        )?;
+
+        // 2 of 3 p2sh multisig signed by A and C. The legacy sighash is over the redeem script
+        // and does not depend on the script sig set afterwards.
+        let (address, redeem_script) = Keygen::p2sh_multisig_addr(2, &keys, Network::Regtest)?;
+        let mut tx = spend_tx(bitcoin::OutPoint::null(), address.script_pubkey());
+        let signatures = [&signers[0], &signers[2]]
+            .iter()
+            .map(|signer| sign_p2sh_input(*signer, &tx, 0, &redeem_script, EcdsaSighashType::All))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        tx.input[0].script_sig = p2sh_multisig_script_sig(&signatures, &redeem_script)?;
+
+        let sighash = bitcoin::sighash::SighashCache::new(&tx).legacy_signature_hash(
+            0,
+            &redeem_script,
+            EcdsaSighashType::All.to_u32(),
+        )?;
+        let msg = Message::from(sighash);
+        SECP.verify_ecdsa(&msg, &signatures[0].signature, &keys[0].inner)?;
+        SECP.verify_ecdsa(&msg, &signatures[1].signature, &keys[2].inner)?;
+        let pushes: Vec<_> = tx.input[0]
+            .script_sig
+            .instructions()
+            .collect::<Result<_, _>>()?;
+        assert_eq!(pushes.len(), 4); // OP_0, 2 signatures, redeem script
+        assert_eq!(
+            pushes[3].push_bytes().map(|bytes| bytes.as_bytes()),
+            Some(redeem_script.as_bytes())
+        );
+        Ok(())
+    }
+}
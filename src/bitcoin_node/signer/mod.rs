@@ -0,0 +1,199 @@
+//! Abstracts where private keys live.
+//!
+//! Transaction code asks a `Signer` for signatures over sighashes it computed itself, so the
+//! secret keys can stay in this process (`InMemorySigner`) or move to another one
+//! (`RemoteSigner`, which talks to a `RemoteSignerServer` over a local unix socket).
+use bitcoin::opcodes::OP_0;
+use bitcoin::script::{self, PushBytes};
+use bitcoin::secp256k1::{ecdsa, schnorr};
+use bitcoin::sighash::{Prevouts, SighashCache};
+use bitcoin::taproot::{LeafVersion, TapLeafHash, TapNodeHash};
+use bitcoin::{
+    Amount, EcdsaSighashType, LegacySighash, PublicKey, Script, ScriptBuf, SegwitV0Sighash,
+    TapSighash, TapSighashType, Transaction, TxOut, Witness, XOnlyPublicKey,
+};
+
+pub mod memory;
+#[cfg(unix)]
+pub mod remote;
+
+pub use memory::InMemorySigner;
+#[cfg(unix)]
+pub use remote::{RemoteSigner, RemoteSignerServer};
+
+pub trait Signer {
+    fn public_key(&self) -> anyhow::Result<PublicKey>;
+
+    fn x_only_public_key(&self) -> anyhow::Result<XOnlyPublicKey> {
+        Ok(self.public_key()?.inner.x_only_public_key().0)
+    }
+
+    /// Sign a taproot key path spend. The key is tweaked with `merkle_root` first
+    /// (`None` for an output without script tree).
+    fn sign_schnorr_key_spend(
+        &self,
+        sighash: TapSighash,
+        merkle_root: Option<TapNodeHash>,
+    ) -> anyhow::Result<schnorr::Signature>;
+
+    /// Sign a taproot script path spend with the untweaked key, the way leaf scripts expect.
+    fn sign_schnorr_script_spend(
+        &self,
+        sighash: TapSighash,
+        leaf_hash: TapLeafHash,
+    ) -> anyhow::Result<schnorr::Signature>;
+
+    /// Sign a segwit v0 (p2wpkh/p2wsh) input.
+    fn sign_ecdsa(&self, sighash: SegwitV0Sighash) -> anyhow::Result<ecdsa::Signature>;
+
+    /// Sign a legacy (p2pkh/p2sh) input.
+    fn sign_ecdsa_legacy(&self, sighash: LegacySighash) -> anyhow::Result<ecdsa::Signature>;
+}
+
+/// Sign input `input_index` of `tx` through the key path and set its witness.
+pub fn sign_key_spend_input<S: Signer + ?Sized>(
+    signer: &S,
+    tx: &mut Transaction,
+    input_index: usize,
+    prevouts: &[TxOut],
+    sighash_type: TapSighashType,
+    merkle_root: Option<TapNodeHash>,
+) -> anyhow::Result<bitcoin::taproot::Signature> {
+    let mut sighasher = SighashCache::new(&mut *tx);
+    let sighash = sighasher.taproot_key_spend_signature_hash(
+        input_index,
+        &Prevouts::All(prevouts),
+        sighash_type,
+    )?;
+    let signature = bitcoin::taproot::Signature {
+        signature: signer.sign_schnorr_key_spend(sighash, merkle_root)?,
+        sighash_type,
+    };
+    tx.input[input_index].witness = Witness::p2tr_key_spend(&signature);
+    Ok(signature)
+}
+
+/// Produce the signature for `leaf_script` on input `input_index`. The witness is left to the
+/// caller, since its layout depends on the script.
+pub fn sign_script_spend_input<S: Signer + ?Sized>(
+    signer: &S,
+    tx: &Transaction,
+    input_index: usize,
+    prevouts: &[TxOut],
+    leaf_script: &Script,
+    sighash_type: TapSighashType,
+) -> anyhow::Result<bitcoin::taproot::Signature> {
+    let leaf_hash = TapLeafHash::from_script(leaf_script, LeafVersion::TapScript);
+    let sighash = SighashCache::new(tx).taproot_script_spend_signature_hash(
+        input_index,
+        &Prevouts::All(prevouts),
+        leaf_hash,
+        sighash_type,
+    )?;
+    Ok(bitcoin::taproot::Signature {
+        signature: signer.sign_schnorr_script_spend(sighash, leaf_hash)?,
+        sighash_type,
+    })
+}
+
+/// Sign a p2wpkh input and set its witness.
+pub fn sign_p2wpkh_input<S: Signer + ?Sized>(
+    signer: &S,
+    tx: &mut Transaction,
+    input_index: usize,
+    prevout: &TxOut,
+    sighash_type: EcdsaSighashType,
+) -> anyhow::Result<bitcoin::ecdsa::Signature> {
+    let mut sighasher = SighashCache::new(&mut *tx);
+    let sighash = sighasher.p2wpkh_signature_hash(
+        input_index,
+        &prevout.script_pubkey,
+        prevout.value,
+        sighash_type,
+    )?;
+    let signature = bitcoin::ecdsa::Signature {
+        signature: signer.sign_ecdsa(sighash)?,
+        sighash_type,
+    };
+    let public_key = bitcoin::CompressedPublicKey::try_from(signer.public_key()?)?;
+    tx.input[input_index].witness = Witness::p2wpkh(&signature, &public_key.0);
+    Ok(signature)
+}
+
+/// Sign a nested segwit (p2sh-p2wpkh) input and set its script sig and witness.
+pub fn sign_p2sh_p2wpkh_input<S: Signer + ?Sized>(
+    signer: &S,
+    tx: &mut Transaction,
+    input_index: usize,
+    prevout: &TxOut,
+    sighash_type: EcdsaSighashType,
+) -> anyhow::Result<bitcoin::ecdsa::Signature> {
+    let public_key = bitcoin::CompressedPublicKey::try_from(signer.public_key()?)?;
+    let redeem_script = ScriptBuf::new_p2wpkh(&public_key.wpubkey_hash());
+    let p2wpkh = TxOut {
+        value: prevout.value,
+        script_pubkey: redeem_script.clone(),
+    };
+    let signature = sign_p2wpkh_input(signer, tx, input_index, &p2wpkh, sighash_type)?;
+    tx.input[input_index].script_sig = script::Builder::new()
+        .push_slice(<&PushBytes>::try_from(redeem_script.as_bytes())?)
+        .into_script();
+    Ok(signature)
+}
+
+/// Produce the signature for `witness_script` on the p2wsh input `input_index`. The witness is
+/// left to the caller, ending with `witness_script`.
+pub fn sign_p2wsh_input<S: Signer + ?Sized>(
+    signer: &S,
+    tx: &Transaction,
+    input_index: usize,
+    witness_script: &Script,
+    value: Amount,
+    sighash_type: EcdsaSighashType,
+) -> anyhow::Result<bitcoin::ecdsa::Signature> {
+    let sighash = SighashCache::new(tx).p2wsh_signature_hash(
+        input_index,
+        witness_script,
+        value,
+        sighash_type,
+    )?;
+    Ok(bitcoin::ecdsa::Signature {
+        signature: signer.sign_ecdsa(sighash)?,
+        sighash_type,
+    })
+}
+
+/// Produce the signature for `redeem_script` on the legacy p2sh input `input_index`. The script
+/// sig is left to the caller, see `p2sh_multisig_script_sig`.
+pub fn sign_p2sh_input<S: Signer + ?Sized>(
+    signer: &S,
+    tx: &Transaction,
+    input_index: usize,
+    redeem_script: &Script,
+    sighash_type: EcdsaSighashType,
+) -> anyhow::Result<bitcoin::ecdsa::Signature> {
+    let sighash = SighashCache::new(tx).legacy_signature_hash(
+        input_index,
+        redeem_script,
+        sighash_type.to_u32(),
+    )?;
+    Ok(bitcoin::ecdsa::Signature {
+        signature: signer.sign_ecdsa_legacy(sighash)?,
+        sighash_type,
+    })
+}
+
+/// `OP_0 <signatures> <redeem_script>`, the signatures in the order of their keys in the
+/// redeem script. `OP_0` is for the extra element `OP_CHECKMULTISIG` pops.
+pub fn p2sh_multisig_script_sig(
+    signatures: &[bitcoin::ecdsa::Signature],
+    redeem_script: &Script,
+) -> anyhow::Result<ScriptBuf> {
+    let mut builder = script::Builder::new().push_opcode(OP_0);
+    for signature in signatures {
+        builder = builder.push_slice(signature.serialize());
+    }
+    Ok(builder
+        .push_slice(<&PushBytes>::try_from(redeem_script.as_bytes())?)
+        .into_script())
+}
@@ -0,0 +1,327 @@
+//! Out-of-process signer over a local unix socket.
+//!
+//! The protocol is one json request per line, answered with one json response per line:
+//!
+//! ```text
+//! -> {"method":"public_key"}
+//! <- {"public_key":"02a6ac..."}
+//! -> {"method":"sign_schnorr_key_spend","sighash":"..","merkle_root":".."}
+//! <- {"schnorr":".."}
+//! -> {"method":"sign_schnorr_script_spend","sighash":"..","leaf_hash":".."}
+//! -> {"method":"sign_ecdsa","sighash":".."}
+//! -> {"method":"sign_ecdsa_legacy","sighash":".."}
+//! <- {"ecdsa":"3044..."}
+//! <- {"error":"..."}
+//! ```
+use crate::bitcoin_node::signer::Signer;
+use anyhow::{anyhow, bail};
+use bitcoin::secp256k1::{ecdsa, schnorr};
+use bitcoin::taproot::{TapLeafHash, TapNodeHash};
+use bitcoin::{LegacySighash, PublicKey, SegwitV0Sighash, TapSighash};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum Request {
+    PublicKey,
+    SignSchnorrKeySpend {
+        sighash: TapSighash,
+        merkle_root: Option<TapNodeHash>,
+    },
+    SignSchnorrScriptSpend {
+        sighash: TapSighash,
+        leaf_hash: TapLeafHash,
+    },
+    SignEcdsa {
+        sighash: SegwitV0Sighash,
+    },
+    SignEcdsaLegacy {
+        sighash: LegacySighash,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Response {
+    PublicKey(PublicKey),
+    Schnorr(schnorr::Signature),
+    Ecdsa(ecdsa::Signature),
+    Error(String),
+}
+
+/// Serves the keys of `signer` to `RemoteSigner` clients.
+pub struct RemoteSignerServer<S: Signer> {
+    signer: S,
+    listener: UnixListener,
+}
+
+impl<S: Signer> RemoteSignerServer<S> {
+    /// Bind the socket at `path`, replacing a stale socket file. Only the owner may connect:
+    /// the socket is bound in a private directory and moved to `path` once restricted.
+    pub fn bind<P: AsRef<Path>>(path: P, signer: S) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let dir = path.with_extension(format!("{}.tmp", std::process::id()));
+        std::fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&dir)
+            .map_err(|e| anyhow!("Fail to create signer socket dir: {:?}, error: {}", dir, e))?;
+        let listener = Self::bind_private(&dir, path);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(Self {
+            signer,
+            listener: listener?,
+        })
+    }
+
+    fn bind_private(dir: &Path, path: &Path) -> anyhow::Result<UnixListener> {
+        let private_path = dir.join("signer.sock");
+        let listener = UnixListener::bind(&private_path)
+            .map_err(|e| anyhow!("Fail to bind signer socket: {:?}, error: {}", path, e))?;
+        std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&private_path, path)?;
+        Ok(listener)
+    }
+
+    /// Handle connections until the listener fails, each one in its own thread. A connection
+    /// may send many requests and is dropped after `REQUEST_TIMEOUT` without any. Connection
+    /// errors are passed to `on_error`.
+    pub fn serve<F>(&self, on_error: F) -> anyhow::Result<()>
+    where
+        S: Sync,
+        F: Fn(anyhow::Error) + Sync,
+    {
+        std::thread::scope(|scope| {
+            for stream in self.listener.incoming() {
+                let stream = stream?;
+                let on_error = &on_error;
+                scope.spawn(move || {
+                    if let Err(e) = self.handle_connection(stream) {
+                        on_error(e);
+                    }
+                });
+            }
+            Ok(())
+        })
+    }
+
+    fn handle_connection(&self, stream: UnixStream) -> anyhow::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let response = match serde_json::from_str::<Request>(&line?) {
+                Ok(request) => self.dispatch(request),
+                Err(e) => Response::Error(format!("invalid request: {}", e)),
+            };
+            serde_json::to_writer(&mut writer, &response)?;
+            writer.write_all(b"\n")?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn dispatch(&self, request: Request) -> Response {
+        let response = match request {
+            Request::PublicKey => self.signer.public_key().map(Response::PublicKey),
+            Request::SignSchnorrKeySpend {
+                sighash,
+                merkle_root,
+            } => self
+                .signer
+                .sign_schnorr_key_spend(sighash, merkle_root)
+                .map(Response::Schnorr),
+            Request::SignSchnorrScriptSpend { sighash, leaf_hash } => self
+                .signer
+                .sign_schnorr_script_spend(sighash, leaf_hash)
+                .map(Response::Schnorr),
+            Request::SignEcdsa { sighash } => self.signer.sign_ecdsa(sighash).map(Response::Ecdsa),
+            Request::SignEcdsaLegacy { sighash } => {
+                self.signer.sign_ecdsa_legacy(sighash).map(Response::Ecdsa)
+            }
+        };
+        response.unwrap_or_else(|e| Response::Error(e.to_string()))
+    }
+}
+
+/// Client side of the signer socket. The public key is fetched once on connect.
+pub struct RemoteSigner {
+    path: PathBuf,
+    public_key: PublicKey,
+}
+
+impl RemoteSigner {
+    pub fn connect<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let public_key = match Self::request(&path, &Request::PublicKey)? {
+            Response::PublicKey(pk) => pk,
+            other => bail!("Unexpected signer response: {:?}", other),
+        };
+        Ok(Self { path, public_key })
+    }
+
+    fn request(path: &Path, request: &Request) -> anyhow::Result<Response> {
+        let mut stream = UnixStream::connect(path)
+            .map_err(|e| anyhow!("Fail to connect signer: {:?}, error: {}", path, e))?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+        serde_json::to_writer(&mut stream, request)?;
+        stream.write_all(b"\n")?;
+        stream.flush()?;
+
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line)?;
+        match serde_json::from_str(&line)? {
+            Response::Error(e) => bail!("Signer error: {}", e),
+            response => Ok(response),
+        }
+    }
+
+    fn request_schnorr(&self, request: &Request) -> anyhow::Result<schnorr::Signature> {
+        match Self::request(&self.path, request)? {
+            Response::Schnorr(signature) => Ok(signature),
+            other => bail!("Unexpected signer response: {:?}", other),
+        }
+    }
+
+    fn request_ecdsa(&self, request: &Request) -> anyhow::Result<ecdsa::Signature> {
+        match Self::request(&self.path, request)? {
+            Response::Ecdsa(signature) => Ok(signature),
+            other => bail!("Unexpected signer response: {:?}", other),
+        }
+    }
+}
+
+impl Signer for RemoteSigner {
+    fn public_key(&self) -> anyhow::Result<PublicKey> {
+        Ok(self.public_key)
+    }
+
+    fn sign_schnorr_key_spend(
+        &self,
+        sighash: TapSighash,
+        merkle_root: Option<TapNodeHash>,
+    ) -> anyhow::Result<schnorr::Signature> {
+        self.request_schnorr(&Request::SignSchnorrKeySpend {
+            sighash,
+            merkle_root,
+        })
+    }
+
+    fn sign_schnorr_script_spend(
+        &self,
+        sighash: TapSighash,
+        leaf_hash: TapLeafHash,
+    ) -> anyhow::Result<schnorr::Signature> {
+        self.request_schnorr(&Request::SignSchnorrScriptSpend { sighash, leaf_hash })
+    }
+
+    fn sign_ecdsa(&self, sighash: SegwitV0Sighash) -> anyhow::Result<ecdsa::Signature> {
+        self.request_ecdsa(&Request::SignEcdsa { sighash })
+    }
+
+    fn sign_ecdsa_legacy(&self, sighash: LegacySighash) -> anyhow::Result<ecdsa::Signature> {
+        self.request_ecdsa(&Request::SignEcdsaLegacy { sighash })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::signer::InMemorySigner;
+    use crate::bitcoin_node::tx::taproot_tree_tx::create_taproot_tree;
+    use crate::bitcoin_node::tx::{USER_A_PRIVATE_KEY, USER_B_PRIVATE_KEY};
+    use bitcoin::hashes::Hash;
+    use bitcoin::key::TapTweak;
+    use secp256k1::{Message, Secp256k1};
+
+    fn spawn_server(tag: &str, xpriv: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("signer-{}-{}.sock", tag, std::process::id()));
+        let server =
+            RemoteSignerServer::bind(&path, InMemorySigner::from_xpriv_str(xpriv).unwrap())
+                .unwrap();
+        std::thread::spawn(move || server.serve(|_| {}));
+        path
+    }
+
+    #[test]
+    fn test_remote_signer_matches_in_memory() -> anyhow::Result<()> {
+        let secp = Secp256k1::new();
+        let path = spawn_server("match", USER_A_PRIVATE_KEY);
+        let remote = RemoteSigner::connect(&path)?;
+        let local = InMemorySigner::from_xpriv_str(USER_A_PRIVATE_KEY)?;
+        assert_eq!(remote.public_key()?, local.public_key()?);
+
+        let tree = create_taproot_tree(&secp);
+        let sighash = TapSighash::from_byte_array([7; 32]);
+        let signature = remote.sign_schnorr_key_spend(sighash, tree.merkle_root())?;
+        let output_key = remote
+            .x_only_public_key()?
+            .tap_tweak(&secp, tree.merkle_root())
+            .0;
+        assert_eq!(output_key, tree.output_key());
+        secp.verify_schnorr(&signature, &Message::from(sighash), &output_key.to_inner())?;
+
+        let leaf_hash = TapLeafHash::from_byte_array([1; 32]);
+        let signature = remote.sign_schnorr_script_spend(sighash, leaf_hash)?;
+        secp.verify_schnorr(
+            &signature,
+            &Message::from(sighash),
+            &remote.x_only_public_key()?,
+        )?;
+
+        let sighash = SegwitV0Sighash::from_byte_array([9; 32]);
+        let signature = remote.sign_ecdsa(sighash)?;
+        assert_eq!(signature, local.sign_ecdsa(sighash)?); // RFC6979 is deterministic.
+        let sighash = LegacySighash::from_byte_array([9; 32]);
+        assert_eq!(
+            remote.sign_ecdsa_legacy(sighash)?,
+            local.sign_ecdsa_legacy(sighash)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_remote_signer_rejects_garbage() -> anyhow::Result<()> {
+        let path = spawn_server("garbage", USER_B_PRIVATE_KEY);
+        let mut stream = UnixStream::connect(&path)?;
+        stream.write_all(b"{\"method\":\"sign_everything\"}\n")?;
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line)?;
+        assert!(matches!(
+            serde_json::from_str::<Response>(&line)?,
+            Response::Error(_)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_remote_signer_connections() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("signer-errors-{}.sock", std::process::id()));
+        std::fs::write(&path, b"stale")?;
+        let server =
+            RemoteSignerServer::bind(&path, InMemorySigner::from_xpriv_str(USER_A_PRIVATE_KEY)?)?;
+        assert_eq!(
+            std::fs::metadata(&path)?.permissions().mode() & 0o777,
+            0o600
+        );
+        let (sender, errors) = std::sync::mpsc::channel();
+        std::thread::spawn(move || server.serve(move |e| sender.send(e.to_string()).unwrap()));
+
+        // an idle client doesn't hold the others up.
+        let _idle = UnixStream::connect(&path)?;
+        RemoteSigner::connect(&path)?;
+
+        let mut stream = UnixStream::connect(&path)?;
+        stream.write_all(b"\xff\n")?;
+        assert!(errors.recv_timeout(Duration::from_secs(5)).is_ok());
+        Ok(())
+    }
+}
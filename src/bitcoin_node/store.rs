@@ -0,0 +1,479 @@
+//! Wallet database: one json file with the accounts, their derived addresses, taproot trees,
+//! utxos, our pending or presigned transactions and the sync checkpoints.
+//!
+//! No secret goes in here, the keys stay in the `Keystore` and an account record only has the
+//! public key and derivation path. Every change is written through, to a temporary file renamed
+//! over the store.
+//!
+//! The file carries its schema `version`. Opening an older file runs the `MIGRATIONS` from its
+//! version on the raw json and keeps the original next to it as `<file>.v<version>.bak`. A file
+//! from a newer version is refused rather than rewritten.
+use crate::bitcoin_node::account::BitcoinAccount;
+use crate::bitcoin_node::follower::Checkpoint;
+use crate::mempool::scanner::{Keychain, ScanState};
+use crate::SECP;
+use anyhow::{anyhow, bail};
+use bitcoin::taproot::{TaprootBuilder, TaprootSpendInfo};
+use bitcoin::{
+    Network, OutPoint, PublicKey, Script, ScriptBuf, Transaction, TxOut, Txid, XOnlyPublicKey,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+type Migration = fn(&mut Value) -> anyhow::Result<()>;
+
+/// `MIGRATIONS[n]` upgrades a store from version `n` to `n + 1`.
+const MIGRATIONS: &[Migration] = &[initial_schema];
+pub const STORE_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Version 0 is an empty object.
+fn initial_schema(store: &mut Value) -> anyhow::Result<()> {
+    let object = store
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("Store isn't a json object"))?;
+    for table in [
+        "accounts",
+        "addresses",
+        "trees",
+        "utxos",
+        "transactions",
+        "checkpoints",
+        "scan_states",
+    ] {
+        object.entry(table).or_insert(json!({}));
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AccountRecord {
+    pub network: Network,
+    pub public_key: PublicKey,
+    pub derivation_path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AddressRecord {
+    pub account: String,
+    pub keychain: Keychain,
+    pub index: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TreeLeaf {
+    pub depth: u8,
+    pub script: ScriptBuf,
+}
+
+/// A taproot tree, its leaves in the depth-first order of `TaprootBuilder`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TreeRecord {
+    pub internal_key: XOnlyPublicKey,
+    pub leaves: Vec<TreeLeaf>,
+}
+
+impl TreeRecord {
+    pub fn spend_info(&self) -> anyhow::Result<TaprootSpendInfo> {
+        let mut builder = TaprootBuilder::new();
+        for leaf in &self.leaves {
+            builder = builder.add_leaf(leaf.depth, leaf.script.clone())?;
+        }
+        builder
+            .finalize(&SECP, self.internal_key)
+            .map_err(|_| anyhow!("Incomplete taproot tree"))
+    }
+
+    pub fn script_pubkey(&self) -> anyhow::Result<ScriptBuf> {
+        Ok(ScriptBuf::new_p2tr_tweaked(self.spend_info()?.output_key()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct UtxoRecord {
+    pub txout: TxOut,
+    /// Confirmation height, `None` while unconfirmed.
+    pub height: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxStatus {
+    /// Signed ahead of time, not to be broadcast yet.
+    Presigned,
+    /// Broadcast, in the mempool.
+    Pending,
+    Confirmed {
+        height: u32,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TxRecord {
+    pub tx: Transaction,
+    pub status: TxStatus,
+    pub label: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+struct StoreData {
+    version: u32,
+    accounts: BTreeMap<String, AccountRecord>,
+    /// By script pubkey, hex.
+    addresses: BTreeMap<String, AddressRecord>,
+    trees: BTreeMap<String, TreeRecord>,
+    utxos: BTreeMap<OutPoint, UtxoRecord>,
+    transactions: BTreeMap<Txid, TxRecord>,
+    /// `ChainFollower` checkpoints, by name.
+    checkpoints: BTreeMap<String, Checkpoint>,
+    /// `WalletScanner` states, by account.
+    scan_states: BTreeMap<String, ScanState>,
+}
+
+pub struct WalletStore {
+    path: PathBuf,
+    data: StoreData,
+}
+
+impl WalletStore {
+    /// Open the store at `path`, created if missing and migrated if older.
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if !path.exists() {
+            let store = Self {
+                path,
+                data: StoreData {
+                    version: STORE_VERSION,
+                    ..Default::default()
+                },
+            };
+            store.save()?;
+            return Ok(store);
+        }
+
+        let raw = std::fs::read(&path)
+            .map_err(|e| anyhow!("Fail to read store: {:?}, error: {}", path, e))?;
+        let mut value: Value = serde_json::from_slice(&raw)?;
+        let version = value.get("version").and_then(Value::as_u64).unwrap_or(0) as u32;
+        if version > STORE_VERSION {
+            bail!(
+                "Store {:?} is version {}, newer than {}",
+                path,
+                version,
+                STORE_VERSION
+            );
+        }
+        for migration in &MIGRATIONS[version as usize..] {
+            migration(&mut value)?;
+        }
+        value["version"] = json!(STORE_VERSION);
+
+        let store = Self {
+            data: serde_json::from_value(value)
+                .map_err(|e| anyhow!("Fail to load store: {:?}, error: {}", path, e))?,
+            path,
+        };
+        if version < STORE_VERSION {
+            let mut backup = store.path.clone().into_os_string();
+            backup.push(format!(".v{}.bak", version));
+            std::fs::write(&backup, &raw)?;
+            store.save()?;
+        }
+        Ok(store)
+    }
+
+    pub fn version(&self) -> u32 {
+        self.data.version
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut file = std::fs::File::create(&tmp_path)?;
+        std::io::Write::write_all(&mut file, &serde_json::to_vec_pretty(&self.data)?)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Record the public part of `account` as `name`.
+    pub fn put_account(
+        &mut self,
+        name: &str,
+        account: &BitcoinAccount,
+        network: Network,
+    ) -> anyhow::Result<()> {
+        let record = AccountRecord {
+            network,
+            public_key: account.public_key,
+            derivation_path: account.derivation.as_ref().map(|d| d.path.to_string()),
+        };
+        self.data.accounts.insert(name.to_string(), record);
+        self.save()
+    }
+
+    pub fn account(&self, name: &str) -> Option<&AccountRecord> {
+        self.data.accounts.get(name)
+    }
+
+    pub fn accounts(&self) -> &BTreeMap<String, AccountRecord> {
+        &self.data.accounts
+    }
+
+    pub fn put_address(
+        &mut self,
+        script_pubkey: &Script,
+        record: AddressRecord,
+    ) -> anyhow::Result<()> {
+        if !self.data.accounts.contains_key(&record.account) {
+            bail!("Unknown account: {}", record.account);
+        }
+        self.data
+            .addresses
+            .insert(script_pubkey.to_hex_string(), record);
+        self.save()
+    }
+
+    pub fn address(&self, script_pubkey: &Script) -> Option<&AddressRecord> {
+        self.data.addresses.get(&script_pubkey.to_hex_string())
+    }
+
+    /// The scripts derived for `account`, by keychain and index.
+    pub fn addresses(&self, account: &str) -> anyhow::Result<Vec<(ScriptBuf, AddressRecord)>> {
+        let mut addresses = vec![];
+        for (script_pubkey, record) in &self.data.addresses {
+            if record.account == account {
+                addresses.push((ScriptBuf::from_hex(script_pubkey)?, record.clone()));
+            }
+        }
+        addresses.sort_by_key(|(_, record)| (record.keychain, record.index));
+        Ok(addresses)
+    }
+
+    pub fn put_tree(&mut self, name: &str, tree: TreeRecord) -> anyhow::Result<()> {
+        tree.spend_info()?;
+        self.data.trees.insert(name.to_string(), tree);
+        self.save()
+    }
+
+    pub fn tree(&self, name: &str) -> Option<&TreeRecord> {
+        self.data.trees.get(name)
+    }
+
+    pub fn put_utxo(&mut self, out_point: OutPoint, utxo: UtxoRecord) -> anyhow::Result<()> {
+        self.data.utxos.insert(out_point, utxo);
+        self.save()
+    }
+
+    pub fn spend_utxo(&mut self, out_point: &OutPoint) -> anyhow::Result<UtxoRecord> {
+        let utxo = self
+            .data
+            .utxos
+            .remove(out_point)
+            .ok_or_else(|| anyhow!("Unknown utxo: {}", out_point))?;
+        self.save()?;
+        Ok(utxo)
+    }
+
+    pub fn utxos(&self) -> &BTreeMap<OutPoint, UtxoRecord> {
+        &self.data.utxos
+    }
+
+    /// Insert or update a transaction.
+    pub fn put_transaction(
+        &mut self,
+        tx: Transaction,
+        status: TxStatus,
+        label: Option<String>,
+    ) -> anyhow::Result<Txid> {
+        let txid = tx.compute_txid();
+        self.data
+            .transactions
+            .insert(txid, TxRecord { tx, status, label });
+        self.save()?;
+        Ok(txid)
+    }
+
+    pub fn set_transaction_status(&mut self, txid: &Txid, status: TxStatus) -> anyhow::Result<()> {
+        self.data
+            .transactions
+            .get_mut(txid)
+            .ok_or_else(|| anyhow!("Unknown transaction: {}", txid))?
+            .status = status;
+        self.save()
+    }
+
+    pub fn transaction(&self, txid: &Txid) -> Option<&TxRecord> {
+        self.data.transactions.get(txid)
+    }
+
+    pub fn transactions_with_status(&self, status: TxStatus) -> Vec<&TxRecord> {
+        self.data
+            .transactions
+            .values()
+            .filter(|record| record.status == status)
+            .collect()
+    }
+
+    pub fn set_checkpoint(&mut self, name: &str, checkpoint: Checkpoint) -> anyhow::Result<()> {
+        self.data.checkpoints.insert(name.to_string(), checkpoint);
+        self.save()
+    }
+
+    pub fn checkpoint(&self, name: &str) -> Option<Checkpoint> {
+        self.data.checkpoints.get(name).copied()
+    }
+
+    pub fn set_scan_state(&mut self, account: &str, state: ScanState) -> anyhow::Result<()> {
+        self.data.scan_states.insert(account.to_string(), state);
+        self.save()
+    }
+
+    pub fn scan_state(&self, account: &str) -> Option<&ScanState> {
+        self.data.scan_states.get(account)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::tx::taproot_tree_tx::template::LeafTemplate;
+    use crate::bitcoin_node::tx::USER_A_PRIVATE_KEY;
+    use bitcoin::bip32::{DerivationPath, Xpriv};
+    use bitcoin::hashes::Hash;
+    use bitcoin::{absolute, transaction, Amount, BlockHash, Sequence, TxIn, Witness};
+    use rand::RngCore;
+    use std::str::FromStr;
+
+    fn test_path(tag: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "store-{}-{}.json",
+            tag,
+            rand::thread_rng().next_u64()
+        ))
+    }
+
+    #[test]
+    fn test_store_roundtrip() -> anyhow::Result<()> {
+        let path = test_path("roundtrip");
+        let account = BitcoinAccount::from_xpriv(
+            Xpriv::from_str(USER_A_PRIVATE_KEY)?,
+            DerivationPath::from_str("m/86'/1'/0'/0/0")?,
+        )?;
+        let key = account.public_key.inner.x_only_public_key().0;
+        let script_pubkey = ScriptBuf::new_p2tr(&SECP, key, None);
+        let tree = TreeRecord {
+            internal_key: key,
+            leaves: vec![
+                TreeLeaf {
+                    depth: 1,
+                    script: LeafTemplate::single_sig(key).to_script(),
+                },
+                TreeLeaf {
+                    depth: 1,
+                    script: LeafTemplate::multi_a(vec![key], 1)?.to_script(),
+                },
+            ],
+        };
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::all_zeros(), 0),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::default(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(10_000),
+                script_pubkey: script_pubkey.clone(),
+            }],
+        };
+        let out_point = OutPoint::new(tx.compute_txid(), 0);
+        let checkpoint = Checkpoint {
+            height: 100,
+            hash: BlockHash::all_zeros(),
+        };
+
+        {
+            let mut store = WalletStore::open(&path)?;
+            assert!(store
+                .put_address(
+                    &script_pubkey,
+                    AddressRecord {
+                        account: "alice".to_string(),
+                        keychain: Keychain::External,
+                        index: 0,
+                    },
+                )
+                .is_err());
+            store.put_account("alice", &account, Network::Regtest)?;
+            store.put_address(
+                &script_pubkey,
+                AddressRecord {
+                    account: "alice".to_string(),
+                    keychain: Keychain::External,
+                    index: 0,
+                },
+            )?;
+            store.put_tree("vault", tree.clone())?;
+            store.put_transaction(tx.clone(), TxStatus::Pending, Some("funding".to_string()))?;
+            store.put_utxo(
+                out_point,
+                UtxoRecord {
+                    txout: tx.output[0].clone(),
+                    height: None,
+                },
+            )?;
+            store.set_checkpoint("regtest", checkpoint)?;
+            store.set_scan_state("alice", ScanState::default())?;
+        }
+
+        let mut store = WalletStore::open(&path)?;
+        assert_eq!(store.version(), STORE_VERSION);
+        let record = store.account("alice").unwrap();
+        assert_eq!(record.public_key, account.public_key);
+        assert_eq!(record.derivation_path.as_deref(), Some("86'/1'/0'/0/0"));
+        assert_eq!(store.address(&script_pubkey).unwrap().index, 0);
+        assert_eq!(store.addresses("alice")?[0].0, script_pubkey);
+        assert_eq!(store.tree("vault"), Some(&tree));
+        assert_eq!(
+            store.tree("vault").unwrap().script_pubkey()?,
+            ScriptBuf::new_p2tr_tweaked(tree.spend_info()?.output_key())
+        );
+        assert_eq!(store.checkpoint("regtest"), Some(checkpoint));
+        assert!(store.scan_state("alice").is_some());
+        assert_eq!(store.utxos()[&out_point].txout, tx.output[0]);
+
+        store.set_transaction_status(&tx.compute_txid(), TxStatus::Confirmed { height: 101 })?;
+        assert!(store.transactions_with_status(TxStatus::Pending).is_empty());
+        store.spend_utxo(&out_point)?;
+        assert!(store.spend_utxo(&out_point).is_err());
+        let store = WalletStore::open(&path)?;
+        assert!(store.utxos().is_empty());
+        assert_eq!(
+            store.transaction(&tx.compute_txid()).unwrap().status,
+            TxStatus::Confirmed { height: 101 }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_migrations() -> anyhow::Result<()> {
+        // version 0, before the schema.
+        let path = test_path("migrate");
+        std::fs::write(&path, "{}")?;
+        let store = WalletStore::open(&path)?;
+        assert_eq!(store.version(), STORE_VERSION);
+        assert!(store.accounts().is_empty());
+        let mut backup = path.clone().into_os_string();
+        backup.push(".v0.bak");
+        assert_eq!(std::fs::read_to_string(backup)?, "{}");
+
+        let path = test_path("newer");
+        std::fs::write(&path, format!("{{\"version\": {}}}", STORE_VERSION + 1))?;
+        let error = WalletStore::open(&path).err().unwrap();
+        assert!(error.to_string().contains("newer"));
+        Ok(())
+    }
+}
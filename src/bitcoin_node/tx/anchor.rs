@@ -0,0 +1,341 @@
+//! Anchor outputs, to attach fees to a presigned transaction when it's broadcast.
+//!
+//! The presigned transaction gets a pay-to-anchor (P2A) output, `OP_1 <4e73>` spent by anyone
+//! with an empty witness, or a keyed anchor, the smallest p2tr output to one of the parties.
+//! It is a TRUC (version 3) transaction, so the fee paying child can't be pinned by a large
+//! descendant. A 0 sat P2A is ephemeral dust: the parent pays no fee and goes along with the
+//! child spending it (`LocalLedger::submit_package`, `cpfp::submit_package`).
+//!
+//! Reference: https://github.com/bitcoin/bips/blob/master/bip-0431.mediawiki
+//!     https://bitcoinops.org/en/topics/ephemeral-anchors/
+use crate::bitcoin_node::tx::cpfp::child_fee;
+use crate::bitcoin_node::tx::rbf::{SpendPath, WalletInput, WalletTx};
+use crate::SECP;
+use anyhow::{anyhow, bail};
+use bitcoin::opcodes::all::OP_PUSHNUM_1;
+use bitcoin::{
+    script, transaction, Amount, FeeRate, OutPoint, ScriptBuf, Transaction, TxOut, XOnlyPublicKey,
+};
+
+/// Version of the transactions opting into the TRUC policy (BIP-431).
+pub const TRUC_VERSION: transaction::Version = transaction::Version(3);
+/// BIP-431 size limits of a TRUC transaction and of a TRUC child.
+pub const TRUC_MAX_VSIZE: usize = 10_000;
+pub const TRUC_CHILD_MAX_VSIZE: usize = 1_000;
+const P2A_PROGRAM: [u8; 2] = [0x4e, 0x73];
+// Smallest non dust p2tr output.
+const KEYED_ANCHOR_VALUE: Amount = Amount::from_sat(330);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Anchor {
+    /// 0 sat pay-to-anchor, any party (or anyone) can bump.
+    PayToAnchor,
+    /// Key only p2tr output of 330 sat, only the key owner can bump.
+    Keyed(XOnlyPublicKey),
+}
+
+impl Anchor {
+    pub fn script_pubkey(&self) -> ScriptBuf {
+        match self {
+            Anchor::PayToAnchor => script::Builder::new()
+                .push_opcode(OP_PUSHNUM_1)
+                .push_slice(P2A_PROGRAM)
+                .into_script(),
+            Anchor::Keyed(key) => ScriptBuf::new_p2tr(&SECP, *key, None),
+        }
+    }
+
+    pub fn txout(&self) -> TxOut {
+        TxOut {
+            value: match self {
+                Anchor::PayToAnchor => Amount::ZERO,
+                Anchor::Keyed(_) => KEYED_ANCHOR_VALUE,
+            },
+            script_pubkey: self.script_pubkey(),
+        }
+    }
+
+    /// Index of the anchor output in `tx`.
+    pub fn find(&self, tx: &Transaction) -> Option<u32> {
+        let script_pubkey = self.script_pubkey();
+        tx.output
+            .iter()
+            .position(|txout| txout.script_pubkey == script_pubkey)
+            .map(|vout| vout as u32)
+    }
+}
+
+/// Turn `tx` into a TRUC transaction with `anchor` as last output, before it's signed.
+/// The anchor value comes out of the fee, a P2A parent usually pays none.
+pub fn add_anchor(tx: &mut Transaction, anchor: &Anchor) -> anyhow::Result<u32> {
+    if anchor.find(tx).is_some() {
+        bail!("Transaction already has anchor {:?}", anchor);
+    }
+    tx.version = TRUC_VERSION;
+    tx.output.push(anchor.txout());
+    Ok(tx.output.len() as u32 - 1)
+}
+
+/// Build and sign the TRUC child spending the anchor `vout` of `parent` along with `funding`,
+/// paying for the package at `target`, the rest going to `change`.
+///
+/// `anchor_spend` is `SpendPath::Anchor` for a P2A, the key path of the anchor key otherwise.
+/// `funding` must be confirmed: a TRUC child has a single unconfirmed parent. The signed child
+/// must fit in `TRUC_CHILD_MAX_VSIZE`, so few funding inputs.
+pub fn build_anchor_child<'a>(
+    parent: &Transaction,
+    parent_fee: Amount,
+    vout: u32,
+    anchor_spend: SpendPath<'a>,
+    funding: Vec<WalletInput<'a>>,
+    change: ScriptBuf,
+    target: FeeRate,
+) -> anyhow::Result<Transaction> {
+    let txid = parent.compute_txid();
+    if parent.version != TRUC_VERSION {
+        bail!("Parent {} isn't a TRUC transaction", txid);
+    }
+    let prevout = parent
+        .output
+        .get(vout as usize)
+        .ok_or_else(|| anyhow!("Parent {} has no output {}", txid, vout))?
+        .clone();
+    let mut inputs = vec![WalletInput {
+        out_point: OutPoint::new(txid, vout),
+        prevout,
+        spend: anchor_spend,
+    }];
+    inputs.extend(funding);
+
+    let parent_vsize = parent.vsize() as u64;
+    let mut child = WalletTx::new(inputs, vec![], change);
+    child.version = TRUC_VERSION;
+    child.sign(|vsize| child_fee(target, parent_vsize, parent_fee, vsize))?;
+    if child.tx.output.is_empty() {
+        bail!("Change of the child of {} would be dust, add funding", txid);
+    }
+    if child.tx.vsize() > TRUC_CHILD_MAX_VSIZE {
+        bail!(
+            "TRUC child of {} of {} vB over {} vB, use fewer funding inputs",
+            txid,
+            child.tx.vsize(),
+            TRUC_CHILD_MAX_VSIZE
+        );
+    }
+    Ok(child.tx)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::ledger::LocalLedger;
+    use crate::bitcoin_node::signer::{sign_key_spend_input, InMemorySigner, Signer};
+    use crate::bitcoin_node::tx::cpfp::package_fee_rate;
+    use crate::bitcoin_node::tx::timelock::ChainTip;
+    use crate::bitcoin_node::tx::{USER_A_PRIVATE_KEY, USER_B_PRIVATE_KEY};
+    use bitcoin::{absolute, Sequence, TapSighashType, TxIn, Witness};
+
+    struct Fixture {
+        ledger: LocalLedger,
+        signer_a: InMemorySigner,
+        signer_b: InMemorySigner,
+    }
+
+    impl Fixture {
+        fn new() -> anyhow::Result<Self> {
+            Ok(Self {
+                ledger: LocalLedger::new(ChainTip {
+                    height: 800_000,
+                    median_time_past: 1_700_000_000,
+                }),
+                signer_a: InMemorySigner::from_xpriv_str(USER_A_PRIVATE_KEY)?,
+                signer_b: InMemorySigner::from_xpriv_str(USER_B_PRIVATE_KEY)?,
+            })
+        }
+
+        fn p2tr(signer: &InMemorySigner) -> anyhow::Result<ScriptBuf> {
+            Ok(ScriptBuf::new_p2tr(
+                &SECP,
+                signer.x_only_public_key()?,
+                None,
+            ))
+        }
+
+        /// A's presigned transfer of 50k sat to B with `anchor`, paying `fee`.
+        fn presigned(&mut self, anchor: &Anchor, fee: Amount) -> anyhow::Result<Transaction> {
+            let (out_point, funding) = self
+                .ledger
+                .fund(Self::p2tr(&self.signer_a)?, Amount::from_sat(50_000));
+            let mut tx = Transaction {
+                version: transaction::Version::TWO,
+                lock_time: absolute::LockTime::ZERO,
+                input: vec![TxIn {
+                    previous_output: out_point,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    witness: Witness::default(),
+                }],
+                output: vec![TxOut {
+                    value: funding.value - anchor.txout().value - fee,
+                    script_pubkey: Self::p2tr(&self.signer_b)?,
+                }],
+            };
+            add_anchor(&mut tx, anchor)?;
+            sign_key_spend_input(
+                &self.signer_a,
+                &mut tx,
+                0,
+                &[funding],
+                TapSighashType::Default,
+                None,
+            )?;
+            Ok(tx)
+        }
+
+        /// A confirmed 20k sat output of B to fund the child with.
+        fn funding(&mut self) -> anyhow::Result<(OutPoint, TxOut)> {
+            Ok(self
+                .ledger
+                .fund(Self::p2tr(&self.signer_b)?, Amount::from_sat(20_000)))
+        }
+
+        fn funding_input(&self, (out_point, prevout): (OutPoint, TxOut)) -> WalletInput<'_> {
+            WalletInput {
+                out_point,
+                prevout,
+                spend: SpendPath::KeyPath {
+                    signer: &self.signer_b,
+                    merkle_root: None,
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn test_p2a_ephemeral_anchor_package() -> anyhow::Result<()> {
+        let mut fixture = Fixture::new()?;
+        let parent = fixture.presigned(&Anchor::PayToAnchor, Amount::ZERO)?;
+        let vout = Anchor::PayToAnchor.find(&parent).unwrap();
+        assert_eq!(
+            parent.output[vout as usize].script_pubkey.to_hex_string(),
+            "51024e73"
+        );
+
+        // zero fee, dust anchor: not without its child.
+        assert!(fixture.ledger.broadcast(&parent).is_err());
+
+        let target = FeeRate::from_sat_per_vb(10).unwrap();
+        let change = Fixture::p2tr(&fixture.signer_b)?;
+        let funding = fixture.funding()?;
+        let child = build_anchor_child(
+            &parent,
+            Amount::ZERO,
+            vout,
+            SpendPath::Anchor,
+            vec![fixture.funding_input(funding)],
+            change.clone(),
+            target,
+        )?;
+        assert_eq!(child.version, TRUC_VERSION);
+        assert!(child.input[0].witness.is_empty());
+        let child_fee = Amount::from_sat(20_000) - child.output[0].value;
+        assert!(package_fee_rate(&parent, Amount::ZERO, &child, child_fee) >= target);
+        fixture
+            .ledger
+            .submit_package(&[parent.clone(), child.clone()])?;
+
+        // one unconfirmed child per TRUC parent, and no non TRUC descendant.
+        let spend_payment = |version| Transaction {
+            version,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(parent.compute_txid(), 0),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::default(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(40_000),
+                script_pubkey: change.clone(),
+            }],
+        };
+        let error = fixture.ledger.broadcast(&spend_payment(TRUC_VERSION));
+        assert!(error.unwrap_err().to_string().contains("unconfirmed child"));
+        let error = fixture
+            .ledger
+            .broadcast(&spend_payment(transaction::Version::TWO));
+        assert!(error.unwrap_err().to_string().contains("Non TRUC"));
+        fixture.ledger.mine(1);
+        fixture
+            .ledger
+            .broadcast(&spend_payment(transaction::Version::TWO))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_keyed_anchor() -> anyhow::Result<()> {
+        let mut fixture = Fixture::new()?;
+        // A keeps the anchor of the transfer to B.
+        let anchor = Anchor::Keyed(fixture.signer_a.x_only_public_key()?);
+        let parent = fixture.presigned(&anchor, Amount::from_sat(100))?;
+        // not dust, the parent can go alone and be bumped later.
+        fixture.ledger.broadcast(&parent)?;
+
+        let vout = anchor.find(&parent).unwrap();
+        let change = Fixture::p2tr(&fixture.signer_b)?;
+        let funding = fixture.funding()?;
+        let child = build_anchor_child(
+            &parent,
+            Amount::from_sat(100),
+            vout,
+            SpendPath::KeyPath {
+                signer: &fixture.signer_a,
+                merkle_root: None,
+            },
+            vec![fixture.funding_input(funding)],
+            change,
+            FeeRate::from_sat_per_vb(5).unwrap(),
+        )?;
+        assert_eq!(
+            child.input[0].previous_output,
+            OutPoint::new(parent.compute_txid(), vout)
+        );
+        fixture.ledger.broadcast(&child)?;
+
+        // 20 key path inputs don't fit in a TRUC child.
+        let funding: Vec<_> = (0..20).map(|_| fixture.funding().unwrap()).collect();
+        let error = build_anchor_child(
+            &parent,
+            Amount::from_sat(100),
+            vout,
+            SpendPath::KeyPath {
+                signer: &fixture.signer_a,
+                merkle_root: None,
+            },
+            funding
+                .into_iter()
+                .map(|funding| fixture.funding_input(funding))
+                .collect(),
+            Fixture::p2tr(&fixture.signer_b)?,
+            FeeRate::from_sat_per_vb(5).unwrap(),
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("over 1000 vB"), "{}", error);
+
+        // no anchor twice, no P2A child of a non TRUC parent.
+        let mut tx = parent.clone();
+        assert!(add_anchor(&mut tx, &anchor).is_err());
+        tx.version = transaction::Version::TWO;
+        assert!(build_anchor_child(
+            &tx,
+            Amount::ZERO,
+            vout,
+            SpendPath::Anchor,
+            vec![],
+            ScriptBuf::new(),
+            FeeRate::from_sat_per_vb(1).unwrap(),
+        )
+        .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+//! Child-pays-for-parent, to bump a transaction we can't replace on our own, e.g. a presigned
+//! one (see `presing_tx_taproot.rs`) whose other signers are gone.
+//!
+//! `build_child` spends one of our outputs of the unconfirmed parent with a fee so the package
+//! (parent and child together) reaches the target fee rate. `submit_package` hands both to
+//! bitcoind's `submitpackage`, which evaluates the package fee rate instead of the parent's
+//! alone, and falls back to broadcasting them one by one on nodes without it.
+//!
+//! Reference: https://bitcoinops.org/en/topics/cpfp/
+use crate::bitcoin_node::tx::rbf::{fee_vb, SpendPath, WalletInput, WalletTx};
+use anyhow::{anyhow, bail};
+use bitcoin::consensus::encode;
+use bitcoin::{Amount, FeeRate, OutPoint, ScriptBuf, Transaction, Txid};
+use bitcoincore_rpc::jsonrpc::error::Error as JsonRpcError;
+use bitcoincore_rpc::{Error, RpcApi};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// JSON-RPC "Method not found", bitcoind before `submitpackage`.
+const RPC_METHOD_NOT_FOUND: i32 = -32601;
+
+/// Build and sign the child of `parent` spending its output `vout` to `destination`.
+///
+/// `parent_fee` is what the parent pays on its own. The child fee covers the package up to
+/// `target` and at least the child itself at `target`, it fails when our output can't pay
+/// for it.
+pub fn build_child(
+    parent: &Transaction,
+    parent_fee: Amount,
+    vout: u32,
+    spend: SpendPath,
+    destination: ScriptBuf,
+    target: FeeRate,
+) -> anyhow::Result<Transaction> {
+    let prevout = parent
+        .output
+        .get(vout as usize)
+        .ok_or_else(|| anyhow!("Parent {} has no output {}", parent.compute_txid(), vout))?
+        .clone();
+    let parent_vsize = parent.vsize() as u64;
+    let input = WalletInput {
+        out_point: OutPoint::new(parent.compute_txid(), vout),
+        prevout,
+        spend,
+    };
+
+    let mut child = WalletTx::new(vec![input], vec![], destination);
+    child.sign(|vsize| child_fee(target, parent_vsize, parent_fee, vsize))?;
+    if child.tx.output.is_empty() {
+        bail!(
+            "Output {} of {} is too small to bump the package to {}",
+            vout,
+            parent.compute_txid(),
+            target
+        );
+    }
+    Ok(child.tx)
+}
+
+/// Fee of a child of `vsize` vB bringing its parent package to `target`, and at least the
+/// child's own fee at `target`.
+pub(crate) fn child_fee(
+    target: FeeRate,
+    parent_vsize: u64,
+    parent_fee: Amount,
+    vsize: u64,
+) -> anyhow::Result<Amount> {
+    let package_fee = fee_vb(target, parent_vsize + vsize)?;
+    let own_fee = fee_vb(target, vsize)?;
+    Ok(package_fee
+        .checked_sub(parent_fee)
+        .unwrap_or_default()
+        .max(own_fee))
+}
+
+/// Fee rate of `parent` and `child` together.
+pub fn package_fee_rate(
+    parent: &Transaction,
+    parent_fee: Amount,
+    child: &Transaction,
+    child_fee: Amount,
+) -> FeeRate {
+    let weight = parent.weight() + child.weight();
+    FeeRate::from_sat_per_kwu((parent_fee + child_fee).to_sat() * 1000 / weight.to_wu())
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct SubmitPackageResult {
+    pub package_msg: String,
+    /// Per transaction results, by wtxid.
+    #[serde(rename = "tx-results")]
+    pub tx_results: HashMap<String, SubmitPackageTxResult>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct SubmitPackageTxResult {
+    pub txid: Txid,
+    pub error: Option<String>,
+}
+
+/// Submit `parent` and `child` as a package, or one after the other if bitcoind has no
+/// `submitpackage`. Returns the txids accepted.
+pub fn submit_package(
+    rpc: &bitcoincore_rpc::Client,
+    parent: &Transaction,
+    child: &Transaction,
+) -> anyhow::Result<Vec<Txid>> {
+    let package = serde_json::json!([encode::serialize_hex(parent), encode::serialize_hex(child)]);
+    let result = match rpc.call::<SubmitPackageResult>("submitpackage", &[package]) {
+        Ok(result) => result,
+        Err(Error::JsonRpc(JsonRpcError::Rpc(ref e))) if e.code == RPC_METHOD_NOT_FOUND => {
+            // The parent alone may be under the mempool min fee, nothing more we can do here.
+            return Ok(vec![
+                rpc.send_raw_transaction(parent)?,
+                rpc.send_raw_transaction(child)?,
+            ]);
+        }
+        Err(e) => bail!("Fail to submitpackage: {}", e),
+    };
+
+    if let Some(error) = result.tx_results.values().find_map(|r| r.error.as_ref()) {
+        bail!("Fail to submitpackage: {}, {}", result.package_msg, error);
+    }
+    if result.package_msg != "success" {
+        bail!("Fail to submitpackage: {}", result.package_msg);
+    }
+    Ok(vec![parent.compute_txid(), child.compute_txid()])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::ledger::LocalLedger;
+    use crate::bitcoin_node::signer::{sign_key_spend_input, InMemorySigner, Signer};
+    use crate::bitcoin_node::tx::timelock::ChainTip;
+    use crate::bitcoin_node::tx::{USER_A_PRIVATE_KEY, USER_B_PRIVATE_KEY};
+    use crate::SECP;
+    use bitcoin::{absolute, transaction, Sequence, TapSighashType, TxIn, TxOut, Witness};
+
+    #[test]
+    fn test_child_bumps_package_to_target() -> anyhow::Result<()> {
+        let signer_a = InMemorySigner::from_xpriv_str(USER_A_PRIVATE_KEY)?;
+        let signer_b = InMemorySigner::from_xpriv_str(USER_B_PRIVATE_KEY)?;
+        let mut ledger = LocalLedger::new(ChainTip {
+            height: 800_000,
+            median_time_past: 1_700_000_000,
+        });
+        let (out_point, funding) = ledger.fund(
+            ScriptBuf::new_p2tr(&SECP, signer_a.x_only_public_key()?, None),
+            Amount::from_sat(100_000),
+        );
+
+        // a parent paying 200 sat, B's output is the one to bump with.
+        let mut parent = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: out_point,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::default(),
+            }],
+            output: vec![
+                TxOut {
+                    value: Amount::from_sat(89_800),
+                    script_pubkey: ScriptBuf::new_op_return([1]),
+                },
+                TxOut {
+                    value: Amount::from_sat(10_000),
+                    script_pubkey: ScriptBuf::new_p2tr(&SECP, signer_b.x_only_public_key()?, None),
+                },
+            ],
+        };
+        sign_key_spend_input(
+            &signer_a,
+            &mut parent,
+            0,
+            &[funding],
+            TapSighashType::Default,
+            None,
+        )?;
+        let parent_fee = Amount::from_sat(200);
+
+        let target = FeeRate::from_sat_per_vb(20).unwrap();
+        let destination = ScriptBuf::new_p2tr(&SECP, signer_b.x_only_public_key()?, None);
+        let child = build_child(
+            &parent,
+            parent_fee,
+            1,
+            SpendPath::KeyPath {
+                signer: &signer_b,
+                merkle_root: None,
+            },
+            destination.clone(),
+            target,
+        )?;
+        let child_fee = Amount::from_sat(10_000) - child.output[0].value;
+        let rate = package_fee_rate(&parent, parent_fee, &child, child_fee);
+        assert!(rate >= target, "{} below {}", rate, target);
+        assert!(rate < FeeRate::from_sat_per_vb(21).unwrap());
+        assert_eq!(
+            child.input[0].previous_output,
+            OutPoint::new(parent.compute_txid(), 1)
+        );
+
+        // the ledger takes the chain of unconfirmed transactions.
+        ledger.broadcast(&parent)?;
+        ledger.broadcast(&child)?;
+
+        // too much to ask of a 10k sat output.
+        let result = build_child(
+            &parent,
+            parent_fee,
+            1,
+            SpendPath::KeyPath {
+                signer: &signer_b,
+                merkle_root: None,
+            },
+            destination,
+            FeeRate::from_sat_per_vb(100).unwrap(),
+        );
+        assert!(result.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,484 @@
+//! Decode a raw transaction or a PSBT into a readable report, instead of pasting the hex
+//! printed by the tests into an external decoder.
+//!
+//! `TxReport` implements `Display` for the pretty printed form.
+use anyhow::anyhow;
+use bitcoin::consensus::encode;
+use bitcoin::hex::FromHex;
+use bitcoin::taproot::ControlBlock;
+use bitcoin::{
+    Address, Amount, EcdsaSighashType, Network, OutPoint, Psbt, Sequence, TapSighashType,
+    Transaction, TxIn, TxOut, Txid, Witness, Wtxid,
+};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SpendType {
+    TaprootKeyPath,
+    TaprootScriptPath {
+        leaf_script_asm: String,
+        /// Number of hashes in the control block merkle path.
+        control_block_depth: usize,
+        has_annex: bool,
+    },
+    P2wpkh,
+    P2wsh {
+        witness_script_asm: String,
+    },
+    /// Non segwit input, `script_sig` only.
+    Legacy,
+    /// Not signed yet, e.g. a PSBT input without final witness.
+    Unsigned,
+    Unknown,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InputReport {
+    pub out_point: OutPoint,
+    pub sequence: Sequence,
+    /// Spent amount, when the prevout could be resolved.
+    pub value: Option<Amount>,
+    pub spend_type: SpendType,
+    /// Sighash types of the signatures found in the witness, e.g. `SIGHASH_DEFAULT`.
+    pub sighash_types: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutputReport {
+    pub value: Amount,
+    pub script_pubkey_asm: String,
+    /// `None` for scripts without address, e.g. `OP_RETURN`.
+    pub address: Option<Address>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxReport {
+    pub txid: Txid,
+    pub wtxid: Wtxid,
+    pub version: i32,
+    pub lock_time: String,
+    pub size: usize,
+    pub vsize: usize,
+    pub weight: u64,
+    /// Signals BIP-125 replaceability (an input sequence below `0xfffffffe`).
+    pub rbf: bool,
+    pub inputs: Vec<InputReport>,
+    pub outputs: Vec<OutputReport>,
+    /// `None` if any prevout is unknown.
+    pub fee: Option<Amount>,
+}
+
+impl TxReport {
+    /// Report on `tx`, `prevouts[i]` being the output spent by input `i` if known.
+    pub fn new(tx: &Transaction, prevouts: &[Option<TxOut>], network: Network) -> Self {
+        let inputs: Vec<_> = tx
+            .input
+            .iter()
+            .enumerate()
+            .map(|(i, input)| input_report(input, prevouts.get(i).cloned().flatten()))
+            .collect();
+        let outputs = tx
+            .output
+            .iter()
+            .map(|output| OutputReport {
+                value: output.value,
+                script_pubkey_asm: output.script_pubkey.to_asm_string(),
+                address: Address::from_script(&output.script_pubkey, network).ok(),
+            })
+            .collect();
+        let input_value: Option<Amount> = inputs.iter().map(|input| input.value).sum();
+        let output_value = tx.output.iter().map(|output| output.value).sum::<Amount>();
+        Self {
+            txid: tx.compute_txid(),
+            wtxid: tx.compute_wtxid(),
+            version: tx.version.0,
+            lock_time: tx.lock_time.to_string(),
+            size: tx.total_size(),
+            vsize: tx.vsize(),
+            weight: tx.weight().to_wu(),
+            rbf: tx.is_explicitly_rbf(),
+            inputs,
+            outputs,
+            fee: input_value.and_then(|value| value.checked_sub(output_value)),
+        }
+    }
+
+    pub fn fee_rate_sat_vb(&self) -> Option<f64> {
+        self.fee.map(|fee| fee.to_sat() as f64 / self.vsize as f64)
+    }
+}
+
+/// Decode `input`: a hex raw transaction, or a PSBT in base64 or hex. Prevouts come from the
+/// PSBT when present, otherwise from `resolve` (e.g. a lookup in a wallet or node).
+pub fn decode(
+    input: &str,
+    network: Network,
+    resolve: &dyn Fn(&OutPoint) -> Option<TxOut>,
+) -> anyhow::Result<TxReport> {
+    let input = input.trim();
+    if let Ok(tx) = encode::deserialize_hex::<Transaction>(input) {
+        let prevouts: Vec<_> = tx
+            .input
+            .iter()
+            .map(|input| resolve(&input.previous_output))
+            .collect();
+        return Ok(TxReport::new(&tx, &prevouts, network));
+    }
+
+    let psbt = match Psbt::from_str(input) {
+        Ok(psbt) => psbt,
+        Err(_) => Vec::<u8>::from_hex(input)
+            .ok()
+            .and_then(|bytes| Psbt::deserialize(&bytes).ok())
+            .ok_or_else(|| anyhow!("Fail to decode input as transaction or PSBT"))?,
+    };
+    let mut tx = psbt.unsigned_tx.clone();
+    let mut prevouts = vec![];
+    for (i, (txin, psbt_input)) in tx.input.iter_mut().zip(&psbt.inputs).enumerate() {
+        if let Some(witness) = &psbt_input.final_script_witness {
+            txin.witness = witness.clone();
+        }
+        if let Some(script_sig) = &psbt_input.final_script_sig {
+            txin.script_sig = script_sig.clone();
+        }
+        let prevout = psbt
+            .spend_utxo(i)
+            .ok()
+            .cloned()
+            .or_else(|| resolve(&txin.previous_output));
+        prevouts.push(prevout);
+    }
+    Ok(TxReport::new(&tx, &prevouts, network))
+}
+
+fn input_report(input: &TxIn, prevout: Option<TxOut>) -> InputReport {
+    let spend_type = spend_type(input, prevout.as_ref());
+    let sighash_types = match &spend_type {
+        SpendType::TaprootKeyPath | SpendType::TaprootScriptPath { .. } => {
+            let items = taproot_stack_items(&input.witness, &spend_type);
+            items
+                .filter_map(|item| match item.len() {
+                    64 => Some(TapSighashType::Default),
+                    65 => TapSighashType::from_consensus_u8(item[64]).ok(),
+                    _ => None,
+                })
+                .map(|sighash_type| sighash_type.to_string())
+                .collect()
+        }
+        SpendType::P2wpkh | SpendType::P2wsh { .. } => input
+            .witness
+            .iter()
+            .filter(|item| is_der_signature(item))
+            .map(|item| EcdsaSighashType::from_consensus(item[item.len() - 1] as u32).to_string())
+            .collect(),
+        _ => vec![],
+    };
+    InputReport {
+        out_point: input.previous_output,
+        sequence: input.sequence,
+        value: prevout.map(|prevout| prevout.value),
+        spend_type,
+        sighash_types,
+    }
+}
+
+// The witness items before the leaf script, or the key path signature.
+fn taproot_stack_items<'a>(
+    witness: &'a Witness,
+    spend_type: &SpendType,
+) -> impl Iterator<Item = &'a [u8]> {
+    let annex = witness.taproot_annex().is_some() as usize;
+    let len = match spend_type {
+        SpendType::TaprootScriptPath { .. } => witness.len().saturating_sub(2 + annex),
+        _ => witness.len().saturating_sub(annex),
+    };
+    witness.iter().take(len)
+}
+
+fn spend_type(input: &TxIn, prevout: Option<&TxOut>) -> SpendType {
+    let witness = &input.witness;
+    if witness.is_empty() {
+        return if input.script_sig.is_empty() {
+            SpendType::Unsigned
+        } else {
+            SpendType::Legacy
+        };
+    }
+    let script_pubkey = prevout.map(|prevout| &prevout.script_pubkey);
+    let is_p2tr = script_pubkey.map(|spk| spk.is_p2tr());
+
+    // Checked first: without prevout a p2wpkh key would also parse as a control block.
+    let is_p2wpkh = match script_pubkey {
+        Some(spk) => spk.is_p2wpkh(),
+        None => {
+            witness.len() == 2
+                && witness.nth(0).is_some_and(is_der_signature)
+                && witness
+                    .nth(1)
+                    .is_some_and(|key| key.len() == 33 && matches!(key[0], 0x02 | 0x03))
+        }
+    };
+    if is_p2wpkh {
+        return SpendType::P2wpkh;
+    }
+
+    if is_p2tr != Some(false) {
+        let has_annex = witness.taproot_annex().is_some();
+        let stack_len = witness.len() - has_annex as usize;
+        if stack_len >= 2 {
+            let control_block = witness
+                .taproot_control_block()
+                .and_then(|bytes| ControlBlock::decode(bytes).ok());
+            if let (Some(control_block), Some(leaf_script)) = (control_block, witness.tapscript()) {
+                return SpendType::TaprootScriptPath {
+                    leaf_script_asm: leaf_script.to_asm_string(),
+                    control_block_depth: control_block.merkle_branch.len(),
+                    has_annex,
+                };
+            }
+        } else if matches!(witness.nth(0).map(<[u8]>::len), Some(64 | 65)) {
+            return SpendType::TaprootKeyPath;
+        }
+    }
+
+    if script_pubkey.is_some_and(|spk| spk.is_p2wsh()) {
+        if let Some(witness_script) = witness.witness_script() {
+            return SpendType::P2wsh {
+                witness_script_asm: witness_script.to_asm_string(),
+            };
+        }
+    }
+    SpendType::Unknown
+}
+
+// DER signature followed by the sighash byte.
+fn is_der_signature(item: &[u8]) -> bool {
+    (9..=73).contains(&item.len()) && item[0] == 0x30 && item[1] as usize == item.len() - 3
+}
+
+impl fmt::Display for SpendType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpendType::TaprootKeyPath => write!(f, "taproot key path"),
+            SpendType::TaprootScriptPath {
+                leaf_script_asm,
+                control_block_depth,
+                has_annex,
+            } => {
+                write!(f, "taproot script path, depth {}", control_block_depth)?;
+                if *has_annex {
+                    write!(f, ", with annex")?;
+                }
+                write!(f, "\n      leaf: {}", leaf_script_asm)
+            }
+            SpendType::P2wpkh => write!(f, "p2wpkh"),
+            SpendType::P2wsh { witness_script_asm } => {
+                write!(f, "p2wsh\n      witness script: {}", witness_script_asm)
+            }
+            SpendType::Legacy => write!(f, "legacy"),
+            SpendType::Unsigned => write!(f, "unsigned"),
+            SpendType::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+impl fmt::Display for TxReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "txid:      {}", self.txid)?;
+        writeln!(f, "wtxid:     {}", self.wtxid)?;
+        writeln!(f, "version:   {}", self.version)?;
+        writeln!(f, "locktime:  {}", self.lock_time)?;
+        writeln!(
+            f,
+            "size:      {} bytes, {} vbytes, {} wu",
+            self.size, self.vsize, self.weight
+        )?;
+        writeln!(f, "rbf:       {}", self.rbf)?;
+        match (self.fee, self.fee_rate_sat_vb()) {
+            (Some(fee), Some(fee_rate)) => writeln!(
+                f,
+                "fee:       {} sat ({:.2} sat/vB)",
+                fee.to_sat(),
+                fee_rate
+            )?,
+            _ => writeln!(f, "fee:       unknown (unresolved prevouts)")?,
+        }
+
+        writeln!(f, "inputs:")?;
+        for (i, input) in self.inputs.iter().enumerate() {
+            let value = input
+                .value
+                .map(|value| format!("{} sat", value.to_sat()))
+                .unwrap_or_else(|| "? sat".to_string());
+            writeln!(
+                f,
+                "  #{} {} {} sequence {:#010x}",
+                i,
+                input.out_point,
+                value,
+                input.sequence.to_consensus_u32()
+            )?;
+            writeln!(f, "      {}", input.spend_type)?;
+            if !input.sighash_types.is_empty() {
+                writeln!(f, "      sighash: {}", input.sighash_types.join(", "))?;
+            }
+        }
+
+        writeln!(f, "outputs:")?;
+        for (i, output) in self.outputs.iter().enumerate() {
+            let address = output
+                .address
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_else(|| output.script_pubkey_asm.clone());
+            writeln!(f, "  #{} {} sat {}", i, output.value.to_sat(), address)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::ledger::LocalLedger;
+    use crate::bitcoin_node::signer::{sign_p2wpkh_input, InMemorySigner, Signer};
+    use crate::bitcoin_node::tx::timelock::ChainTip;
+    use crate::bitcoin_node::tx::{
+        dummy_unspent_transaction_output, senders_keys, USER_A_PRIVATE_KEY,
+    };
+    use crate::contract::htlc::{Htlc, HtlcTimeout, HtlcUtxo};
+    use bitcoin::hashes::{sha256, Hash};
+    use bitcoin::{absolute, transaction, CompressedPublicKey, ScriptBuf};
+    use secp256k1::Secp256k1;
+
+    // signed in `key_path_spend::test_a_to_taproot_tree_addr`
+    const KEY_PATH_TX: &str = "0200000000010171d2374579bad491c8f066383cba9a13ba1662dde101bbccd95685667d13bba30000000000ffffffff02404b4c000000000022512092eb55895873bc9a200002ee94b9d65ccff9a133b147b0be481cb9caeb9cc8b9d8a9b69400000000225120be27fa8b1f5278faf82cab8da23e8761f8f9bd5d5ebebbb37e0e12a70d92dd160140813da642f242cced77f6b971274e7879f0ffe40c0fffd735ab564dbfe99b32e56b52a286dce540218eb966a5119ea2c2440bf9a6036cf0ad476173703ed9ed6c00000000";
+
+    #[test]
+    fn test_decode_key_path_tx() -> anyhow::Result<()> {
+        let secp = Secp256k1::new();
+        let internal_key = senders_keys(&secp, USER_A_PRIVATE_KEY)
+            .x_only_public_key()
+            .0;
+        let (out_point, prevout) = dummy_unspent_transaction_output(
+            &secp,
+            internal_key,
+            "a3bb137d668556d9ccbb01e1dd6216ba139aba3c3866f0c891d4ba794537d271",
+            0,
+            Amount::from_btc(25.0)?,
+        );
+        let resolve = |op: &OutPoint| (*op == out_point).then(|| prevout.clone());
+
+        let report = decode(KEY_PATH_TX, Network::Regtest, &resolve)?;
+        assert_eq!(
+            report.txid.to_string(),
+            "eadd615a91e83aa81c8eb670f4bcb44a6265288617086b48c81a8dc8b28b5084"
+        );
+        assert!(!report.rbf);
+        assert_eq!(report.inputs[0].spend_type, SpendType::TaprootKeyPath);
+        assert_eq!(report.inputs[0].sighash_types, vec!["SIGHASH_DEFAULT"]);
+        assert_eq!(report.fee, Some(Amount::from_sat(1_000)));
+        assert!(report.outputs[1]
+            .address
+            .as_ref()
+            .is_some_and(|address| address.to_string().starts_with("bcrt1p")));
+        assert!(report.to_string().contains("sat/vB"));
+
+        // without prevouts the fee is unknown, the witness shape still gives the spend type.
+        let report = decode(KEY_PATH_TX, Network::Regtest, &|_| None)?;
+        assert_eq!(report.fee, None);
+        assert_eq!(report.inputs[0].spend_type, SpendType::TaprootKeyPath);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_script_path_and_psbt() -> anyhow::Result<()> {
+        let sender = InMemorySigner::from_xpriv_str(USER_A_PRIVATE_KEY)?;
+        let receiver = InMemorySigner::from_xpriv_str(crate::bitcoin_node::tx::USER_B_PRIVATE_KEY)?;
+        let preimage = [7; 32];
+        let htlc = Htlc::new(
+            sender.public_key()?.inner,
+            receiver.public_key()?.inner,
+            sha256::Hash::hash(&preimage),
+            HtlcTimeout::Absolute(absolute::LockTime::from_height(500)?),
+        )?;
+        let mut ledger = LocalLedger::new(ChainTip {
+            height: 100,
+            median_time_past: 1_700_000_000,
+        });
+        let (out_point, txout) = ledger.fund(htlc.script_pubkey()?, Amount::from_sat(50_000));
+        let utxo = HtlcUtxo {
+            out_point,
+            txout: txout.clone(),
+            confirmation: None,
+        };
+        let claim = htlc.claim_tx(
+            &utxo,
+            ScriptBuf::new_op_return([]),
+            Amount::from_sat(300),
+            preimage,
+            &receiver,
+        )?;
+
+        let resolve = |_: &OutPoint| Some(txout.clone());
+        let report = decode(&encode::serialize_hex(&claim), Network::Regtest, &resolve)?;
+        match &report.inputs[0].spend_type {
+            SpendType::TaprootScriptPath {
+                leaf_script_asm,
+                control_block_depth,
+                has_annex,
+            } => {
+                assert!(leaf_script_asm.contains("OP_SHA256"));
+                assert_eq!(*control_block_depth, 1);
+                assert!(!has_annex);
+            }
+            other => panic!("unexpected spend type {:?}", other),
+        }
+        assert_eq!(report.inputs[0].sighash_types, vec!["SIGHASH_DEFAULT"]);
+        assert!(report.rbf);
+        assert_eq!(report.outputs[0].address, None);
+        assert_eq!(report.fee, Some(Amount::from_sat(300)));
+
+        // the unsigned PSBT carries the prevout itself.
+        let mut unsigned = claim.clone();
+        unsigned.input[0].witness = Witness::default();
+        let mut psbt = Psbt::from_unsigned_tx(unsigned)?;
+        psbt.inputs[0].witness_utxo = Some(txout);
+        let report = decode(&psbt.to_string(), Network::Regtest, &|_| None)?;
+        assert_eq!(report.inputs[0].spend_type, SpendType::Unsigned);
+        assert_eq!(report.fee, Some(Amount::from_sat(300)));
+
+        assert!(decode("not a transaction", Network::Regtest, &|_| None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_p2wpkh_without_prevout() -> anyhow::Result<()> {
+        let signer = InMemorySigner::from_xpriv_str(USER_A_PRIVATE_KEY)?;
+        let public_key = CompressedPublicKey::try_from(signer.public_key()?)?;
+        let prevout = TxOut {
+            value: Amount::from_sat(10_000),
+            script_pubkey: ScriptBuf::new_p2wpkh(&public_key.wpubkey_hash()),
+        };
+        let mut tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::all_zeros(), 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(9_000),
+                script_pubkey: prevout.script_pubkey.clone(),
+            }],
+        };
+        sign_p2wpkh_input(&signer, &mut tx, 0, &prevout, EcdsaSighashType::All)?;
+
+        // the 33 byte key would also decode as a control block.
+        let report = decode(&encode::serialize_hex(&tx), Network::Regtest, &|_| None)?;
+        assert_eq!(report.inputs[0].spend_type, SpendType::P2wpkh);
+        assert_eq!(report.inputs[0].sighash_types, vec!["SIGHASH_ALL"]);
+        Ok(())
+    }
+}
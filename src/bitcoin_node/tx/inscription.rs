@@ -0,0 +1,376 @@
+//! Ordinals inscriptions: the envelope leaf, the commit transaction paying its taproot address
+//! and the reveal transaction spending it through the script path.
+//!
+//! Envelope, after the `<key> OP_CHECKSIG` of the reveal signer:
+//! `OP_FALSE OP_IF "ord" 0x01 <content type> OP_0 <body chunk>... OP_ENDIF`
+//! A push is at most 520 bytes, so the body is split into 520 bytes chunks.
+//!
+//! Reference: https://docs.ordinals.com/inscriptions.html
+use crate::bitcoin_node::signer::{sign_key_spend_input, sign_script_spend_input, Signer};
+use crate::SECP;
+use anyhow::{anyhow, bail};
+use bitcoin::blockdata::opcodes::all::{OP_CHECKSIG, OP_ENDIF, OP_IF, OP_PUSHNUM_1};
+use bitcoin::blockdata::opcodes::OP_FALSE;
+use bitcoin::script::{Builder, Instruction, PushBytesBuf};
+use bitcoin::taproot::{ControlBlock, LeafVersion, TaprootBuilder};
+use bitcoin::{
+    absolute, transaction, Amount, FeeRate, OutPoint, Script, ScriptBuf, Sequence, TapSighashType,
+    Transaction, TxIn, TxOut, Witness, XOnlyPublicKey,
+};
+
+const PROTOCOL_ID: &[u8] = b"ord";
+const CONTENT_TYPE_TAG: [u8; 1] = [1];
+const MAX_PUSH_SIZE: usize = 520;
+/// Value of the inscribed output, `ord` default.
+pub const POSTAGE: Amount = Amount::from_sat(10_000);
+// Below this a p2tr change output is dust.
+const P2TR_DUST: Amount = Amount::from_sat(330);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Inscription {
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+impl Inscription {
+    pub fn new(content_type: &str, body: Vec<u8>) -> anyhow::Result<Self> {
+        if content_type.len() > MAX_PUSH_SIZE {
+            bail!("Content type longer than {} bytes", MAX_PUSH_SIZE);
+        }
+        Ok(Self {
+            content_type: content_type.to_string(),
+            body,
+        })
+    }
+
+    /// `<key> OP_CHECKSIG` followed by the envelope.
+    pub fn reveal_script(&self, key: XOnlyPublicKey) -> anyhow::Result<ScriptBuf> {
+        let mut builder = Builder::new()
+            .push_x_only_key(&key)
+            .push_opcode(OP_CHECKSIG)
+            .push_opcode(OP_FALSE)
+            .push_opcode(OP_IF)
+            .push_slice(<&[u8; 3]>::try_from(PROTOCOL_ID)?)
+            .push_slice(CONTENT_TYPE_TAG)
+            .push_slice(PushBytesBuf::try_from(
+                self.content_type.as_bytes().to_vec(),
+            )?)
+            .push_opcode(OP_FALSE);
+        for chunk in self.body.chunks(MAX_PUSH_SIZE) {
+            builder = builder.push_slice(PushBytesBuf::try_from(chunk.to_vec())?);
+        }
+        Ok(builder.push_opcode(OP_ENDIF).into_script())
+    }
+
+    /// The inscriptions of every envelope in `script`. Unknown fields are skipped.
+    pub fn from_script(script: &Script) -> Vec<Self> {
+        let mut inscriptions = vec![];
+        let mut instructions = script.instructions().filter_map(Result::ok).peekable();
+        while let Some(instruction) = instructions.next() {
+            let is_envelope_start = is_push(&instruction, &[])
+                && instructions
+                    .next_if(|i| i.opcode() == Some(OP_IF))
+                    .is_some()
+                && instructions.next_if(|i| is_push(i, PROTOCOL_ID)).is_some();
+            if !is_envelope_start {
+                continue;
+            }
+
+            let mut content_type = vec![];
+            let mut body = vec![];
+            let mut in_body = false;
+            let mut ended = false;
+            while let Some(instruction) = instructions.next() {
+                if instruction.opcode() == Some(OP_ENDIF) {
+                    ended = true;
+                    break;
+                }
+                if in_body {
+                    if let Instruction::PushBytes(bytes) = instruction {
+                        body.extend_from_slice(bytes.as_bytes());
+                    }
+                } else if is_push(&instruction, &[]) {
+                    in_body = true;
+                } else if is_push(&instruction, &CONTENT_TYPE_TAG)
+                    || instruction.opcode() == Some(OP_PUSHNUM_1)
+                {
+                    if let Some(Instruction::PushBytes(bytes)) = instructions.next() {
+                        content_type = bytes.as_bytes().to_vec();
+                    }
+                } else {
+                    // value of an unknown tag.
+                    instructions.next();
+                }
+            }
+            if ended {
+                inscriptions.push(Self {
+                    content_type: String::from_utf8_lossy(&content_type).into_owned(),
+                    body,
+                });
+            }
+        }
+        inscriptions
+    }
+}
+
+fn is_push(instruction: &Instruction, data: &[u8]) -> bool {
+    matches!(instruction, Instruction::PushBytes(bytes) if bytes.as_bytes() == data)
+}
+
+/// Inscriptions revealed by the script path spends of `tx`.
+pub fn parse_inscriptions(tx: &Transaction) -> Vec<Inscription> {
+    tx.input
+        .iter()
+        .filter_map(|input| input.witness.tapscript())
+        .flat_map(Inscription::from_script)
+        .collect()
+}
+
+pub struct InscriptionTxs {
+    /// Pays the envelope taproot address, spends `funding` with key path signatures.
+    pub commit_tx: Transaction,
+    /// Spends the commit output and inscribes `POSTAGE` sats to the destination.
+    pub reveal_tx: Transaction,
+}
+
+/// Build and sign the commit and reveal transactions of `inscription` at `fee_rate`.
+///
+/// `funding` are p2tr key path outputs of `signer`, the same key signs the reveal leaf.
+/// Change under the dust limit is left to the commit fee.
+pub fn inscribe(
+    inscription: &Inscription,
+    signer: &dyn Signer,
+    funding: &[(OutPoint, TxOut)],
+    destination: ScriptBuf,
+    change: ScriptBuf,
+    fee_rate: FeeRate,
+) -> anyhow::Result<InscriptionTxs> {
+    let key = signer.x_only_public_key()?;
+    let reveal_script = inscription.reveal_script(key)?;
+    let spend_info = TaprootBuilder::new()
+        .add_leaf(0, reveal_script.clone())?
+        .finalize(&SECP, key)
+        .map_err(|_| anyhow!("Fail to finalize inscription taproot tree"))?;
+    let control_block = spend_info
+        .control_block(&(reveal_script.clone(), LeafVersion::TapScript))
+        .ok_or_else(|| anyhow!("Missing control block of reveal script"))?;
+
+    let mut reveal_tx = unsigned_tx(
+        vec![OutPoint::null()],
+        vec![TxOut {
+            value: POSTAGE,
+            script_pubkey: destination,
+        }],
+    );
+    reveal_tx.input[0].witness = reveal_witness(&[0; 64], &reveal_script, &control_block);
+    let commit_value = POSTAGE + fee(&reveal_tx, fee_rate)?;
+
+    let commit_txout = TxOut {
+        value: commit_value,
+        script_pubkey: ScriptBuf::new_p2tr_tweaked(spend_info.output_key()),
+    };
+    let mut commit_tx = commit_tx(funding, commit_txout, change, fee_rate)?;
+    let prevouts: Vec<_> = funding.iter().map(|(_, txout)| txout.clone()).collect();
+    for i in 0..commit_tx.input.len() {
+        sign_key_spend_input(
+            signer,
+            &mut commit_tx,
+            i,
+            &prevouts,
+            TapSighashType::Default,
+            None,
+        )?;
+    }
+
+    reveal_tx.input[0].previous_output = OutPoint::new(commit_tx.compute_txid(), 0);
+    let signature = sign_script_spend_input(
+        signer,
+        &reveal_tx,
+        0,
+        std::slice::from_ref(&commit_tx.output[0]),
+        &reveal_script,
+        TapSighashType::Default,
+    )?;
+    reveal_tx.input[0].witness =
+        reveal_witness(&signature.to_vec(), &reveal_script, &control_block);
+    Ok(InscriptionTxs {
+        commit_tx,
+        reveal_tx,
+    })
+}
+
+fn commit_tx(
+    funding: &[(OutPoint, TxOut)],
+    commit: TxOut,
+    change: ScriptBuf,
+    fee_rate: FeeRate,
+) -> anyhow::Result<Transaction> {
+    if funding.is_empty() {
+        bail!("No funding output to inscribe with");
+    }
+    let input_value = funding.iter().map(|(_, txout)| txout.value).sum::<Amount>();
+    let mut tx = unsigned_tx(
+        funding.iter().map(|(out_point, _)| *out_point).collect(),
+        vec![
+            commit.clone(),
+            TxOut {
+                value: Amount::ZERO,
+                script_pubkey: change,
+            },
+        ],
+    );
+    // key path signatures, 64 bytes with `SIGHASH_DEFAULT`.
+    for input in &mut tx.input {
+        input.witness = Witness::from_slice(&[[0u8; 64]]);
+    }
+
+    let needed = commit.value + fee(&tx, fee_rate)?;
+    let change_value = input_value.checked_sub(needed).ok_or_else(|| {
+        anyhow!(
+            "Insufficient funds: {} available, {} needed",
+            input_value,
+            needed
+        )
+    })?;
+    if change_value < P2TR_DUST {
+        tx.output.pop();
+    } else {
+        tx.output[1].value = change_value;
+    }
+    for input in &mut tx.input {
+        input.witness = Witness::default();
+    }
+    Ok(tx)
+}
+
+fn reveal_witness(signature: &[u8], script: &Script, control_block: &ControlBlock) -> Witness {
+    let mut witness = Witness::new();
+    witness.push(signature);
+    witness.push(script.as_bytes());
+    witness.push(control_block.serialize());
+    witness
+}
+
+fn fee(tx: &Transaction, fee_rate: FeeRate) -> anyhow::Result<Amount> {
+    fee_rate
+        .fee_vb(tx.vsize() as u64)
+        .ok_or_else(|| anyhow!("Fee overflow at {}", fee_rate))
+}
+
+fn unsigned_tx(inputs: Vec<OutPoint>, output: Vec<TxOut>) -> Transaction {
+    Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: inputs
+            .into_iter()
+            .map(|previous_output| TxIn {
+                previous_output,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::default(),
+            })
+            .collect(),
+        output,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::ledger::LocalLedger;
+    use crate::bitcoin_node::signer::InMemorySigner;
+    use crate::bitcoin_node::tx::timelock::ChainTip;
+    use crate::bitcoin_node::tx::USER_A_PRIVATE_KEY;
+    use bitcoin::sighash::{Prevouts, SighashCache};
+    use bitcoin::TapLeafHash;
+    use secp256k1::Message;
+
+    #[test]
+    fn test_envelope_round_trip() -> anyhow::Result<()> {
+        let signer = InMemorySigner::from_xpriv_str(USER_A_PRIVATE_KEY)?;
+        let body: Vec<u8> = (0..1_200u32).map(|i| i as u8).collect();
+        let inscription = Inscription::new("image/png", body)?;
+        let script = inscription.reveal_script(signer.x_only_public_key()?)?;
+
+        // 520 + 520 + 160 bytes.
+        let pushes = script
+            .instructions()
+            .filter_map(Result::ok)
+            .filter(|i| matches!(i, Instruction::PushBytes(b) if b.len() >= 160))
+            .count();
+        assert_eq!(pushes, 3);
+        assert_eq!(Inscription::from_script(&script), vec![inscription]);
+
+        let text = Inscription::new("text/plain;charset=utf-8", b"Hello, world!".to_vec())?;
+        assert!(text
+            .reveal_script(signer.x_only_public_key()?)?
+            .to_asm_string()
+            .ends_with(
+                "OP_0 OP_IF OP_PUSHBYTES_3 6f7264 OP_PUSHBYTES_1 01 \
+                 OP_PUSHBYTES_24 746578742f706c61696e3b636861727365743d7574662d38 \
+                 OP_0 OP_PUSHBYTES_13 48656c6c6f2c20776f726c6421 OP_ENDIF"
+            ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_inscribe_commit_reveal() -> anyhow::Result<()> {
+        let signer = InMemorySigner::from_xpriv_str(USER_A_PRIVATE_KEY)?;
+        let key = signer.x_only_public_key()?;
+        let own = ScriptBuf::new_p2tr(&SECP, key, None);
+        let mut ledger = LocalLedger::new(ChainTip {
+            height: 100,
+            median_time_past: 1_700_000_000,
+        });
+        let funding = ledger.fund(own.clone(), Amount::from_sat(100_000));
+        let fee_rate = FeeRate::from_sat_per_vb(10).unwrap();
+        let inscription = Inscription::new("text/plain", vec![b'a'; 2_000])?;
+
+        let txs = inscribe(
+            &inscription,
+            &signer,
+            std::slice::from_ref(&funding),
+            own.clone(),
+            own.clone(),
+            fee_rate,
+        )?;
+        let commit_out = &txs.commit_tx.output[0];
+        assert_eq!(
+            commit_out.value,
+            POSTAGE + fee_rate.fee_vb(txs.reveal_tx.vsize() as u64).unwrap()
+        );
+        let commit_fee =
+            funding.1.value - txs.commit_tx.output.iter().map(|o| o.value).sum::<Amount>();
+        assert_eq!(
+            commit_fee,
+            fee_rate.fee_vb(txs.commit_tx.vsize() as u64).unwrap()
+        );
+
+        let script = inscription.reveal_script(key)?;
+        let sighash = SighashCache::new(&txs.reveal_tx).taproot_script_spend_signature_hash(
+            0,
+            &Prevouts::All(std::slice::from_ref(commit_out)),
+            TapLeafHash::from_script(&script, LeafVersion::TapScript),
+            TapSighashType::Default,
+        )?;
+        let signature =
+            secp256k1::schnorr::Signature::from_slice(&txs.reveal_tx.input[0].witness[0])?;
+        SECP.verify_schnorr(&signature, &Message::from(sighash), &key)?;
+
+        ledger.broadcast(&txs.commit_tx)?;
+        ledger.broadcast(&txs.reveal_tx)?;
+        assert_eq!(parse_inscriptions(&txs.reveal_tx), vec![inscription]);
+        assert!(parse_inscriptions(&txs.commit_tx).is_empty());
+
+        assert!(inscribe(
+            &Inscription::new("text/plain", vec![0; 50_000])?,
+            &signer,
+            &[funding],
+            own.clone(),
+            own,
+            fee_rate,
+        )
+        .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,1094 @@
+//! Tapscript interpreter, to see why a script path spend fails instead of a bare node error.
+//!
+//! `ScriptPathSpend::verify` checks the control block of an input against its prevout and runs
+//! the leaf on the witness stack, recording every opcode with the stacks after it in a `Trace`.
+//! It follows the BIP-342 consensus rules: `OP_SUCCESSx` makes the leaf valid before execution,
+//! `OP_CHECKMULTISIG` is disabled in favour of `OP_CHECKSIGADD`, `OP_IF` takes minimal booleans,
+//! signature opcodes spend from the validation weight budget and the final stack must be a
+//! single true element. Policy rules (minimal pushes, discouraged upgradable opcodes) are not
+//! checked.
+//!
+//! Reference: https://github.com/bitcoin/bips/blob/master/bip-0342.mediawiki
+use crate::SECP;
+use anyhow::{anyhow, bail};
+use bitcoin::hashes::{hash160, ripemd160, sha1, sha256, sha256d, Hash};
+use bitcoin::opcodes::all::*;
+use bitcoin::opcodes::{Class, ClassifyContext, Opcode};
+use bitcoin::script::Instruction;
+use bitcoin::sighash::{Annex, Prevouts, SighashCache};
+use bitcoin::taproot::{ControlBlock, LeafVersion, TapLeafHash};
+use bitcoin::{Script, Sequence, Transaction, TxOut, Witness, XOnlyPublicKey};
+use secp256k1::Message;
+use std::fmt;
+
+const MAX_STACK_SIZE: usize = 1000;
+const MAX_ELEMENT_SIZE: usize = 520;
+const VALIDATION_WEIGHT_OFFSET: i64 = 50;
+const VALIDATION_WEIGHT_PER_SIGOP: i64 = 50;
+const LOCKTIME_THRESHOLD: i64 = 500_000_000;
+const SEQUENCE_DISABLE_FLAG: i64 = 1 << 31;
+const SEQUENCE_TYPE_FLAG: i64 = 1 << 22;
+const SEQUENCE_MASK: i64 = 0xffff;
+
+/// One opcode of the leaf, with the stacks once it ran.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Step {
+    /// Opcode position in the leaf, as used by `OP_CODESEPARATOR`.
+    pub position: u32,
+    pub instruction: String,
+    /// False in the untaken branch of an `OP_IF`.
+    pub executed: bool,
+    pub stack: Vec<Vec<u8>>,
+    pub altstack: Vec<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub struct Trace {
+    pub leaf_hash: TapLeafHash,
+    pub initial_stack: Vec<Vec<u8>>,
+    pub steps: Vec<Step>,
+    /// The `OP_SUCCESSx` that made the leaf valid without running it.
+    pub op_success: Option<Opcode>,
+    pub validation_weight_left: i64,
+    /// Execution outcome, errors are prefixed with the failing step.
+    pub result: anyhow::Result<()>,
+}
+
+impl Trace {
+    pub fn is_success(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+/// The input to check and the transaction context its signatures commit to.
+pub struct ScriptPathSpend<'a> {
+    pub tx: &'a Transaction,
+    pub input_index: usize,
+    pub prevouts: &'a [TxOut],
+}
+
+impl<'a> ScriptPathSpend<'a> {
+    pub fn new(tx: &'a Transaction, input_index: usize, prevouts: &'a [TxOut]) -> Self {
+        Self {
+            tx,
+            input_index,
+            prevouts,
+        }
+    }
+
+    /// Run the script path spend found in the input witness.
+    pub fn verify(&self) -> anyhow::Result<Trace> {
+        let input = self
+            .tx
+            .input
+            .get(self.input_index)
+            .ok_or_else(|| anyhow!("Missing input {}", self.input_index))?;
+        self.verify_witness(&input.witness)
+    }
+
+    /// Run the spend with `witness` in place of the input one, e.g. to try a witness before
+    /// setting it. Signatures don't commit to the witness, so the sighashes are the same.
+    ///
+    /// Fails when the witness is not a tapscript spend of the prevout, the execution outcome
+    /// is in `Trace::result`.
+    pub fn verify_witness(&self, witness: &Witness) -> anyhow::Result<Trace> {
+        if self.input_index >= self.tx.input.len() {
+            bail!(
+                "Missing input {}, the tx has {}",
+                self.input_index,
+                self.tx.input.len()
+            );
+        }
+        if self.prevouts.len() != self.tx.input.len() {
+            bail!(
+                "{} prevouts for {} inputs",
+                self.prevouts.len(),
+                self.tx.input.len()
+            );
+        }
+        let prevout = &self.prevouts[self.input_index];
+        if !prevout.script_pubkey.is_p2tr() {
+            bail!("Input {} doesn't spend a taproot output", self.input_index);
+        }
+        let output_key = XOnlyPublicKey::from_slice(&prevout.script_pubkey.as_bytes()[2..])?;
+
+        let mut stack = witness.to_vec();
+        let annex = match stack.last() {
+            Some(last) if stack.len() >= 2 && last.first() == Some(&0x50) => stack.pop(),
+            _ => None,
+        };
+        if stack.len() < 2 {
+            bail!("Input {} is a key path spend", self.input_index);
+        }
+        let control_block = ControlBlock::decode(&stack.pop().unwrap())
+            .map_err(|e| anyhow!("Invalid control block: {}", e))?;
+        let leaf = Script::from_bytes(&stack.pop().unwrap()).to_owned();
+        if control_block.leaf_version != LeafVersion::TapScript {
+            bail!(
+                "Unknown leaf version {}, only tapscript can be run",
+                control_block.leaf_version
+            );
+        }
+        if !control_block.verify_taproot_commitment(&SECP, output_key, &leaf) {
+            bail!(
+                "Control block doesn't commit to the leaf in output key {}",
+                output_key
+            );
+        }
+
+        let mut machine = Machine {
+            spend: self,
+            leaf_hash: TapLeafHash::from_script(&leaf, LeafVersion::TapScript),
+            annex: annex.as_deref(),
+            stack: stack.clone(),
+            altstack: vec![],
+            conditions: vec![],
+            code_separator: u32::MAX,
+            validation_weight_left: VALIDATION_WEIGHT_OFFSET + witness.size() as i64,
+            steps: vec![],
+        };
+        let (op_success, result) = machine.run(&leaf);
+        Ok(Trace {
+            leaf_hash: machine.leaf_hash,
+            initial_stack: stack,
+            steps: machine.steps,
+            op_success,
+            validation_weight_left: machine.validation_weight_left,
+            result,
+        })
+    }
+}
+
+/// Check the taproot input `input_index` of `tx`, a key path spend against the output key or
+/// a script path spend run by `ScriptPathSpend`.
+pub fn verify_taproot_input(
+    tx: &Transaction,
+    input_index: usize,
+    prevouts: &[TxOut],
+) -> anyhow::Result<()> {
+    let spend = ScriptPathSpend::new(tx, input_index, prevouts);
+    let witness = &spend
+        .tx
+        .input
+        .get(input_index)
+        .ok_or_else(|| anyhow!("Missing input {}", input_index))?
+        .witness;
+    let has_annex = witness.len() >= 2 && witness.last().and_then(|e| e.first()) == Some(&0x50);
+    if witness.len() - has_annex as usize >= 2 {
+        return spend.verify()?.result;
+    }
+
+    let prevout = prevouts
+        .get(input_index)
+        .ok_or_else(|| anyhow!("Missing prevout of input {}", input_index))?;
+    if !prevout.script_pubkey.is_p2tr() {
+        bail!("Input {} doesn't spend a taproot output", input_index);
+    }
+    let signature = witness
+        .nth(0)
+        .ok_or_else(|| anyhow!("Empty witness of input {}", input_index))?;
+    if signature.len() == 65 && signature[64] == 0 {
+        bail!("Explicit SIGHASH_DEFAULT byte in a 65 bytes signature");
+    }
+    let signature = bitcoin::taproot::Signature::from_slice(signature)
+        .map_err(|e| anyhow!("Invalid signature: {}", e))?;
+    let annex = match has_annex {
+        true => Some(Annex::new(witness.last().unwrap()).map_err(|e| anyhow!("{}", e))?),
+        false => None,
+    };
+    let sighash = SighashCache::new(tx).taproot_signature_hash(
+        input_index,
+        &Prevouts::All(prevouts),
+        annex,
+        None,
+        signature.sighash_type,
+    )?;
+    let output_key = XOnlyPublicKey::from_slice(&prevout.script_pubkey.as_bytes()[2..])?;
+    SECP.verify_schnorr(&signature.signature, &Message::from(sighash), &output_key)
+        .map_err(|_| anyhow!("Invalid key path signature for {}", output_key))
+}
+
+struct Machine<'a, 'b> {
+    spend: &'a ScriptPathSpend<'b>,
+    leaf_hash: TapLeafHash,
+    annex: Option<&'a [u8]>,
+    stack: Vec<Vec<u8>>,
+    altstack: Vec<Vec<u8>>,
+    /// Taken/untaken state of the enclosing `OP_IF`s.
+    conditions: Vec<bool>,
+    code_separator: u32,
+    validation_weight_left: i64,
+    steps: Vec<Step>,
+}
+
+impl Machine<'_, '_> {
+    fn run(&mut self, leaf: &Script) -> (Option<Opcode>, anyhow::Result<()>) {
+        // The whole leaf is decoded first: an OP_SUCCESSx anywhere wins over the rest.
+        let mut instructions = vec![];
+        let mut indices = leaf.instruction_indices().peekable();
+        while let Some(next) = indices.next() {
+            let (offset, instruction) = match next {
+                Ok(next) => next,
+                Err(e) => return (None, Err(anyhow!("Fail to decode leaf: {}", e))),
+            };
+            if let Instruction::Op(op) = instruction {
+                if op.classify(ClassifyContext::TapScript) == Class::SuccessOp {
+                    return (Some(op), Ok(()));
+                }
+            }
+            let end = match indices.peek() {
+                Some(Ok((end, _))) => *end,
+                _ => leaf.len(),
+            };
+            let asm = Script::from_bytes(&leaf.as_bytes()[offset..end]).to_asm_string();
+            instructions.push((instruction, asm));
+        }
+
+        (None, self.execute(instructions))
+    }
+
+    fn execute(&mut self, instructions: Vec<(Instruction, String)>) -> anyhow::Result<()> {
+        if self.stack.len() > MAX_STACK_SIZE {
+            bail!("Witness stack has {} elements", self.stack.len());
+        }
+        if let Some(element) = self.stack.iter().find(|e| e.len() > MAX_ELEMENT_SIZE) {
+            bail!("Witness element of {} bytes", element.len());
+        }
+
+        for (position, (instruction, asm)) in instructions.into_iter().enumerate() {
+            let position = position as u32;
+            let executed = self.conditions.iter().all(|taken| *taken);
+            self.step(instruction, position, executed)
+                .map_err(|e| anyhow!("#{} {}: {}", position, asm, e))?;
+            self.steps.push(Step {
+                position,
+                instruction: asm,
+                executed,
+                stack: self.stack.clone(),
+                altstack: self.altstack.clone(),
+            });
+        }
+
+        if !self.conditions.is_empty() {
+            bail!("Unbalanced conditional, missing OP_ENDIF");
+        }
+        match self.stack.as_slice() {
+            [top] if cast_to_bool(top) => Ok(()),
+            [_] => bail!("Script ended with a false stack element"),
+            stack => bail!(
+                "Script ended with {} stack elements instead of 1",
+                stack.len()
+            ),
+        }
+    }
+
+    fn step(
+        &mut self,
+        instruction: Instruction,
+        position: u32,
+        executed: bool,
+    ) -> anyhow::Result<()> {
+        let op = match instruction {
+            Instruction::PushBytes(bytes) => {
+                if bytes.len() > MAX_ELEMENT_SIZE {
+                    bail!("Push of {} bytes", bytes.len());
+                }
+                if executed {
+                    self.stack.push(bytes.as_bytes().to_vec());
+                }
+                return Ok(());
+            }
+            Instruction::Op(op) => op,
+        };
+
+        match op {
+            OP_IF | OP_NOTIF => {
+                let mut taken = false;
+                if executed {
+                    let top = self.pop()?;
+                    if top.len() > 1 || (top.len() == 1 && top[0] != 1) {
+                        bail!("OP_IF argument must be empty or 0x01 (MINIMALIF)");
+                    }
+                    taken = cast_to_bool(&top) == (op == OP_IF);
+                }
+                self.conditions.push(taken);
+            }
+            OP_ELSE => {
+                let taken = self
+                    .conditions
+                    .last_mut()
+                    .ok_or_else(|| anyhow!("OP_ELSE without OP_IF"))?;
+                *taken = !*taken;
+            }
+            OP_ENDIF => {
+                self.conditions
+                    .pop()
+                    .ok_or_else(|| anyhow!("OP_ENDIF without OP_IF"))?;
+            }
+            // Like OP_IF, they are evaluated even in an untaken branch.
+            OP_VERIF | OP_VERNOTIF => bail!("Illegal opcode"),
+            _ if !executed => {}
+            _ => self.execute_op(op, position)?,
+        }
+
+        if self.stack.len() + self.altstack.len() > MAX_STACK_SIZE {
+            bail!("Stack size over {}", MAX_STACK_SIZE);
+        }
+        Ok(())
+    }
+
+    fn execute_op(&mut self, op: Opcode, position: u32) -> anyhow::Result<()> {
+        match op {
+            OP_CLTV => self.check_lock_time()?,
+            OP_CSV => self.check_sequence()?,
+            OP_CODESEPARATOR => self.code_separator = position,
+            OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                let pubkey = self.pop()?;
+                let signature = self.pop()?;
+                let success = self.check_signature(&signature, &pubkey)?;
+                if op == OP_CHECKSIGVERIFY {
+                    if !success {
+                        bail!("Empty signature");
+                    }
+                } else {
+                    self.push_bool(success);
+                }
+            }
+            OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                bail!("OP_CHECKMULTISIG is disabled in tapscript, use OP_CHECKSIGADD")
+            }
+            OP_CHECKSIGADD => {
+                let pubkey = self.pop()?;
+                let n = decode_num(&self.pop()?, 4)?;
+                let signature = self.pop()?;
+                let success = self.check_signature(&signature, &pubkey)?;
+                self.push_num(n + success as i64);
+            }
+            _ => match op.classify(ClassifyContext::TapScript) {
+                Class::PushNum(n) => self.push_num(n as i64),
+                Class::NoOp => {}
+                Class::ReturnOp => bail!("Script returned"),
+                Class::IllegalOp => bail!("Illegal opcode"),
+                _ => self.execute_ordinary(op)?,
+            },
+        }
+        Ok(())
+    }
+
+    fn execute_ordinary(&mut self, op: Opcode) -> anyhow::Result<()> {
+        match op {
+            OP_VERIFY => {
+                if !cast_to_bool(&self.pop()?) {
+                    bail!("OP_VERIFY failed");
+                }
+            }
+            OP_TOALTSTACK => {
+                let top = self.pop()?;
+                self.altstack.push(top);
+            }
+            OP_FROMALTSTACK => {
+                let top = self
+                    .altstack
+                    .pop()
+                    .ok_or_else(|| anyhow!("Altstack underflow"))?;
+                self.stack.push(top);
+            }
+            OP_2DROP => {
+                self.pop()?;
+                self.pop()?;
+            }
+            OP_2DUP => self.copy(&[2, 1])?,
+            OP_3DUP => self.copy(&[3, 2, 1])?,
+            OP_2OVER => self.copy(&[4, 3])?,
+            OP_2ROT => {
+                self.roll(6)?;
+                self.roll(6)?;
+            }
+            OP_2SWAP => {
+                self.roll(4)?;
+                self.roll(4)?;
+            }
+            OP_IFDUP => {
+                if cast_to_bool(self.peek(1)?) {
+                    self.copy(&[1])?;
+                }
+            }
+            OP_DEPTH => self.push_num(self.stack.len() as i64),
+            OP_DROP => {
+                self.pop()?;
+            }
+            OP_DUP => self.copy(&[1])?,
+            OP_NIP => {
+                self.roll(2)?;
+                self.pop()?;
+            }
+            OP_OVER => self.copy(&[2])?,
+            OP_PICK | OP_ROLL => {
+                let n = decode_num(&self.pop()?, 4)?;
+                if n < 0 || n as usize >= self.stack.len() {
+                    bail!("Stack underflow");
+                }
+                match op {
+                    OP_PICK => self.copy(&[n as usize + 1])?,
+                    _ => self.roll(n as usize + 1)?,
+                }
+            }
+            OP_ROT => self.roll(3)?,
+            OP_SWAP => self.roll(2)?,
+            OP_TUCK => {
+                self.peek(2)?;
+                let top = self.peek(1)?.clone();
+                let len = self.stack.len();
+                self.stack.insert(len - 2, top);
+            }
+            OP_SIZE => self.push_num(self.peek(1)?.len() as i64),
+            OP_EQUAL | OP_EQUALVERIFY => {
+                let equal = self.pop()? == self.pop()?;
+                if op == OP_EQUALVERIFY {
+                    if !equal {
+                        bail!("OP_EQUALVERIFY failed");
+                    }
+                } else {
+                    self.push_bool(equal);
+                }
+            }
+            OP_1ADD | OP_1SUB | OP_NEGATE | OP_ABS | OP_NOT | OP_0NOTEQUAL => {
+                let n = decode_num(&self.pop()?, 4)?;
+                self.push_num(match op {
+                    OP_1ADD => n + 1,
+                    OP_1SUB => n - 1,
+                    OP_NEGATE => -n,
+                    OP_ABS => n.abs(),
+                    OP_NOT => (n == 0) as i64,
+                    _ => (n != 0) as i64,
+                });
+            }
+            OP_WITHIN => {
+                let max = decode_num(&self.pop()?, 4)?;
+                let min = decode_num(&self.pop()?, 4)?;
+                let n = decode_num(&self.pop()?, 4)?;
+                self.push_bool(min <= n && n < max);
+            }
+            OP_RIPEMD160 => {
+                self.hash(|data| ripemd160::Hash::hash(data).to_byte_array().to_vec())?
+            }
+            OP_SHA1 => self.hash(|data| sha1::Hash::hash(data).to_byte_array().to_vec())?,
+            OP_SHA256 => self.hash(|data| sha256::Hash::hash(data).to_byte_array().to_vec())?,
+            OP_HASH160 => self.hash(|data| hash160::Hash::hash(data).to_byte_array().to_vec())?,
+            OP_HASH256 => self.hash(|data| sha256d::Hash::hash(data).to_byte_array().to_vec())?,
+            _ => self.execute_binary_num(op)?,
+        }
+        Ok(())
+    }
+
+    fn execute_binary_num(&mut self, op: Opcode) -> anyhow::Result<()> {
+        let (b, a) = match op {
+            OP_ADD
+            | OP_SUB
+            | OP_BOOLAND
+            | OP_BOOLOR
+            | OP_NUMEQUAL
+            | OP_NUMEQUALVERIFY
+            | OP_NUMNOTEQUAL
+            | OP_LESSTHAN
+            | OP_GREATERTHAN
+            | OP_LESSTHANOREQUAL
+            | OP_GREATERTHANOREQUAL
+            | OP_MIN
+            | OP_MAX => (decode_num(&self.pop()?, 4)?, decode_num(&self.pop()?, 4)?),
+            _ => bail!("Unknown opcode"),
+        };
+        let result = match op {
+            OP_ADD => a + b,
+            OP_SUB => a - b,
+            OP_BOOLAND => (a != 0 && b != 0) as i64,
+            OP_BOOLOR => (a != 0 || b != 0) as i64,
+            OP_NUMEQUAL | OP_NUMEQUALVERIFY => (a == b) as i64,
+            OP_NUMNOTEQUAL => (a != b) as i64,
+            OP_LESSTHAN => (a < b) as i64,
+            OP_GREATERTHAN => (a > b) as i64,
+            OP_LESSTHANOREQUAL => (a <= b) as i64,
+            OP_GREATERTHANOREQUAL => (a >= b) as i64,
+            OP_MIN => a.min(b),
+            _ => a.max(b),
+        };
+        if op == OP_NUMEQUALVERIFY {
+            if result == 0 {
+                bail!("OP_NUMEQUALVERIFY failed");
+            }
+        } else {
+            self.push_num(result);
+        }
+        Ok(())
+    }
+
+    /// BIP-342 signature check: empty signatures fail softly, 32 bytes keys are verified,
+    /// other non empty keys are an upgrade path and succeed.
+    fn check_signature(&mut self, signature: &[u8], pubkey: &[u8]) -> anyhow::Result<bool> {
+        if !signature.is_empty() {
+            self.validation_weight_left -= VALIDATION_WEIGHT_PER_SIGOP;
+            if self.validation_weight_left < 0 {
+                bail!("Validation weight budget exceeded");
+            }
+        }
+        if pubkey.is_empty() {
+            bail!("Empty public key");
+        }
+        if signature.is_empty() || pubkey.len() != 32 {
+            return Ok(!signature.is_empty());
+        }
+
+        if signature.len() == 65 && signature[64] == 0 {
+            bail!("Explicit SIGHASH_DEFAULT byte in a 65 bytes signature");
+        }
+        let signature = bitcoin::taproot::Signature::from_slice(signature)
+            .map_err(|e| anyhow!("Invalid signature: {}", e))?;
+        let annex = self
+            .annex
+            .map(Annex::new)
+            .transpose()
+            .map_err(|e| anyhow!("Invalid annex: {}", e))?;
+        let sighash = SighashCache::new(self.spend.tx).taproot_signature_hash(
+            self.spend.input_index,
+            &Prevouts::All(self.spend.prevouts),
+            annex,
+            Some((self.leaf_hash, self.code_separator)),
+            signature.sighash_type,
+        )?;
+        let pubkey = XOnlyPublicKey::from_slice(pubkey)?;
+        SECP.verify_schnorr(&signature.signature, &Message::from(sighash), &pubkey)
+            .map_err(|_| anyhow!("Invalid schnorr signature for {}", pubkey))?;
+        Ok(true)
+    }
+
+    fn check_lock_time(&self) -> anyhow::Result<()> {
+        let lock_time = decode_num(self.peek(1)?, 5)?;
+        if lock_time < 0 {
+            bail!("Negative lock time");
+        }
+        let tx_lock_time = self.spend.tx.lock_time.to_consensus_u32() as i64;
+        if (lock_time < LOCKTIME_THRESHOLD) != (tx_lock_time < LOCKTIME_THRESHOLD) {
+            bail!(
+                "Lock time {} and tx lock time {} differ in unit",
+                lock_time,
+                tx_lock_time
+            );
+        }
+        if lock_time > tx_lock_time {
+            bail!(
+                "Lock time {} not reached by tx lock time {}",
+                lock_time,
+                tx_lock_time
+            );
+        }
+        if self.spend.tx.input[self.spend.input_index].sequence == Sequence::MAX {
+            bail!("Final input sequence disables the lock time");
+        }
+        Ok(())
+    }
+
+    fn check_sequence(&self) -> anyhow::Result<()> {
+        let sequence = decode_num(self.peek(1)?, 5)?;
+        if sequence < 0 {
+            bail!("Negative sequence");
+        }
+        if sequence & SEQUENCE_DISABLE_FLAG != 0 {
+            return Ok(());
+        }
+        if self.spend.tx.version.0 < 2 {
+            bail!(
+                "Transaction version {} has no relative lock time",
+                self.spend.tx.version
+            );
+        }
+        let tx_sequence = self.spend.tx.input[self.spend.input_index].sequence.0 as i64;
+        if tx_sequence & SEQUENCE_DISABLE_FLAG != 0 {
+            bail!(
+                "Input sequence {:#x} disables the relative lock time",
+                tx_sequence
+            );
+        }
+        let (locked, tx_locked) = (
+            sequence & (SEQUENCE_TYPE_FLAG | SEQUENCE_MASK),
+            tx_sequence & (SEQUENCE_TYPE_FLAG | SEQUENCE_MASK),
+        );
+        if (locked < SEQUENCE_TYPE_FLAG) != (tx_locked < SEQUENCE_TYPE_FLAG) {
+            bail!(
+                "Sequence {:#x} and input sequence {:#x} differ in unit",
+                locked,
+                tx_locked
+            );
+        }
+        if locked > tx_locked {
+            bail!(
+                "Sequence {:#x} not reached by input sequence {:#x}",
+                locked,
+                tx_locked
+            );
+        }
+        Ok(())
+    }
+
+    fn pop(&mut self) -> anyhow::Result<Vec<u8>> {
+        self.stack.pop().ok_or_else(|| anyhow!("Stack underflow"))
+    }
+
+    /// The `depth`th element from the top, 1 being the top.
+    fn peek(&self, depth: usize) -> anyhow::Result<&Vec<u8>> {
+        self.stack
+            .len()
+            .checked_sub(depth)
+            .map(|index| &self.stack[index])
+            .ok_or_else(|| anyhow!("Stack underflow"))
+    }
+
+    /// Push copies of the elements at `depths`, in order.
+    fn copy(&mut self, depths: &[usize]) -> anyhow::Result<()> {
+        let copies = depths
+            .iter()
+            .map(|depth| self.peek(*depth).cloned())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        self.stack.extend(copies);
+        Ok(())
+    }
+
+    /// Move the element at `depth` to the top.
+    fn roll(&mut self, depth: usize) -> anyhow::Result<()> {
+        let index = self
+            .stack
+            .len()
+            .checked_sub(depth)
+            .ok_or_else(|| anyhow!("Stack underflow"))?;
+        let element = self.stack.remove(index);
+        self.stack.push(element);
+        Ok(())
+    }
+
+    fn hash(&mut self, hash: impl Fn(&[u8]) -> Vec<u8>) -> anyhow::Result<()> {
+        let data = self.pop()?;
+        self.stack.push(hash(&data));
+        Ok(())
+    }
+
+    fn push_bool(&mut self, value: bool) {
+        self.push_num(value as i64);
+    }
+
+    fn push_num(&mut self, n: i64) {
+        self.stack.push(encode_num(n));
+    }
+}
+
+fn cast_to_bool(element: &[u8]) -> bool {
+    match element.split_last() {
+        // negative zero is false
+        Some((last, rest)) => rest.iter().any(|b| *b != 0) || (*last != 0 && *last != 0x80),
+        None => false,
+    }
+}
+
+/// Little endian sign-magnitude script number of at most `max_size` bytes.
+fn decode_num(element: &[u8], max_size: usize) -> anyhow::Result<i64> {
+    if element.len() > max_size {
+        bail!("Script number of {} bytes, max {}", element.len(), max_size);
+    }
+    let Some((last, _)) = element.split_last() else {
+        return Ok(0);
+    };
+    let magnitude = element
+        .iter()
+        .enumerate()
+        .fold(0i64, |n, (i, b)| n | ((*b as i64) << (8 * i)));
+    match last & 0x80 {
+        0 => Ok(magnitude),
+        _ => Ok(-(magnitude & !(0x80 << (8 * (element.len() - 1))))),
+    }
+}
+
+fn encode_num(n: i64) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut magnitude = n.unsigned_abs();
+    while magnitude > 0 {
+        bytes.push(magnitude as u8);
+        magnitude >>= 8;
+    }
+    match bytes.last() {
+        Some(last) if last & 0x80 != 0 => bytes.push(if n < 0 { 0x80 } else { 0 }),
+        Some(_) if n < 0 => *bytes.last_mut().unwrap() |= 0x80,
+        _ => {}
+    }
+    bytes
+}
+
+struct StackDisplay<'a>(&'a [Vec<u8>]);
+
+impl fmt::Display for StackDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use bitcoin::hex::DisplayHex;
+        write!(f, "[")?;
+        for (i, element) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            match element.is_empty() {
+                true => write!(f, "<>")?,
+                false => write!(f, "{}", element.as_hex())?,
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "leaf: {}", self.leaf_hash)?;
+        writeln!(f, "initial stack: {}", StackDisplay(&self.initial_stack))?;
+        if let Some(op) = self.op_success {
+            writeln!(f, "{} found, the leaf is valid without execution", op)?;
+        }
+        for step in &self.steps {
+            let skipped = if step.executed { "" } else { " (not executed)" };
+            writeln!(
+                f,
+                "#{} {}{}\n    stack: {}",
+                step.position,
+                step.instruction,
+                skipped,
+                StackDisplay(&step.stack)
+            )?;
+            if !step.altstack.is_empty() {
+                writeln!(f, "    altstack: {}", StackDisplay(&step.altstack))?;
+            }
+        }
+        writeln!(f, "validation weight left: {}", self.validation_weight_left)?;
+        match &self.result {
+            Ok(()) => writeln!(f, "result: success"),
+            Err(e) => writeln!(f, "result: failure, {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::signer::{sign_script_spend_input, InMemorySigner, Signer};
+    use crate::bitcoin_node::tx::taproot_tree_tx::template::LeafTemplate;
+    use crate::bitcoin_node::tx::{USER_A_PRIVATE_KEY, USER_B_PRIVATE_KEY, USER_C_PRIVATE_KEY};
+    use bitcoin::taproot::{TaprootBuilder, TaprootSpendInfo};
+    use bitcoin::{
+        absolute, script, transaction, Amount, OutPoint, ScriptBuf, TapSighashType, TxIn,
+    };
+
+    fn spend_tx(
+        leaf: &ScriptBuf,
+        internal_key: XOnlyPublicKey,
+    ) -> (Transaction, TxOut, TaprootSpendInfo) {
+        let spend_info = TaprootBuilder::new()
+            .add_leaf(0, leaf.clone())
+            .unwrap()
+            .finalize(&SECP, internal_key)
+            .unwrap();
+        let prevout = TxOut {
+            value: Amount::from_sat(20_000),
+            script_pubkey: ScriptBuf::new_p2tr_tweaked(spend_info.output_key()),
+        };
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::default(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(19_000),
+                script_pubkey: ScriptBuf::new_op_return([]),
+            }],
+        };
+        (tx, prevout, spend_info)
+    }
+
+    fn witness(stack: &[Vec<u8>], leaf: &ScriptBuf, spend_info: &TaprootSpendInfo) -> Witness {
+        let control_block = spend_info
+            .control_block(&(leaf.clone(), LeafVersion::TapScript))
+            .unwrap();
+        let mut witness = Witness::from_slice(stack);
+        witness.push(leaf.as_bytes());
+        witness.push(control_block.serialize());
+        witness
+    }
+
+    #[test]
+    fn test_checksigadd_multisig_trace() -> anyhow::Result<()> {
+        let signers: Vec<_> = [USER_A_PRIVATE_KEY, USER_B_PRIVATE_KEY, USER_C_PRIVATE_KEY]
+            .iter()
+            .map(|sk| InMemorySigner::from_xpriv_str(sk))
+            .collect::<anyhow::Result<_>>()?;
+        let keys = signers
+            .iter()
+            .map(|signer| signer.x_only_public_key())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let leaf = LeafTemplate::multi_a(keys.clone(), 2)?.to_script();
+        let (tx, prevout, spend_info) = spend_tx(&leaf, keys[0]);
+        let prevouts = [prevout];
+        let sign = |signer: &InMemorySigner, sighash_type| -> anyhow::Result<Vec<u8>> {
+            Ok(sign_script_spend_input(signer, &tx, 0, &prevouts, &leaf, sighash_type)?.to_vec())
+        };
+        let spend = ScriptPathSpend::new(&tx, 0, &prevouts);
+
+        // B and C sign, the witness lists signatures in reverse key order.
+        let sig_b = sign(&signers[1], TapSighashType::All)?;
+        let sig_c = sign(&signers[2], TapSighashType::Default)?;
+        let signed = witness(&[sig_c.clone(), sig_b, vec![]], &leaf, &spend_info);
+        let trace = spend.verify_witness(&signed)?;
+        assert!(trace.is_success(), "{}", trace);
+        assert_eq!(trace.steps.len(), leaf.instructions().count());
+        assert_eq!(trace.steps.last().unwrap().stack, vec![vec![1]]);
+        // 2 signatures of 50 each out of 50 + witness size.
+        assert_eq!(
+            trace.validation_weight_left,
+            50 + signed.size() as i64 - 100
+        );
+
+        // A's signature in B's slot fails at B's OP_CHECKSIGADD.
+        let sig_a = sign(&signers[0], TapSighashType::Default)?;
+        let trace = spend.verify_witness(&witness(
+            &[sig_c.clone(), sig_a, vec![]],
+            &leaf,
+            &spend_info,
+        ))?;
+        let error = trace.result.as_ref().unwrap_err().to_string();
+        assert!(
+            error.contains("OP_CHECKSIGADD") && error.contains("Invalid schnorr signature"),
+            "{}",
+            error
+        );
+
+        // a single signature goes through every opcode but fails OP_NUMEQUAL, the empty
+        // signatures don't spend validation weight.
+        let single = witness(&[sig_c, vec![], vec![]], &leaf, &spend_info);
+        let trace = spend.verify_witness(&single)?;
+        assert_eq!(trace.steps.len(), leaf.instructions().count());
+        assert!(!trace.is_success());
+        assert_eq!(trace.validation_weight_left, 50 + single.size() as i64 - 50);
+
+        // the input and its prevouts must exist.
+        let signed = witness(&[vec![]], &leaf, &spend_info);
+        assert!(ScriptPathSpend::new(&tx, 1, &prevouts)
+            .verify_witness(&signed)
+            .is_err());
+        assert!(ScriptPathSpend::new(&tx, 0, &[])
+            .verify_witness(&signed)
+            .is_err());
+
+        // the leaf must be in the output tree.
+        let other_leaf = LeafTemplate::single_sig(keys[1]).to_script();
+        let (_, _, other_info) = spend_tx(&other_leaf, keys[0]);
+        assert!(spend
+            .verify_witness(&witness(&[vec![]], &other_leaf, &other_info))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_op_success_minimal_if_and_weight_budget() -> anyhow::Result<()> {
+        let signer = InMemorySigner::from_xpriv_str(USER_A_PRIVATE_KEY)?;
+        let key = signer.x_only_public_key()?;
+
+        // OP_SUCCESSx wins over the OP_RETURN before it.
+        let leaf = script::Builder::new()
+            .push_opcode(OP_RETURN)
+            .push_opcode(OP_CAT)
+            .into_script();
+        let (tx, prevout, spend_info) = spend_tx(&leaf, key);
+        let prevouts = [prevout];
+        let trace = ScriptPathSpend::new(&tx, 0, &prevouts).verify_witness(&witness(
+            &[],
+            &leaf,
+            &spend_info,
+        ))?;
+        assert!(trace.is_success());
+        assert_eq!(trace.op_success, Some(OP_CAT));
+        assert!(trace.steps.is_empty());
+
+        // OP_IF only takes an empty or 0x01 argument.
+        let leaf = script::Builder::new()
+            .push_opcode(OP_IF)
+            .push_int(1)
+            .push_opcode(OP_ELSE)
+            .push_int(0)
+            .push_opcode(OP_ENDIF)
+            .into_script();
+        let (tx, prevout, spend_info) = spend_tx(&leaf, key);
+        let prevouts = [prevout];
+        let spend = ScriptPathSpend::new(&tx, 0, &prevouts);
+        let trace = spend.verify_witness(&witness(&[vec![1]], &leaf, &spend_info))?;
+        assert!(trace.is_success(), "{}", trace);
+        assert!(!trace.steps[3].executed, "OP_ELSE branch skipped");
+        let trace = spend.verify_witness(&witness(&[vec![2]], &leaf, &spend_info))?;
+        assert!(trace.result.unwrap_err().to_string().contains("MINIMALIF"));
+
+        // one signature checked over and over runs out of validation weight.
+        let mut builder = script::Builder::new();
+        for _ in 0..20 {
+            builder = builder
+                .push_opcode(OP_DUP)
+                .push_x_only_key(&key)
+                .push_opcode(OP_CHECKSIGVERIFY);
+        }
+        let leaf = builder
+            .push_x_only_key(&key)
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+        let (tx, prevout, spend_info) = spend_tx(&leaf, key);
+        let prevouts = [prevout];
+        let signature =
+            sign_script_spend_input(&signer, &tx, 0, &prevouts, &leaf, TapSighashType::Default)?;
+        let trace = ScriptPathSpend::new(&tx, 0, &prevouts).verify_witness(&witness(
+            &[signature.to_vec()],
+            &leaf,
+            &spend_info,
+        ))?;
+        assert!(trace
+            .result
+            .unwrap_err()
+            .to_string()
+            .contains("Validation weight"));
+        assert!(trace.validation_weight_left < 0);
+        Ok(())
+    }
+
+    fn run(leaf: &ScriptBuf, tx: impl Fn(&mut Transaction)) -> anyhow::Result<()> {
+        let key = InMemorySigner::from_xpriv_str(USER_A_PRIVATE_KEY)?.x_only_public_key()?;
+        let (mut spend, prevout, spend_info) = spend_tx(leaf, key);
+        tx(&mut spend);
+        let prevouts = [prevout];
+        ScriptPathSpend::new(&spend, 0, &prevouts)
+            .verify_witness(&witness(&[], leaf, &spend_info))?
+            .result
+    }
+
+    fn assert_fails(result: anyhow::Result<()>, message: &str) {
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains(message), "{}", error);
+    }
+
+    #[test]
+    fn test_lock_times() -> anyhow::Result<()> {
+        let leaf = script::Builder::new()
+            .push_int(500)
+            .push_opcode(OP_CLTV)
+            .push_opcode(OP_DROP)
+            .push_int(1)
+            .into_script();
+        let lock_time = |height| {
+            move |tx: &mut Transaction| {
+                tx.lock_time = absolute::LockTime::from_consensus(height);
+            }
+        };
+        run(&leaf, lock_time(500))?;
+        assert_fails(run(&leaf, lock_time(499)), "not reached");
+        assert_fails(run(&leaf, lock_time(1_700_000_000)), "differ in unit");
+        assert_fails(
+            run(&leaf, |tx| {
+                tx.lock_time = absolute::LockTime::from_consensus(500);
+                tx.input[0].sequence = Sequence::MAX;
+            }),
+            "Final input sequence",
+        );
+
+        let leaf = script::Builder::new()
+            .push_int(10)
+            .push_opcode(OP_CSV)
+            .push_opcode(OP_DROP)
+            .push_int(1)
+            .into_script();
+        let sequence = |sequence| move |tx: &mut Transaction| tx.input[0].sequence = sequence;
+        run(&leaf, sequence(Sequence::from_height(10)))?;
+        assert_fails(
+            run(&leaf, sequence(Sequence::from_height(9))),
+            "not reached",
+        );
+        assert_fails(
+            run(&leaf, sequence(Sequence::from_512_second_intervals(10))),
+            "differ in unit",
+        );
+        assert_fails(
+            run(&leaf, sequence(Sequence::ENABLE_RBF_NO_LOCKTIME)),
+            "disables the relative lock time",
+        );
+        assert_fails(
+            run(&leaf, |tx| {
+                tx.version = transaction::Version::ONE;
+                tx.input[0].sequence = Sequence::from_height(10);
+            }),
+            "no relative lock time",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_untaken_branches() -> anyhow::Result<()> {
+        let untaken = |op| {
+            script::Builder::new()
+                .push_int(0)
+                .push_opcode(OP_IF)
+                .push_opcode(op)
+                .push_opcode(OP_ENDIF)
+                .push_int(1)
+                .into_script()
+        };
+        let unchanged = |_: &mut Transaction| {};
+
+        // only OP_VERIF and OP_VERNOTIF fail without being executed.
+        run(&untaken(OP_CHECKMULTISIG), unchanged)?;
+        run(&untaken(OP_RETURN), unchanged)?;
+        assert_fails(run(&untaken(OP_VERIF), unchanged), "Illegal opcode");
+        assert_fails(run(&untaken(OP_VERNOTIF), unchanged), "Illegal opcode");
+
+        let leaf = script::Builder::new()
+            .push_int(1)
+            .push_int(0)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script();
+        assert_fails(run(&leaf, unchanged), "disabled in tapscript");
+
+        // an OP_SUCCESSx counts wherever it is, even after a failing opcode.
+        let leaf = script::Builder::new()
+            .push_int(0)
+            .push_opcode(OP_VERIFY)
+            .push_int(0)
+            .push_opcode(OP_IF)
+            .push_opcode(OP_RESERVED)
+            .push_opcode(OP_ENDIF)
+            .into_script();
+        run(&leaf, unchanged)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_numbers() -> anyhow::Result<()> {
+        for n in [
+            0,
+            1,
+            -1,
+            127,
+            128,
+            -128,
+            255,
+            32_767,
+            -32_768,
+            0x7fff_ffff,
+            -0x7fff_ffff,
+        ] {
+            assert_eq!(decode_num(&encode_num(n), 4)?, n);
+        }
+        assert_eq!(encode_num(-1), vec![0x81]);
+        assert_eq!(encode_num(128), vec![0x80, 0]);
+        assert!(decode_num(&[0, 0, 0, 0, 1], 4).is_err());
+        assert!(!cast_to_bool(&[0, 0x80]));
+        assert!(cast_to_bool(&[0x80, 0]));
+        Ok(())
+    }
+}
@@ -4,9 +4,18 @@ use bitcoin::{Amount, OutPoint, ScriptBuf, TxOut, Txid};
 use secp256k1::{Keypair, Secp256k1, Signing, Verification};
 use std::str::FromStr;
 
+pub mod anchor;
+pub mod cpfp;
+pub mod decode;
+pub mod inscription;
+pub mod interpreter;
+pub mod pay_to_contract;
 mod presing_tx_taproot;
+pub mod psbt;
+pub mod rbf;
 pub mod sign_tx_taproot;
 pub mod taproot_tree_tx;
+pub mod timelock;
 
 // User BTC regtest info:
 // -rpcwallet=benefactor
@@ -0,0 +1,352 @@
+//! Sign and finalize PSBTs with a `Signer`, for taproot key/script path and p2wpkh inputs.
+//!
+//! Reference: https://github.com/bitcoin/bips/blob/master/bip-0174.mediawiki
+//!            https://github.com/bitcoin/bips/blob/master/bip-0371.mediawiki
+use crate::bitcoin_node::signer::Signer;
+use crate::SECP;
+use anyhow::{anyhow, bail};
+use bitcoin::key::TapTweak;
+use bitcoin::opcodes::all::{
+    OP_CHECKSIG, OP_CHECKSIGADD, OP_CHECKSIGVERIFY, OP_HASH160, OP_HASH256, OP_NUMEQUAL,
+    OP_RIPEMD160, OP_SHA1, OP_SHA256,
+};
+use bitcoin::psbt::Input;
+use bitcoin::script::Instruction;
+use bitcoin::sighash::{Prevouts, SighashCache};
+use bitcoin::taproot::{ControlBlock, LeafVersion, TapLeafHash};
+use bitcoin::{
+    CompressedPublicKey, EcdsaSighashType, Psbt, Script, ScriptBuf, TapSighashType, TxOut, Witness,
+    XOnlyPublicKey,
+};
+use std::collections::BTreeMap;
+
+/// Add the signatures `signer` can make to `psbt`: taproot key path when the output key is
+/// its key tweaked with `tap_merkle_root`, every `tap_scripts` leaf using its key, and p2wpkh
+/// inputs of its key. Returns the number of signatures added.
+pub fn sign_psbt(psbt: &mut Psbt, signer: &dyn Signer) -> anyhow::Result<usize> {
+    let key = signer.x_only_public_key()?;
+    let p2wpkh = CompressedPublicKey::try_from(signer.public_key()?)
+        .ok()
+        .map(|pk| (pk, ScriptBuf::new_p2wpkh(&pk.wpubkey_hash())));
+    // Taproot sighashes commit to all the prevouts.
+    let prevouts: Option<Vec<TxOut>> = (0..psbt.inputs.len())
+        .map(|i| psbt.spend_utxo(i).ok().cloned())
+        .collect();
+
+    let mut count = 0;
+    let mut sighasher = SighashCache::new(&psbt.unsigned_tx);
+    for (i, input) in psbt.inputs.iter_mut().enumerate() {
+        let Ok(prevout) = input_prevout(input) else {
+            continue;
+        };
+        if prevout.script_pubkey.is_p2tr() {
+            let prevouts = prevouts
+                .as_deref()
+                .ok_or_else(|| anyhow!("Missing prevouts, can't sign taproot input {}", i))?;
+            let sighash_type = match input.sighash_type {
+                Some(sighash_type) => sighash_type.taproot_hash_ty()?,
+                None => TapSighashType::Default,
+            };
+
+            let internal_key = input.tap_internal_key.unwrap_or(key);
+            let (output_key, _) = internal_key.tap_tweak(&SECP, input.tap_merkle_root);
+            if internal_key == key
+                && prevout.script_pubkey == ScriptBuf::new_p2tr_tweaked(output_key)
+            {
+                let sighash = sighasher.taproot_key_spend_signature_hash(
+                    i,
+                    &Prevouts::All(prevouts),
+                    sighash_type,
+                )?;
+                input.tap_key_sig = Some(bitcoin::taproot::Signature {
+                    signature: signer.sign_schnorr_key_spend(sighash, input.tap_merkle_root)?,
+                    sighash_type,
+                });
+                count += 1;
+            }
+
+            for (script, leaf_version) in input.tap_scripts.values() {
+                if !checksig_keys(script).contains(&key) {
+                    continue;
+                }
+                let leaf_hash = TapLeafHash::from_script(script, *leaf_version);
+                let sighash = sighasher.taproot_script_spend_signature_hash(
+                    i,
+                    &Prevouts::All(prevouts),
+                    leaf_hash,
+                    sighash_type,
+                )?;
+                let signature = bitcoin::taproot::Signature {
+                    signature: signer.sign_schnorr_script_spend(sighash, leaf_hash)?,
+                    sighash_type,
+                };
+                input.tap_script_sigs.insert((key, leaf_hash), signature);
+                count += 1;
+            }
+        } else if let Some((pk, script_pubkey)) = &p2wpkh {
+            if prevout.script_pubkey != *script_pubkey {
+                continue;
+            }
+            let sighash_type = match input.sighash_type {
+                Some(sighash_type) => sighash_type.ecdsa_hash_ty()?,
+                None => EcdsaSighashType::All,
+            };
+            let sighash =
+                sighasher.p2wpkh_signature_hash(i, script_pubkey, prevout.value, sighash_type)?;
+            input.partial_sigs.insert(
+                (*pk).into(),
+                bitcoin::ecdsa::Signature {
+                    signature: signer.sign_ecdsa(sighash)?,
+                    sighash_type,
+                },
+            );
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Turn the signatures of every input into its final witness, preferring the key path.
+///
+/// Script path leaves are finalized when they only check signatures (single sig, time
+/// locked, k-of-n `OP_CHECKSIGADD`), hash locks need the preimage and are left to the caller.
+pub fn finalize_psbt(psbt: &mut Psbt) -> anyhow::Result<()> {
+    for (i, input) in psbt.inputs.iter_mut().enumerate() {
+        if input.final_script_witness.is_some() || input.final_script_sig.is_some() {
+            continue;
+        }
+        let witness = if let Some(signature) = &input.tap_key_sig {
+            Witness::p2tr_key_spend(signature)
+        } else if let Some(witness) = script_path_witness(input) {
+            witness
+        } else if let Some((pk, signature)) = input.partial_sigs.iter().next() {
+            let pk = CompressedPublicKey::try_from(*pk)?;
+            if input_prevout(input)?.script_pubkey != ScriptBuf::new_p2wpkh(&pk.wpubkey_hash()) {
+                bail!("Input {} is not a p2wpkh of its signature key", i);
+            }
+            Witness::p2wpkh(signature, &pk.0)
+        } else {
+            bail!("Input {} doesn't have enough signatures to finalize", i);
+        };
+
+        // BIP-174: the finalizer clears everything but the utxo and the final fields.
+        *input = Input {
+            witness_utxo: input.witness_utxo.take(),
+            non_witness_utxo: input.non_witness_utxo.take(),
+            final_script_witness: Some(witness),
+            ..Default::default()
+        };
+    }
+    Ok(())
+}
+
+fn input_prevout(input: &Input) -> anyhow::Result<&TxOut> {
+    input
+        .witness_utxo
+        .as_ref()
+        .ok_or_else(|| anyhow!("Missing witness utxo"))
+}
+
+fn script_path_witness(input: &Input) -> Option<Witness> {
+    let mut leaves: Vec<(&ControlBlock, &(ScriptBuf, LeafVersion))> =
+        input.tap_scripts.iter().collect();
+    // The shallowest leaf gives the smallest witness.
+    leaves.sort_by_key(|(control_block, _)| control_block.merkle_branch.len());
+
+    for (control_block, (script, leaf_version)) in leaves {
+        let has_hash_lock = script.instructions().any(|i| {
+            matches!(
+                i.ok().and_then(|i| i.opcode()),
+                Some(OP_SHA256 | OP_HASH160 | OP_HASH256 | OP_RIPEMD160 | OP_SHA1)
+            )
+        });
+        if has_hash_lock {
+            continue;
+        }
+        let keys = checksig_keys(script);
+        let threshold = numequal_threshold(script).unwrap_or(keys.len());
+        let leaf_hash = TapLeafHash::from_script(script, *leaf_version);
+        let mut signatures: BTreeMap<usize, _> = BTreeMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            if signatures.len() == threshold {
+                break;
+            }
+            if let Some(signature) = input.tap_script_sigs.get(&(*key, leaf_hash)) {
+                signatures.insert(i, signature);
+            }
+        }
+        if keys.is_empty() || signatures.len() < threshold {
+            continue;
+        }
+
+        // The first key reads the top of the stack.
+        let mut witness = Witness::new();
+        for i in (0..keys.len()).rev() {
+            match signatures.get(&i) {
+                Some(signature) => witness.push(signature.to_vec()),
+                None => witness.push([]),
+            }
+        }
+        witness.push(script.as_bytes());
+        witness.push(control_block.serialize());
+        return Some(witness);
+    }
+    None
+}
+
+/// The keys checked by `OP_CHECKSIG`, `OP_CHECKSIGVERIFY` or `OP_CHECKSIGADD`, in order.
+fn checksig_keys(script: &Script) -> Vec<XOnlyPublicKey> {
+    let instructions: Vec<_> = script.instructions().filter_map(Result::ok).collect();
+    instructions
+        .windows(2)
+        .filter_map(|pair| match pair {
+            [Instruction::PushBytes(bytes), Instruction::Op(op)]
+                if matches!(*op, OP_CHECKSIG | OP_CHECKSIGVERIFY | OP_CHECKSIGADD) =>
+            {
+                XOnlyPublicKey::from_slice(bytes.as_bytes()).ok()
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// `<k> OP_NUMEQUAL` closing a k-of-n leaf.
+fn numequal_threshold(script: &Script) -> Option<usize> {
+    let instructions: Vec<_> = script.instructions().filter_map(Result::ok).collect();
+    match instructions.as_slice() {
+        [.., k, Instruction::Op(OP_NUMEQUAL)] => k.script_num().map(|k| k as usize),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::signer::InMemorySigner;
+    use crate::bitcoin_node::tx::taproot_tree_tx::multisig::MultiSigScript;
+    use crate::bitcoin_node::tx::taproot_tree_tx::template::LeafTemplate;
+    use crate::bitcoin_node::tx::{USER_A_PRIVATE_KEY, USER_B_PRIVATE_KEY, USER_C_PRIVATE_KEY};
+    use bitcoin::taproot::TaprootBuilder;
+    use bitcoin::{absolute, transaction, Amount, OutPoint, Sequence, Transaction, TxIn, Txid};
+    use secp256k1::{schnorr, Message};
+    use std::str::FromStr;
+
+    fn unsigned_psbt(prevouts: &[TxOut]) -> anyhow::Result<Psbt> {
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: (0..prevouts.len())
+                .map(|vout| TxIn {
+                    previous_output: OutPoint::new(
+                        Txid::from_str(&"11".repeat(32)).unwrap(),
+                        vout as u32,
+                    ),
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    witness: Witness::default(),
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: Amount::from_sat(10_000),
+                script_pubkey: ScriptBuf::new_op_return([]),
+            }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx)?;
+        for (input, prevout) in psbt.inputs.iter_mut().zip(prevouts) {
+            input.witness_utxo = Some(prevout.clone());
+        }
+        Ok(psbt)
+    }
+
+    #[test]
+    fn test_sign_and_finalize_key_path_and_p2wpkh() -> anyhow::Result<()> {
+        let signer = InMemorySigner::from_xpriv_str(USER_A_PRIVATE_KEY)?;
+        let key = signer.x_only_public_key()?;
+        let pk = CompressedPublicKey::try_from(signer.public_key()?)?;
+        let prevouts = vec![
+            TxOut {
+                value: Amount::from_sat(20_000),
+                script_pubkey: ScriptBuf::new_p2tr(&SECP, key, None),
+            },
+            TxOut {
+                value: Amount::from_sat(20_000),
+                script_pubkey: ScriptBuf::new_p2wpkh(&pk.wpubkey_hash()),
+            },
+        ];
+        let mut psbt = unsigned_psbt(&prevouts)?;
+        assert!(finalize_psbt(&mut psbt.clone()).is_err());
+
+        let other = InMemorySigner::from_xpriv_str(USER_B_PRIVATE_KEY)?;
+        assert_eq!(sign_psbt(&mut psbt, &other)?, 0);
+        assert_eq!(sign_psbt(&mut psbt, &signer)?, 2);
+        finalize_psbt(&mut psbt)?;
+        let tx = psbt.extract_tx_unchecked_fee_rate();
+
+        let sighash = SighashCache::new(&tx).taproot_key_spend_signature_hash(
+            0,
+            &Prevouts::All(&prevouts),
+            TapSighashType::Default,
+        )?;
+        let (output_key, _) = key.tap_tweak(&SECP, None);
+        SECP.verify_schnorr(
+            &schnorr::Signature::from_slice(&tx.input[0].witness[0])?,
+            &Message::from(sighash),
+            &output_key.to_inner(),
+        )?;
+        assert_eq!(tx.input[1].witness.len(), 2);
+        assert_eq!(tx.input[1].witness[1], pk.to_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn test_combine_and_finalize_multisig_leaf() -> anyhow::Result<()> {
+        let signers: Vec<_> = [USER_A_PRIVATE_KEY, USER_B_PRIVATE_KEY, USER_C_PRIVATE_KEY]
+            .iter()
+            .map(|sk| InMemorySigner::from_xpriv_str(sk))
+            .collect::<anyhow::Result<_>>()?;
+        let keys = signers
+            .iter()
+            .map(|signer| signer.x_only_public_key())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let multisig = MultiSigScript::new(keys.clone(), 2)?;
+        let hash_lock = LeafTemplate::hash_lock(bitcoin::hashes::Hash::hash(&[1; 32]), keys[0]);
+        let spend_info = TaprootBuilder::new()
+            .add_leaf(1, multisig.to_script())?
+            .add_leaf(1, hash_lock.to_script())?
+            .finalize(&SECP, keys[0])
+            .map_err(|_| anyhow!("Fail to finalize taproot tree"))?;
+        let prevout = TxOut {
+            value: Amount::from_sat(20_000),
+            script_pubkey: ScriptBuf::new_p2tr_tweaked(spend_info.output_key()),
+        };
+
+        let mut psbt = unsigned_psbt(std::slice::from_ref(&prevout))?;
+        let input = &mut psbt.inputs[0];
+        input.tap_internal_key = Some(keys[0]);
+        input.tap_merkle_root = spend_info.merkle_root();
+        for script in [multisig.to_script(), hash_lock.to_script()] {
+            let leaf = (script, LeafVersion::TapScript);
+            let control_block = spend_info.control_block(&leaf).unwrap();
+            input.tap_scripts.insert(control_block, leaf);
+        }
+        // B and C sign their own copy, no key path spend without A.
+        let mut psbt_b = psbt.clone();
+        let mut psbt_c = psbt.clone();
+        assert_eq!(sign_psbt(&mut psbt_b, &signers[1])?, 1);
+        assert_eq!(sign_psbt(&mut psbt_c, &signers[2])?, 1);
+        assert!(finalize_psbt(&mut psbt_b.clone()).is_err());
+
+        psbt_b.combine(psbt_c)?;
+        finalize_psbt(&mut psbt_b)?;
+        let witness = psbt_b.inputs[0].final_script_witness.clone().unwrap();
+        assert_eq!(
+            witness.len(),
+            5,
+            "3 signature slots, script and control block"
+        );
+        assert!(witness[2].is_empty(), "A didn't sign");
+        assert_eq!(witness[3], *multisig.to_script().as_bytes());
+        assert!(psbt_b.inputs[0].tap_script_sigs.is_empty());
+        Ok(())
+    }
+}
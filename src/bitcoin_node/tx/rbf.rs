@@ -0,0 +1,405 @@
+//! Replace-by-fee for the transactions we sign ourselves.
+//!
+//! `RbfWallet::send` builds transactions whose inputs all signal replaceability and keeps how
+//! each input is spent, so `bump_fee` can rebuild a stuck transaction at a higher fee rate out
+//! of its change output and sign it again, key path or script path.
+//!
+//! The replacement spends the same inputs and pays the same outputs, as BIP-125 requires it
+//! pays a higher absolute fee, higher by at least the incremental relay fee for its own size.
+//!
+//! Reference: https://github.com/bitcoin/bips/blob/master/bip-0125.mediawiki
+use crate::bitcoin_node::signer::{sign_key_spend_input, Signer};
+use crate::bitcoin_node::tx::taproot_tree_tx::template::{LeafSecrets, LeafTemplate};
+use anyhow::{anyhow, bail};
+use bitcoin::taproot::{ControlBlock, TapNodeHash};
+use bitcoin::{
+    absolute, transaction, Amount, FeeRate, OutPoint, ScriptBuf, Sequence, TapSighashType,
+    Transaction, TxIn, TxOut, Txid, Witness,
+};
+use std::collections::HashMap;
+
+/// Bitcoin Core default `-incrementalrelayfee`, 1 sat/vB.
+pub const INCREMENTAL_RELAY_FEE: FeeRate = FeeRate::from_sat_per_kwu(250);
+// Below this a p2tr change output is dust.
+const P2TR_DUST: Amount = Amount::from_sat(330);
+
+/// How an input is signed, again on every replacement.
+pub enum SpendPath<'a> {
+    KeyPath {
+        signer: &'a dyn Signer,
+        /// Tweak of the output key, `None` for a key only output.
+        merkle_root: Option<TapNodeHash>,
+    },
+    ScriptPath {
+        leaf: LeafTemplate,
+        control_block: ControlBlock,
+        secrets: LeafSecrets<'a>,
+    },
+    /// Pay-to-anchor output, anyone can spend it with an empty witness.
+    Anchor,
+}
+
+pub struct WalletInput<'a> {
+    pub out_point: OutPoint,
+    pub prevout: TxOut,
+    pub spend: SpendPath<'a>,
+}
+
+/// A transaction we sign ourselves: its inputs, what it pays and where the change goes.
+pub(crate) struct WalletTx<'a> {
+    inputs: Vec<WalletInput<'a>>,
+    payments: Vec<TxOut>,
+    change: ScriptBuf,
+    pub(crate) version: transaction::Version,
+    pub(crate) tx: Transaction,
+}
+
+impl<'a> WalletTx<'a> {
+    pub(crate) fn new(
+        inputs: Vec<WalletInput<'a>>,
+        payments: Vec<TxOut>,
+        change: ScriptBuf,
+    ) -> Self {
+        Self {
+            inputs,
+            payments,
+            change,
+            version: transaction::Version::TWO,
+            tx: Transaction {
+                version: transaction::Version::TWO,
+                lock_time: absolute::LockTime::ZERO,
+                input: vec![],
+                output: vec![],
+            },
+        }
+    }
+
+    fn input_value(&self) -> Amount {
+        self.inputs.iter().map(|input| input.prevout.value).sum()
+    }
+
+    pub(crate) fn fee(&self) -> Amount {
+        self.input_value() - self.tx.output.iter().map(|out| out.value).sum::<Amount>()
+    }
+
+    /// Build and sign the transaction, its fee is `fee(vsize)` and whatever change is left
+    /// under the dust limit.
+    pub(crate) fn sign(
+        &mut self,
+        fee: impl Fn(u64) -> anyhow::Result<Amount>,
+    ) -> anyhow::Result<()> {
+        let payment_value = self.payments.iter().map(|out| out.value).sum::<Amount>();
+        let available = self
+            .input_value()
+            .checked_sub(payment_value)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Insufficient funds: {} available, {} to pay",
+                    self.input_value(),
+                    payment_value
+                )
+            })?;
+        let mut output = self.payments.clone();
+        output.push(TxOut {
+            value: available,
+            script_pubkey: self.change.clone(),
+        });
+        self.tx = Transaction {
+            version: self.version,
+            lock_time: absolute::LockTime::ZERO,
+            input: self
+                .inputs
+                .iter()
+                .map(|input| TxIn {
+                    previous_output: input.out_point,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    witness: Witness::default(),
+                })
+                .collect(),
+            output,
+        };
+
+        // Signatures have a fixed size: the first pass gives the final vsize, and the time
+        // locks applied by the leaves, signed over by the second pass.
+        self.sign_inputs()?;
+        let fee = fee(self.tx.vsize() as u64)?;
+        let change = available.checked_sub(fee).ok_or_else(|| {
+            anyhow!(
+                "Insufficient funds: {} left after payments, fee is {}",
+                available,
+                fee
+            )
+        })?;
+        if change < P2TR_DUST {
+            self.tx.output.pop();
+        } else {
+            self.tx.output.last_mut().unwrap().value = change;
+        }
+        self.sign_inputs()
+    }
+
+    fn sign_inputs(&mut self) -> anyhow::Result<()> {
+        let prevouts: Vec<TxOut> = self.inputs.iter().map(|i| i.prevout.clone()).collect();
+        for (index, input) in self.inputs.iter().enumerate() {
+            match &input.spend {
+                SpendPath::KeyPath {
+                    signer,
+                    merkle_root,
+                } => {
+                    sign_key_spend_input(
+                        *signer,
+                        &mut self.tx,
+                        index,
+                        &prevouts,
+                        TapSighashType::Default,
+                        *merkle_root,
+                    )?;
+                }
+                SpendPath::ScriptPath {
+                    leaf,
+                    control_block,
+                    secrets,
+                } => {
+                    leaf.sign_input(
+                        secrets,
+                        control_block,
+                        &mut self.tx,
+                        index,
+                        &prevouts,
+                        TapSighashType::Default,
+                    )?;
+                }
+                SpendPath::Anchor => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The replaceable transactions sent so far, by txid.
+pub struct RbfWallet<'a> {
+    txs: HashMap<Txid, WalletTx<'a>>,
+    incremental_relay_fee: FeeRate,
+}
+
+impl Default for RbfWallet<'_> {
+    fn default() -> Self {
+        Self::new(INCREMENTAL_RELAY_FEE)
+    }
+}
+
+impl<'a> RbfWallet<'a> {
+    pub fn new(incremental_relay_fee: FeeRate) -> Self {
+        Self {
+            txs: HashMap::new(),
+            incremental_relay_fee,
+        }
+    }
+
+    /// Build and sign a transaction paying `payments` at `fee_rate`, the rest going to
+    /// `change`. Broadcasting it is left to the caller.
+    pub fn send(
+        &mut self,
+        inputs: Vec<WalletInput<'a>>,
+        payments: Vec<TxOut>,
+        change: ScriptBuf,
+        fee_rate: FeeRate,
+    ) -> anyhow::Result<Transaction> {
+        if inputs.is_empty() {
+            bail!("No input to spend");
+        }
+        let mut wallet_tx = WalletTx::new(inputs, payments, change);
+        wallet_tx.sign(|vsize| fee_vb(fee_rate, vsize))?;
+        let tx = wallet_tx.tx.clone();
+        self.txs.insert(tx.compute_txid(), wallet_tx);
+        Ok(tx)
+    }
+
+    /// Replace `txid` by the same payments at `new_rate`, paid out of the change.
+    ///
+    /// The replacement takes the place of `txid` in the wallet, the caller broadcasts it.
+    pub fn bump_fee(&mut self, txid: &Txid, new_rate: FeeRate) -> anyhow::Result<Transaction> {
+        let mut wallet_tx = self
+            .txs
+            .remove(txid)
+            .ok_or_else(|| anyhow!("Unknown wallet transaction {}", txid))?;
+        let original = wallet_tx.tx.clone();
+        let original_fee = wallet_tx.fee();
+        let incremental_relay_fee = self.incremental_relay_fee;
+
+        let result = wallet_tx.sign(|vsize| {
+            let fee = fee_vb(new_rate, vsize)?;
+            // BIP-125 rules 3 and 4: pay for the original and for our own relay.
+            let min_fee = original_fee + fee_vb(incremental_relay_fee, vsize)?;
+            if fee < min_fee {
+                bail!(
+                    "Fee rate {} too low to replace {}: fee {} must be at least {}",
+                    new_rate,
+                    txid,
+                    fee,
+                    min_fee
+                );
+            }
+            Ok(fee)
+        });
+        if let Err(e) = result {
+            wallet_tx.tx = original;
+            self.txs.insert(*txid, wallet_tx);
+            return Err(e);
+        }
+
+        let tx = wallet_tx.tx.clone();
+        self.txs.insert(tx.compute_txid(), wallet_tx);
+        Ok(tx)
+    }
+
+    pub fn transaction(&self, txid: &Txid) -> Option<&Transaction> {
+        self.txs.get(txid).map(|wallet_tx| &wallet_tx.tx)
+    }
+
+    pub fn fee(&self, txid: &Txid) -> Option<Amount> {
+        self.txs.get(txid).map(WalletTx::fee)
+    }
+}
+
+pub(crate) fn fee_vb(fee_rate: FeeRate, vsize: u64) -> anyhow::Result<Amount> {
+    fee_rate
+        .fee_vb(vsize)
+        .ok_or_else(|| anyhow!("Fee overflow at {}", fee_rate))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::signer::InMemorySigner;
+    use crate::bitcoin_node::tx::interpreter::ScriptPathSpend;
+    use crate::bitcoin_node::tx::{USER_A_PRIVATE_KEY, USER_B_PRIVATE_KEY};
+    use crate::SECP;
+    use bitcoin::hashes::Hash;
+    use bitcoin::sighash::{Prevouts, SighashCache};
+    use bitcoin::taproot::{LeafVersion, TaprootBuilder};
+    use bitcoin::XOnlyPublicKey;
+    use secp256k1::{schnorr, Message};
+
+    #[test]
+    fn test_send_and_bump_fee() -> anyhow::Result<()> {
+        let signer_a = InMemorySigner::from_xpriv_str(USER_A_PRIVATE_KEY)?;
+        let signer_b = InMemorySigner::from_xpriv_str(USER_B_PRIVATE_KEY)?;
+        let key_a = signer_a.x_only_public_key()?;
+        let key_b = signer_b.x_only_public_key()?;
+
+        // input 0: A's key only output, input 1: B's leaf under A's internal key.
+        let key_prevout = TxOut {
+            value: Amount::from_sat(30_000),
+            script_pubkey: ScriptBuf::new_p2tr(&SECP, key_a, None),
+        };
+        let leaf = LeafTemplate::single_sig(key_b);
+        let spend_info = TaprootBuilder::new()
+            .add_leaf(0, leaf.to_script())?
+            .finalize(&SECP, key_a)
+            .map_err(|_| anyhow!("Fail to finalize taproot tree"))?;
+        let leaf_prevout = TxOut {
+            value: Amount::from_sat(20_000),
+            script_pubkey: ScriptBuf::new_p2tr_tweaked(spend_info.output_key()),
+        };
+        let control_block = spend_info
+            .control_block(&(leaf.to_script(), LeafVersion::TapScript))
+            .unwrap();
+        let signers: [&dyn Signer; 1] = [&signer_b];
+        let inputs = vec![
+            WalletInput {
+                out_point: OutPoint::new(Txid::from_byte_array([1; 32]), 0),
+                prevout: key_prevout.clone(),
+                spend: SpendPath::KeyPath {
+                    signer: &signer_a,
+                    merkle_root: None,
+                },
+            },
+            WalletInput {
+                out_point: OutPoint::new(Txid::from_byte_array([2; 32]), 1),
+                prevout: leaf_prevout.clone(),
+                spend: SpendPath::ScriptPath {
+                    leaf,
+                    control_block,
+                    secrets: LeafSecrets {
+                        signers: &signers,
+                        preimage: None,
+                    },
+                },
+            },
+        ];
+        let payment = TxOut {
+            value: Amount::from_sat(40_000),
+            script_pubkey: ScriptBuf::new_p2tr(&SECP, key_b, None),
+        };
+        let change = ScriptBuf::new_p2tr(&SECP, key_a, None);
+        let prevouts = [key_prevout, leaf_prevout];
+
+        let mut wallet = RbfWallet::default();
+        let rate = FeeRate::from_sat_per_vb(2).unwrap();
+        let tx = wallet.send(inputs, vec![payment.clone()], change, rate)?;
+        assert!(tx.is_explicitly_rbf());
+        let fee = wallet.fee(&tx.compute_txid()).unwrap();
+        assert_eq!(fee, rate.fee_vb(tx.vsize() as u64).unwrap());
+
+        // too small an increase for BIP-125 leaves the original in place.
+        let txid = tx.compute_txid();
+        let rate_3 = FeeRate::from_sat_per_vb(3).unwrap();
+        assert!(wallet
+            .bump_fee(&txid, FeeRate::from_sat_per_kwu(501))
+            .is_err());
+        assert_eq!(wallet.transaction(&txid), Some(&tx));
+
+        let replacement = wallet.bump_fee(&txid, rate_3)?;
+        assert!(wallet.transaction(&txid).is_none());
+        let new_fee = wallet.fee(&replacement.compute_txid()).unwrap();
+        assert!(
+            new_fee
+                >= fee
+                    + INCREMENTAL_RELAY_FEE
+                        .fee_vb(replacement.vsize() as u64)
+                        .unwrap()
+        );
+        assert_eq!(replacement.output[0], payment);
+        assert_eq!(
+            replacement.output[1].value,
+            tx.output[1].value - (new_fee - fee)
+        );
+        let spent = |tx: &Transaction| {
+            tx.input
+                .iter()
+                .map(|i| i.previous_output)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(spent(&replacement), spent(&tx));
+
+        // both inputs signed again over the replacement.
+        let sighash = SighashCache::new(&replacement).taproot_key_spend_signature_hash(
+            0,
+            &Prevouts::All(&prevouts),
+            TapSighashType::Default,
+        )?;
+        let output_key = ScriptBuf::new_p2tr(&SECP, key_a, None).as_bytes()[2..].to_vec();
+        SECP.verify_schnorr(
+            &schnorr::Signature::from_slice(&replacement.input[0].witness[0])?,
+            &Message::from(sighash),
+            &XOnlyPublicKey::from_slice(&output_key)?,
+        )?;
+        let trace = ScriptPathSpend::new(&replacement, 1, &prevouts).verify()?;
+        assert!(trace.is_success(), "{}", trace);
+
+        // change under the dust limit goes to the fee, then there's nothing left to bump with.
+        let txid = replacement.compute_txid();
+        let available = replacement.output[1].value + new_fee;
+        let rate = FeeRate::from_sat_per_kwu(
+            (available.to_sat() - 100) * 1000 / replacement.weight().to_wu(),
+        );
+        let tx = wallet.bump_fee(&txid, rate)?;
+        assert_eq!(tx.output, vec![payment]);
+        assert!(wallet
+            .bump_fee(&tx.compute_txid(), FeeRate::from_sat_per_vb(1_000).unwrap())
+            .is_err());
+        Ok(())
+    }
+}
@@ -1,4 +1,7 @@
+#[cfg(test)]
+use crate::bitcoin_node::address::parse_address;
 use crate::bitcoin_node::tx::sign_tx_taproot::{GAS_FEE, SPEND_AMOUNT};
+#[cfg(test)]
 use crate::bitcoin_node::tx::taproot_tree_tx::{
     create_p2tr_address, create_taproot_tree, gen_one_of_two_multi_sig_scripts,
 };
@@ -125,7 +128,7 @@ fn test_key_path_spend_taproot_tree_addr_to_a() -> anyhow::Result<()> {
     let secp = Secp256k1::new();
 
     // receiver addr
-    let receiver_addr = Address::from_str(RECEIVER_ADDR_STR)?.assume_checked();
+    let receiver_addr = parse_address(RECEIVER_ADDR_STR, Network::Regtest)?;
     // taproot tree, and related tree leaves
     // let tree_leaves_scripts = gen_one_of_two_multi_sig_scripts(&secp);
     let taproot_tree = create_taproot_tree(&secp);
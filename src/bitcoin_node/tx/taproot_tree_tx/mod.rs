@@ -1,17 +1,29 @@
 //! Reference: https://learnmeabitcoin.com/technical/upgrades/taproot/
+#[cfg(test)]
 use crate::bitcoin_node::tx::{
     USER_A_PRIVATE_KEY, USER_A_PUBLIC_KEY, USER_B_PRIVATE_KEY, USER_C_PRIVATE_KEY,
 };
+#[cfg(test)]
 use bitcoin::bip32::Xpriv;
-use bitcoin::key::TapTweak;
+#[cfg(test)]
 use bitcoin::opcodes::all::OP_CHECKSIG;
-use bitcoin::taproot::{TaprootBuilder, TaprootSpendInfo};
-use bitcoin::{script, Address, Network, Script, ScriptBuf};
+#[cfg(test)]
+use bitcoin::taproot::TaprootBuilder;
+use bitcoin::taproot::TaprootSpendInfo;
+#[cfg(test)]
+use bitcoin::{script, Script, ScriptBuf};
+use bitcoin::{Address, Network};
+#[cfg(test)]
 use secp256k1::{Keypair, Secp256k1, XOnlyPublicKey};
+#[cfg(test)]
 use std::str::FromStr;
+#[cfg(test)]
+use template::LeafTemplate;
 
 pub mod key_path_spend;
+pub mod multisig;
 pub mod script_path_spend;
+pub mod template;
 
 pub fn create_p2tr_address(tree: TaprootSpendInfo) -> Address {
     let output_key = tree.output_key();
@@ -22,7 +34,8 @@ pub fn create_p2tr_address(tree: TaprootSpendInfo) -> Address {
 // Taproot output corresponds to a combination of a single public key condition (known as the
 // internal key), and zero or more general conditions encoded in scripts organized in the form of a
 // binary tree.
-pub fn create_taproot_tree(secp: &Secp256k1<secp256k1::All>) -> TaprootSpendInfo {
+#[cfg(test)]
+pub(crate) fn create_taproot_tree(secp: &Secp256k1<secp256k1::All>) -> TaprootSpendInfo {
     // Taproot can be spent by either:
     // - Spending using the key path i.e., with secret key corresponding to the tweaked `output_key`.
     let sk_a = Xpriv::from_str(&USER_A_PRIVATE_KEY).unwrap();
@@ -41,21 +54,21 @@ pub fn create_taproot_tree(secp: &Secp256k1<secp256k1::All>) -> TaprootSpendInfo
     builder.finalize(secp, internal_key).unwrap()
 }
 
-// Create two basic scripts to test script path spend.
-pub fn gen_one_of_two_multi_sig_scripts(secp: &Secp256k1<secp256k1::All>) -> Vec<ScriptBuf> {
+// Create two basic scripts to test script path spend. These are two single-sig leaves (either B
+// or C can spend), see `multisig::MultiSigScript` for a real k-of-n leaf.
+#[cfg(test)]
+pub(crate) fn gen_one_of_two_multi_sig_scripts(secp: &Secp256k1<secp256k1::All>) -> Vec<ScriptBuf> {
     let user_a_single_sig_scipt = create_basic_single_sig_script(secp, USER_B_PRIVATE_KEY); // m/86'/1'/0'/0/0
     let user_b_single_sig_scipt = create_basic_single_sig_script(secp, USER_C_PRIVATE_KEY); // m/86'/1'/0'/0/0
     vec![user_a_single_sig_scipt, user_b_single_sig_scipt]
 }
+// Leaf keys are the raw x-only keys, only the internal key gets the taproot tweak.
+#[cfg(test)]
 fn create_basic_single_sig_script(secp: &Secp256k1<secp256k1::All>, sk: &str) -> ScriptBuf {
     let sk = Xpriv::from_str(sk).unwrap();
     let kp = Keypair::from_secret_key(secp, &sk.private_key);
     let x_only_public_key = kp.x_only_public_key().0;
-    let tweaked_x_only_public_key = x_only_public_key.tap_tweak(&secp, None).0;
-    script::Builder::new()
-        .push_slice(tweaked_x_only_public_key.serialize())
-        .push_opcode(OP_CHECKSIG)
-        .into_script()
+    LeafTemplate::single_sig(x_only_public_key).to_script()
 }
 
 #[cfg(test)]
@@ -97,14 +110,9 @@ mod tests {
 
         let public_key = PublicKey::from_str(USER_B_PUBLIC_KEY).unwrap();
         let x_only_public_key = XOnlyPublicKey::from(public_key);
-        // tweaked x_only_public_key
-        let tweaked_x_only_public_key = x_only_public_key.tap_tweak(&secp, None).0;
-        println!(
-            "tweaked_x_only_public_key: {:?}",
-            tweaked_x_only_public_key.to_string()
-        );
+        // untweaked x_only_public_key
         let actual = script::Builder::new()
-            .push_slice(tweaked_x_only_public_key.serialize())
+            .push_slice(x_only_public_key.serialize())
             .push_opcode(OP_CHECKSIG)
             .into_script();
         println!("actual: {:?}", actual.to_string());
@@ -114,6 +122,12 @@ mod tests {
 
         assert_eq!(expect.to_bytes(), actual.to_bytes());
         assert_ne!(p2tr_script.to_bytes(), actual.to_bytes());
+
+        let tweaked_x_only_public_key = x_only_public_key.tap_tweak(&secp, None).0;
+        assert!(!expect
+            .as_bytes()
+            .windows(32)
+            .any(|w| w == tweaked_x_only_public_key.serialize()));
     }
 
     #[test]
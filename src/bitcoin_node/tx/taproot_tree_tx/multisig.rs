@@ -0,0 +1,260 @@
+//! k-of-n tapscript multisig leaves.
+//!
+//! Tapscript has no OP_CHECKMULTISIG, the BIP-342 replacement is:
+//! `<pk_1> OP_CHECKSIG <pk_2> OP_CHECKSIGADD ... <pk_n> OP_CHECKSIGADD <k> OP_NUMEQUAL`
+//! Every key consumes one witness element, an empty one for a key that does not sign.
+//!
+//! Reference: https://github.com/bitcoin/bips/blob/master/bip-0342.mediawiki
+use crate::bitcoin_node::signer::{sign_script_spend_input, Signer};
+use anyhow::{anyhow, bail};
+use bitcoin::opcodes::all::{OP_CHECKSIG, OP_CHECKSIGADD, OP_NUMEQUAL, OP_NUMEQUALVERIFY};
+use bitcoin::taproot::ControlBlock;
+use bitcoin::{script, ScriptBuf, TapSighashType, Transaction, TxOut, Witness, XOnlyPublicKey};
+
+// BIP-342 allows 999 stack elements, keep room for the script and the control block.
+const MAX_MULTI_SIG_KEYS: usize = 997;
+
+/// A `threshold`-of-`keys.len()` multisig leaf. Keys are used as they are (untweaked), in order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MultiSigScript {
+    pub keys: Vec<XOnlyPublicKey>,
+    pub threshold: usize,
+}
+
+impl MultiSigScript {
+    pub fn new(keys: Vec<XOnlyPublicKey>, threshold: usize) -> anyhow::Result<Self> {
+        if keys.is_empty() || keys.len() > MAX_MULTI_SIG_KEYS {
+            bail!(
+                "Multisig needs 1 to {} keys, got {}",
+                MAX_MULTI_SIG_KEYS,
+                keys.len()
+            );
+        }
+        if threshold == 0 || threshold > keys.len() {
+            bail!("Invalid multisig threshold {} of {}", threshold, keys.len());
+        }
+        for (i, key) in keys.iter().enumerate() {
+            if keys[..i].contains(key) {
+                bail!("Duplicated multisig key {}", key);
+            }
+        }
+        Ok(Self { keys, threshold })
+    }
+
+    pub fn to_script(&self) -> ScriptBuf {
+        self.push_into(script::Builder::new(), false).into_script()
+    }
+
+    /// Append the multisig check, ending with `OP_NUMEQUALVERIFY` when `verify` is set so
+    /// more conditions can follow it in the same leaf.
+    pub(crate) fn push_into(&self, mut builder: script::Builder, verify: bool) -> script::Builder {
+        for (i, key) in self.keys.iter().enumerate() {
+            builder = builder.push_x_only_key(key).push_opcode(if i == 0 {
+                OP_CHECKSIG
+            } else {
+                OP_CHECKSIGADD
+            });
+        }
+        builder
+            .push_int(self.threshold as i64)
+            .push_opcode(if verify {
+                OP_NUMEQUALVERIFY
+            } else {
+                OP_NUMEQUAL
+            })
+    }
+
+    /// Build the script path witness from the signatures found, indexed like `keys`.
+    ///
+    /// The first key is checked against the top of the stack, so the signatures are pushed
+    /// in reverse key order, followed by the leaf script and its control block.
+    pub fn witness(
+        &self,
+        signatures: &[Option<bitcoin::taproot::Signature>],
+        control_block: &ControlBlock,
+    ) -> anyhow::Result<Witness> {
+        if signatures.len() != self.keys.len() {
+            bail!(
+                "Expect {} signature slots, got {}",
+                self.keys.len(),
+                signatures.len()
+            );
+        }
+        // OP_NUMEQUAL fails with more signatures than the threshold, too.
+        let count = signatures.iter().flatten().count();
+        if count != self.threshold {
+            bail!("Need exactly {} signatures, got {}", self.threshold, count);
+        }
+
+        let mut witness = Witness::new();
+        for signature in signatures.iter().rev() {
+            match signature {
+                Some(signature) => witness.push(signature.to_vec()),
+                None => witness.push([]),
+            }
+        }
+        witness.push(self.to_script().as_bytes());
+        witness.push(control_block.serialize());
+        Ok(witness)
+    }
+}
+
+/// Sign input `input_index` of `tx` through the multisig leaf and set its witness.
+///
+/// Signers may come in any order and may hold keys that are not in the leaf. Only the first
+/// `threshold` matching keys (in leaf order) sign, the other keys get an empty element.
+pub fn sign_multi_sig_input(
+    signers: &[&dyn Signer],
+    multisig: &MultiSigScript,
+    control_block: &ControlBlock,
+    tx: &mut Transaction,
+    input_index: usize,
+    prevouts: &[TxOut],
+    sighash_type: TapSighashType,
+) -> anyhow::Result<Witness> {
+    let leaf_script = multisig.to_script();
+    let mut signer_keys = Vec::with_capacity(signers.len());
+    for signer in signers {
+        signer_keys.push(signer.x_only_public_key()?);
+    }
+
+    let mut signatures = vec![None; multisig.keys.len()];
+    let mut count = 0;
+    for (slot, key) in signatures.iter_mut().zip(&multisig.keys) {
+        if count == multisig.threshold {
+            break;
+        }
+        if let Some(i) = signer_keys.iter().position(|k| k == key) {
+            *slot = Some(sign_script_spend_input(
+                signers[i],
+                tx,
+                input_index,
+                prevouts,
+                &leaf_script,
+                sighash_type,
+            )?);
+            count += 1;
+        }
+    }
+    if count < multisig.threshold {
+        return Err(anyhow!(
+            "Fail to sign multisig: {} of {} signers available",
+            count,
+            multisig.threshold
+        ));
+    }
+
+    let witness = multisig.witness(&signatures, control_block)?;
+    tx.input[input_index].witness = witness.clone();
+    Ok(witness)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::signer::InMemorySigner;
+    use crate::bitcoin_node::tx::{
+        senders_keys, USER_A_PRIVATE_KEY, USER_B_PRIVATE_KEY, USER_C_PRIVATE_KEY,
+    };
+    use bitcoin::sighash::{Prevouts, SighashCache};
+    use bitcoin::taproot::{LeafVersion, TapLeafHash, TaprootBuilder};
+    use bitcoin::{absolute, transaction, Amount, OutPoint, Sequence, TxIn};
+    use secp256k1::{Message, Secp256k1};
+
+    fn user_keys() -> Vec<XOnlyPublicKey> {
+        let secp = Secp256k1::new();
+        [USER_A_PRIVATE_KEY, USER_B_PRIVATE_KEY, USER_C_PRIVATE_KEY]
+            .iter()
+            .map(|sk| senders_keys(&secp, sk).x_only_public_key().0)
+            .collect()
+    }
+
+    #[test]
+    fn test_multi_sig_script() -> anyhow::Result<()> {
+        let keys = user_keys();
+        let multisig = MultiSigScript::new(keys.clone(), 2)?;
+        let expect = format!(
+            "OP_PUSHBYTES_32 {} OP_CHECKSIG OP_PUSHBYTES_32 {} OP_CHECKSIGADD OP_PUSHBYTES_32 {} OP_CHECKSIGADD OP_PUSHNUM_2 OP_NUMEQUAL",
+            keys[0], keys[1], keys[2]
+        );
+        assert_eq!(multisig.to_script().to_asm_string(), expect);
+
+        assert!(MultiSigScript::new(keys.clone(), 0).is_err());
+        assert!(MultiSigScript::new(keys.clone(), 4).is_err());
+        assert!(MultiSigScript::new(vec![keys[0], keys[0]], 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sign_two_of_three() -> anyhow::Result<()> {
+        let secp = Secp256k1::new();
+        let keys = user_keys();
+        let multisig = MultiSigScript::new(keys.clone(), 2)?;
+        let leaf_script = multisig.to_script();
+        let internal_key = keys[0];
+        let tree = TaprootBuilder::new()
+            .add_leaf(0, leaf_script.clone())?
+            .finalize(&secp, internal_key)
+            .map_err(|_| anyhow!("Fail to finalize taproot tree"))?;
+        let control_block = tree
+            .control_block(&(leaf_script.clone(), LeafVersion::TapScript))
+            .ok_or_else(|| anyhow!("Missing control block"))?;
+
+        let prevouts = vec![TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: ScriptBuf::new_p2tr_tweaked(tree.output_key()),
+        }];
+        let mut tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::default(),
+            }],
+            output: vec![prevouts[0].clone()],
+        };
+
+        // C and B sign, A is absent.
+        let signer_b = InMemorySigner::from_xpriv_str(USER_B_PRIVATE_KEY)?;
+        let signer_c = InMemorySigner::from_xpriv_str(USER_C_PRIVATE_KEY)?;
+        let witness = sign_multi_sig_input(
+            &[&signer_c, &signer_b],
+            &multisig,
+            &control_block,
+            &mut tx,
+            0,
+            &prevouts,
+            TapSighashType::Default,
+        )?;
+
+        // stack bottom to top: sig_c, sig_b, <empty for A>, script, control block.
+        assert_eq!(witness.len(), 5);
+        assert!(witness.nth(2).unwrap().is_empty());
+        assert_eq!(witness.nth(3).unwrap(), leaf_script.as_bytes());
+        let sighash = SighashCache::new(&tx).taproot_script_spend_signature_hash(
+            0,
+            &Prevouts::All(&prevouts),
+            TapLeafHash::from_script(&leaf_script, LeafVersion::TapScript),
+            TapSighashType::Default,
+        )?;
+        for (i, key) in [(0, keys[2]), (1, keys[1])] {
+            let signature = bitcoin::taproot::Signature::from_slice(witness.nth(i).unwrap())?;
+            secp.verify_schnorr(&signature.signature, &Message::from(sighash), &key)?;
+        }
+
+        // a single signer can't reach the threshold.
+        let result = sign_multi_sig_input(
+            &[&signer_b],
+            &multisig,
+            &control_block,
+            &mut tx,
+            0,
+            &prevouts,
+            TapSighashType::Default,
+        );
+        assert!(result.is_err());
+        Ok(())
+    }
+}
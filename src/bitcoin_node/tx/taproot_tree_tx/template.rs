@@ -0,0 +1,439 @@
+//! Typed leaf script templates.
+//!
+//! A leaf is a list of conditions that must all hold, e.g. `[RelTimeLock, SingleSig]` for
+//! "B can spend after 144 blocks". Every key in a leaf is a raw (untweaked) x-only key, the
+//! taproot tweak only applies to the internal key of the output. Signing a leaf therefore uses
+//! the untweaked secret key, which is what `Signer::sign_schnorr_script_spend` does.
+//!
+//! Reference: https://github.com/bitcoin/bips/blob/master/bip-0342.mediawiki
+use crate::bitcoin_node::signer::{sign_script_spend_input, Signer};
+use crate::bitcoin_node::tx::taproot_tree_tx::multisig::MultiSigScript;
+use crate::bitcoin_node::tx::timelock::apply_time_locks;
+use crate::SECP;
+use anyhow::{anyhow, bail};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::key::TapTweak;
+use bitcoin::opcodes::all::{
+    OP_CHECKSIG, OP_CHECKSIGVERIFY, OP_CLTV, OP_CSV, OP_DROP, OP_EQUAL, OP_EQUALVERIFY, OP_SHA256,
+    OP_SIZE,
+};
+use bitcoin::taproot::ControlBlock;
+use bitcoin::{
+    absolute, relative, script, ScriptBuf, TapSighashType, Transaction, TxOut, Witness,
+    XOnlyPublicKey,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Condition {
+    /// `<pk> OP_CHECKSIG`, `pk` untweaked.
+    SingleSig(XOnlyPublicKey),
+    /// `<pk_1> OP_CHECKSIG ... <pk_n> OP_CHECKSIGADD <k> OP_NUMEQUAL`, keys untweaked.
+    MultiA(MultiSigScript),
+    /// `OP_SIZE 32 OP_EQUALVERIFY OP_SHA256 <hash> OP_EQUAL`, satisfied by the 32 bytes preimage.
+    /// The size check keeps a preimage usable on both sides of a cross-chain swap.
+    HashLock(sha256::Hash),
+    /// `<lock_time> OP_CHECKLOCKTIMEVERIFY OP_DROP`
+    AbsTimeLock(absolute::LockTime),
+    /// `<sequence> OP_CHECKSEQUENCEVERIFY OP_DROP`
+    RelTimeLock(relative::LockTime),
+}
+
+/// What a spender brings to satisfy a leaf.
+pub struct LeafSecrets<'a> {
+    pub signers: &'a [&'a dyn Signer],
+    /// Preimage of the `HashLock` condition, if any.
+    pub preimage: Option<[u8; 32]>,
+}
+
+/// A validated leaf script: conditions are checked in order, the last one leaves the result
+/// on the stack and the others use their `VERIFY` form.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeafTemplate {
+    conditions: Vec<Condition>,
+}
+
+impl LeafTemplate {
+    pub fn new(conditions: Vec<Condition>) -> anyhow::Result<Self> {
+        let mut abs = 0;
+        let mut rel = 0;
+        for condition in &conditions {
+            match condition {
+                Condition::AbsTimeLock(_) => abs += 1,
+                Condition::RelTimeLock(_) => rel += 1,
+                _ => {}
+            }
+        }
+        if abs > 1 || rel > 1 {
+            bail!("A leaf takes at most one absolute and one relative time lock");
+        }
+        // `OP_DROP` leaves nothing behind, a leaf ending with a time lock always fails.
+        match conditions.last() {
+            None => bail!("Empty leaf template"),
+            Some(Condition::AbsTimeLock(_)) | Some(Condition::RelTimeLock(_)) => {
+                bail!("A leaf must end with a signature or hash lock")
+            }
+            _ => {}
+        }
+        Ok(Self { conditions })
+    }
+
+    pub fn single_sig(key: XOnlyPublicKey) -> Self {
+        Self {
+            conditions: vec![Condition::SingleSig(key)],
+        }
+    }
+
+    pub fn multi_a(keys: Vec<XOnlyPublicKey>, threshold: usize) -> anyhow::Result<Self> {
+        Ok(Self {
+            conditions: vec![Condition::MultiA(MultiSigScript::new(keys, threshold)?)],
+        })
+    }
+
+    /// `key` can spend with the preimage of `hash`.
+    pub fn hash_lock(hash: sha256::Hash, key: XOnlyPublicKey) -> Self {
+        Self {
+            conditions: vec![Condition::HashLock(hash), Condition::SingleSig(key)],
+        }
+    }
+
+    /// `key` can spend once the chain reaches `lock_time`.
+    pub fn abs_time_lock(lock_time: absolute::LockTime, key: XOnlyPublicKey) -> Self {
+        Self {
+            conditions: vec![Condition::AbsTimeLock(lock_time), Condition::SingleSig(key)],
+        }
+    }
+
+    /// `key` can spend once the output is `lock_time` old.
+    pub fn rel_time_lock(lock_time: relative::LockTime, key: XOnlyPublicKey) -> Self {
+        Self {
+            conditions: vec![Condition::RelTimeLock(lock_time), Condition::SingleSig(key)],
+        }
+    }
+
+    pub fn conditions(&self) -> &[Condition] {
+        &self.conditions
+    }
+
+    pub fn absolute_lock_time(&self) -> Option<absolute::LockTime> {
+        self.conditions
+            .iter()
+            .find_map(|condition| match condition {
+                Condition::AbsTimeLock(lock_time) => Some(*lock_time),
+                _ => None,
+            })
+    }
+
+    pub fn relative_lock_time(&self) -> Option<relative::LockTime> {
+        self.conditions
+            .iter()
+            .find_map(|condition| match condition {
+                Condition::RelTimeLock(lock_time) => Some(*lock_time),
+                _ => None,
+            })
+    }
+
+    /// Every key the leaf checks a signature for, in script order.
+    pub fn keys(&self) -> Vec<XOnlyPublicKey> {
+        let mut keys = vec![];
+        for condition in &self.conditions {
+            match condition {
+                Condition::SingleSig(key) => keys.push(*key),
+                Condition::MultiA(multisig) => keys.extend(multisig.keys.iter().copied()),
+                _ => {}
+            }
+        }
+        keys
+    }
+
+    pub fn to_script(&self) -> ScriptBuf {
+        let mut builder = script::Builder::new();
+        let last = self.conditions.len() - 1;
+        for (i, condition) in self.conditions.iter().enumerate() {
+            let verify = i != last;
+            builder = match condition {
+                Condition::SingleSig(key) => builder.push_x_only_key(key).push_opcode(if verify {
+                    OP_CHECKSIGVERIFY
+                } else {
+                    OP_CHECKSIG
+                }),
+                Condition::MultiA(multisig) => multisig.push_into(builder, verify),
+                Condition::HashLock(hash) => builder
+                    .push_opcode(OP_SIZE)
+                    .push_int(32)
+                    .push_opcode(OP_EQUALVERIFY)
+                    .push_opcode(OP_SHA256)
+                    .push_slice(hash.to_byte_array())
+                    .push_opcode(if verify { OP_EQUALVERIFY } else { OP_EQUAL }),
+                Condition::AbsTimeLock(lock_time) => builder
+                    .push_lock_time(*lock_time)
+                    .push_opcode(OP_CLTV)
+                    .push_opcode(OP_DROP),
+                Condition::RelTimeLock(lock_time) => builder
+                    .push_sequence(lock_time.to_sequence())
+                    .push_opcode(OP_CSV)
+                    .push_opcode(OP_DROP),
+            };
+        }
+        builder.into_script()
+    }
+
+    /// Sign input `input_index` of `tx` through this leaf and set its witness. The time locks
+    /// of the leaf are applied to `tx` first (see `timelock::apply_time_locks`), use
+    /// `timelock::check_final` before broadcasting.
+    ///
+    /// Each key of the leaf is matched against the untweaked key of the signers, a signer
+    /// whose tweaked key is in the leaf is an error: that leaf was built the wrong way.
+    pub fn sign_input(
+        &self,
+        secrets: &LeafSecrets,
+        control_block: &ControlBlock,
+        tx: &mut Transaction,
+        input_index: usize,
+        prevouts: &[TxOut],
+        sighash_type: TapSighashType,
+    ) -> anyhow::Result<Witness> {
+        let LeafSecrets { signers, preimage } = *secrets;
+        apply_time_locks(tx, input_index, self)?;
+        let leaf_script = self.to_script();
+        let mut signer_keys = Vec::with_capacity(signers.len());
+        for signer in signers {
+            let key = signer.x_only_public_key()?;
+            let (tweaked, _) = key.tap_tweak(&SECP, None);
+            if self.keys().contains(&tweaked.to_inner()) {
+                bail!(
+                    "Leaf uses the tweaked key {} of signer {}, leaf keys must be untweaked",
+                    tweaked,
+                    key
+                );
+            }
+            signer_keys.push(key);
+        }
+        let sign = |key: &XOnlyPublicKey| -> anyhow::Result<Option<bitcoin::taproot::Signature>> {
+            match signer_keys.iter().position(|k| k == key) {
+                Some(i) => Ok(Some(sign_script_spend_input(
+                    signers[i],
+                    tx,
+                    input_index,
+                    prevouts,
+                    &leaf_script,
+                    sighash_type,
+                )?)),
+                None => Ok(None),
+            }
+        };
+
+        // The first condition reads the top of the stack, so collect the elements top first
+        // and reverse them into the witness.
+        let mut stack: Vec<Vec<u8>> = vec![];
+        for condition in &self.conditions {
+            match condition {
+                Condition::SingleSig(key) => {
+                    let signature = sign(key)?
+                        .ok_or_else(|| anyhow!("Fail to sign leaf: no signer for key {}", key))?;
+                    stack.push(signature.to_vec());
+                }
+                Condition::MultiA(multisig) => {
+                    let mut count = 0;
+                    for key in &multisig.keys {
+                        let signature = if count < multisig.threshold {
+                            sign(key)?
+                        } else {
+                            None
+                        };
+                        count += signature.is_some() as usize;
+                        stack.push(signature.map(|s| s.to_vec()).unwrap_or_default());
+                    }
+                    if count < multisig.threshold {
+                        bail!(
+                            "Fail to sign multisig: {} of {} signers available",
+                            count,
+                            multisig.threshold
+                        );
+                    }
+                }
+                Condition::HashLock(hash) => {
+                    let preimage =
+                        preimage.ok_or_else(|| anyhow!("Missing preimage of hash lock"))?;
+                    if sha256::Hash::hash(&preimage) != *hash {
+                        bail!("Preimage doesn't match hash lock {}", hash);
+                    }
+                    stack.push(preimage.to_vec());
+                }
+                Condition::AbsTimeLock(_) | Condition::RelTimeLock(_) => {}
+            }
+        }
+
+        let mut witness = Witness::new();
+        for element in stack.iter().rev() {
+            witness.push(element);
+        }
+        witness.push(leaf_script.as_bytes());
+        witness.push(control_block.serialize());
+        tx.input[input_index].witness = witness.clone();
+        Ok(witness)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::signer::InMemorySigner;
+    use crate::bitcoin_node::tx::{USER_A_PRIVATE_KEY, USER_B_PRIVATE_KEY, USER_C_PRIVATE_KEY};
+    use bitcoin::taproot::{LeafVersion, TaprootBuilder};
+    use bitcoin::{transaction, Amount, OutPoint, Sequence, TxIn};
+
+    fn user_signers() -> anyhow::Result<Vec<InMemorySigner>> {
+        [USER_A_PRIVATE_KEY, USER_B_PRIVATE_KEY, USER_C_PRIVATE_KEY]
+            .iter()
+            .map(|sk| InMemorySigner::from_xpriv_str(sk))
+            .collect()
+    }
+
+    fn spend(leaf: &LeafTemplate) -> anyhow::Result<(Transaction, Vec<TxOut>, ControlBlock)> {
+        let internal_key = user_signers()?[0].x_only_public_key()?;
+        let tree = TaprootBuilder::new()
+            .add_leaf(0, leaf.to_script())?
+            .finalize(&SECP, internal_key)
+            .map_err(|_| anyhow!("Fail to finalize taproot tree"))?;
+        let control_block = tree
+            .control_block(&(leaf.to_script(), LeafVersion::TapScript))
+            .ok_or_else(|| anyhow!("Missing control block"))?;
+        let prevouts = vec![TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: ScriptBuf::new_p2tr_tweaked(tree.output_key()),
+        }];
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::default(),
+            }],
+            output: vec![prevouts[0].clone()],
+        };
+        Ok((tx, prevouts, control_block))
+    }
+
+    #[test]
+    fn test_templates_to_script() -> anyhow::Result<()> {
+        let signers = user_signers()?;
+        let b = signers[1].x_only_public_key()?;
+        let hash = sha256::Hash::hash(&[7u8; 32]);
+
+        assert_eq!(
+            LeafTemplate::single_sig(b).to_script().to_asm_string(),
+            format!("OP_PUSHBYTES_32 {} OP_CHECKSIG", b)
+        );
+        assert_eq!(
+            LeafTemplate::hash_lock(hash, b).to_script().to_asm_string(),
+            format!(
+                "OP_SIZE OP_PUSHBYTES_1 20 OP_EQUALVERIFY OP_SHA256 OP_PUSHBYTES_32 {} OP_EQUALVERIFY OP_PUSHBYTES_32 {} OP_CHECKSIG",
+                hash, b
+            )
+        );
+        let csv = relative::LockTime::from_height(144);
+        assert_eq!(
+            LeafTemplate::rel_time_lock(csv, b)
+                .to_script()
+                .to_asm_string(),
+            format!(
+                "OP_PUSHBYTES_2 9000 OP_CSV OP_DROP OP_PUSHBYTES_32 {} OP_CHECKSIG",
+                b
+            )
+        );
+        let cltv = absolute::LockTime::from_height(800_000)?;
+        assert_eq!(
+            LeafTemplate::abs_time_lock(cltv, b)
+                .to_script()
+                .to_asm_string(),
+            format!(
+                "OP_PUSHBYTES_3 00350c OP_CLTV OP_DROP OP_PUSHBYTES_32 {} OP_CHECKSIG",
+                b
+            )
+        );
+
+        assert!(LeafTemplate::new(vec![]).is_err());
+        assert!(
+            LeafTemplate::new(vec![Condition::SingleSig(b), Condition::AbsTimeLock(cltv)]).is_err()
+        );
+        assert!(LeafTemplate::new(vec![
+            Condition::RelTimeLock(csv),
+            Condition::RelTimeLock(csv),
+            Condition::SingleSig(b)
+        ])
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sign_combined_leaf() -> anyhow::Result<()> {
+        let signers = user_signers()?;
+        let keys = signers
+            .iter()
+            .map(|s| s.x_only_public_key())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let preimage = [7u8; 32];
+        // hash lock, then 2-of-2 (B, C), then A.
+        let leaf = LeafTemplate::new(vec![
+            Condition::HashLock(sha256::Hash::hash(&preimage)),
+            Condition::MultiA(MultiSigScript::new(vec![keys[1], keys[2]], 2)?),
+            Condition::SingleSig(keys[0]),
+        ])?;
+        let (mut tx, prevouts, control_block) = spend(&leaf)?;
+        let signers: Vec<&dyn Signer> = signers.iter().map(|s| s as &dyn Signer).collect();
+
+        let witness = leaf.sign_input(
+            &LeafSecrets {
+                signers: &signers,
+                preimage: Some(preimage),
+            },
+            &control_block,
+            &mut tx,
+            0,
+            &prevouts,
+            TapSighashType::Default,
+        )?;
+        // bottom to top: sig_a, sig_c, sig_b, preimage, script, control block.
+        assert_eq!(witness.len(), 6);
+        assert_eq!(witness.nth(3).unwrap(), preimage);
+        assert_eq!(witness.nth(4).unwrap(), leaf.to_script().as_bytes());
+
+        let result = leaf.sign_input(
+            &LeafSecrets {
+                signers: &signers,
+                preimage: Some([8u8; 32]),
+            },
+            &control_block,
+            &mut tx,
+            0,
+            &prevouts,
+            TapSighashType::Default,
+        );
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tweaked_leaf_key_is_rejected() -> anyhow::Result<()> {
+        let signer = InMemorySigner::from_xpriv_str(USER_B_PRIVATE_KEY)?;
+        let (tweaked, _) = signer.x_only_public_key()?.tap_tweak(&SECP, None);
+        let leaf = LeafTemplate::single_sig(tweaked.to_inner());
+        let (mut tx, prevouts, control_block) = spend(&leaf)?;
+
+        let result = leaf.sign_input(
+            &LeafSecrets {
+                signers: &[&signer],
+                preimage: None,
+            },
+            &control_block,
+            &mut tx,
+            0,
+            &prevouts,
+            TapSighashType::Default,
+        );
+        assert!(result.is_err());
+        Ok(())
+    }
+}
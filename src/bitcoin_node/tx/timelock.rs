@@ -0,0 +1,311 @@
+//! `lock_time`/`sequence` handling for leaves with CLTV/CSV conditions, and a finality check
+//! against the chain tip so a time locked spend is not broadcast too early.
+//!
+//! Reference: https://github.com/bitcoin/bips/blob/master/bip-0065.mediawiki
+//!            https://github.com/bitcoin/bips/blob/master/bip-0068.mediawiki
+//!            https://github.com/bitcoin/bips/blob/master/bip-0112.mediawiki
+//!            https://github.com/bitcoin/bips/blob/master/bip-0113.mediawiki
+use crate::bitcoin_node::tx::taproot_tree_tx::template::LeafTemplate;
+use anyhow::{anyhow, bail};
+use bitcoin::{absolute, relative, transaction, Sequence, Transaction, Txid};
+use bitcoincore_rpc::RpcApi;
+
+/// The best block, a transaction is final if it can be mined in the block after it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChainTip {
+    pub height: u32,
+    /// Median time past of the tip (BIP-113), the clock time locks are compared with.
+    pub median_time_past: u32,
+}
+
+impl ChainTip {
+    pub fn from_rpc(rpc: &bitcoincore_rpc::Client) -> anyhow::Result<Self> {
+        let info = rpc.get_blockchain_info()?;
+        Ok(Self {
+            height: info.blocks as u32,
+            median_time_past: info.median_time as u32,
+        })
+    }
+}
+
+/// Where the output spent by an input was confirmed, needed by relative time locks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Confirmation {
+    pub height: u32,
+    /// Median time past of the block before the one that confirmed the output (BIP-68).
+    pub prev_median_time_past: u32,
+}
+
+impl Confirmation {
+    /// `None` while `txid` is still unconfirmed. Needs `-txindex` for non wallet transactions.
+    pub fn from_rpc(rpc: &bitcoincore_rpc::Client, txid: &Txid) -> anyhow::Result<Option<Self>> {
+        let tx_info = rpc.get_raw_transaction_info(txid, None)?;
+        let Some(block_hash) = tx_info.blockhash else {
+            return Ok(None);
+        };
+        let header = rpc.get_block_header_info(&block_hash)?;
+        let prev_median_time_past = match header.previous_block_hash {
+            Some(prev_hash) => rpc
+                .get_block_header_info(&prev_hash)?
+                .median_time
+                .ok_or_else(|| anyhow!("Fail to get median time of block {}", prev_hash))?,
+            None => header.time,
+        };
+        Ok(Some(Self {
+            height: header.height as u32,
+            prev_median_time_past: prev_median_time_past as u32,
+        }))
+    }
+}
+
+/// Set `tx.lock_time`, `tx.version` and the input sequence so the CLTV/CSV conditions of
+/// `leaf` pass. Existing locks are kept when compatible, raised when lower.
+pub fn apply_time_locks(
+    tx: &mut Transaction,
+    input_index: usize,
+    leaf: &LeafTemplate,
+) -> anyhow::Result<()> {
+    if input_index >= tx.input.len() {
+        bail!("Input index {} out of range", input_index);
+    }
+
+    if let Some(lock_time) = leaf.absolute_lock_time() {
+        let current = tx.lock_time;
+        if current == absolute::LockTime::ZERO
+            || (current.is_same_unit(lock_time) && !lock_time.is_implied_by(current))
+        {
+            tx.lock_time = lock_time;
+        } else if !current.is_same_unit(lock_time) {
+            bail!(
+                "Lock time {} and {} use different units, can't be spent together",
+                current,
+                lock_time
+            );
+        }
+        // `Sequence::MAX` on every input disables `lock_time`.
+        let sequence = &mut tx.input[input_index].sequence;
+        if !sequence.enables_absolute_lock_time() {
+            *sequence = Sequence::ENABLE_RBF_NO_LOCKTIME;
+        }
+    }
+
+    if let Some(lock_time) = leaf.relative_lock_time() {
+        // BIP-68 only applies from version 2 on.
+        if tx.version < transaction::Version::TWO {
+            tx.version = transaction::Version::TWO;
+        }
+        let sequence = &mut tx.input[input_index].sequence;
+        match sequence.to_relative_lock_time() {
+            Some(current) if lock_time.is_implied_by(current) => {}
+            Some(current) if !current.is_same_unit(lock_time) => bail!(
+                "Relative lock time {} and {} use different units",
+                current,
+                lock_time
+            ),
+            _ => *sequence = lock_time.to_sequence(),
+        }
+    }
+    Ok(())
+}
+
+/// Check `tx` could be mined in the block after `tip`, `confirmations[i]` being the
+/// confirmation of the output spent by input `i`.
+pub fn check_final(
+    tx: &Transaction,
+    tip: &ChainTip,
+    confirmations: &[Option<Confirmation>],
+) -> anyhow::Result<()> {
+    if confirmations.len() != tx.input.len() {
+        bail!(
+            "Expect {} confirmations, got {}",
+            tx.input.len(),
+            confirmations.len()
+        );
+    }
+    let next_height = tip.height + 1;
+
+    let lock_time_enabled = tx
+        .input
+        .iter()
+        .any(|input| input.sequence.enables_absolute_lock_time());
+    if lock_time_enabled {
+        let final_ = match tx.lock_time {
+            absolute::LockTime::Blocks(height) => height.to_consensus_u32() < next_height,
+            absolute::LockTime::Seconds(time) => time.to_consensus_u32() < tip.median_time_past,
+        };
+        if !final_ {
+            bail!(
+                "Transaction is not final: lock time {} at height {} (median time {})",
+                tx.lock_time,
+                tip.height,
+                tip.median_time_past
+            );
+        }
+    }
+
+    if tx.version < transaction::Version::TWO {
+        return Ok(());
+    }
+    for (i, (input, confirmation)) in tx.input.iter().zip(confirmations).enumerate() {
+        let Some(lock_time) = input.sequence.to_relative_lock_time() else {
+            continue;
+        };
+        let final_ = match (lock_time, confirmation) {
+            (relative::LockTime::Blocks(height), Some(conf)) => {
+                conf.height + height.value() as u32 <= next_height
+            }
+            (relative::LockTime::Time(time), Some(conf)) => {
+                conf.prev_median_time_past + time.value() as u32 * 512 <= tip.median_time_past
+            }
+            // An output confirming in the same block only satisfies a zero lock.
+            (relative::LockTime::Blocks(height), None) => height.value() == 0,
+            (relative::LockTime::Time(time), None) => time.value() == 0,
+        };
+        if !final_ {
+            bail!(
+                "Input {} is not final: relative lock time {} not reached at height {}",
+                i,
+                lock_time,
+                tip.height
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::tx::taproot_tree_tx::template::Condition;
+    use crate::bitcoin_node::tx::{senders_keys, USER_B_PRIVATE_KEY};
+    use bitcoin::{Amount, OutPoint, ScriptBuf, TxIn, TxOut, Witness, XOnlyPublicKey};
+    use secp256k1::Secp256k1;
+
+    fn key() -> XOnlyPublicKey {
+        senders_keys(&Secp256k1::new(), USER_B_PRIVATE_KEY)
+            .x_only_public_key()
+            .0
+    }
+
+    fn unsigned_tx() -> Transaction {
+        Transaction {
+            version: transaction::Version::ONE,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::default(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(1_000),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_cltv_height() -> anyhow::Result<()> {
+        let lock_time = absolute::LockTime::from_height(800_000)?;
+        let leaf = LeafTemplate::abs_time_lock(lock_time, key());
+        let mut tx = unsigned_tx();
+        apply_time_locks(&mut tx, 0, &leaf)?;
+        assert_eq!(tx.lock_time, lock_time);
+        assert!(tx.input[0].sequence.enables_absolute_lock_time());
+        assert!(!tx.input[0].sequence.is_relative_lock_time());
+
+        let tip = |height| ChainTip {
+            height,
+            median_time_past: 1_700_000_000,
+        };
+        assert!(check_final(&tx, &tip(799_999), &[None]).is_err());
+        // lock time is the last height it can't be mined at, so it goes in block 800_001.
+        check_final(&tx, &tip(800_000), &[None])?;
+
+        // a time based lock can't be combined with the height one.
+        let leaf =
+            LeafTemplate::abs_time_lock(absolute::LockTime::from_time(1_700_000_000)?, key());
+        assert!(apply_time_locks(&mut tx, 0, &leaf).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cltv_time() -> anyhow::Result<()> {
+        let lock_time = absolute::LockTime::from_time(1_700_000_000)?;
+        let leaf = LeafTemplate::abs_time_lock(lock_time, key());
+        let mut tx = unsigned_tx();
+        apply_time_locks(&mut tx, 0, &leaf)?;
+        assert_eq!(tx.lock_time, lock_time);
+
+        let tip = |median_time_past| ChainTip {
+            height: 800_000,
+            median_time_past,
+        };
+        assert!(check_final(&tx, &tip(1_700_000_000), &[None]).is_err());
+        check_final(&tx, &tip(1_700_000_001), &[None])?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_csv_blocks_and_time() -> anyhow::Result<()> {
+        let leaf = LeafTemplate::rel_time_lock(relative::LockTime::from_height(144), key());
+        let mut tx = unsigned_tx();
+        apply_time_locks(&mut tx, 0, &leaf)?;
+        assert_eq!(tx.version, transaction::Version::TWO);
+        assert_eq!(tx.input[0].sequence, Sequence::from_height(144));
+        assert_eq!(tx.lock_time, absolute::LockTime::ZERO);
+
+        let conf = Confirmation {
+            height: 1_000,
+            prev_median_time_past: 0,
+        };
+        let tip = |height| ChainTip {
+            height,
+            median_time_past: 0,
+        };
+        assert!(check_final(&tx, &tip(1_142), &[Some(conf)]).is_err());
+        check_final(&tx, &tip(1_143), &[Some(conf)])?;
+        assert!(check_final(&tx, &tip(5_000), &[None]).is_err());
+
+        let leaf =
+            LeafTemplate::rel_time_lock(relative::LockTime::from_512_second_intervals(10), key());
+        let mut tx = unsigned_tx();
+        apply_time_locks(&mut tx, 0, &leaf)?;
+        assert_eq!(
+            tx.input[0].sequence,
+            Sequence::from_512_second_intervals(10)
+        );
+        let conf = Confirmation {
+            height: 1_000,
+            prev_median_time_past: 1_700_000_000,
+        };
+        let tip = |median_time_past| ChainTip {
+            height: 1_010,
+            median_time_past,
+        };
+        assert!(check_final(&tx, &tip(1_700_005_119), &[Some(conf)]).is_err());
+        check_final(&tx, &tip(1_700_005_120), &[Some(conf)])?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_cltv_and_csv_in_one_leaf() -> anyhow::Result<()> {
+        let leaf = LeafTemplate::new(vec![
+            Condition::AbsTimeLock(absolute::LockTime::from_height(500)?),
+            Condition::RelTimeLock(relative::LockTime::from_height(6)),
+            Condition::SingleSig(key()),
+        ])?;
+        let mut tx = unsigned_tx();
+        apply_time_locks(&mut tx, 0, &leaf)?;
+        assert_eq!(tx.lock_time, absolute::LockTime::from_height(500)?);
+        // a CSV sequence also enables the absolute lock time.
+        assert_eq!(tx.input[0].sequence, Sequence::from_height(6));
+        assert!(tx.input[0].sequence.enables_absolute_lock_time());
+
+        // a lower lock of another leaf on the same tx keeps the higher one.
+        let lower = LeafTemplate::abs_time_lock(absolute::LockTime::from_height(400)?, key());
+        apply_time_locks(&mut tx, 0, &lower)?;
+        assert_eq!(tx.lock_time, absolute::LockTime::from_height(500)?);
+        Ok(())
+    }
+}
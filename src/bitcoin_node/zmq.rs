@@ -0,0 +1,524 @@
+//! Subscriber to bitcoind ZMQ notifications (`-zmqpubrawtx`, `-zmqpubrawblock`,
+//! `-zmqpubsequence`), and a watcher of addresses and outpoints fed by it.
+//!
+//! bitcoind publishes on a ZMQ PUB socket. The subscriber speaks ZMTP 3.0 with the NULL
+//! mechanism over a plain TCP stream, all a SUB socket of bitcoind needs: a greeting, a READY
+//! command, one subscription message per topic, then multipart messages of
+//! `[topic, body, 4 bytes LE sequence]`.
+//!
+//! Notifications are best effort, a gap in the sequence of a topic means some were dropped:
+//! `ZmqSubscriber::missed` counts them, resync with the `ChainFollower` on a gap.
+//!
+//! Reference: https://github.com/bitcoin/bitcoin/blob/master/doc/zmq.md
+//!     https://rfc.zeromq.org/spec/23/
+use anyhow::{anyhow, bail};
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::Hash;
+use bitcoin::{Address, Block, BlockHash, OutPoint, ScriptBuf, Transaction, TxOut, Txid};
+use std::collections::{HashMap, HashSet};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const FLAG_MORE: u8 = 0x01;
+const FLAG_LONG: u8 = 0x02;
+const FLAG_COMMAND: u8 = 0x04;
+// ZMTP 3.0 subscription message, `0x01 <topic>`.
+const SUBSCRIBE: u8 = 0x01;
+// bitcoind messages are blocks at most, with some room.
+const MAX_FRAME_SIZE: u64 = 8 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Topic {
+    RawTx,
+    RawBlock,
+    Sequence,
+}
+
+impl Topic {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Topic::RawTx => "rawtx",
+            Topic::RawBlock => "rawblock",
+            Topic::Sequence => "sequence",
+        }
+    }
+
+    fn from_name(name: &[u8]) -> Option<Self> {
+        [Topic::RawTx, Topic::RawBlock, Topic::Sequence]
+            .into_iter()
+            .find(|topic| topic.name().as_bytes() == name)
+    }
+}
+
+/// Body of a `sequence` notification.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SequenceEvent {
+    BlockConnected(BlockHash),
+    BlockDisconnected(BlockHash),
+    /// Added to the mempool, with the mempool sequence number.
+    TxAdded(Txid, u64),
+    /// Removed from the mempool for another reason than a block.
+    TxRemoved(Txid, u64),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Notification {
+    RawTx(Transaction),
+    RawBlock(Block),
+    Sequence(SequenceEvent),
+}
+
+impl Notification {
+    fn decode(topic: Topic, body: &[u8]) -> anyhow::Result<Self> {
+        Ok(match topic {
+            Topic::RawTx => Notification::RawTx(deserialize(body)?),
+            Topic::RawBlock => Notification::RawBlock(deserialize(body)?),
+            Topic::Sequence => Notification::Sequence(decode_sequence(body)?),
+        })
+    }
+}
+
+/// `<32 bytes hash><label>[<8 bytes LE mempool sequence>]`, the hash in RPC byte order.
+fn decode_sequence(body: &[u8]) -> anyhow::Result<SequenceEvent> {
+    if body.len() < 33 {
+        bail!("Invalid sequence notification length: {}", body.len());
+    }
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&body[..32]);
+    hash.reverse();
+    let mempool_sequence = || -> anyhow::Result<u64> {
+        let bytes: [u8; 8] = body[33..]
+            .try_into()
+            .map_err(|_| anyhow!("Missing mempool sequence"))?;
+        Ok(u64::from_le_bytes(bytes))
+    };
+    Ok(match body[32] {
+        b'C' => SequenceEvent::BlockConnected(BlockHash::from_byte_array(hash)),
+        b'D' => SequenceEvent::BlockDisconnected(BlockHash::from_byte_array(hash)),
+        b'A' => SequenceEvent::TxAdded(Txid::from_byte_array(hash), mempool_sequence()?),
+        b'R' => SequenceEvent::TxRemoved(Txid::from_byte_array(hash), mempool_sequence()?),
+        label => bail!("Unknown sequence label: {}", label as char),
+    })
+}
+
+/// Greeting of ZMTP 3.0 with the NULL mechanism.
+fn greeting() -> [u8; 64] {
+    let mut greeting = [0u8; 64];
+    greeting[0] = 0xff;
+    // The padding libzmq sends, a one byte identity to a ZMTP 1.0 peer.
+    greeting[8] = 0x01;
+    greeting[9] = 0x7f;
+    greeting[10] = 3;
+    greeting[12..16].copy_from_slice(b"NULL");
+    greeting
+}
+
+async fn write_frame<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    flags: u8,
+    body: &[u8],
+) -> anyhow::Result<()> {
+    let mut frame = vec![];
+    if body.len() > u8::MAX as usize {
+        frame.push(flags | FLAG_LONG);
+        frame.extend((body.len() as u64).to_be_bytes());
+    } else {
+        frame.push(flags);
+        frame.push(body.len() as u8);
+    }
+    frame.extend(body);
+    stream.write_all(&frame).await?;
+    Ok(())
+}
+
+async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<(u8, Vec<u8>)> {
+    let flags = stream.read_u8().await?;
+    let size = if flags & FLAG_LONG != 0 {
+        stream.read_u64().await?
+    } else {
+        stream.read_u8().await? as u64
+    };
+    if size > MAX_FRAME_SIZE {
+        bail!("ZMQ frame too large: {}", size);
+    }
+    let mut body = vec![0u8; size as usize];
+    stream.read_exact(&mut body).await?;
+    Ok((flags, body))
+}
+
+/// `READY` command with the `Socket-Type` property.
+fn ready_command(socket_type: &str) -> Vec<u8> {
+    let mut command = vec![5];
+    command.extend(b"READY");
+    command.push(11);
+    command.extend(b"Socket-Type");
+    command.extend((socket_type.len() as u32).to_be_bytes());
+    command.extend(socket_type.as_bytes());
+    command
+}
+
+/// Exchange greetings and READY commands as `socket_type`.
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    socket_type: &str,
+) -> anyhow::Result<()> {
+    stream.write_all(&greeting()).await?;
+    let mut peer = [0u8; 64];
+    stream.read_exact(&mut peer).await?;
+    if peer[0] != 0xff || peer[9] != 0x7f {
+        bail!("Not a ZMTP peer");
+    }
+    if peer[10] < 3 || &peer[12..16] != b"NULL" {
+        bail!("Unsupported ZMTP version {} or mechanism", peer[10]);
+    }
+
+    write_frame(stream, FLAG_COMMAND, &ready_command(socket_type)).await?;
+    let (flags, command) = read_frame(stream).await?;
+    if flags & FLAG_COMMAND == 0 || command.get(..6) != Some(b"\x05READY") {
+        bail!("Fail to handshake with ZMTP peer");
+    }
+    Ok(())
+}
+
+pub struct ZmqSubscriber {
+    stream: TcpStream,
+    sequences: HashMap<Topic, u32>,
+    missed: u64,
+}
+
+impl ZmqSubscriber {
+    /// Connect to `endpoint` (`tcp://host:port` as in `-zmqpub*`) and subscribe to `topics`.
+    pub async fn connect(endpoint: &str, topics: &[Topic]) -> anyhow::Result<Self> {
+        let address = endpoint.strip_prefix("tcp://").unwrap_or(endpoint);
+        let mut stream = TcpStream::connect(address)
+            .await
+            .map_err(|e| anyhow!("Fail to connect to zmq: {}, error: {}", endpoint, e))?;
+        handshake(&mut stream, "SUB").await?;
+        for topic in topics {
+            let mut subscription = vec![SUBSCRIBE];
+            subscription.extend(topic.name().as_bytes());
+            write_frame(&mut stream, 0, &subscription).await?;
+        }
+        Ok(Self {
+            stream,
+            sequences: HashMap::new(),
+            missed: 0,
+        })
+    }
+
+    /// Notifications dropped by the publisher since the connection.
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+
+    async fn read_message(&mut self) -> anyhow::Result<Vec<Vec<u8>>> {
+        let mut parts = vec![];
+        loop {
+            let (flags, body) = read_frame(&mut self.stream).await?;
+            if flags & FLAG_COMMAND != 0 {
+                continue;
+            }
+            parts.push(body);
+            if flags & FLAG_MORE == 0 {
+                return Ok(parts);
+            }
+        }
+    }
+
+    /// Wait for the next notification.
+    pub async fn next(&mut self) -> anyhow::Result<Notification> {
+        loop {
+            let parts = self.read_message().await?;
+            let [topic, body, sequence] = parts.as_slice() else {
+                bail!("Unexpected zmq message of {} parts", parts.len());
+            };
+            let Some(topic) = Topic::from_name(topic) else {
+                continue;
+            };
+            let sequence = u32::from_le_bytes(
+                sequence
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| anyhow!("Invalid zmq sequence"))?,
+            );
+            if let Some(last) = self.sequences.insert(topic, sequence) {
+                self.missed += sequence.wrapping_sub(last).wrapping_sub(1) as u64;
+            }
+            return Notification::decode(topic, body);
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// An output to a watched script, `block` is `None` in the mempool.
+    Received {
+        out_point: OutPoint,
+        txout: TxOut,
+        block: Option<BlockHash>,
+    },
+    Spent {
+        out_point: OutPoint,
+        spending_txid: Txid,
+        block: Option<BlockHash>,
+    },
+}
+
+/// Watch scripts and outpoints in the notifications. The outputs received by a watched
+/// script are watched for their spend.
+#[derive(Default)]
+pub struct Watcher {
+    scripts: HashSet<ScriptBuf>,
+    out_points: HashSet<OutPoint>,
+}
+
+impl Watcher {
+    pub fn watch_address(&mut self, address: &Address) {
+        self.scripts.insert(address.script_pubkey());
+    }
+
+    pub fn watch_script(&mut self, script_pubkey: ScriptBuf) {
+        self.scripts.insert(script_pubkey);
+    }
+
+    pub fn watch_out_point(&mut self, out_point: OutPoint) {
+        self.out_points.insert(out_point);
+    }
+
+    pub fn handle(&mut self, notification: &Notification) -> Vec<WatchEvent> {
+        match notification {
+            Notification::RawTx(tx) => self.handle_tx(tx, None),
+            Notification::RawBlock(block) => {
+                let hash = block.block_hash();
+                block
+                    .txdata
+                    .iter()
+                    .flat_map(|tx| self.handle_tx(tx, Some(hash)))
+                    .collect()
+            }
+            Notification::Sequence(_) => vec![],
+        }
+    }
+
+    // A mempool transaction is reported again once mined.
+    fn handle_tx(&mut self, tx: &Transaction, block: Option<BlockHash>) -> Vec<WatchEvent> {
+        let txid = tx.compute_txid();
+        let mut events = vec![];
+        for input in &tx.input {
+            if self.out_points.contains(&input.previous_output) {
+                if block.is_some() {
+                    self.out_points.remove(&input.previous_output);
+                }
+                events.push(WatchEvent::Spent {
+                    out_point: input.previous_output,
+                    spending_txid: txid,
+                    block,
+                });
+            }
+        }
+        for (vout, txout) in tx.output.iter().enumerate() {
+            if self.scripts.contains(&txout.script_pubkey) {
+                let out_point = OutPoint::new(txid, vout as u32);
+                self.out_points.insert(out_point);
+                events.push(WatchEvent::Received {
+                    out_point,
+                    txout: txout.clone(),
+                    block,
+                });
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::block::{Header, Version};
+    use bitcoin::consensus::serialize;
+    use bitcoin::constants::genesis_block;
+    use bitcoin::hex::FromHex;
+    use bitcoin::{
+        absolute, transaction, Amount, CompactTarget, Network, Sequence, TxIn, TxMerkleNode,
+        Witness,
+    };
+    use tokio::net::TcpListener;
+
+    /// What a libzmq 4.3 PUB socket, as bitcoind's, writes to a subscriber: its greeting, READY
+    /// command, then the `rawtx` and `rawblock` notifications of the genesis block.
+    fn libzmq_publisher_bytes() -> Vec<u8> {
+        let genesis = genesis_block(Network::Bitcoin);
+        let hex = |hex: &str| Vec::<u8>::from_hex(hex).unwrap();
+        [
+            // signature, ZMTP 3.1, NULL mechanism, not as server, filler.
+            hex("ff00000000000000017f03014e554c4c"),
+            vec![0; 48],
+            // READY, Socket-Type PUB.
+            hex("04190552454144590b536f636b65742d5479706500000003505542"),
+            // "rawtx", 204 bytes short frame, sequence 0.
+            hex("01057261777478"),
+            hex("01cc"),
+            serialize(&genesis.txdata[0]),
+            hex("000400000000"),
+            // "rawblock", 285 bytes long frame, sequence 0.
+            hex("0108726177626c6f636b"),
+            hex("03000000000000011d"),
+            serialize(&genesis),
+            hex("000400000000"),
+        ]
+        .concat()
+    }
+
+    /// What a libzmq SUB socket writes to a ZMTP 3.0 publisher subscribing to `rawtx` and
+    /// `rawblock`, our ZMTP 3.0 minor version aside.
+    fn libzmq_subscriber_bytes() -> Vec<u8> {
+        let hex = |hex: &str| Vec::<u8>::from_hex(hex).unwrap();
+        [
+            hex("ff00000000000000017f03004e554c4c"),
+            vec![0; 48],
+            hex("04190552454144590b536f636b65742d5479706500000003535542"),
+            hex("0006017261777478000901726177626c6f636b"),
+        ]
+        .concat()
+    }
+
+    /// Stand-in for the PUB socket of bitcoind: waits for `subscriptions` and publishes
+    /// `messages`, each `(topic, body, sequence)`.
+    async fn publisher(
+        listener: TcpListener,
+        subscriptions: usize,
+        messages: Vec<(Topic, Vec<u8>, u32)>,
+    ) -> anyhow::Result<Vec<Vec<u8>>> {
+        let (mut stream, _) = listener.accept().await?;
+        handshake(&mut stream, "PUB").await?;
+        let mut subscribed = vec![];
+        for _ in 0..subscriptions {
+            subscribed.push(read_frame(&mut stream).await?.1);
+        }
+        for (topic, body, sequence) in messages {
+            write_frame(&mut stream, FLAG_MORE, topic.name().as_bytes()).await?;
+            write_frame(&mut stream, FLAG_MORE, &body).await?;
+            write_frame(&mut stream, 0, &sequence.to_le_bytes()).await?;
+        }
+        Ok(subscribed)
+    }
+
+    fn tx(previous_output: OutPoint, script_pubkey: ScriptBuf) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::default(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(1_000),
+                script_pubkey,
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_and_watch() -> anyhow::Result<()> {
+        let ours = ScriptBuf::new_op_return([1]);
+        let paying = tx(OutPoint::null(), ours.clone());
+        let out_point = OutPoint::new(paying.compute_txid(), 0);
+        let spending = tx(out_point, ScriptBuf::new_op_return([2]));
+        let block = Block {
+            header: Header {
+                version: Version::ONE,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+            },
+            txdata: vec![spending.clone()],
+        };
+        let mut added = paying.compute_txid().to_byte_array().to_vec();
+        added.reverse();
+        added.push(b'A');
+        added.extend(7u64.to_le_bytes());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let endpoint = format!("tcp://{}", listener.local_addr()?);
+        let messages = vec![
+            (Topic::Sequence, added, 0),
+            (Topic::RawTx, serialize(&paying), 0),
+            // rawtx 1 was dropped.
+            (Topic::RawTx, serialize(&spending), 2),
+            (Topic::RawBlock, serialize(&block), 0),
+        ];
+        let publisher = tokio::spawn(publisher(listener, 3, messages));
+
+        let topics = [Topic::RawTx, Topic::RawBlock, Topic::Sequence];
+        let mut subscriber = ZmqSubscriber::connect(&endpoint, &topics).await?;
+        let mut watcher = Watcher::default();
+        watcher.watch_script(ours);
+
+        assert_eq!(
+            subscriber.next().await?,
+            Notification::Sequence(SequenceEvent::TxAdded(paying.compute_txid(), 7))
+        );
+        let mut events = vec![];
+        for _ in 0..3 {
+            events.extend(watcher.handle(&subscriber.next().await?));
+        }
+        assert_eq!(
+            events,
+            vec![
+                WatchEvent::Received {
+                    out_point,
+                    txout: paying.output[0].clone(),
+                    block: None,
+                },
+                WatchEvent::Spent {
+                    out_point,
+                    spending_txid: spending.compute_txid(),
+                    block: None,
+                },
+                WatchEvent::Spent {
+                    out_point,
+                    spending_txid: spending.compute_txid(),
+                    block: Some(block.block_hash()),
+                },
+            ]
+        );
+        assert_eq!(subscriber.missed(), 1);
+        // the spend is mined, the outpoint isn't watched anymore.
+        assert!(watcher.handle(&Notification::RawBlock(block)).is_empty());
+
+        let subscribed = publisher.await??;
+        assert_eq!(subscribed[0], b"\x01rawtx");
+        assert_eq!(subscribed[2], b"\x01sequence");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_libzmq_wire_format() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let endpoint = format!("tcp://{}", listener.local_addr()?);
+        let publisher = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            stream.write_all(&libzmq_publisher_bytes()).await?;
+            let mut sent = vec![0u8; libzmq_subscriber_bytes().len()];
+            stream.read_exact(&mut sent).await?;
+            anyhow::Ok(sent)
+        });
+
+        let topics = [Topic::RawTx, Topic::RawBlock];
+        let mut subscriber = ZmqSubscriber::connect(&endpoint, &topics).await?;
+        let genesis = genesis_block(Network::Bitcoin);
+        assert_eq!(
+            subscriber.next().await?,
+            Notification::RawTx(genesis.txdata[0].clone())
+        );
+        assert_eq!(subscriber.next().await?, Notification::RawBlock(genesis));
+        assert_eq!(subscriber.missed(), 0);
+
+        assert_eq!(publisher.await??, libzmq_subscriber_bytes());
+        Ok(())
+    }
+}
@@ -0,0 +1,390 @@
+//! Hash time locked contract on taproot, for atomic swaps (cross-chain, or Lightning
+//! submarine swaps where `payment_hash` is the invoice payment hash).
+//!
+//! - internal key: MuSig2 aggregate of sender and receiver, so both can settle cooperatively
+//!   with a plain key path spend that looks like any other taproot payment;
+//! - claim leaf: the receiver reveals the 32 bytes preimage of `payment_hash` and signs;
+//! - refund leaf: the sender signs once the timeout is reached.
+//!
+//! Revealing the preimage on chain is what lets the other side of the swap settle, see
+//! `extract_preimage`.
+use crate::bitcoin_node::signer::Signer;
+use crate::bitcoin_node::tx::taproot_tree_tx::template::{LeafSecrets, LeafTemplate};
+use crate::bitcoin_node::tx::timelock::{check_final, ChainTip, Confirmation};
+use crate::musig2::KeyAggContext;
+use anyhow::{anyhow, bail};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::secp256k1::schnorr;
+use bitcoin::sighash::{Prevouts, SighashCache};
+use bitcoin::taproot::{LeafVersion, TaprootBuilder, TaprootSpendInfo};
+use bitcoin::{
+    absolute, relative, transaction, Address, Amount, Network, OutPoint, ScriptBuf, Sequence,
+    TapSighash, TapSighashType, Transaction, TxIn, TxOut, Witness,
+};
+use secp256k1::PublicKey;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HtlcTimeout {
+    /// Refund from a block height or time on, the usual choice for swaps.
+    Absolute(absolute::LockTime),
+    /// Refund once the HTLC output is this old.
+    Relative(relative::LockTime),
+}
+
+/// The HTLC output being spent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HtlcUtxo {
+    pub out_point: OutPoint,
+    pub txout: TxOut,
+    /// Needed for a `HtlcTimeout::Relative` refund, `None` while unconfirmed.
+    pub confirmation: Option<Confirmation>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Htlc {
+    pub sender: PublicKey,
+    pub receiver: PublicKey,
+    pub payment_hash: sha256::Hash,
+    pub timeout: HtlcTimeout,
+}
+
+impl Htlc {
+    pub fn new(
+        sender: PublicKey,
+        receiver: PublicKey,
+        payment_hash: sha256::Hash,
+        timeout: HtlcTimeout,
+    ) -> anyhow::Result<Self> {
+        if sender == receiver {
+            bail!("HTLC sender and receiver must differ");
+        }
+        Ok(Self {
+            sender,
+            receiver,
+            payment_hash,
+            timeout,
+        })
+    }
+
+    /// The untweaked MuSig2 aggregate of `[sender, receiver]`, the internal key.
+    pub fn key_agg(&self) -> anyhow::Result<KeyAggContext> {
+        KeyAggContext::new(vec![self.sender, self.receiver])
+    }
+
+    pub fn claim_leaf(&self) -> LeafTemplate {
+        LeafTemplate::hash_lock(self.payment_hash, self.receiver.x_only_public_key().0)
+    }
+
+    pub fn refund_leaf(&self) -> LeafTemplate {
+        let sender = self.sender.x_only_public_key().0;
+        match self.timeout {
+            HtlcTimeout::Absolute(lock_time) => LeafTemplate::abs_time_lock(lock_time, sender),
+            HtlcTimeout::Relative(lock_time) => LeafTemplate::rel_time_lock(lock_time, sender),
+        }
+    }
+
+    pub fn spend_info(&self) -> anyhow::Result<TaprootSpendInfo> {
+        let builder = TaprootBuilder::new()
+            .add_leaf(1, self.claim_leaf().to_script())?
+            .add_leaf(1, self.refund_leaf().to_script())?;
+        self.key_agg()?.taproot_spend_info(builder)
+    }
+
+    pub fn script_pubkey(&self) -> anyhow::Result<ScriptBuf> {
+        Ok(ScriptBuf::new_p2tr_tweaked(self.spend_info()?.output_key()))
+    }
+
+    pub fn address(&self, network: Network) -> anyhow::Result<Address> {
+        Ok(Address::p2tr_tweaked(
+            self.spend_info()?.output_key(),
+            network,
+        ))
+    }
+
+    /// The receiver takes the funds by revealing `preimage`.
+    pub fn claim_tx(
+        &self,
+        utxo: &HtlcUtxo,
+        to: ScriptBuf,
+        fee: Amount,
+        preimage: [u8; 32],
+        receiver: &dyn Signer,
+    ) -> anyhow::Result<Transaction> {
+        if sha256::Hash::hash(&preimage) != self.payment_hash {
+            bail!("Preimage doesn't match payment hash {}", self.payment_hash);
+        }
+        self.sign_leaf(utxo, to, fee, &self.claim_leaf(), receiver, Some(preimage))
+    }
+
+    /// The sender takes the funds back after the timeout. Fails while the transaction would
+    /// not be final at `tip`.
+    pub fn refund_tx(
+        &self,
+        utxo: &HtlcUtxo,
+        to: ScriptBuf,
+        fee: Amount,
+        sender: &dyn Signer,
+        tip: &ChainTip,
+    ) -> anyhow::Result<Transaction> {
+        let tx = self.sign_leaf(utxo, to, fee, &self.refund_leaf(), sender, None)?;
+        check_final(&tx, tip, &[utxo.confirmation])?;
+        Ok(tx)
+    }
+
+    /// Unsigned cooperative spend and its key path sighash. Both parties sign the sighash with
+    /// MuSig2 over `cooperative_key_agg`, then `finalize_cooperative` sets the witness.
+    pub fn cooperative_tx(
+        &self,
+        utxo: &HtlcUtxo,
+        to: ScriptBuf,
+        fee: Amount,
+    ) -> anyhow::Result<(Transaction, TapSighash)> {
+        let tx = spend_tx(utxo, to, fee)?;
+        let sighash = SighashCache::new(&tx).taproot_key_spend_signature_hash(
+            0,
+            &Prevouts::All(std::slice::from_ref(&utxo.txout)),
+            TapSighashType::Default,
+        )?;
+        Ok((tx, sighash))
+    }
+
+    /// The aggregate key tweaked into the output key, to run the MuSig2 session with.
+    pub fn cooperative_key_agg(&self) -> anyhow::Result<KeyAggContext> {
+        let merkle_root = self.spend_info()?.merkle_root();
+        self.key_agg()?.with_taproot_tweak(merkle_root)
+    }
+
+    pub fn finalize_cooperative(tx: &mut Transaction, signature: schnorr::Signature) {
+        tx.input[0].witness = Witness::p2tr_key_spend(&bitcoin::taproot::Signature {
+            signature,
+            sighash_type: TapSighashType::Default,
+        });
+    }
+
+    fn sign_leaf(
+        &self,
+        utxo: &HtlcUtxo,
+        to: ScriptBuf,
+        fee: Amount,
+        leaf: &LeafTemplate,
+        signer: &dyn Signer,
+        preimage: Option<[u8; 32]>,
+    ) -> anyhow::Result<Transaction> {
+        let spend_info = self.spend_info()?;
+        if utxo.txout.script_pubkey != ScriptBuf::new_p2tr_tweaked(spend_info.output_key()) {
+            bail!("Output {} is not this HTLC", utxo.out_point);
+        }
+        let control_block = spend_info
+            .control_block(&(leaf.to_script(), LeafVersion::TapScript))
+            .ok_or_else(|| anyhow!("Missing control block of HTLC leaf"))?;
+        let mut tx = spend_tx(utxo, to, fee)?;
+        leaf.sign_input(
+            &LeafSecrets {
+                signers: &[signer],
+                preimage,
+            },
+            &control_block,
+            &mut tx,
+            0,
+            std::slice::from_ref(&utxo.txout),
+            TapSighashType::Default,
+        )?;
+        Ok(tx)
+    }
+}
+
+fn spend_tx(utxo: &HtlcUtxo, to: ScriptBuf, fee: Amount) -> anyhow::Result<Transaction> {
+    let value = utxo
+        .txout
+        .value
+        .checked_sub(fee)
+        .ok_or_else(|| anyhow!("HTLC value {} doesn't cover the fee", utxo.txout.value))?;
+    Ok(Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: utxo.out_point,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::default(),
+        }],
+        output: vec![TxOut {
+            value,
+            script_pubkey: to,
+        }],
+    })
+}
+
+/// Find the preimage of `payment_hash` in the witnesses of an observed claim transaction.
+pub fn extract_preimage(tx: &Transaction, payment_hash: &sha256::Hash) -> Option<[u8; 32]> {
+    tx.input
+        .iter()
+        .flat_map(|input| input.witness.iter())
+        .filter_map(|element| <[u8; 32]>::try_from(element).ok())
+        .find(|preimage| sha256::Hash::hash(preimage) == *payment_hash)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::ledger::LocalLedger;
+    use crate::bitcoin_node::signer::InMemorySigner;
+    use crate::bitcoin_node::tx::{USER_A_PRIVATE_KEY, USER_B_PRIVATE_KEY};
+    use crate::musig2::{nonce_agg, nonce_gen, Session};
+    use crate::SECP;
+    use bitcoin::bip32::Xpriv;
+    use secp256k1::Message;
+    use std::str::FromStr;
+
+    const PREIMAGE: [u8; 32] = [42; 32];
+    const FEE: Amount = Amount::from_sat(500);
+
+    struct Swap {
+        ledger: LocalLedger,
+        htlc: Htlc,
+        utxo: HtlcUtxo,
+        sender: InMemorySigner,
+        receiver: InMemorySigner,
+    }
+
+    fn setup(timeout: HtlcTimeout) -> anyhow::Result<Swap> {
+        let sender = InMemorySigner::from_xpriv_str(USER_A_PRIVATE_KEY)?;
+        let receiver = InMemorySigner::from_xpriv_str(USER_B_PRIVATE_KEY)?;
+        let htlc = Htlc::new(
+            sender.public_key()?.inner,
+            receiver.public_key()?.inner,
+            sha256::Hash::hash(&PREIMAGE),
+            timeout,
+        )?;
+        let mut ledger = LocalLedger::new(ChainTip {
+            height: 1_000,
+            median_time_past: 1_700_000_000,
+        });
+        let (out_point, txout) = ledger.fund(htlc.script_pubkey()?, Amount::from_sat(50_000));
+        let utxo = HtlcUtxo {
+            out_point,
+            txout,
+            confirmation: ledger.confirmation(&out_point),
+        };
+        Ok(Swap {
+            ledger,
+            htlc,
+            utxo,
+            sender,
+            receiver,
+        })
+    }
+
+    #[test]
+    fn test_htlc_claim_reveals_preimage() -> anyhow::Result<()> {
+        let mut swap = setup(HtlcTimeout::Absolute(absolute::LockTime::from_height(
+            1_144,
+        )?))?;
+        let to = ScriptBuf::new_p2tr(&SECP, swap.receiver.x_only_public_key()?, None);
+
+        assert!(swap
+            .htlc
+            .claim_tx(&swap.utxo, to.clone(), FEE, [0; 32], &swap.receiver)
+            .is_err());
+        // only the receiver key is in the claim leaf.
+        assert!(swap
+            .htlc
+            .claim_tx(&swap.utxo, to.clone(), FEE, PREIMAGE, &swap.sender)
+            .is_err());
+
+        let claim = swap
+            .htlc
+            .claim_tx(&swap.utxo, to, FEE, PREIMAGE, &swap.receiver)?;
+        swap.ledger.broadcast(&claim)?;
+        assert_eq!(
+            extract_preimage(&claim, &swap.htlc.payment_hash),
+            Some(PREIMAGE)
+        );
+        assert_eq!(
+            extract_preimage(&claim, &sha256::Hash::hash(&[1; 32])),
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_htlc_refund_after_timeout() -> anyhow::Result<()> {
+        let mut swap = setup(HtlcTimeout::Absolute(absolute::LockTime::from_height(
+            1_144,
+        )?))?;
+        let to = ScriptBuf::new_p2tr(&SECP, swap.sender.x_only_public_key()?, None);
+        let tip = swap.ledger.tip();
+        assert!(swap
+            .htlc
+            .refund_tx(&swap.utxo, to.clone(), FEE, &swap.sender, &tip)
+            .is_err());
+
+        swap.ledger.mine(144);
+        let tip = swap.ledger.tip();
+        let refund = swap
+            .htlc
+            .refund_tx(&swap.utxo, to, FEE, &swap.sender, &tip)?;
+        assert_eq!(refund.lock_time, absolute::LockTime::from_height(1_144)?);
+        swap.ledger.broadcast(&refund)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_htlc_relative_refund() -> anyhow::Result<()> {
+        let mut swap = setup(HtlcTimeout::Relative(relative::LockTime::from_height(10)))?;
+        let to = ScriptBuf::new_p2tr(&SECP, swap.sender.x_only_public_key()?, None);
+        swap.ledger.mine(8);
+        let tip = swap.ledger.tip();
+        assert!(swap
+            .htlc
+            .refund_tx(&swap.utxo, to.clone(), FEE, &swap.sender, &tip)
+            .is_err());
+
+        swap.ledger.mine(1);
+        let tip = swap.ledger.tip();
+        let refund = swap
+            .htlc
+            .refund_tx(&swap.utxo, to, FEE, &swap.sender, &tip)?;
+        assert_eq!(refund.input[0].sequence, Sequence::from_height(10));
+        swap.ledger.broadcast(&refund)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_htlc_cooperative_key_path() -> anyhow::Result<()> {
+        let mut swap = setup(HtlcTimeout::Absolute(absolute::LockTime::from_height(
+            1_144,
+        )?))?;
+        let (mut tx, sighash) = swap
+            .htlc
+            .cooperative_tx(&swap.utxo, ScriptBuf::new(), FEE)?;
+
+        let ctx = swap.htlc.cooperative_key_agg()?;
+        let sks: Vec<_> = [USER_A_PRIVATE_KEY, USER_B_PRIVATE_KEY]
+            .iter()
+            .map(|sk| Xpriv::from_str(sk).unwrap().private_key)
+            .collect();
+        let msg = sighash.to_byte_array();
+        let nonces = sks
+            .iter()
+            .map(|sk| nonce_gen(Some(sk), &sk.public_key(&SECP), None, Some(&msg[..]), None))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let pubnonces: Vec<_> = nonces.iter().map(|(_, pubnonce)| *pubnonce).collect();
+        let session = Session::new(&ctx, &nonce_agg(&pubnonces)?, &msg)?;
+        let mut psigs = vec![];
+        for ((secnonce, _), sk) in nonces.into_iter().zip(&sks) {
+            psigs.push(session.partial_sign(secnonce, sk)?);
+        }
+        let signature = session.aggregate(&psigs)?;
+        SECP.verify_schnorr(
+            &signature,
+            &Message::from(sighash),
+            &swap.htlc.spend_info()?.output_key().to_inner(),
+        )?;
+
+        Htlc::finalize_cooperative(&mut tx, signature);
+        assert_eq!(tx.input[0].witness.len(), 1);
+        swap.ledger.broadcast(&tx)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,665 @@
+//! Inheritance (dead man's switch) wallet.
+//!
+//! The benefactor locks funds in a taproot output whose internal key is their own and whose
+//! single leaf lets the beneficiary spend after an absolute lock time:
+//! `<lock_height> OP_CHECKLOCKTIMEVERIFY OP_DROP <beneficiary_key> OP_CHECKSIG`
+//! As long as the benefactor is around, they "refresh" the output before the lock expires:
+//! a key path spend (not time locked) into a new output with a later lock time.
+//!
+//! Both sides derive their keys at `101/1/0/0/<index>`, so the benefactor only needs the
+//! beneficiary xpub. The benefactor state is persisted as json after every change.
+//!
+//! A funding or refresh output stays pending until it confirms, the beneficiary keeps the
+//! current output meanwhile, so a refresh that never gets mined doesn't lose track of the funds.
+use crate::bitcoin_node::signer::{sign_key_spend_input, InMemorySigner, Signer};
+use crate::bitcoin_node::tx::taproot_tree_tx::template::{LeafSecrets, LeafTemplate};
+use crate::bitcoin_node::tx::timelock::{check_final, ChainTip, Confirmation};
+use crate::SECP;
+use anyhow::{anyhow, bail};
+use bitcoin::bip32::{ChildNumber, DerivationPath, Xpriv, Xpub};
+use bitcoin::taproot::{LeafVersion, TaprootBuilder, TaprootSpendInfo};
+use bitcoin::{
+    absolute, transaction, Address, Amount, Network, OutPoint, ScriptBuf, Sequence, TapSighashType,
+    Transaction, TxIn, TxOut, Witness, XOnlyPublicKey,
+};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+const STATE_VERSION: u32 = 1;
+// Unhardened, so the beneficiary keys can be derived from the xpub.
+const INHERITANCE_PATH: [u32; 4] = [101, 1, 0, 0];
+
+fn derivation_path(index: u32) -> anyhow::Result<DerivationPath> {
+    let mut path = vec![];
+    for i in INHERITANCE_PATH.iter().chain(&[index]) {
+        path.push(ChildNumber::from_normal_idx(*i)?);
+    }
+    Ok(DerivationPath::from(path))
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InheritanceConfig {
+    /// How many blocks after a (re)fresh the beneficiary can spend.
+    pub lock_blocks: u32,
+    /// Refresh every `refresh_interval` blocks, must be below `lock_blocks`.
+    pub refresh_interval: u32,
+    /// Absolute fee of each funding, refresh and inheritance spend transaction.
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub fee: Amount,
+}
+
+impl Default for InheritanceConfig {
+    // About one year of lock time, refreshed every month.
+    fn default() -> Self {
+        Self {
+            lock_blocks: 52_560,
+            refresh_interval: 4_320,
+            fee: Amount::from_sat(1_000),
+        }
+    }
+}
+
+impl InheritanceConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.refresh_interval == 0 || self.refresh_interval >= self.lock_blocks {
+            bail!(
+                "Refresh interval {} must be between 1 and the lock of {} blocks",
+                self.refresh_interval,
+                self.lock_blocks
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The live inheritance output. It's everything the beneficiary needs to spend it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct InheritanceOutput {
+    pub index: u32,
+    pub out_point: OutPoint,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub value: Amount,
+    pub internal_key: XOnlyPublicKey,
+    pub beneficiary_key: XOnlyPublicKey,
+    pub lock_time: absolute::LockTime,
+    /// Tip height when the output was created, refreshes are scheduled from it.
+    pub created_height: u32,
+}
+
+impl InheritanceOutput {
+    pub fn leaf(&self) -> LeafTemplate {
+        LeafTemplate::abs_time_lock(self.lock_time, self.beneficiary_key)
+    }
+
+    pub fn spend_info(&self) -> anyhow::Result<TaprootSpendInfo> {
+        inheritance_spend_info(self.internal_key, &self.leaf())
+    }
+
+    pub fn txout(&self) -> anyhow::Result<TxOut> {
+        Ok(TxOut {
+            value: self.value,
+            script_pubkey: ScriptBuf::new_p2tr_tweaked(self.spend_info()?.output_key()),
+        })
+    }
+}
+
+fn inheritance_spend_info(
+    internal_key: XOnlyPublicKey,
+    leaf: &LeafTemplate,
+) -> anyhow::Result<TaprootSpendInfo> {
+    TaprootBuilder::new()
+        .add_leaf(0, leaf.to_script())?
+        .finalize(&SECP, internal_key)
+        .map_err(|_| anyhow!("Fail to finalize inheritance taproot tree"))
+}
+
+/// A funding or refresh transaction waiting for its confirmation.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PendingOutput {
+    pub output: InheritanceOutput,
+    pub tx: Transaction,
+}
+
+/// Persisted benefactor state.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct InheritanceState {
+    pub version: u32,
+    pub network: Network,
+    pub beneficiary_xpub: Xpub,
+    pub config: InheritanceConfig,
+    /// Derivation index of the next inheritance output.
+    pub next_index: u32,
+    pub current: Option<InheritanceOutput>,
+    #[serde(default)]
+    pub pending: Option<PendingOutput>,
+}
+
+/// Creates the inheritance output and keeps refreshing it through the key path.
+pub struct BenefactorWallet {
+    master_xpriv: Xpriv,
+    state: InheritanceState,
+    path: Option<PathBuf>,
+}
+
+impl BenefactorWallet {
+    /// An in-memory wallet, see `create`/`open` for a persisted one.
+    pub fn new(
+        master_xpriv: Xpriv,
+        beneficiary_xpub: Xpub,
+        network: Network,
+        config: InheritanceConfig,
+    ) -> anyhow::Result<Self> {
+        config.validate()?;
+        Ok(Self {
+            master_xpriv,
+            state: InheritanceState {
+                version: STATE_VERSION,
+                network,
+                beneficiary_xpub,
+                config,
+                next_index: 0,
+                current: None,
+                pending: None,
+            },
+            path: None,
+        })
+    }
+
+    /// Create a wallet persisted at `path`, which must not exist yet.
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        master_xpriv: Xpriv,
+        beneficiary_xpub: Xpub,
+        network: Network,
+        config: InheritanceConfig,
+    ) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if path.exists() {
+            bail!("Inheritance state already exists: {:?}", path);
+        }
+        let mut wallet = Self::new(master_xpriv, beneficiary_xpub, network, config)?;
+        wallet.path = Some(path);
+        wallet.save()?;
+        Ok(wallet)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P, master_xpriv: Xpriv) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let data = std::fs::read(&path)
+            .map_err(|e| anyhow!("Fail to read inheritance state: {:?}, error: {}", path, e))?;
+        let state: InheritanceState = serde_json::from_slice(&data)?;
+        if state.version != STATE_VERSION {
+            bail!("Unsupported inheritance state version {}", state.version);
+        }
+        state.config.validate()?;
+        Ok(Self {
+            master_xpriv,
+            state,
+            path: Some(path),
+        })
+    }
+
+    pub fn state(&self) -> &InheritanceState {
+        &self.state
+    }
+
+    pub fn current(&self) -> Option<&InheritanceOutput> {
+        self.state.current.as_ref()
+    }
+
+    pub fn pending(&self) -> Option<&PendingOutput> {
+        self.state.pending.as_ref()
+    }
+
+    pub fn address(&self) -> anyhow::Result<Option<Address>> {
+        match self.current() {
+            Some(current) => Ok(Some(Address::p2tr_tweaked(
+                current.spend_info()?.output_key(),
+                self.state.network,
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    /// Height at which `poll` refreshes the current output.
+    pub fn next_refresh_height(&self) -> Option<u32> {
+        self.current()
+            .map(|current| current.created_height + self.state.config.refresh_interval)
+    }
+
+    /// Lock the value of `inputs` (BIP-86 outputs of `signer`) in a first inheritance output.
+    ///
+    /// Broadcast the returned transaction, its output stays pending until `poll` sees it
+    /// confirmed.
+    pub fn create_funding_tx(
+        &mut self,
+        tip: &ChainTip,
+        inputs: &[(OutPoint, TxOut)],
+        signer: &dyn Signer,
+    ) -> anyhow::Result<Transaction> {
+        if self.state.current.is_some() || self.state.pending.is_some() {
+            bail!("Inheritance output already exists, refresh it instead");
+        }
+        if inputs.is_empty() {
+            bail!("No input to fund the inheritance");
+        }
+        let input_value = inputs.iter().map(|(_, txout)| txout.value).sum::<Amount>();
+        let (output, txout) = self.next_output(tip, input_value)?;
+
+        let mut tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: inputs
+                .iter()
+                .map(|(out_point, _)| TxIn {
+                    previous_output: *out_point,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    witness: Witness::default(),
+                })
+                .collect(),
+            output: vec![txout],
+        };
+        let prevouts: Vec<TxOut> = inputs.iter().map(|(_, txout)| txout.clone()).collect();
+        for i in 0..tx.input.len() {
+            sign_key_spend_input(signer, &mut tx, i, &prevouts, TapSighashType::Default, None)?;
+        }
+
+        self.set_pending(output, &tx)?;
+        Ok(tx)
+    }
+
+    /// Spend the current output through the key path into a new one, locked `lock_blocks`
+    /// after `tip`. The new output stays pending until `poll` sees it confirmed.
+    pub fn refresh_tx(&mut self, tip: &ChainTip) -> anyhow::Result<Transaction> {
+        if let Some(pending) = &self.state.pending {
+            bail!(
+                "Inheritance output {} is still pending",
+                pending.output.out_point
+            );
+        }
+        let current = self
+            .state
+            .current
+            .clone()
+            .ok_or_else(|| anyhow!("No inheritance output, create one first"))?;
+        let (output, txout) = self.next_output(tip, current.value)?;
+
+        let mut tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: current.out_point,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::default(),
+            }],
+            output: vec![txout],
+        };
+        let signer = self.internal_signer(current.index)?;
+        sign_key_spend_input(
+            &signer,
+            &mut tx,
+            0,
+            &[current.txout()?],
+            TapSighashType::Default,
+            current.spend_info()?.merkle_root(),
+        )?;
+
+        self.set_pending(output, &tx)?;
+        Ok(tx)
+    }
+
+    /// Meant to be called on every new block, with the confirmation of the pending output if
+    /// any. Moves to the pending output once confirmed, and returns the transaction to
+    /// broadcast: the pending one again while unconfirmed, or a refresh of the current output
+    /// once `next_refresh_height` is reached.
+    pub fn poll(
+        &mut self,
+        tip: &ChainTip,
+        pending_confirmation: Option<Confirmation>,
+    ) -> anyhow::Result<Option<Transaction>> {
+        if let Some(pending) = &self.state.pending {
+            if pending_confirmation.is_none() {
+                return Ok(Some(pending.tx.clone()));
+            }
+            self.advance()?;
+        }
+        match self.next_refresh_height() {
+            Some(height) if tip.height >= height => Ok(Some(self.refresh_tx(tip)?)),
+            _ => Ok(None),
+        }
+    }
+
+    fn internal_signer(&self, index: u32) -> anyhow::Result<InMemorySigner> {
+        let xpriv = self
+            .master_xpriv
+            .derive_priv(&SECP, &derivation_path(index)?)?;
+        Ok(InMemorySigner::from_private_key(&xpriv.to_priv()))
+    }
+
+    // The output at `next_index`, the outpoint is set by `set_pending` once the tx is signed.
+    fn next_output(
+        &self,
+        tip: &ChainTip,
+        input_value: Amount,
+    ) -> anyhow::Result<(InheritanceOutput, TxOut)> {
+        let config = &self.state.config;
+        let value = input_value
+            .checked_sub(config.fee)
+            .filter(|value| *value > Amount::ZERO)
+            .ok_or_else(|| anyhow!("Input value {} doesn't cover the fee", input_value))?;
+        let index = self.state.next_index;
+        let beneficiary_key = self
+            .state
+            .beneficiary_xpub
+            .derive_pub(&SECP, &derivation_path(index)?)?
+            .to_x_only_pub();
+        let output = InheritanceOutput {
+            index,
+            out_point: OutPoint::null(),
+            value,
+            internal_key: self.internal_signer(index)?.x_only_public_key()?,
+            beneficiary_key,
+            lock_time: absolute::LockTime::from_height(tip.height + config.lock_blocks)?,
+            created_height: tip.height,
+        };
+        let txout = output.txout()?;
+        Ok((output, txout))
+    }
+
+    fn set_pending(
+        &mut self,
+        mut output: InheritanceOutput,
+        tx: &Transaction,
+    ) -> anyhow::Result<()> {
+        output.out_point = OutPoint::new(tx.compute_txid(), 0);
+        self.state.next_index = output.index + 1;
+        self.state.pending = Some(PendingOutput {
+            output,
+            tx: tx.clone(),
+        });
+        self.save()
+    }
+
+    // The pending output confirmed, it's now the one to refresh.
+    fn advance(&mut self) -> anyhow::Result<()> {
+        if let Some(pending) = self.state.pending.take() {
+            self.state.current = Some(pending.output);
+        }
+        self.save()
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(&self.state)?)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Spends an inheritance output through its time locked leaf.
+pub struct BeneficiaryWallet {
+    master_xpriv: Xpriv,
+}
+
+impl BeneficiaryWallet {
+    pub fn new(master_xpriv: Xpriv) -> Self {
+        Self { master_xpriv }
+    }
+
+    /// The xpub to hand to the benefactor.
+    pub fn master_xpub(&self) -> Xpub {
+        Xpub::from_priv(&SECP, &self.master_xpriv)
+    }
+
+    /// Send the inheritance to `to`. Fails while the lock time is not reached at `tip`.
+    pub fn spend_inheritance(
+        &self,
+        output: &InheritanceOutput,
+        to: ScriptBuf,
+        fee: Amount,
+        tip: &ChainTip,
+    ) -> anyhow::Result<Transaction> {
+        let xpriv = self
+            .master_xpriv
+            .derive_priv(&SECP, &derivation_path(output.index)?)?;
+        let signer = InMemorySigner::from_private_key(&xpriv.to_priv());
+        if signer.x_only_public_key()? != output.beneficiary_key {
+            bail!("Inheritance output {} is not ours", output.out_point);
+        }
+        let value = output
+            .value
+            .checked_sub(fee)
+            .ok_or_else(|| anyhow!("Inheritance {} doesn't cover the fee", output.value))?;
+
+        let leaf = output.leaf();
+        let control_block = output
+            .spend_info()?
+            .control_block(&(leaf.to_script(), LeafVersion::TapScript))
+            .ok_or_else(|| anyhow!("Missing control block of inheritance leaf"))?;
+        let mut tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: output.out_point,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::default(),
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: to,
+            }],
+        };
+        leaf.sign_input(
+            &LeafSecrets {
+                signers: &[&signer],
+                preimage: None,
+            },
+            &control_block,
+            &mut tx,
+            0,
+            &[output.txout()?],
+            TapSighashType::Default,
+        )?;
+        check_final(&tx, tip, &[None])?;
+        Ok(tx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::ledger::LocalLedger;
+    use bitcoin::hashes::Hash;
+    use bitcoin::sighash::{Prevouts, SighashCache};
+    use rand::RngCore;
+    use secp256k1::Message;
+
+    const CONFIG: InheritanceConfig = InheritanceConfig {
+        lock_blocks: 100,
+        refresh_interval: 30,
+        fee: Amount::from_sat(1_000),
+    };
+
+    fn master(seed: u8) -> Xpriv {
+        Xpriv::new_master(Network::Regtest, &[seed; 32]).unwrap()
+    }
+
+    fn setup() -> (
+        LocalLedger,
+        BeneficiaryWallet,
+        (OutPoint, TxOut),
+        InMemorySigner,
+    ) {
+        let mut ledger = LocalLedger::new(ChainTip {
+            height: 200,
+            median_time_past: 1_700_000_000,
+        });
+        let funder = InMemorySigner::from_private_key(&master(1).to_priv());
+        let utxo = ledger.fund(
+            ScriptBuf::new_p2tr(&SECP, funder.x_only_public_key().unwrap(), None),
+            Amount::from_sat(100_000),
+        );
+        (ledger, BeneficiaryWallet::new(master(2)), utxo, funder)
+    }
+
+    fn poll(
+        benefactor: &mut BenefactorWallet,
+        ledger: &LocalLedger,
+    ) -> anyhow::Result<Option<Transaction>> {
+        let confirmation = benefactor
+            .pending()
+            .and_then(|pending| ledger.confirmation(&pending.output.out_point));
+        benefactor.poll(&ledger.tip(), confirmation)
+    }
+
+    #[test]
+    fn test_inheritance_refresh_and_spend() -> anyhow::Result<()> {
+        let (mut ledger, beneficiary, utxo, funder) = setup();
+        let mut benefactor = BenefactorWallet::new(
+            master(1),
+            beneficiary.master_xpub(),
+            Network::Regtest,
+            CONFIG,
+        )?;
+
+        let tx = benefactor.create_funding_tx(&ledger.tip(), &[utxo], &funder)?;
+        assert!(benefactor.current().is_none());
+        ledger.broadcast(&tx)?;
+        ledger.mine(1);
+        assert!(poll(&mut benefactor, &ledger)?.is_none());
+        let first = benefactor.current().unwrap().clone();
+        assert_eq!(first.lock_time, absolute::LockTime::from_height(300)?);
+        assert_eq!(benefactor.next_refresh_height(), Some(230));
+        assert_eq!(ledger.utxo(&first.out_point), Some(&first.txout()?));
+
+        // nothing to do until the refresh height.
+        ledger.mine(28);
+        assert!(poll(&mut benefactor, &ledger)?.is_none());
+        ledger.mine(1);
+        let refresh = poll(&mut benefactor, &ledger)?.unwrap();
+        ledger.broadcast(&refresh)?;
+        ledger.mine(1);
+        assert!(poll(&mut benefactor, &ledger)?.is_none());
+
+        // key path spend by the tweaked internal key.
+        let sighash = SighashCache::new(&refresh).taproot_key_spend_signature_hash(
+            0,
+            &Prevouts::All(&[first.txout()?]),
+            TapSighashType::Default,
+        )?;
+        let signature = bitcoin::taproot::Signature::from_slice(&refresh.input[0].witness[0])?;
+        SECP.verify_schnorr(
+            &signature.signature,
+            &Message::from_digest(sighash.to_byte_array()),
+            &first.spend_info()?.output_key().to_inner(),
+        )?;
+
+        let second = benefactor.current().unwrap().clone();
+        assert_eq!(second.index, 1);
+        assert_eq!(second.lock_time, absolute::LockTime::from_height(330)?);
+        assert_ne!(second.internal_key, first.internal_key);
+
+        // the benefactor disappears, the beneficiary waits for the lock time.
+        let to = ScriptBuf::new_p2tr(&SECP, second.beneficiary_key, None);
+        assert!(beneficiary
+            .spend_inheritance(&second, to.clone(), CONFIG.fee, &ledger.tip())
+            .is_err());
+        ledger.mine(330 - ledger.tip().height);
+        let spend = beneficiary.spend_inheritance(&second, to, CONFIG.fee, &ledger.tip())?;
+        assert_eq!(spend.lock_time, second.lock_time);
+        ledger.broadcast(&spend)?;
+
+        // someone else's output
+        assert!(BeneficiaryWallet::new(master(3))
+            .spend_inheritance(&second, ScriptBuf::new(), CONFIG.fee, &ledger.tip())
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_inheritance_refresh_never_mined() -> anyhow::Result<()> {
+        let (mut ledger, beneficiary, utxo, funder) = setup();
+        let mut benefactor = BenefactorWallet::new(
+            master(1),
+            beneficiary.master_xpub(),
+            Network::Regtest,
+            CONFIG,
+        )?;
+        let tx =
+            benefactor.create_funding_tx(&ledger.tip(), std::slice::from_ref(&utxo), &funder)?;
+        assert!(benefactor
+            .create_funding_tx(&ledger.tip(), &[utxo], &funder)
+            .is_err());
+        ledger.broadcast(&tx)?;
+        ledger.mine(1);
+        poll(&mut benefactor, &ledger)?;
+        let first = benefactor.current().unwrap().clone();
+
+        // the refresh is signed but never makes it into a block.
+        ledger.mine(29);
+        let refresh = poll(&mut benefactor, &ledger)?.unwrap();
+        ledger.mine(10);
+        assert_eq!(poll(&mut benefactor, &ledger)?, Some(refresh.clone()));
+        assert_eq!(benefactor.current(), Some(&first));
+        assert_eq!(
+            benefactor.pending().unwrap().output.out_point,
+            OutPoint::new(refresh.compute_txid(), 0)
+        );
+        assert!(benefactor.refresh_tx(&ledger.tip()).is_err());
+
+        // the beneficiary still spends the output that is actually on chain.
+        ledger.mine(300 - ledger.tip().height);
+        let to = ScriptBuf::new_p2tr(&SECP, first.beneficiary_key, None);
+        let spend = beneficiary.spend_inheritance(&first, to, CONFIG.fee, &ledger.tip())?;
+        ledger.broadcast(&spend)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_inheritance_state_persisted() -> anyhow::Result<()> {
+        let (ledger, beneficiary, utxo, funder) = setup();
+        let path = std::env::temp_dir().join(format!(
+            "inheritance-{}.json",
+            rand::thread_rng().next_u64()
+        ));
+        let mut benefactor = BenefactorWallet::create(
+            &path,
+            master(1),
+            beneficiary.master_xpub(),
+            Network::Regtest,
+            CONFIG,
+        )?;
+        benefactor.create_funding_tx(&ledger.tip(), &[utxo], &funder)?;
+
+        let reopened = BenefactorWallet::open(&path, master(1))?;
+        assert_eq!(reopened.state(), benefactor.state());
+        assert!(BenefactorWallet::create(
+            &path,
+            master(1),
+            beneficiary.master_xpub(),
+            Network::Regtest,
+            CONFIG
+        )
+        .is_err());
+        std::fs::remove_file(&path)?;
+
+        let invalid = InheritanceConfig {
+            refresh_interval: 100,
+            ..CONFIG
+        };
+        assert!(BenefactorWallet::new(
+            master(1),
+            beneficiary.master_xpub(),
+            Network::Regtest,
+            invalid
+        )
+        .is_err());
+        Ok(())
+    }
+}
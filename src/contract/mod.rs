@@ -0,0 +1,4 @@
+//! Spending policies built on top of `bitcoin_node::tx` leaf templates.
+pub mod htlc;
+pub mod inheritance;
+pub mod vault;
@@ -0,0 +1,404 @@
+//! Vault with presigned transactions, no covenant opcode needed.
+//!
+//! 1. deposit: a taproot key path output of a one time vault key;
+//! 2. unvault (presigned by the vault key): deposit -> staging output with two leaves,
+//!    - withdraw: `<delay> OP_CHECKSEQUENCEVERIFY OP_DROP <hot_key> OP_CHECKSIG`,
+//!    - clawback: `<vault_key> OP_CHECKSIG`;
+//! 3. clawback (presigned by the vault key): staging -> cold key, valid right away.
+//!
+//! The vault key is dropped once both transactions are signed, so the deposit can only go
+//! through the unvault and the staging output only to the hot key after `delay_blocks` or to
+//! the cold key. A `Watchtower` that sees an unvault it wasn't told about broadcasts the
+//! clawback during the delay.
+//!
+//! The clawback is signed `SIGHASH_SINGLE|ANYONECANPAY` like the presign demo, so the
+//! watchtower can attach an input and a change output to bump its fee. The unvault is signed
+//! `SIGHASH_DEFAULT`: its txid must not change, the clawback commits to it.
+use crate::bitcoin_node::signer::{sign_key_spend_input, InMemorySigner, Signer};
+use crate::bitcoin_node::tx::taproot_tree_tx::template::{LeafSecrets, LeafTemplate};
+use crate::bitcoin_node::tx::timelock::{check_final, ChainTip, Confirmation};
+use crate::SECP;
+use anyhow::{anyhow, bail};
+use bitcoin::secp256k1::Keypair;
+use bitcoin::taproot::{LeafVersion, TaprootBuilder, TaprootSpendInfo};
+use bitcoin::{
+    absolute, relative, transaction, Address, Amount, Network, OutPoint, ScriptBuf, Sequence,
+    TapSighashType, Transaction, TxIn, TxOut, Txid, Witness, XOnlyPublicKey,
+};
+use bitcoincore_rpc::RpcApi;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+// BIP-341 NUMS point `H`, nobody knows its secret key: disables the staging key path.
+const UNSPENDABLE_KEY: &str = "50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VaultConfig {
+    /// Key allowed to withdraw once the delay is over.
+    pub hot_key: XOnlyPublicKey,
+    /// Key receiving the funds on clawback.
+    pub cold_key: XOnlyPublicKey,
+    /// Blocks the staging output must wait before a withdrawal, the watchtower reaction time.
+    pub delay_blocks: u16,
+    /// Absolute fee of each unvault, clawback and withdraw transaction.
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub fee: Amount,
+}
+
+impl VaultConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.delay_blocks == 0 {
+            bail!("Vault delay must be at least one block");
+        }
+        if self.hot_key == self.cold_key {
+            bail!("Vault hot and cold keys must differ");
+        }
+        Ok(())
+    }
+
+    fn delay(&self) -> relative::LockTime {
+        relative::LockTime::from_height(self.delay_blocks)
+    }
+}
+
+/// A vault waiting for its deposit. It holds the one time vault key until `presign`.
+pub struct Vault {
+    config: VaultConfig,
+    vault_key: InMemorySigner,
+}
+
+impl Vault {
+    pub fn new(config: VaultConfig) -> anyhow::Result<Self> {
+        config.validate()?;
+        let keypair = Keypair::new(&SECP, &mut secp256k1::rand::thread_rng());
+        Ok(Self {
+            config,
+            vault_key: InMemorySigner::new(keypair),
+        })
+    }
+
+    pub fn config(&self) -> &VaultConfig {
+        &self.config
+    }
+
+    pub fn deposit_script_pubkey(&self) -> anyhow::Result<ScriptBuf> {
+        Ok(ScriptBuf::new_p2tr(
+            &SECP,
+            self.vault_key.x_only_public_key()?,
+            None,
+        ))
+    }
+
+    pub fn deposit_address(&self, network: Network) -> anyhow::Result<Address> {
+        Ok(Address::from_script(
+            &self.deposit_script_pubkey()?,
+            network,
+        )?)
+    }
+
+    /// Sign the unvault and clawback transactions of the deposit `out_point` and forget the
+    /// vault key. Only fund the deposit address once.
+    pub fn presign(self, out_point: OutPoint, txout: TxOut) -> anyhow::Result<PresignedVault> {
+        if txout.script_pubkey != self.deposit_script_pubkey()? {
+            bail!("Output {} doesn't pay the vault deposit address", out_point);
+        }
+        let vault_key = self.vault_key.x_only_public_key()?;
+        let staging = StagingOutput {
+            config: self.config,
+            vault_key,
+        };
+
+        let mut unvault_tx =
+            spend_tx(out_point, &txout, staging.script_pubkey()?, self.config.fee)?;
+        sign_key_spend_input(
+            &self.vault_key,
+            &mut unvault_tx,
+            0,
+            std::slice::from_ref(&txout),
+            TapSighashType::Default,
+            None,
+        )?;
+
+        let staging_txout = unvault_tx.output[0].clone();
+        let mut clawback_tx = spend_tx(
+            OutPoint::new(unvault_tx.compute_txid(), 0),
+            &staging_txout,
+            ScriptBuf::new_p2tr(&SECP, self.config.cold_key, None),
+            self.config.fee,
+        )?;
+        let leaf = staging.clawback_leaf();
+        leaf.sign_input(
+            &LeafSecrets {
+                signers: &[&self.vault_key],
+                preimage: None,
+            },
+            &staging.control_block(&leaf)?,
+            &mut clawback_tx,
+            0,
+            std::slice::from_ref(&staging_txout),
+            TapSighashType::SinglePlusAnyoneCanPay,
+        )?;
+
+        Ok(PresignedVault {
+            config: self.config,
+            vault_key,
+            deposit: out_point,
+            unvault_tx,
+            clawback_tx,
+        })
+    }
+}
+
+struct StagingOutput {
+    config: VaultConfig,
+    vault_key: XOnlyPublicKey,
+}
+
+impl StagingOutput {
+    fn withdraw_leaf(&self) -> LeafTemplate {
+        LeafTemplate::rel_time_lock(self.config.delay(), self.config.hot_key)
+    }
+
+    fn clawback_leaf(&self) -> LeafTemplate {
+        LeafTemplate::single_sig(self.vault_key)
+    }
+
+    fn spend_info(&self) -> anyhow::Result<TaprootSpendInfo> {
+        let builder = TaprootBuilder::new()
+            .add_leaf(1, self.withdraw_leaf().to_script())?
+            .add_leaf(1, self.clawback_leaf().to_script())?;
+        builder
+            .finalize(&SECP, XOnlyPublicKey::from_str(UNSPENDABLE_KEY)?)
+            .map_err(|_| anyhow!("Fail to finalize staging taproot tree"))
+    }
+
+    fn script_pubkey(&self) -> anyhow::Result<ScriptBuf> {
+        Ok(ScriptBuf::new_p2tr_tweaked(self.spend_info()?.output_key()))
+    }
+
+    fn control_block(&self, leaf: &LeafTemplate) -> anyhow::Result<bitcoin::taproot::ControlBlock> {
+        self.spend_info()?
+            .control_block(&(leaf.to_script(), LeafVersion::TapScript))
+            .ok_or_else(|| anyhow!("Missing control block of staging leaf"))
+    }
+}
+
+/// Everything needed to operate a funded vault, no secret in it: share it with watchtowers.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PresignedVault {
+    pub config: VaultConfig,
+    pub vault_key: XOnlyPublicKey,
+    pub deposit: OutPoint,
+    pub unvault_tx: Transaction,
+    pub clawback_tx: Transaction,
+}
+
+impl PresignedVault {
+    pub fn staging_out_point(&self) -> OutPoint {
+        OutPoint::new(self.unvault_tx.compute_txid(), 0)
+    }
+
+    pub fn staging_txout(&self) -> &TxOut {
+        &self.unvault_tx.output[0]
+    }
+
+    fn staging(&self) -> StagingOutput {
+        StagingOutput {
+            config: self.config,
+            vault_key: self.vault_key,
+        }
+    }
+
+    /// Spend the staging output to `to` with the hot key. Fails until the unvault has
+    /// `delay_blocks` confirmations, `confirmation` being where it was mined.
+    pub fn withdraw_tx(
+        &self,
+        to: ScriptBuf,
+        hot_key: &dyn Signer,
+        tip: &ChainTip,
+        confirmation: Option<Confirmation>,
+    ) -> anyhow::Result<Transaction> {
+        let staging = self.staging();
+        let leaf = staging.withdraw_leaf();
+        let mut tx = spend_tx(
+            self.staging_out_point(),
+            self.staging_txout(),
+            to,
+            self.config.fee,
+        )?;
+        leaf.sign_input(
+            &LeafSecrets {
+                signers: &[hot_key],
+                preimage: None,
+            },
+            &staging.control_block(&leaf)?,
+            &mut tx,
+            0,
+            std::slice::from_ref(self.staging_txout()),
+            TapSighashType::Default,
+        )?;
+        check_final(&tx, tip, &[confirmation])?;
+        Ok(tx)
+    }
+}
+
+/// Watch a vault and claw it back on any unvault the owner didn't announce.
+pub struct Watchtower {
+    vault: PresignedVault,
+    authorized: bool,
+}
+
+impl Watchtower {
+    pub fn new(vault: PresignedVault) -> Self {
+        Self {
+            vault,
+            authorized: false,
+        }
+    }
+
+    /// The owner is about to unvault, let it through.
+    pub fn authorize_unvault(&mut self) {
+        self.authorized = true;
+    }
+
+    /// The clawback to broadcast if `tx` is an unauthorized unvault.
+    pub fn check(&self, tx: &Transaction) -> Option<&Transaction> {
+        let is_unvault = tx
+            .input
+            .iter()
+            .any(|input| input.previous_output == self.vault.deposit);
+        (is_unvault && !self.authorized).then_some(&self.vault.clawback_tx)
+    }
+
+    /// Look for the staging output in the node mempool and utxo set, and broadcast the
+    /// clawback if the unvault wasn't authorized. Returns the clawback txid when sent.
+    pub fn poll_rpc(&self, rpc: &bitcoincore_rpc::Client) -> anyhow::Result<Option<Txid>> {
+        let staging = self.vault.staging_out_point();
+        if self.authorized
+            || rpc
+                .get_tx_out(&staging.txid, staging.vout, Some(true))?
+                .is_none()
+        {
+            return Ok(None);
+        }
+        Ok(Some(rpc.send_raw_transaction(&self.vault.clawback_tx)?))
+    }
+}
+
+fn spend_tx(
+    out_point: OutPoint,
+    txout: &TxOut,
+    to: ScriptBuf,
+    fee: Amount,
+) -> anyhow::Result<Transaction> {
+    let value = txout
+        .value
+        .checked_sub(fee)
+        .ok_or_else(|| anyhow!("Vault value {} doesn't cover the fee", txout.value))?;
+    Ok(Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: out_point,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::default(),
+        }],
+        output: vec![TxOut {
+            value,
+            script_pubkey: to,
+        }],
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::ledger::LocalLedger;
+    use crate::bitcoin_node::tx::{USER_A_PRIVATE_KEY, USER_B_PRIVATE_KEY};
+
+    struct Setup {
+        ledger: LocalLedger,
+        vault: PresignedVault,
+        hot: InMemorySigner,
+    }
+
+    fn setup() -> anyhow::Result<Setup> {
+        let hot = InMemorySigner::from_xpriv_str(USER_A_PRIVATE_KEY)?;
+        let cold = InMemorySigner::from_xpriv_str(USER_B_PRIVATE_KEY)?;
+        let vault = Vault::new(VaultConfig {
+            hot_key: hot.x_only_public_key()?,
+            cold_key: cold.x_only_public_key()?,
+            delay_blocks: 6,
+            fee: Amount::from_sat(500),
+        })?;
+        let mut ledger = LocalLedger::new(ChainTip {
+            height: 1_000,
+            median_time_past: 1_700_000_000,
+        });
+        let (out_point, txout) =
+            ledger.fund(vault.deposit_script_pubkey()?, Amount::from_sat(100_000));
+        let vault = vault.presign(out_point, txout)?;
+        Ok(Setup { ledger, vault, hot })
+    }
+
+    #[test]
+    fn test_vault_withdraw_after_delay() -> anyhow::Result<()> {
+        let Setup {
+            mut ledger,
+            vault,
+            hot,
+        } = setup()?;
+        let mut watchtower = Watchtower::new(vault.clone());
+        watchtower.authorize_unvault();
+        assert!(watchtower.check(&vault.unvault_tx).is_none());
+
+        ledger.broadcast(&vault.unvault_tx)?;
+        ledger.mine(1);
+        let confirmation = ledger.confirmation(&vault.staging_out_point());
+        assert!(confirmation.is_some());
+
+        let to = ScriptBuf::new_p2tr(&SECP, hot.x_only_public_key()?, None);
+        ledger.mine(4);
+        assert!(vault
+            .withdraw_tx(to.clone(), &hot, &ledger.tip(), confirmation)
+            .is_err());
+        ledger.mine(1);
+        let withdraw = vault.withdraw_tx(to, &hot, &ledger.tip(), confirmation)?;
+        assert_eq!(withdraw.input[0].sequence, Sequence::from_height(6));
+        ledger.broadcast(&withdraw)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_watchtower_claws_back_unauthorized_unvault() -> anyhow::Result<()> {
+        let Setup {
+            mut ledger, vault, ..
+        } = setup()?;
+        let watchtower = Watchtower::new(vault.clone());
+        ledger.broadcast(&vault.unvault_tx)?;
+
+        let clawback = watchtower
+            .check(&vault.unvault_tx)
+            .ok_or_else(|| anyhow!("Unvault not detected"))?;
+        // not time locked, it goes in the same block as the unvault.
+        ledger.broadcast(clawback)?;
+        assert_eq!(
+            clawback.output[0].script_pubkey,
+            ScriptBuf::new_p2tr(&SECP, vault.config.cold_key, None)
+        );
+        assert_eq!(
+            clawback.input[0].witness.len(),
+            3,
+            "signature, leaf script and control block"
+        );
+
+        // the staging output is gone, the hot key has nothing left to withdraw.
+        ledger.mine(6);
+        assert!(ledger.utxo(&vault.staging_out_point()).is_none());
+
+        let json = serde_json::to_string(&vault)?;
+        assert_eq!(serde_json::from_str::<PresignedVault>(&json)?, vault);
+        Ok(())
+    }
+}
@@ -1,18 +1,19 @@
+use crate::SECP;
+use anyhow::bail;
 use bitcoin::address::AddressData::P2sh;
 use bitcoin::bip32::Xpriv;
 use bitcoin::hashes::{hash160, Hash};
-use bitcoin::opcodes::all::OP_CHECKSIG;
+use bitcoin::opcodes::all::{OP_CHECKMULTISIG, OP_CHECKSIG};
+use bitcoin::taproot::TaprootSpendInfo;
 use bitcoin::{
     Address, CompressedPublicKey, KnownHrp, Network, PrivateKey, PubkeyHash, PublicKey, Script,
     ScriptBuf,
 };
-use lazy_static::lazy_static;
 use secp256k1::XOnlyPublicKey;
 use std::str::FromStr;
 
-lazy_static! {
-    static ref SECP: secp256k1::Secp256k1<secp256k1::All> = secp256k1::Secp256k1::new();
-}
+// Standard bare and P2SH multisig limit.
+const MAX_MULTISIG_KEYS: usize = 15;
 
 pub struct Keygen;
 impl Keygen {
@@ -42,19 +43,55 @@ impl Keygen {
     ////////////////////////////////////////////////
     //////////// gen address from public key
     ////////////////////////////////////////////////
-    pub(crate) fn p2sh_addr_from_pk(
-        public_key: &PublicKey,
-        network: Network,
-    ) -> anyhow::Result<Address> {
-        // TODO: needs test.
-        // Create a P2WSH address
-        let p2pk_script = Script::builder()
-            .push_key(&public_key)
+    /// P2SH of the P2PK redeem script `<pk> OP_CHECKSIG`, spent with `sign_p2sh_input`.
+    pub fn p2sh_addr_from_pk(public_key: &PublicKey, network: Network) -> anyhow::Result<Address> {
+        let addr = Address::p2sh(&Self::p2pk_script(public_key), network)?;
+        Ok(addr)
+    }
+
+    /// `<pk> OP_CHECKSIG` for legacy and segwit v0 scripts. Not for tapscript leaves: there a
+    /// 33 byte key is an unknown key type that any signature satisfies, use
+    /// `LeafTemplate::single_sig` with the x-only key instead.
+    pub fn p2pk_script(public_key: &PublicKey) -> ScriptBuf {
+        Script::builder()
+            .push_key(public_key)
             .push_opcode(OP_CHECKSIG)
-            .into_script();
+            .into_script()
+    }
 
-        let addr = Address::p2sh(&p2pk_script, network)?;
-        Ok(addr)
+    /// `OP_<threshold> <keys> OP_<n> OP_CHECKMULTISIG`, as redeem or witness script.
+    pub fn multisig_script(threshold: usize, keys: &[PublicKey]) -> anyhow::Result<ScriptBuf> {
+        if threshold == 0 || threshold > keys.len() || keys.len() > MAX_MULTISIG_KEYS {
+            bail!("Invalid {} of {} multisig", threshold, keys.len());
+        }
+        let mut builder = Script::builder().push_int(threshold as i64);
+        for key in keys {
+            builder = builder.push_key(key);
+        }
+        Ok(builder
+            .push_int(keys.len() as i64)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script())
+    }
+
+    /// P2SH `threshold` of `keys` multisig, with its redeem script.
+    pub fn p2sh_multisig_addr(
+        threshold: usize,
+        keys: &[PublicKey],
+        network: Network,
+    ) -> anyhow::Result<(Address, ScriptBuf)> {
+        let redeem_script = Self::multisig_script(threshold, keys)?;
+        Ok((Address::p2sh(&redeem_script, network)?, redeem_script))
+    }
+
+    /// Nested segwit, P2WPKH wrapped in P2SH.
+    pub fn p2sh_p2wpkh_addr_from_pk(pk: &PublicKey, network: Network) -> anyhow::Result<Address> {
+        let pk = CompressedPublicKey::try_from(*pk)?;
+        Ok(Address::p2shwpkh(&pk, network))
+    }
+
+    pub fn p2wsh_addr_from_script(witness_script: &Script, network: Network) -> Address {
+        Address::p2wsh(witness_script, network)
     }
 
     pub fn p2wpkh_addr_from_pk(pk: &PublicKey, network: Network) -> anyhow::Result<Address> {
@@ -74,10 +111,16 @@ impl Keygen {
         let addr = Address::p2tr(&SECP, internal_key, None, KnownHrp::from(network));
         Ok(addr)
     }
+
+    /// P2TR committing to the script tree of `spend_info`.
+    pub fn p2tr_addr_from_tree(spend_info: &TaprootSpendInfo, network: Network) -> Address {
+        Address::p2tr_tweaked(spend_info.output_key(), KnownHrp::from(network))
+    }
 }
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::bitcoin_node::taproot_tree_tx::template::LeafTemplate;
 
     #[test]
     fn test_gen_regtest() {
@@ -154,4 +197,61 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_script_address_types() -> anyhow::Result<()> {
+        // Vectors from BIP49 (nested segwit) and BIP173 (p2wsh of `<G> OP_CHECKSIG`).
+        let pk = PublicKey::from_str(
+            "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+        )?;
+        let p2sh_p2wpkh = Keygen::p2sh_p2wpkh_addr_from_pk(&pk, Network::Bitcoin)?;
+        assert_eq!(
+            p2sh_p2wpkh.to_string(),
+            "3JvL6Ymt8MVWiCNHC7oWU6nLeHNJKLZGLN"
+        );
+        let p2wsh = Keygen::p2wsh_addr_from_script(&Keygen::p2pk_script(&pk), Network::Bitcoin);
+        assert_eq!(
+            p2wsh.to_string(),
+            "bc1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qccfmv3"
+        );
+
+        let p2sh = Keygen::p2sh_addr_from_pk(&pk, Network::Bitcoin)?;
+        let p2pk = Keygen::p2pk_script(&pk);
+        assert_eq!(
+            p2sh.script_pubkey(),
+            ScriptBuf::new_p2sh(&p2pk.script_hash())
+        );
+        assert_eq!(p2pk.p2pk_public_key(), Some(pk));
+
+        let keys: Vec<PublicKey> = (1..=3u8)
+            .map(|i| {
+                let sk = secp256k1::SecretKey::from_slice(&[i; 32]).unwrap();
+                PublicKey::new(sk.public_key(&SECP))
+            })
+            .collect();
+        let (addr, redeem_script) = Keygen::p2sh_multisig_addr(2, &keys, Network::Regtest)?;
+        assert!(addr.is_spend_standard());
+        assert_eq!(
+            addr.script_pubkey(),
+            ScriptBuf::new_p2sh(&redeem_script.script_hash())
+        );
+        assert!(redeem_script.is_multisig());
+        assert!(Keygen::multisig_script(0, &keys).is_err());
+        assert!(Keygen::multisig_script(4, &keys).is_err());
+
+        let leaf = |key: &PublicKey| LeafTemplate::single_sig(key.inner.into()).to_script();
+        assert_eq!(leaf(&keys[0]).len(), 34);
+        let tree = bitcoin::taproot::TaprootBuilder::new()
+            .add_leaf(1, leaf(&keys[0]))?
+            .add_leaf(1, leaf(&keys[1]))?
+            .finalize(&SECP, keys[2].into())
+            .unwrap();
+        let p2tr = Keygen::p2tr_addr_from_tree(&tree, Network::Regtest);
+        assert_eq!(
+            p2tr.script_pubkey(),
+            ScriptBuf::new_p2tr_tweaked(tree.output_key())
+        );
+        assert_ne!(p2tr, Keygen::p2tr_addr_from_pk(keys[2], Network::Regtest)?);
+        Ok(())
+    }
 }
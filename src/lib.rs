@@ -1,3 +1,13 @@
 pub mod bitcoin_node;
+pub mod contract;
 pub mod keygen;
 pub mod mempool;
+pub mod musig2;
+pub mod silent_payments;
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// Context shared by the whole crate, creating one is costly.
+    pub(crate) static ref SECP: secp256k1::Secp256k1<secp256k1::All> = secp256k1::Secp256k1::new();
+}
@@ -0,0 +1,412 @@
+//! Broadcast with pre-flight checks, so a transaction the network won't take fails here with a
+//! reason instead of a bare Esplora error, or silently never propagates.
+//!
+//! Before sending, `Broadcaster` checks that the inputs exist and are unspent, verifies the
+//! taproot inputs locally (`verify_taproot_input`, libbitcoinconsensus predates taproot and the
+//! other inputs are left to the node), that the transaction is final at the tip, has no dust
+//! output and pays the min relay fee. With a bitcoind at hand it asks `testmempoolaccept` too.
+//!
+//! A failed check is a `Rejection` inside the `anyhow::Error`, match it with
+//! `error.downcast_ref::<Rejection>()`.
+use crate::bitcoin_node::tx::interpreter::verify_taproot_input;
+use crate::bitcoin_node::tx::rbf::fee_vb;
+use crate::bitcoin_node::tx::timelock::{check_final, ChainTip, Confirmation};
+use bitcoin::{Amount, FeeRate, OutPoint, Transaction, TxOut, Txid};
+use bitcoincore_rpc::RpcApi;
+use esplora_client::AsyncClient;
+use std::fmt;
+use std::future::Future;
+
+/// bitcoind `-minrelaytxfee` default, 1 sat/vB.
+pub const DEFAULT_MIN_RELAY_FEE: FeeRate = FeeRate::from_sat_per_kwu(250);
+// Blocks in the median time past (BIP-113).
+const MEDIAN_TIME_SPAN: u32 = 11;
+
+/// Why a transaction wouldn't be accepted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// Inputs unknown or already spent.
+    MissingInputs(Vec<OutPoint>),
+    MinRelayFee {
+        fee: Amount,
+        required: Amount,
+    },
+    /// Lock time or sequence lock not reached at the tip.
+    NonFinal(String),
+    /// Outputs below their dust threshold.
+    Dust(Vec<u32>),
+    /// Invalid spend of an input, or outputs exceeding inputs.
+    Consensus(String),
+    /// Another `testmempoolaccept` reject reason.
+    Mempool(String),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::MissingInputs(out_points) => {
+                write!(f, "Missing or spent inputs: ")?;
+                for (i, out_point) in out_points.iter().enumerate() {
+                    write!(f, "{}{}", if i > 0 { ", " } else { "" }, out_point)?;
+                }
+                Ok(())
+            }
+            Rejection::MinRelayFee { fee, required } => {
+                write!(f, "Min relay fee not met: {} < {}", fee, required)
+            }
+            Rejection::NonFinal(reason) => write!(f, "Non final: {}", reason),
+            Rejection::Dust(vouts) => write!(f, "Dust outputs: {:?}", vouts),
+            Rejection::Consensus(reason) => write!(f, "Consensus: {}", reason),
+            Rejection::Mempool(reason) => write!(f, "Rejected by mempool: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+impl Rejection {
+    /// Map a bitcoind reject reason of `tx`.
+    fn from_reject_reason(reason: &str, tx: &Transaction) -> Self {
+        match reason {
+            "missing-inputs" | "bad-txns-inputs-missingorspent" => Rejection::MissingInputs(
+                tx.input.iter().map(|input| input.previous_output).collect(),
+            ),
+            "non-final" | "non-BIP68-final" => Rejection::NonFinal(reason.to_string()),
+            "dust" => Rejection::Dust(dust_outputs(tx)),
+            _ => {
+                // "min relay fee not met, <fee> < <required>", in sat.
+                let fees = reason
+                    .strip_prefix("min relay fee not met, ")
+                    .and_then(|fees| fees.split_once(" < "))
+                    .and_then(|(fee, required)| Some((fee.parse().ok()?, required.parse().ok()?)));
+                match fees {
+                    Some((fee, required)) => Rejection::MinRelayFee {
+                        fee: Amount::from_sat(fee),
+                        required: Amount::from_sat(required),
+                    },
+                    None => Rejection::Mempool(reason.to_string()),
+                }
+            }
+        }
+    }
+}
+
+fn dust_outputs(tx: &Transaction) -> Vec<u32> {
+    tx.output
+        .iter()
+        .enumerate()
+        .filter(|(_, txout)| txout.value < txout.script_pubkey.minimal_non_dust())
+        .map(|(vout, _)| vout as u32)
+        .collect()
+}
+
+/// An unspent output, with where it was confirmed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Prevout {
+    pub txout: TxOut,
+    pub confirmation: Option<Confirmation>,
+}
+
+/// The chain to check against and broadcast to, Esplora or a test double.
+pub trait BroadcastBackend {
+    /// `None` when `out_point` is unknown or spent.
+    fn prevout(
+        &self,
+        out_point: &OutPoint,
+    ) -> impl Future<Output = anyhow::Result<Option<Prevout>>>;
+    fn tip(&self) -> impl Future<Output = anyhow::Result<ChainTip>>;
+    fn send(&self, tx: &Transaction) -> impl Future<Output = anyhow::Result<()>>;
+}
+
+/// Esplora has no median time past, it's computed from the block headers.
+async fn median_time_past(client: &AsyncClient, height: u32) -> anyhow::Result<u32> {
+    let mut times = vec![];
+    for height in height.saturating_sub(MEDIAN_TIME_SPAN - 1)..=height {
+        let hash = client.get_block_hash(height).await?;
+        times.push(client.get_header_by_hash(&hash).await?.time);
+    }
+    times.sort();
+    Ok(times[times.len() / 2])
+}
+
+impl BroadcastBackend for AsyncClient {
+    async fn prevout(&self, out_point: &OutPoint) -> anyhow::Result<Option<Prevout>> {
+        let Some(tx) = self.get_tx(&out_point.txid).await? else {
+            return Ok(None);
+        };
+        let Some(txout) = tx.output.get(out_point.vout as usize) else {
+            return Ok(None);
+        };
+        let status = self
+            .get_output_status(&out_point.txid, out_point.vout as u64)
+            .await?;
+        if status.is_some_and(|status| status.spent) {
+            return Ok(None);
+        }
+        let confirmation = match self.get_tx_status(&out_point.txid).await?.block_height {
+            Some(height) => Some(Confirmation {
+                height,
+                prev_median_time_past: median_time_past(self, height.saturating_sub(1)).await?,
+            }),
+            None => None,
+        };
+        Ok(Some(Prevout {
+            txout: txout.clone(),
+            confirmation,
+        }))
+    }
+
+    async fn tip(&self) -> anyhow::Result<ChainTip> {
+        let height = self.get_height().await?;
+        Ok(ChainTip {
+            height,
+            median_time_past: median_time_past(self, height).await?,
+        })
+    }
+
+    async fn send(&self, tx: &Transaction) -> anyhow::Result<()> {
+        Ok(self.broadcast(tx).await?)
+    }
+}
+
+pub struct Broadcaster<'a, B> {
+    backend: &'a B,
+    rpc: Option<&'a bitcoincore_rpc::Client>,
+    min_relay_fee: FeeRate,
+}
+
+impl<'a, B: BroadcastBackend> Broadcaster<'a, B> {
+    pub fn new(backend: &'a B) -> Self {
+        Self {
+            backend,
+            rpc: None,
+            min_relay_fee: DEFAULT_MIN_RELAY_FEE,
+        }
+    }
+
+    /// Also run `testmempoolaccept` on `rpc`, a node of the same network.
+    pub fn with_rpc(mut self, rpc: &'a bitcoincore_rpc::Client) -> Self {
+        self.rpc = Some(rpc);
+        self
+    }
+
+    pub fn with_min_relay_fee(mut self, min_relay_fee: FeeRate) -> Self {
+        self.min_relay_fee = min_relay_fee;
+        self
+    }
+
+    /// Run the pre-flight checks without broadcasting.
+    pub async fn check(&self, tx: &Transaction) -> anyhow::Result<()> {
+        let mut prevouts = vec![];
+        let mut missing = vec![];
+        for input in &tx.input {
+            match self.backend.prevout(&input.previous_output).await? {
+                Some(prevout) => prevouts.push(prevout),
+                None => missing.push(input.previous_output),
+            }
+        }
+        if !missing.is_empty() {
+            return Err(Rejection::MissingInputs(missing).into());
+        }
+
+        let input_value: Amount = prevouts.iter().map(|p| p.txout.value).sum();
+        let output_value: Amount = tx.output.iter().map(|txout| txout.value).sum();
+        let Some(fee) = input_value.checked_sub(output_value) else {
+            let reason = format!("outputs {} exceed inputs {}", output_value, input_value);
+            return Err(Rejection::Consensus(reason).into());
+        };
+        let txouts: Vec<TxOut> = prevouts.iter().map(|p| p.txout.clone()).collect();
+        for (i, txout) in txouts.iter().enumerate() {
+            if txout.script_pubkey.is_p2tr() {
+                verify_taproot_input(tx, i, &txouts)
+                    .map_err(|e| Rejection::Consensus(format!("input {}: {}", i, e)))?;
+            }
+        }
+
+        let tip = self.backend.tip().await?;
+        let confirmations: Vec<_> = prevouts.iter().map(|p| p.confirmation).collect();
+        check_final(tx, &tip, &confirmations).map_err(|e| Rejection::NonFinal(e.to_string()))?;
+
+        let dust = dust_outputs(tx);
+        if !dust.is_empty() {
+            return Err(Rejection::Dust(dust).into());
+        }
+        let required = fee_vb(self.min_relay_fee, tx.vsize() as u64)?;
+        if fee < required {
+            return Err(Rejection::MinRelayFee { fee, required }.into());
+        }
+
+        if let Some(rpc) = self.rpc {
+            let result = rpc.test_mempool_accept(&[tx])?;
+            if let Some(reason) = result.first().and_then(|r| r.reject_reason.as_ref()) {
+                return Err(Rejection::from_reject_reason(reason, tx).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Check and broadcast `tx`.
+    pub async fn broadcast(&self, tx: &Transaction) -> anyhow::Result<Txid> {
+        self.check(tx).await?;
+        self.backend.send(tx).await?;
+        Ok(tx.compute_txid())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::ledger::LocalLedger;
+    use crate::bitcoin_node::signer::{sign_key_spend_input, InMemorySigner, Signer};
+    use crate::bitcoin_node::tx::{USER_A_PRIVATE_KEY, USER_B_PRIVATE_KEY};
+    use crate::SECP;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{absolute, transaction, ScriptBuf, Sequence, TapSighashType, TxIn, Witness};
+    use std::cell::RefCell;
+
+    struct LedgerBackend(RefCell<LocalLedger>);
+
+    impl BroadcastBackend for LedgerBackend {
+        async fn prevout(&self, out_point: &OutPoint) -> anyhow::Result<Option<Prevout>> {
+            let ledger = self.0.borrow();
+            Ok(ledger.utxo(out_point).map(|txout| Prevout {
+                txout: txout.clone(),
+                confirmation: ledger.confirmation(out_point),
+            }))
+        }
+
+        async fn tip(&self) -> anyhow::Result<ChainTip> {
+            Ok(self.0.borrow().tip())
+        }
+
+        async fn send(&self, tx: &Transaction) -> anyhow::Result<()> {
+            self.0.borrow_mut().broadcast(tx)?;
+            Ok(())
+        }
+    }
+
+    fn rejection(result: anyhow::Result<Txid>) -> Rejection {
+        result.unwrap_err().downcast::<Rejection>().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_preflight_rejections() -> anyhow::Result<()> {
+        let signer = InMemorySigner::from_xpriv_str(USER_A_PRIVATE_KEY)?;
+        let other = InMemorySigner::from_xpriv_str(USER_B_PRIVATE_KEY)?;
+        let script_pubkey = ScriptBuf::new_p2tr(&SECP, signer.x_only_public_key()?, None);
+        let mut ledger = LocalLedger::new(ChainTip {
+            height: 800_000,
+            median_time_past: 1_700_000_000,
+        });
+        let (out_point, funding) = ledger.fund(script_pubkey.clone(), Amount::from_sat(10_000));
+        let backend = LedgerBackend(RefCell::new(ledger));
+        let broadcaster = Broadcaster::new(&backend);
+
+        let spend = |value, lock_time, signer: &InMemorySigner| -> anyhow::Result<Transaction> {
+            let mut tx = Transaction {
+                version: transaction::Version::TWO,
+                lock_time,
+                input: vec![TxIn {
+                    previous_output: out_point,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    witness: Witness::default(),
+                }],
+                output: vec![TxOut {
+                    value: Amount::from_sat(value),
+                    script_pubkey: script_pubkey.clone(),
+                }],
+            };
+            sign_key_spend_input(
+                signer,
+                &mut tx,
+                0,
+                std::slice::from_ref(&funding),
+                TapSighashType::Default,
+                None,
+            )?;
+            Ok(tx)
+        };
+        let now = absolute::LockTime::ZERO;
+
+        let mut unknown = spend(9_000, now, &signer)?;
+        unknown.input[0].previous_output = OutPoint::new(Txid::all_zeros(), 0);
+        assert_eq!(
+            rejection(broadcaster.broadcast(&unknown).await),
+            Rejection::MissingInputs(vec![OutPoint::new(Txid::all_zeros(), 0)])
+        );
+        assert!(matches!(
+            rejection(broadcaster.broadcast(&spend(9_000, now, &other)?).await),
+            Rejection::Consensus(_)
+        ));
+        assert!(matches!(
+            rejection(broadcaster.broadcast(&spend(11_000, now, &signer)?).await),
+            Rejection::Consensus(_)
+        ));
+        let later = absolute::LockTime::from_height(800_010)?;
+        assert!(matches!(
+            rejection(broadcaster.broadcast(&spend(9_000, later, &signer)?).await),
+            Rejection::NonFinal(_)
+        ));
+        assert_eq!(
+            rejection(broadcaster.broadcast(&spend(100, now, &signer)?).await),
+            Rejection::Dust(vec![0])
+        );
+        let tx = spend(9_990, now, &signer)?;
+        assert_eq!(
+            rejection(broadcaster.broadcast(&tx).await),
+            Rejection::MinRelayFee {
+                fee: Amount::from_sat(10),
+                required: Amount::from_sat(tx.vsize() as u64),
+            }
+        );
+
+        let tx = spend(9_000, now, &signer)?;
+        assert_eq!(broadcaster.broadcast(&tx).await?, tx.compute_txid());
+        assert!(backend.0.borrow().in_mempool(&tx.compute_txid()));
+        assert_eq!(
+            rejection(broadcaster.broadcast(&tx).await),
+            Rejection::MissingInputs(vec![out_point])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_reasons() {
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![],
+            output: vec![TxOut {
+                value: Amount::from_sat(1),
+                script_pubkey: ScriptBuf::new_p2tr(
+                    &SECP,
+                    InMemorySigner::from_xpriv_str(USER_A_PRIVATE_KEY)
+                        .unwrap()
+                        .x_only_public_key()
+                        .unwrap(),
+                    None,
+                ),
+            }],
+        };
+        assert_eq!(
+            Rejection::from_reject_reason("min relay fee not met, 110 < 141", &tx),
+            Rejection::MinRelayFee {
+                fee: Amount::from_sat(110),
+                required: Amount::from_sat(141),
+            }
+        );
+        assert_eq!(
+            Rejection::from_reject_reason("dust", &tx),
+            Rejection::Dust(vec![0])
+        );
+        assert_eq!(
+            Rejection::from_reject_reason("non-BIP68-final", &tx),
+            Rejection::NonFinal("non-BIP68-final".to_string())
+        );
+        assert_eq!(
+            Rejection::from_reject_reason("txn-mempool-conflict", &tx),
+            Rejection::Mempool("txn-mempool-conflict".to_string())
+        );
+    }
+}
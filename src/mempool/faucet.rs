@@ -21,10 +21,10 @@ pub struct FaucetClient {
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
-struct FaucetResponse {
-    txid: Txid,
+pub struct FaucetResponse {
+    pub txid: Txid,
     // address: Address,
-    address: String,
+    pub address: String,
 }
 
 impl FaucetClient {
@@ -43,11 +43,8 @@ impl FaucetClient {
             reqwest::header::CONTENT_TYPE,
             "application/json".parse().unwrap(),
         );
-        headers.insert(reqwest::header::ORIGIN, ESPLORA_FAUCET_URL.parse().unwrap());
-        headers.insert(
-            reqwest::header::REFERER,
-            ESPLORA_FAUCET_URL.parse().unwrap(),
-        );
+        headers.insert(reqwest::header::ORIGIN, self.url.parse()?);
+        headers.insert(reqwest::header::REFERER, self.url.parse()?);
 
         let data = json!({
             "sats": amount,
@@ -56,7 +53,7 @@ impl FaucetClient {
 
         let resp = self
             .client
-            .post(&format!("{}/api/onchain", ESPLORA_FAUCET_URL))
+            .post(&format!("{}/api/onchain", self.url))
             .headers(headers)
             .json(&data)
             .send()
@@ -1,3 +1,5 @@
-mod client;
-mod faucet;
+pub mod broadcast;
+pub mod client;
+pub mod faucet;
+pub mod scanner;
 mod tx;
@@ -0,0 +1,627 @@
+//! Wallet scanner over Esplora: walks the addresses of an HD account with a gap limit and keeps
+//! their transaction history, utxos and balance.
+//!
+//! The receive and change chains are `.../0/*` and `.../1/*` next to the account derivation
+//! path, `m/86'/1'/0'/0/0` scans `m/86'/1'/0'/0/*` and `m/86'/1'/0'/1/*`, as BIP-86 p2tr
+//! addresses. A chain is walked until `gap_limit` addresses in a row have no history.
+//!
+//! `sync` only fetches what's new: the addresses with history are refreshed and the walk goes on
+//! after the last used index, the history of an address is paged until a confirmed transaction
+//! already known. Unconfirmed transactions gone from the mempool are dropped, as are confirmed
+//! ones whose block is no longer in the best chain. `full_scan` walks again from index 0.
+//! `ScanState` is serializable to resume from it.
+//!
+//! Reference: https://github.com/Blockstream/esplora/blob/master/API.md#addresses
+use crate::bitcoin_node::account::BitcoinAccount;
+use crate::SECP;
+use anyhow::{anyhow, bail};
+use bitcoin::bip32::{ChildNumber, DerivationPath, Xpriv, Xpub};
+use bitcoin::{
+    Address, Amount, BlockHash, Network, OutPoint, Script, ScriptBuf, Transaction, TxOut, Txid,
+};
+use esplora_client::AsyncClient;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::future::Future;
+
+/// BIP-44 default gap limit.
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Keychain {
+    /// Receive addresses, `.../0/*`.
+    External,
+    /// Change addresses, `.../1/*`.
+    Internal,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct HistoryTx {
+    pub tx: Transaction,
+    /// Confirmation height, `None` in the mempool.
+    pub height: Option<u32>,
+    /// Hash of the confirming block, to notice a reorg.
+    #[serde(default)]
+    pub block_hash: Option<BlockHash>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WalletUtxo {
+    pub out_point: OutPoint,
+    pub txout: TxOut,
+    pub keychain: Keychain,
+    pub index: u32,
+    pub height: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Balance {
+    pub confirmed: Amount,
+    pub unconfirmed: Amount,
+}
+
+impl Balance {
+    pub fn total(&self) -> Amount {
+        self.confirmed + self.unconfirmed
+    }
+}
+
+/// What a sync knows, to resume from.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScanState {
+    /// Highest index with history, per keychain. `sync` goes on walking after it.
+    pub last_used: BTreeMap<Keychain, u32>,
+    pub transactions: BTreeMap<Txid, HistoryTx>,
+}
+
+/// Where the history of a script comes from, Esplora or a test double.
+pub trait ChainSource {
+    /// Most confirmed transactions in a page, a shorter page is the last one.
+    const PAGE_SIZE: usize;
+
+    /// History of `script`, newest first: mempool transactions and a page of confirmed ones,
+    /// or the confirmed ones after `last_seen`.
+    fn script_history(
+        &self,
+        script: &Script,
+        last_seen: Option<Txid>,
+    ) -> impl Future<Output = anyhow::Result<Vec<HistoryTx>>>;
+
+    /// Hash of the block at `height` in the best chain.
+    fn block_hash(&self, height: u32) -> impl Future<Output = anyhow::Result<BlockHash>>;
+}
+
+impl ChainSource for AsyncClient {
+    // `/scripthash/:hash/txs/chain`
+    const PAGE_SIZE: usize = 25;
+
+    async fn script_history(
+        &self,
+        script: &Script,
+        last_seen: Option<Txid>,
+    ) -> anyhow::Result<Vec<HistoryTx>> {
+        let txs = self.scripthash_txs(script, last_seen).await?;
+        Ok(txs
+            .iter()
+            .map(|tx| HistoryTx {
+                tx: tx.to_tx(),
+                height: tx.status.block_height,
+                block_hash: tx.status.block_hash,
+            })
+            .collect())
+    }
+
+    // `/block-height/:height`
+    async fn block_hash(&self, height: u32) -> anyhow::Result<BlockHash> {
+        Ok(self.get_block_hash(height).await?)
+    }
+}
+
+pub struct WalletScanner {
+    network: Network,
+    chains: [(Keychain, Xpub); 2],
+    gap_limit: u32,
+    state: ScanState,
+}
+
+impl WalletScanner {
+    pub fn new(account: &BitcoinAccount, network: Network, gap_limit: u32) -> anyhow::Result<Self> {
+        let derivation = account
+            .derivation
+            .as_ref()
+            .ok_or_else(|| anyhow!("Account has no HD derivation to scan"))?;
+        let path: &[ChildNumber] = derivation.path.as_ref();
+        if path.len() < 2 {
+            bail!("Derivation path {} has no chain level", derivation.path);
+        }
+        let account_path = DerivationPath::from(&path[..path.len() - 2]);
+        let chain = |keychain: Keychain, index| -> anyhow::Result<(Keychain, Xpub)> {
+            let path = account_path.child(ChildNumber::from_normal_idx(index)?);
+            let xpriv: Xpriv = derivation.master.derive_priv(&SECP, &path)?;
+            Ok((keychain, Xpub::from_priv(&SECP, &xpriv)))
+        };
+        Ok(Self {
+            network,
+            chains: [chain(Keychain::External, 0)?, chain(Keychain::Internal, 1)?],
+            gap_limit,
+            state: ScanState::default(),
+        })
+    }
+
+    /// Resume from a previous `state()`.
+    pub fn with_state(mut self, state: ScanState) -> Self {
+        self.state = state;
+        self
+    }
+
+    pub fn state(&self) -> &ScanState {
+        &self.state
+    }
+
+    pub fn script_pubkey(&self, keychain: Keychain, index: u32) -> anyhow::Result<ScriptBuf> {
+        let (_, xpub) = self
+            .chains
+            .iter()
+            .find(|(chain, _)| *chain == keychain)
+            .unwrap();
+        let key = xpub.derive_pub(&SECP, &[ChildNumber::from_normal_idx(index)?])?;
+        Ok(ScriptBuf::new_p2tr(&SECP, key.to_x_only_pub(), None))
+    }
+
+    pub fn address(&self, keychain: Keychain, index: u32) -> anyhow::Result<Address> {
+        Ok(Address::from_script(
+            &self.script_pubkey(keychain, index)?,
+            self.network,
+        )?)
+    }
+
+    /// First address after the last used one of `keychain`.
+    pub fn next_unused_address(&self, keychain: Keychain) -> anyhow::Result<Address> {
+        let next = self
+            .state
+            .last_used
+            .get(&keychain)
+            .map_or(0, |last| last + 1);
+        self.address(keychain, next)
+    }
+
+    /// Fetch the new history: the addresses already used, then both chains from their last used
+    /// index on. Unused addresses below it aren't asked for again, see `full_scan`.
+    pub async fn sync<C: ChainSource>(&mut self, source: &C) -> anyhow::Result<()> {
+        let mut seen = HashSet::new();
+        for (keychain, index) in self.used_indexes()? {
+            let script = self.script_pubkey(keychain, index)?;
+            self.sync_script(source, &script, &mut seen).await?;
+        }
+        for (keychain, _) in self.chains {
+            let start = self
+                .state
+                .last_used
+                .get(&keychain)
+                .map_or(0, |last| last + 1);
+            self.walk(source, keychain, start, &mut seen).await?;
+        }
+        self.drop_stale(source, &seen).await
+    }
+
+    /// Walk both chains from index 0, for addresses handed out but paid only after a later one.
+    pub async fn full_scan<C: ChainSource>(&mut self, source: &C) -> anyhow::Result<()> {
+        let mut seen = HashSet::new();
+        for (keychain, _) in self.chains {
+            self.walk(source, keychain, 0, &mut seen).await?;
+        }
+        self.drop_stale(source, &seen).await
+    }
+
+    async fn walk<C: ChainSource>(
+        &mut self,
+        source: &C,
+        keychain: Keychain,
+        mut index: u32,
+        seen: &mut HashSet<Txid>,
+    ) -> anyhow::Result<()> {
+        let mut gap = 0;
+        while gap < self.gap_limit {
+            let script = self.script_pubkey(keychain, index)?;
+            self.sync_script(source, &script, seen).await?;
+            if self.is_used(&script) {
+                let last = self.state.last_used.entry(keychain).or_insert(index);
+                *last = (*last).max(index);
+                gap = 0;
+            } else {
+                gap += 1;
+            }
+            index += 1;
+        }
+        Ok(())
+    }
+
+    /// Drop unconfirmed transactions the sync didn't see (evicted or replaced) and confirmed ones
+    /// it didn't see whose block was reorged out.
+    async fn drop_stale<C: ChainSource>(
+        &mut self,
+        source: &C,
+        seen: &HashSet<Txid>,
+    ) -> anyhow::Result<()> {
+        let mut best_chain = HashMap::new();
+        let mut stale = vec![];
+        for (txid, history_tx) in &self.state.transactions {
+            if seen.contains(txid) {
+                continue;
+            }
+            let Some(height) = history_tx.height else {
+                stale.push(*txid);
+                continue;
+            };
+            let block_hash = match best_chain.get(&height) {
+                Some(block_hash) => *block_hash,
+                None => {
+                    let block_hash = source.block_hash(height).await?;
+                    best_chain.insert(height, block_hash);
+                    block_hash
+                }
+            };
+            if history_tx.block_hash != Some(block_hash) {
+                stale.push(*txid);
+            }
+        }
+        for txid in stale {
+            self.state.transactions.remove(&txid);
+        }
+        Ok(())
+    }
+
+    async fn sync_script<C: ChainSource>(
+        &mut self,
+        source: &C,
+        script: &Script,
+        seen: &mut HashSet<Txid>,
+    ) -> anyhow::Result<()> {
+        let mut last_seen = None;
+        loop {
+            let page = source.script_history(script, last_seen).await?;
+            let mut confirmed = 0;
+            let mut known = false;
+            for history_tx in page {
+                let txid = history_tx.tx.compute_txid();
+                if history_tx.height.is_some() {
+                    confirmed += 1;
+                    last_seen = Some(txid);
+                    known |= self.state.transactions.get(&txid) == Some(&history_tx);
+                }
+                seen.insert(txid);
+                self.state.transactions.insert(txid, history_tx);
+            }
+            if known || confirmed < C::PAGE_SIZE {
+                return Ok(());
+            }
+        }
+    }
+
+    fn is_used(&self, script: &Script) -> bool {
+        self.state.transactions.values().any(|history_tx| {
+            history_tx
+                .tx
+                .output
+                .iter()
+                .any(|txout| txout.script_pubkey.as_script() == script)
+        })
+    }
+
+    /// Indexes paid by a known transaction.
+    fn used_indexes(&self) -> anyhow::Result<BTreeSet<(Keychain, u32)>> {
+        let scripts = self.scripts()?;
+        Ok(self
+            .state
+            .transactions
+            .values()
+            .flat_map(|history_tx| &history_tx.tx.output)
+            .filter_map(|txout| scripts.get(&txout.script_pubkey).copied())
+            .collect())
+    }
+
+    /// Scripts of both chains up to the gap limit after their last used index.
+    fn scripts(&self) -> anyhow::Result<HashMap<ScriptBuf, (Keychain, u32)>> {
+        let mut scripts = HashMap::new();
+        for (keychain, _) in &self.chains {
+            let end = self
+                .state
+                .last_used
+                .get(keychain)
+                .map_or(self.gap_limit, |last| last + 1 + self.gap_limit);
+            for index in 0..end {
+                scripts.insert(self.script_pubkey(*keychain, index)?, (*keychain, index));
+            }
+        }
+        Ok(scripts)
+    }
+
+    pub fn utxos(&self) -> anyhow::Result<Vec<WalletUtxo>> {
+        let scripts = self.scripts()?;
+        let spent: HashSet<OutPoint> = self
+            .state
+            .transactions
+            .values()
+            .flat_map(|history_tx| {
+                history_tx
+                    .tx
+                    .input
+                    .iter()
+                    .map(|input| input.previous_output)
+            })
+            .collect();
+
+        let mut utxos = vec![];
+        for (txid, history_tx) in &self.state.transactions {
+            for (vout, txout) in history_tx.tx.output.iter().enumerate() {
+                let out_point = OutPoint::new(*txid, vout as u32);
+                match scripts.get(&txout.script_pubkey) {
+                    Some((keychain, index)) if !spent.contains(&out_point) => {
+                        utxos.push(WalletUtxo {
+                            out_point,
+                            txout: txout.clone(),
+                            keychain: *keychain,
+                            index: *index,
+                            height: history_tx.height,
+                        })
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(utxos)
+    }
+
+    pub fn balance(&self) -> anyhow::Result<Balance> {
+        let mut balance = Balance::default();
+        for utxo in self.utxos()? {
+            match utxo.height {
+                Some(_) => balance.confirmed += utxo.txout.value,
+                None => balance.unconfirmed += utxo.txout.value,
+            }
+        }
+        Ok(balance)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::tx::USER_A_PRIVATE_KEY;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{absolute, transaction, Sequence, TxIn, Witness};
+    use std::cell::RefCell;
+    use std::str::FromStr;
+
+    /// Esplora-like history with pages of 2 confirmed transactions.
+    #[derive(Default)]
+    struct FakeChain {
+        history: RefCell<Vec<HistoryTx>>,
+        requests: RefCell<Vec<(ScriptBuf, Option<Txid>)>>,
+        /// Heights whose block was replaced by a reorg.
+        reorged: RefCell<HashSet<u32>>,
+    }
+
+    fn block(height: u32, fork: u8) -> BlockHash {
+        let mut hash = [fork; 32];
+        hash[..4].copy_from_slice(&height.to_le_bytes());
+        BlockHash::from_byte_array(hash)
+    }
+
+    impl ChainSource for FakeChain {
+        const PAGE_SIZE: usize = 2;
+
+        async fn script_history(
+            &self,
+            script: &Script,
+            last_seen: Option<Txid>,
+        ) -> anyhow::Result<Vec<HistoryTx>> {
+            self.requests
+                .borrow_mut()
+                .push((script.to_owned(), last_seen));
+            let history: Vec<HistoryTx> = self
+                .history
+                .borrow()
+                .iter()
+                .rev()
+                .filter(|history_tx| {
+                    let tx = &history_tx.tx;
+                    tx.output
+                        .iter()
+                        .any(|o| o.script_pubkey.as_script() == script)
+                })
+                .cloned()
+                .collect();
+            let (mempool, confirmed): (Vec<_>, Vec<_>) =
+                history.into_iter().partition(|h| h.height.is_none());
+            let start = match last_seen {
+                Some(txid) => {
+                    confirmed
+                        .iter()
+                        .position(|h| h.tx.compute_txid() == txid)
+                        .unwrap()
+                        + 1
+                }
+                None => 0,
+            };
+            let page = confirmed.into_iter().skip(start).take(Self::PAGE_SIZE);
+            match last_seen {
+                Some(_) => Ok(page.collect()),
+                None => Ok(mempool.into_iter().chain(page).collect()),
+            }
+        }
+
+        async fn block_hash(&self, height: u32) -> anyhow::Result<BlockHash> {
+            Ok(block(height, self.reorged.borrow().contains(&height) as u8))
+        }
+    }
+
+    fn pay(n: u8, script_pubkey: ScriptBuf, sats: u64, height: Option<u32>) -> HistoryTx {
+        HistoryTx {
+            tx: Transaction {
+                version: transaction::Version::TWO,
+                lock_time: absolute::LockTime::ZERO,
+                input: vec![TxIn {
+                    previous_output: OutPoint::new(Txid::from_byte_array([n; 32]), 0),
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::default(),
+                }],
+                output: vec![TxOut {
+                    value: Amount::from_sat(sats),
+                    script_pubkey,
+                }],
+            },
+            height,
+            block_hash: height.map(|height| block(height, 0)),
+        }
+    }
+
+    fn account() -> BitcoinAccount {
+        BitcoinAccount::from_xpriv(
+            Xpriv::from_str(USER_A_PRIVATE_KEY).unwrap(),
+            DerivationPath::from_str("m/86'/1'/0'/0/0").unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_scan_gap_limit_balance_and_resync() -> anyhow::Result<()> {
+        let account = account();
+        let mut scanner = WalletScanner::new(&account, Network::Regtest, 5)?;
+        assert_eq!(
+            scanner.script_pubkey(Keychain::External, 0)?,
+            ScriptBuf::new_p2tr(&SECP, account.public_key.inner.x_only_public_key().0, None)
+        );
+        let keys = WalletScanner::new(&account, Network::Regtest, 5)?;
+        let receive = |i| keys.script_pubkey(Keychain::External, i).unwrap();
+        let change = |i| keys.script_pubkey(Keychain::Internal, i).unwrap();
+
+        let chain = FakeChain::default();
+        let funding = pay(1, receive(0), 50_000, Some(100));
+        let funding_out_point = OutPoint::new(funding.tx.compute_txid(), 0);
+        // spends the funding, 30k to someone else and 19k of change.
+        let mut spend = pay(2, change(0), 19_000, Some(101));
+        spend.tx.input[0].previous_output = funding_out_point;
+        spend.tx.output.insert(
+            0,
+            TxOut {
+                value: Amount::from_sat(30_000),
+                script_pubkey: ScriptBuf::new_op_return([]),
+            },
+        );
+        *chain.history.borrow_mut() = vec![
+            funding,
+            pay(3, receive(0), 1_000, Some(102)),
+            pay(4, receive(0), 2_000, Some(103)),
+            spend,
+            pay(5, receive(4), 7_000, None),
+            // past the gap limit after receive(4).
+            pay(6, receive(10), 100_000, Some(104)),
+        ];
+
+        scanner.sync(&chain).await?;
+        assert_eq!(scanner.state().last_used[&Keychain::External], 4);
+        assert_eq!(scanner.state().last_used[&Keychain::Internal], 0);
+        assert_eq!(
+            scanner.next_unused_address(Keychain::External)?,
+            scanner.address(Keychain::External, 5)?
+        );
+        assert_eq!(
+            scanner.balance()?,
+            Balance {
+                confirmed: Amount::from_sat(22_000),
+                unconfirmed: Amount::from_sat(7_000),
+            }
+        );
+        assert_eq!(scanner.utxos()?.len(), 4);
+        // receive(0) has 3 confirmed transactions: two pages of 2.
+        let pages = |chain: &FakeChain| {
+            chain
+                .requests
+                .borrow()
+                .iter()
+                .filter(|(script, _)| *script == receive(0))
+                .count()
+        };
+        assert_eq!(pages(&chain), 2);
+
+        // resume from the saved state: the mempool transaction is gone, a new one came in.
+        let state: ScanState = serde_json::from_str(&serde_json::to_string(scanner.state())?)?;
+        let mut scanner = WalletScanner::new(&account, Network::Regtest, 5)?.with_state(state);
+        chain.history.borrow_mut().remove(4);
+        chain
+            .history
+            .borrow_mut()
+            .push(pay(7, receive(0), 3_000, Some(105)));
+        chain.requests.borrow_mut().clear();
+        scanner.sync(&chain).await?;
+        assert_eq!(pages(&chain), 1, "stops at a known confirmed transaction");
+        assert_eq!(
+            scanner.balance()?,
+            Balance {
+                confirmed: Amount::from_sat(25_000),
+                unconfirmed: Amount::ZERO,
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sync_resumes_after_last_used_and_drops_reorged() -> anyhow::Result<()> {
+        let mut scanner = WalletScanner::new(&account(), Network::Regtest, 3)?;
+        let keys = WalletScanner::new(&account(), Network::Regtest, 3)?;
+        let receive = |i| keys.script_pubkey(Keychain::External, i).unwrap();
+
+        let chain = FakeChain::default();
+        let funding = pay(1, receive(0), 10_000, Some(100));
+        *chain.history.borrow_mut() = vec![
+            funding.clone(),
+            pay(2, receive(0), 20_000, Some(101)),
+            pay(3, receive(0), 30_000, Some(102)),
+            pay(4, receive(2), 40_000, Some(102)),
+        ];
+        scanner.sync(&chain).await?;
+        assert_eq!(scanner.state().last_used[&Keychain::External], 2);
+        assert_eq!(scanner.balance()?.confirmed, Amount::from_sat(100_000));
+
+        // block 100 is reorged out with the funding, block 103 pays receive(0) again.
+        chain.history.borrow_mut().remove(0);
+        chain.reorged.borrow_mut().insert(100);
+        chain
+            .history
+            .borrow_mut()
+            .push(pay(5, receive(0), 50_000, Some(103)));
+        chain.requests.borrow_mut().clear();
+        scanner.sync(&chain).await?;
+
+        let requested: Vec<ScriptBuf> = chain
+            .requests
+            .borrow()
+            .iter()
+            .map(|(script, _)| script.clone())
+            .collect();
+        // the used addresses, then 3..6 on the receive chain and 0..3 on the change chain.
+        assert_eq!(requested.len(), 2 + 3 + 3);
+        assert_eq!(requested[..2], [receive(0), receive(2)]);
+        assert!(!requested.contains(&receive(1)), "unused below the cursor");
+
+        // receive(0) stopped at a known transaction before the funding, the block hash drops it.
+        assert!(!scanner
+            .state()
+            .transactions
+            .contains_key(&funding.tx.compute_txid()));
+        assert_eq!(scanner.state().transactions.len(), 4);
+        assert_eq!(scanner.balance()?.confirmed, Amount::from_sat(140_000));
+
+        // a full scan asks for index 1 again.
+        chain.requests.borrow_mut().clear();
+        scanner.full_scan(&chain).await?;
+        assert!(chain
+            .requests
+            .borrow()
+            .iter()
+            .any(|(script, _)| *script == receive(1)));
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 #[cfg(test)]
 mod test {
-    use std::str::FromStr;
-
+    use crate::bitcoin_node::address::parse_address;
+    use crate::mempool::broadcast::{Broadcaster, Rejection};
     use crate::mempool::client::CLIENT;
     use bitcoin::hashes::Hash;
     use bitcoin::key::{Keypair, TapTweak, TweakedKeypair, UntweakedPublicKey};
@@ -90,7 +90,13 @@ mod test {
         // BOOM! Transaction signed and ready to broadcast.
         println!("{:#?}", tx);
 
-        CLIENT.broadcast(tx).await.unwrap();
+        // The dummy input doesn't exist: rejected before reaching Esplora.
+        let client = CLIENT;
+        let error = Broadcaster::new(&*client).broadcast(tx).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Rejection>(),
+            Some(Rejection::MissingInputs(_))
+        ));
     }
 
     /// An example of keys controlled by the transaction sender.
@@ -103,14 +109,14 @@ mod test {
 
     /// A dummy address for the receiver.
     ///
-    /// We lock the spend output to the key associated with this address.
-    ///
-    /// (FWIW this is an arbitrary mainnet address from block 805222.)
+    /// We lock the spend output to the key associated with this address. `CLIENT` talks to
+    /// mutinynet, so the address has to be a signet one.
     fn receivers_address() -> Address {
-        Address::from_str("bc1p0dq0tzg2r780hldthn5mrznmpxsxc0jux5f20fwj0z3wqxxk6fpqm7q0va")
-            .expect("a valid address")
-            .require_network(Network::Bitcoin)
-            .expect("valid address for mainnet")
+        parse_address(
+            "tb1ql9mjwcp9swms3hm6kyvp832myv4ujmqcpmn7az",
+            Network::Signet,
+        )
+        .expect("valid address for signet")
     }
 
     /// Creates a p2wpkh output locked to the key associated with `wpkh`.
@@ -0,0 +1,870 @@
+//! MuSig2 (BIP-327) n-of-n key aggregation and signing, so a group of signers can own the
+//! internal key of a taproot output and spend it cooperatively through the key path.
+//!
+//! Flow for every signer `i`:
+//!   1. `KeyAggContext::new(all_pubkeys)`, then `with_taproot_tweak(merkle_root)`;
+//!   2. `nonce_gen(..)`, send the `PubNonce` to the others, `nonce_agg(all_pubnonces)`;
+//!   3. `Session::new(&ctx, &agg_nonce, sighash)`, `session.partial_sign(secnonce, sk)`;
+//!   4. whoever collects all partial signatures calls `session.aggregate(..)`.
+//!
+//! Reference: https://github.com/bitcoin/bips/blob/master/bip-0327.mediawiki
+use crate::SECP;
+use anyhow::{anyhow, bail};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::key::TapTweak;
+use bitcoin::taproot::{TapNodeHash, TapTweakHash, TaprootBuilder, TaprootSpendInfo};
+use rand::RngCore;
+use secp256k1::{schnorr, PublicKey, Scalar, SecretKey, XOnlyPublicKey};
+
+// The order of the secp256k1 group.
+const CURVE_ORDER: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+    0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x41,
+];
+
+pub(crate) fn tagged_hash(tag: &str, chunks: &[&[u8]]) -> [u8; 32] {
+    let tag_hash = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag_hash.as_ref());
+    engine.input(tag_hash.as_ref());
+    for chunk in chunks {
+        engine.input(chunk);
+    }
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+/// An integer modulo the curve order. libsecp256k1 can't represent zero as a `SecretKey`,
+/// so zero is kept as `None`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct ModN(Option<SecretKey>);
+
+impl ModN {
+    const ZERO: ModN = ModN(None);
+
+    fn one() -> ModN {
+        ModN(Some(
+            SecretKey::from_slice(&Scalar::ONE.to_be_bytes()).unwrap(),
+        ))
+    }
+
+    /// Interpret 32 bytes as a big endian integer and reduce it modulo n.
+    fn reduce(mut bytes: [u8; 32]) -> ModN {
+        if bytes >= CURVE_ORDER {
+            // bytes < 2^256 < 2n, so one subtraction is enough.
+            let mut borrow = 0i16;
+            for i in (0..32).rev() {
+                let v = bytes[i] as i16 - CURVE_ORDER[i] as i16 - borrow;
+                borrow = (v < 0) as i16;
+                bytes[i] = v.rem_euclid(256) as u8;
+            }
+        }
+        ModN(SecretKey::from_slice(&bytes).ok())
+    }
+
+    /// Parse a scalar that must already be below n.
+    fn from_canonical(bytes: [u8; 32]) -> anyhow::Result<ModN> {
+        if bytes >= CURVE_ORDER {
+            bail!("Scalar out of range");
+        }
+        Ok(ModN(SecretKey::from_slice(&bytes).ok()))
+    }
+
+    fn to_bytes(self) -> [u8; 32] {
+        self.0.map(|sk| sk.secret_bytes()).unwrap_or([0; 32])
+    }
+
+    fn add(self, other: ModN) -> ModN {
+        match (self.0, other.0) {
+            (None, _) => other,
+            (_, None) => self,
+            (Some(a), Some(b)) => ModN(a.add_tweak(&Scalar::from(b)).ok()),
+        }
+    }
+
+    fn mul(self, other: ModN) -> ModN {
+        match (self.0, other.0) {
+            (Some(a), Some(b)) => ModN(a.mul_tweak(&Scalar::from(b)).ok()),
+            _ => ModN::ZERO,
+        }
+    }
+
+    fn negate(self) -> ModN {
+        ModN(self.0.map(SecretKey::negate))
+    }
+
+    /// `1` or `n - 1`.
+    fn sign(even: bool) -> ModN {
+        if even {
+            ModN::one()
+        } else {
+            ModN::one().negate()
+        }
+    }
+}
+
+/// A curve point, `None` being the point at infinity.
+type Point = Option<PublicKey>;
+
+fn point_add(a: Point, b: Point) -> Point {
+    match (a, b) {
+        (None, _) => b,
+        (_, None) => a,
+        (Some(a), Some(b)) => a.combine(&b).ok(),
+    }
+}
+
+fn point_mul(p: Point, k: ModN) -> Point {
+    match (p, k.0) {
+        (Some(p), Some(k)) => p.mul_tweak(&SECP, &Scalar::from(k)).ok(),
+        _ => None,
+    }
+}
+
+fn generator_mul(k: ModN) -> Point {
+    k.0.map(|sk| sk.public_key(&SECP))
+}
+
+fn has_even_y(p: &PublicKey) -> bool {
+    p.serialize()[0] == 0x02
+}
+
+fn xbytes(p: &PublicKey) -> [u8; 32] {
+    p.x_only_public_key().0.serialize()
+}
+
+// `cbytes_ext`/`cpoint_ext`: 33 zero bytes stand for the point at infinity.
+fn cbytes_ext(p: Point) -> [u8; 33] {
+    p.map(|p| p.serialize()).unwrap_or([0; 33])
+}
+
+fn cpoint_ext(bytes: &[u8]) -> anyhow::Result<Point> {
+    if bytes.iter().all(|b| *b == 0) {
+        return Ok(None);
+    }
+    Ok(Some(PublicKey::from_slice(bytes)?))
+}
+
+/// The aggregated key of a set of signers, plus the tweaks applied on top of it.
+#[derive(Clone, Debug)]
+pub struct KeyAggContext {
+    pubkeys: Vec<PublicKey>,
+    q: PublicKey,
+    gacc: [u8; 32],
+    tacc: [u8; 32],
+}
+
+impl KeyAggContext {
+    /// `KeyAgg` of BIP-327. The order of `pubkeys` matters, every signer must use the same one.
+    pub fn new(pubkeys: Vec<PublicKey>) -> anyhow::Result<Self> {
+        if pubkeys.is_empty() {
+            bail!("No public keys to aggregate");
+        }
+        let mut q: Point = None;
+        for pk in &pubkeys {
+            let a = Self::coefficient(&pubkeys, pk);
+            q = point_add(q, point_mul(Some(*pk), a));
+        }
+        let q = q.ok_or_else(|| anyhow!("Aggregated key is the point at infinity"))?;
+        Ok(Self {
+            pubkeys,
+            q,
+            gacc: ModN::one().to_bytes(),
+            tacc: ModN::ZERO.to_bytes(),
+        })
+    }
+
+    fn coefficient(pubkeys: &[PublicKey], pk: &PublicKey) -> ModN {
+        let serialized: Vec<[u8; 33]> = pubkeys.iter().map(|pk| pk.serialize()).collect();
+        let second_key = pubkeys.iter().find(|k| *k != &pubkeys[0]);
+        if Some(pk) == second_key {
+            return ModN::one();
+        }
+        let list = tagged_hash("KeyAgg list", &[&serialized.concat()]);
+        ModN::reduce(tagged_hash("KeyAgg coefficient", &[&list, &pk.serialize()]))
+    }
+
+    pub fn pubkeys(&self) -> &[PublicKey] {
+        &self.pubkeys
+    }
+
+    /// The (possibly tweaked) aggregated public key.
+    pub fn aggregated_pubkey(&self) -> PublicKey {
+        self.q
+    }
+
+    pub fn x_only_public_key(&self) -> XOnlyPublicKey {
+        self.q.x_only_public_key().0
+    }
+
+    /// `ApplyTweak` of BIP-327. `is_xonly` selects an x-only (taproot) tweak over a plain one.
+    pub fn apply_tweak(&self, tweak: [u8; 32], is_xonly: bool) -> anyhow::Result<Self> {
+        let g = ModN::sign(!is_xonly || has_even_y(&self.q));
+        let t = ModN::from_canonical(tweak)?;
+        let q = point_add(point_mul(Some(self.q), g), generator_mul(t))
+            .ok_or_else(|| anyhow!("Tweaked key is the point at infinity"))?;
+        let gacc = g.mul(ModN::reduce(self.gacc));
+        let tacc = t.add(g.mul(ModN::reduce(self.tacc)));
+        Ok(Self {
+            pubkeys: self.pubkeys.clone(),
+            q,
+            gacc: gacc.to_bytes(),
+            tacc: tacc.to_bytes(),
+        })
+    }
+
+    /// Tweak the aggregated key into a taproot output key committing to `merkle_root`
+    /// (`None` for a key path only output, as in BIP-86).
+    pub fn with_taproot_tweak(&self, merkle_root: Option<TapNodeHash>) -> anyhow::Result<Self> {
+        let internal_key = self.x_only_public_key();
+        let tweak = TapTweakHash::from_key_and_tweak(internal_key, merkle_root).to_scalar();
+        let tweaked = self.apply_tweak(tweak.to_be_bytes(), true)?;
+
+        let (expected, _) = internal_key.tap_tweak(&SECP, merkle_root);
+        if tweaked.x_only_public_key() != expected.to_inner() {
+            bail!("Taproot tweak mismatch");
+        }
+        Ok(tweaked)
+    }
+
+    /// Use the aggregated key as internal key of a taproot tree.
+    pub fn taproot_spend_info(&self, builder: TaprootBuilder) -> anyhow::Result<TaprootSpendInfo> {
+        builder
+            .finalize(&SECP, self.x_only_public_key())
+            .map_err(|_| anyhow!("Fail to finalize taproot tree"))
+    }
+}
+
+/// Secret nonce. It is consumed by `Session::partial_sign` and wiped on drop, so it can't be
+/// reused by accident.
+pub struct SecNonce {
+    k1: SecretKey,
+    k2: SecretKey,
+    pk: PublicKey,
+}
+
+impl Drop for SecNonce {
+    fn drop(&mut self) {
+        self.k1.non_secure_erase();
+        self.k2.non_secure_erase();
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PubNonce(pub [u8; 66]);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AggNonce(pub [u8; 66]);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PartialSignature(pub [u8; 32]);
+
+/// `NonceGen` of BIP-327 with fresh randomness. `agg_pk` and `msg` are optional but make
+/// nonce reuse even less likely.
+pub fn nonce_gen(
+    sk: Option<&SecretKey>,
+    pk: &PublicKey,
+    agg_pk: Option<&XOnlyPublicKey>,
+    msg: Option<&[u8]>,
+    extra_in: Option<&[u8]>,
+) -> anyhow::Result<(SecNonce, PubNonce)> {
+    let mut rand = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut rand);
+    nonce_gen_with_rand(rand, sk, pk, agg_pk, msg, extra_in)
+}
+
+/// `NonceGen` with caller provided randomness. Never call it twice with the same `rand`.
+pub fn nonce_gen_with_rand(
+    rand: [u8; 32],
+    sk: Option<&SecretKey>,
+    pk: &PublicKey,
+    agg_pk: Option<&XOnlyPublicKey>,
+    msg: Option<&[u8]>,
+    extra_in: Option<&[u8]>,
+) -> anyhow::Result<(SecNonce, PubNonce)> {
+    let rand = match sk {
+        Some(sk) => {
+            let aux = tagged_hash("MuSig/aux", &[&rand]);
+            let mut masked = sk.secret_bytes();
+            masked.iter_mut().zip(aux).for_each(|(b, a)| *b ^= a);
+            masked
+        }
+        None => rand,
+    };
+    let pk_bytes = pk.serialize();
+    let agg_pk_bytes = agg_pk.map(|k| k.serialize().to_vec()).unwrap_or_default();
+    let msg_prefixed = match msg {
+        Some(m) => [&[1u8][..], &(m.len() as u64).to_be_bytes(), m].concat(),
+        None => vec![0u8],
+    };
+    let extra_in = extra_in.unwrap_or_default();
+
+    let k = |i: u8| {
+        ModN::reduce(tagged_hash(
+            "MuSig/nonce",
+            &[
+                &rand,
+                &[pk_bytes.len() as u8],
+                &pk_bytes,
+                &[agg_pk_bytes.len() as u8],
+                &agg_pk_bytes,
+                &msg_prefixed,
+                &(extra_in.len() as u32).to_be_bytes(),
+                extra_in,
+                &[i],
+            ],
+        ))
+        .0
+        .ok_or_else(|| anyhow!("Nonce is zero"))
+    };
+    let (k1, k2) = (k(0)?, k(1)?);
+
+    let mut pubnonce = [0u8; 66];
+    pubnonce[..33].copy_from_slice(&k1.public_key(&SECP).serialize());
+    pubnonce[33..].copy_from_slice(&k2.public_key(&SECP).serialize());
+    Ok((SecNonce { k1, k2, pk: *pk }, PubNonce(pubnonce)))
+}
+
+/// `NonceAgg` of BIP-327.
+pub fn nonce_agg(pubnonces: &[PubNonce]) -> anyhow::Result<AggNonce> {
+    let mut r1: Point = None;
+    let mut r2: Point = None;
+    for (i, nonce) in pubnonces.iter().enumerate() {
+        let parse = |b: &[u8]| {
+            PublicKey::from_slice(b).map_err(|_| anyhow!("Invalid public nonce of signer {}", i))
+        };
+        r1 = point_add(r1, Some(parse(&nonce.0[..33])?));
+        r2 = point_add(r2, Some(parse(&nonce.0[33..])?));
+    }
+    let mut aggnonce = [0u8; 66];
+    aggnonce[..33].copy_from_slice(&cbytes_ext(r1));
+    aggnonce[33..].copy_from_slice(&cbytes_ext(r2));
+    Ok(AggNonce(aggnonce))
+}
+
+/// Everything needed to produce, check and combine partial signatures over one message.
+pub struct Session {
+    ctx: KeyAggContext,
+    b: ModN,
+    r: PublicKey,
+    e: ModN,
+}
+
+impl Session {
+    pub fn new(ctx: &KeyAggContext, aggnonce: &AggNonce, msg: &[u8]) -> anyhow::Result<Self> {
+        let q_bytes = xbytes(&ctx.q);
+        let b = ModN::reduce(tagged_hash(
+            "MuSig/noncecoef",
+            &[&aggnonce.0, &q_bytes, msg],
+        ));
+        let r1 = cpoint_ext(&aggnonce.0[..33])?;
+        let r2 = cpoint_ext(&aggnonce.0[33..])?;
+        let r =
+            point_add(r1, point_mul(r2, b)).unwrap_or_else(|| generator_mul(ModN::one()).unwrap());
+        let e = ModN::reduce(tagged_hash(
+            "BIP0340/challenge",
+            &[&xbytes(&r), &q_bytes, msg],
+        ));
+        Ok(Self {
+            ctx: ctx.clone(),
+            b,
+            r,
+            e,
+        })
+    }
+
+    fn key_coefficient(&self, pk: &PublicKey) -> anyhow::Result<ModN> {
+        if !self.ctx.pubkeys.contains(pk) {
+            bail!("Public key is not part of the aggregated key");
+        }
+        Ok(KeyAggContext::coefficient(&self.ctx.pubkeys, pk))
+    }
+
+    /// `Sign` of BIP-327.
+    pub fn partial_sign(
+        &self,
+        secnonce: SecNonce,
+        sk: &SecretKey,
+    ) -> anyhow::Result<PartialSignature> {
+        let pk = sk.public_key(&SECP);
+        if pk != secnonce.pk {
+            bail!("Secret nonce was generated for another key");
+        }
+        let r_even = has_even_y(&self.r);
+        let k1 = ModN(Some(secnonce.k1));
+        let k2 = ModN(Some(secnonce.k2));
+        let (k1, k2) = if r_even {
+            (k1, k2)
+        } else {
+            (k1.negate(), k2.negate())
+        };
+
+        let a = self.key_coefficient(&pk)?;
+        let g = ModN::sign(has_even_y(&self.ctx.q));
+        let d = g.mul(ModN::reduce(self.ctx.gacc)).mul(ModN(Some(*sk)));
+        let s = k1.add(self.b.mul(k2)).add(self.e.mul(a).mul(d));
+        Ok(PartialSignature(s.to_bytes()))
+    }
+
+    /// `PartialSigVerify` of BIP-327, to blame a signer before aggregating.
+    pub fn partial_verify(
+        &self,
+        psig: &PartialSignature,
+        pubnonce: &PubNonce,
+        pk: &PublicKey,
+    ) -> anyhow::Result<()> {
+        let s = ModN::from_canonical(psig.0)?;
+        let r1 = PublicKey::from_slice(&pubnonce.0[..33])?;
+        let r2 = PublicKey::from_slice(&pubnonce.0[33..])?;
+        let mut re = point_add(Some(r1), point_mul(Some(r2), self.b));
+        if !has_even_y(&self.r) {
+            re = re.map(|p| p.negate(&SECP));
+        }
+        let a = self.key_coefficient(pk)?;
+        let g = ModN::sign(has_even_y(&self.ctx.q)).mul(ModN::reduce(self.ctx.gacc));
+        let expected = point_add(re, point_mul(Some(*pk), self.e.mul(a).mul(g)));
+        if generator_mul(s) != expected {
+            bail!("Invalid partial signature");
+        }
+        Ok(())
+    }
+
+    /// `PartialSigAgg` of BIP-327, giving a BIP-340 signature for the aggregated key.
+    pub fn aggregate(&self, psigs: &[PartialSignature]) -> anyhow::Result<schnorr::Signature> {
+        let mut s = ModN::ZERO;
+        for psig in psigs {
+            s = s.add(ModN::from_canonical(psig.0)?);
+        }
+        let g = ModN::sign(has_even_y(&self.ctx.q));
+        s = s.add(self.e.mul(g).mul(ModN::reduce(self.ctx.tacc)));
+
+        let mut sig = [0u8; 64];
+        sig[..32].copy_from_slice(&xbytes(&self.r));
+        sig[32..].copy_from_slice(&s.to_bytes());
+        Ok(schnorr::Signature::from_slice(&sig)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::tx::taproot_tree_tx::gen_one_of_two_multi_sig_scripts;
+    use crate::bitcoin_node::tx::{USER_A_PRIVATE_KEY, USER_B_PRIVATE_KEY, USER_C_PRIVATE_KEY};
+    use bitcoin::bip32::Xpriv;
+    use bitcoin::hex::FromHex;
+    use bitcoin::sighash::{Prevouts, SighashCache};
+    use bitcoin::{
+        absolute, transaction, Amount, OutPoint, ScriptBuf, Sequence, TapSighashType, Transaction,
+        TxIn, TxOut, Witness,
+    };
+    use secp256k1::Message;
+    use std::str::FromStr;
+
+    fn pk(hex: &str) -> PublicKey {
+        PublicKey::from_slice(&Vec::<u8>::from_hex(hex).unwrap()).unwrap()
+    }
+
+    fn user_secret_keys() -> Vec<SecretKey> {
+        [USER_A_PRIVATE_KEY, USER_B_PRIVATE_KEY, USER_C_PRIVATE_KEY]
+            .iter()
+            .map(|sk| Xpriv::from_str(sk).unwrap().private_key)
+            .collect()
+    }
+
+    // key_agg_vectors.json of BIP-327.
+    #[test]
+    fn test_key_agg_vectors() -> anyhow::Result<()> {
+        let x1 = pk("02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9");
+        let x2 = pk("03DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659");
+        let x3 = pk("023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66");
+        let cases = [
+            (
+                vec![x1, x2, x3],
+                "90539eede565f5d054f32cc0c220126889ed1e5d193baf15aef344fe59d4610c",
+            ),
+            (
+                vec![x3, x2, x1],
+                "6204de8b083426dc6eaf9502d27024d53fc826bf7d2012148a0575435df54b2b",
+            ),
+            (
+                vec![x1, x1, x1],
+                "b436e3bad62b8cd409969a224731c193d051162d8c5ae8b109306127da3aa935",
+            ),
+            (
+                vec![x1, x1, x2, x2],
+                "69bc22bfa5d106306e48a20679de1d7389386124d07571d0d872686028c26a3e",
+            ),
+        ];
+        for (keys, expected) in cases {
+            let ctx = KeyAggContext::new(keys)?;
+            assert_eq!(ctx.x_only_public_key().to_string(), expected);
+        }
+        Ok(())
+    }
+
+    fn bytes<const N: usize>(hex: &str) -> [u8; N] {
+        <[u8; N]>::from_hex(hex).unwrap()
+    }
+
+    // sk, pk, aggpk, msg, extra_in, expected secnonce and pubnonce.
+    type NonceGenCase<'a> = (
+        Option<&'a SecretKey>,
+        PublicKey,
+        Option<&'a XOnlyPublicKey>,
+        Option<&'a [u8]>,
+        Option<&'a [u8]>,
+        &'a str,
+        &'a str,
+    );
+
+    // nonce_gen_vectors.json of BIP-327.
+    #[test]
+    fn test_nonce_gen_vectors() -> anyhow::Result<()> {
+        let rand = [0x0f; 32];
+        let sk = SecretKey::from_slice(&[0x02; 32])?;
+        let pk1 = pk("024D4B6CD1361032CA9BD2AEB9D900AA4D45D9EAD80AC9423374C451A7254D0766");
+        let pk2 = pk("02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9");
+        let agg_pk = XOnlyPublicKey::from_slice(&[0x07; 32])?;
+        let cases: [NonceGenCase; 4] = [
+            (
+                Some(&sk),
+                pk1,
+                Some(&agg_pk),
+                Some(&[0x01; 32]),
+                Some(&[0x08; 32]),
+                "B114E502BEAA4E301DD08A50264172C84E41650E6CB726B410C0694D59EFFB6495B5CAF28D045B973D63E3C99A44B807BDE375FD6CB39E46DC4A511708D0E9D2",
+                "02F7BE7089E8376EB355272368766B17E88E7DB72047D05E56AA881EA52B3B35DF02C29C8046FDD0DED4C7E55869137200FBDBFE2EB654267B6D7013602CAED3115A",
+            ),
+            (
+                Some(&sk),
+                pk1,
+                Some(&agg_pk),
+                Some(&[]),
+                Some(&[0x08; 32]),
+                "E862B068500320088138468D47E0E6F147E01B6024244AE45EAC40ACE5929B9F0789E051170B9E705D0B9EB49049A323BBBBB206D8E05C19F46C6228742AA7A9",
+                "023034FA5E2679F01EE66E12225882A7A48CC66719B1B9D3B6C4DBD743EFEDA2C503F3FD6F01EB3A8E9CB315D73F1F3D287CAFBB44AB321153C6287F407600205109",
+            ),
+            (
+                Some(&sk),
+                pk1,
+                Some(&agg_pk),
+                Some(&[0x26; 38]),
+                Some(&[0x08; 32]),
+                "3221975ACBDEA6820EABF02A02B7F27D3A8EF68EE42787B88CBEFD9AA06AF3632EE85B1A61D8EF31126D4663A00DD96E9D1D4959E72D70FE5EBB6E7696EBA66F",
+                "02E5BBC21C69270F59BD634FCBFA281BE9D76601295345112C58954625BF23793A021307511C79F95D38ACACFF1B4DA98228B77E65AA216AD075E9673286EFB4EAF3",
+            ),
+            (
+                None,
+                pk2,
+                None,
+                None,
+                None,
+                "89BDD787D0284E5E4D5FC572E49E316BAB7E21E3B1830DE37DFE80156FA41A6D0B17AE8D024C53679699A6FD7944D9C4A366B514BAF43088E0708B1023DD2897",
+                "02C96E7CB1E8AA5DAC64D872947914198F607D90ECDE5200DE52978AD5DED63C000299EC5117C2D29EDEE8A2092587C3909BE694D5CFF0667D6C02EA4059F7CD9786",
+            ),
+        ];
+        for (sk, pk, agg_pk, msg, extra_in, secnonce, pubnonce) in cases {
+            let (sec, public) = nonce_gen_with_rand(rand, sk, &pk, agg_pk, msg, extra_in)?;
+            let expected: [u8; 64] = bytes(secnonce);
+            assert_eq!(sec.k1.secret_bytes(), expected[..32]);
+            assert_eq!(sec.k2.secret_bytes(), expected[32..]);
+            assert_eq!(public, PubNonce(bytes(pubnonce)));
+        }
+        Ok(())
+    }
+
+    // The shared inputs of sign_verify_vectors.json and tweak_vectors.json of BIP-327, the
+    // signer being the one with `SIGNER_SK`.
+    const SIGNER_SK: &str = "7FB9E0E687ADA1EEBF7ECFE2F21E73EBDB51A7D450948DFE8D76D7F2D1007671";
+    const SIGNER_SECNONCE: &str = "508B81A611F100A6B2B6B29656590898AF488BCF2E1F55CF22E5CFB84421FE61FA27FD49B1D50085B481285E1CA205D55C82CC1B31FF5CD54A489829355901F7";
+    const VECTOR_PUBKEYS: [&str; 3] = [
+        "03935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9",
+        "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+        "02DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA661",
+    ];
+    const VECTOR_PUBNONCES: [&str; 3] = [
+        "0337C87821AFD50A8644D820A8F3E02E499C931865C2360FB43D0A0D20DAFE07EA0287BF891D2A6DEAEBADC909352AA9405D1428C15F4B75F04DAE642A95C2548480",
+        "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F817980279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+        "032DE2662628C90B03F5E720284EB52FF7D71F4284F627B68A853D78C78E1FFE9303E4C5524E83FFE1493B9077CF1CA6BEB2090C93D930321071AD40B2F44E599046",
+    ];
+    const VECTOR_AGGNONCE: &str = "028465FCF0BBDBCF443AABCCE533D42B4B5A10966AC09A49655E8C42DAAB8FCD61037496A3CC86926D452CAFCFD55D25972CA1675D549310DE296BFF42F72EEEA8C9";
+    const VECTOR_MSG: &str = "F95466D086770E689964664219266FE5ED215C92AE20BAB5C9D79ADDDDF3C0CF";
+
+    fn signer() -> (SecretKey, SecNonce) {
+        let sk = SecretKey::from_slice(&bytes::<32>(SIGNER_SK)).unwrap();
+        let secnonce: [u8; 64] = bytes(SIGNER_SECNONCE);
+        let secnonce = SecNonce {
+            k1: SecretKey::from_slice(&secnonce[..32]).unwrap(),
+            k2: SecretKey::from_slice(&secnonce[32..]).unwrap(),
+            pk: sk.public_key(&SECP),
+        };
+        (sk, secnonce)
+    }
+
+    fn vector_pubnonces() -> Vec<PubNonce> {
+        VECTOR_PUBNONCES
+            .iter()
+            .map(|nonce| PubNonce(bytes(nonce)))
+            .collect()
+    }
+
+    // sign_verify_vectors.json of BIP-327.
+    #[test]
+    fn test_sign_verify_vectors() -> anyhow::Result<()> {
+        let pubkeys: Vec<_> = VECTOR_PUBKEYS.iter().map(|key| pk(key)).collect();
+        let pubnonces = vector_pubnonces();
+        let aggnonce = nonce_agg(&pubnonces)?;
+        assert_eq!(aggnonce, AggNonce(bytes(VECTOR_AGGNONCE)));
+
+        let msg = Vec::<u8>::from_hex(VECTOR_MSG)?;
+        let cases = [
+            (
+                [0, 1, 2],
+                msg.clone(),
+                "012ABBCB52B3016AC03AD82395A1A415C48B93DEF78718E62A7A90052FE224FB",
+            ),
+            (
+                [1, 0, 2],
+                msg.clone(),
+                "9FF2F7AAA856150CC8819254218D3ADEEB0535269051897724F9DB3789513A52",
+            ),
+            (
+                [1, 2, 0],
+                msg.clone(),
+                "FA23C359F6FAC4E7796BB93BC9F0532A95468C539BA20FF86D7C76ED92227900",
+            ),
+            (
+                [0, 1, 2],
+                vec![],
+                "D7D63FFD644CCDA4E62BC2BC0B1D02DD32A1DC3030E155195810231D1037D82D",
+            ),
+            (
+                [0, 1, 2],
+                vec![0x26; 38],
+                "E184351828DA5094A97C79CABDAAA0BFB87608C32E8829A4DF5340A6F243B78C",
+            ),
+        ];
+        for (order, msg, expected) in cases {
+            let ctx = KeyAggContext::new(order.iter().map(|i| pubkeys[*i]).collect())?;
+            let session = Session::new(&ctx, &aggnonce, &msg)?;
+            let (sk, secnonce) = signer();
+            let psig = session.partial_sign(secnonce, &sk)?;
+            assert_eq!(psig, PartialSignature(bytes(expected)));
+            session.partial_verify(&psig, &pubnonces[0], &pubkeys[0])?;
+        }
+
+        // wrong signature, wrong signer and a signature exceeding the group order.
+        let ctx = KeyAggContext::new(pubkeys.clone())?;
+        let session = Session::new(&ctx, &aggnonce, &msg)?;
+        let (sk, secnonce) = signer();
+        let psig = session.partial_sign(secnonce, &sk)?;
+        let negated = ModN::from_canonical(psig.0)?.negate().to_bytes();
+        assert!(session
+            .partial_verify(&PartialSignature(negated), &pubnonces[0], &pubkeys[0])
+            .is_err());
+        assert!(session
+            .partial_verify(&psig, &pubnonces[1], &pubkeys[1])
+            .is_err());
+        assert!(session
+            .partial_verify(&PartialSignature(CURVE_ORDER), &pubnonces[0], &pubkeys[0])
+            .is_err());
+        Ok(())
+    }
+
+    // tweak_vectors.json of BIP-327.
+    #[test]
+    fn test_tweak_vectors() -> anyhow::Result<()> {
+        let mut pubkeys: Vec<_> = VECTOR_PUBKEYS.iter().map(|key| pk(key)).collect();
+        pubkeys[2] = pk("02DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659");
+        let ctx = KeyAggContext::new(vec![pubkeys[1], pubkeys[2], pubkeys[0]])?;
+        let aggnonce = AggNonce(bytes(VECTOR_AGGNONCE));
+        let msg = Vec::<u8>::from_hex(VECTOR_MSG)?;
+        let tweak = bytes("E8F791FF9225A2AF0102AFFF4A9A723D9612A682A25EBE79802B263CDFCD83BB");
+
+        let cases = [
+            (
+                true,
+                "E28A5C66E61E178C2BA19DB77B6CF9F7E2F0F56C17918CD13135E60CC848FE91",
+            ),
+            (
+                false,
+                "38B0767798252F21BF5702C48028B095428320F73A4B14DB1E25DE58543D2D2D",
+            ),
+        ];
+        for (is_xonly, expected) in cases {
+            let session = Session::new(&ctx.apply_tweak(tweak, is_xonly)?, &aggnonce, &msg)?;
+            let (sk, secnonce) = signer();
+            let psig = session.partial_sign(secnonce, &sk)?;
+            assert_eq!(psig, PartialSignature(bytes(expected)));
+            session.partial_verify(&psig, &vector_pubnonces()[0], &pubkeys[0])?;
+        }
+
+        // the tweak must be below the group order.
+        assert!(ctx.apply_tweak(CURVE_ORDER, true).is_err());
+        Ok(())
+    }
+
+    // sig_agg_vectors.json of BIP-327.
+    #[test]
+    fn test_sig_agg_vectors() -> anyhow::Result<()> {
+        let ctx = KeyAggContext::new(vec![
+            pk("03935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9"),
+            pk("02D2DC6F5DF7C56ACF38C7FA0AE7A759AE30E19B37359DFDE015872324C7EF6E05"),
+        ])?;
+        let aggnonce = AggNonce(bytes("0341432722C5CD0268D829C702CF0D1CBCE57033EED201FD335191385227C3210C03D377F2D258B64AADC0E16F26462323D701D286046A2EA93365656AFD9875982B"));
+        let msg: [u8; 32] =
+            bytes("599C67EA410D005B9DA90817CF03ED3B1C868E4DA4EDF00A5880B0082C237869");
+        let psigs = [
+            PartialSignature(bytes(
+                "B15D2CD3C3D22B04DAE438CE653F6B4ECF042F42CFDED7C41B64AAF9B4AF53FB",
+            )),
+            PartialSignature(bytes(
+                "6193D6AC61B354E9105BBDC8937A3454A6D705B6D57322A5A472A02CE99FCB64",
+            )),
+        ];
+
+        let session = Session::new(&ctx, &aggnonce, &msg)?;
+        let sig = session.aggregate(&psigs)?;
+        assert_eq!(
+            sig.serialize(),
+            bytes::<64>("041DA22223CE65C92C9A0D6C2CAC828AAF1EEE56304FEC371DDF91EBB2B9EF0912F1038025857FEDEB3FF696F8B99FA4BB2C5812F6095A2E0004EC99CE18DE1E")
+        );
+        SECP.verify_schnorr(&sig, &Message::from_digest(msg), &ctx.x_only_public_key())?;
+
+        assert!(session
+            .aggregate(&[psigs[0], PartialSignature(CURVE_ORDER)])
+            .is_err());
+        Ok(())
+    }
+
+    fn musig_sign(
+        ctx: &KeyAggContext,
+        sks: &[SecretKey],
+        msg: &[u8; 32],
+    ) -> anyhow::Result<schnorr::Signature> {
+        // round 1: every signer generates and shares a nonce.
+        let mut secnonces = vec![];
+        let mut pubnonces = vec![];
+        for sk in sks {
+            let (secnonce, pubnonce) = nonce_gen(
+                Some(sk),
+                &sk.public_key(&SECP),
+                Some(&ctx.x_only_public_key()),
+                Some(msg),
+                None,
+            )?;
+            secnonces.push(secnonce);
+            pubnonces.push(pubnonce);
+        }
+        let aggnonce = nonce_agg(&pubnonces)?;
+
+        // round 2: partial signatures, checked one by one before aggregation.
+        let session = Session::new(ctx, &aggnonce, msg)?;
+        let mut psigs = vec![];
+        for ((secnonce, sk), pubnonce) in secnonces.into_iter().zip(sks).zip(&pubnonces) {
+            let psig = session.partial_sign(secnonce, sk)?;
+            session.partial_verify(&psig, pubnonce, &sk.public_key(&SECP))?;
+            psigs.push(psig);
+        }
+        session.aggregate(&psigs)
+    }
+
+    #[test]
+    fn test_sign_untweaked_and_plain_tweaked() -> anyhow::Result<()> {
+        let sks = user_secret_keys();
+        let ctx = KeyAggContext::new(sks.iter().map(|sk| sk.public_key(&SECP)).collect())?;
+        let msg = [42u8; 32];
+
+        let sig = musig_sign(&ctx, &sks, &msg)?;
+        SECP.verify_schnorr(&sig, &Message::from_digest(msg), &ctx.x_only_public_key())?;
+
+        let tweaked = ctx
+            .apply_tweak([3u8; 32], false)?
+            .apply_tweak([5u8; 32], true)?;
+        let sig = musig_sign(&tweaked, &sks, &msg)?;
+        SECP.verify_schnorr(
+            &sig,
+            &Message::from_digest(msg),
+            &tweaked.x_only_public_key(),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_bad_partial_signature_is_blamed() -> anyhow::Result<()> {
+        let sks = user_secret_keys();
+        let pks: Vec<_> = sks.iter().map(|sk| sk.public_key(&SECP)).collect();
+        let ctx = KeyAggContext::new(pks.clone())?;
+        let msg = [1u8; 32];
+
+        let nonces: Vec<_> = sks
+            .iter()
+            .zip(&pks)
+            .map(|(sk, pk)| nonce_gen(Some(sk), pk, None, None, None).unwrap())
+            .collect();
+        let aggnonce = nonce_agg(&nonces.iter().map(|(_, p)| *p).collect::<Vec<_>>())?;
+        let session = Session::new(&ctx, &aggnonce, &msg)?;
+
+        let (secnonce, pubnonce) = nonce_gen(Some(&sks[0]), &pks[0], None, None, None)?;
+        // signed with a nonce the others never saw.
+        let psig = session.partial_sign(secnonce, &sks[0])?;
+        assert!(session
+            .partial_verify(&psig, &nonces[0].1, &pks[0])
+            .is_err());
+        assert!(session.partial_verify(&psig, &pubnonce, &pks[1]).is_err());
+        Ok(())
+    }
+
+    // A+B+C aggregated as the internal key of the B/C script tree, spent through the key path.
+    #[test]
+    fn test_musig_key_path_spend_of_taproot_tree() -> anyhow::Result<()> {
+        let sks = user_secret_keys();
+        let ctx = KeyAggContext::new(sks.iter().map(|sk| sk.public_key(&SECP)).collect())?;
+
+        let scripts = gen_one_of_two_multi_sig_scripts(&SECP);
+        let builder = TaprootBuilder::new()
+            .add_leaf(1, scripts[0].clone())?
+            .add_leaf(1, scripts[1].clone())?;
+        let tree = ctx.taproot_spend_info(builder)?;
+        let output_ctx = ctx.with_taproot_tweak(tree.merkle_root())?;
+        assert_eq!(output_ctx.x_only_public_key(), tree.output_key().to_inner());
+
+        let prevouts = vec![TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: ScriptBuf::new_p2tr_tweaked(tree.output_key()),
+        }];
+        let mut tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::from_str(
+                    "eadd615a91e83aa81c8eb670f4bcb44a6265288617086b48c81a8dc8b28b5084:0",
+                )?,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::default(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(99_000),
+                script_pubkey: ScriptBuf::new_p2tr_tweaked(tree.output_key()),
+            }],
+        };
+        let sighash = SighashCache::new(&tx).taproot_key_spend_signature_hash(
+            0,
+            &Prevouts::All(&prevouts),
+            TapSighashType::Default,
+        )?;
+
+        let signature = musig_sign(&output_ctx, &sks, &sighash.to_byte_array())?;
+        SECP.verify_schnorr(
+            &signature,
+            &Message::from(sighash),
+            &tree.output_key().to_inner(),
+        )?;
+        tx.input[0].witness = Witness::p2tr_key_spend(&bitcoin::taproot::Signature {
+            signature,
+            sighash_type: TapSighashType::Default,
+        });
+        assert_eq!(tx.input[0].witness.len(), 1);
+        Ok(())
+    }
+}
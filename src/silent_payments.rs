@@ -0,0 +1,1200 @@
+//! Silent Payments (BIP-352): a static `sp1..` address from which every sender derives a fresh
+//! taproot output, without any interaction and without the outputs being linkable on chain.
+//!
+//! Sender: `output_keys(inputs, recipients)` with the secret keys of the transaction inputs,
+//! then one p2tr output per returned key (the key is the output key, it isn't tweaked again).
+//! Receiver: `SilentPaymentReceiver::scan_block` (or `scan_transaction`) finds the outputs and
+//! `ReceivedOutput::keypair` derives the key that spends them through the key path.
+//!
+//! Address hrp: `sp` mainnet, `tsp` testnet and signet, `sprt` regtest.
+//!
+//! Reference: https://github.com/bitcoin/bips/blob/master/bip-0352.mediawiki
+use crate::bitcoin_node::address::AddressNetwork;
+use crate::musig2::tagged_hash;
+use crate::SECP;
+use anyhow::{anyhow, bail};
+use bitcoin::bech32::primitives::decode::CheckedHrpstring;
+use bitcoin::bech32::{Bech32m, ByteIterExt, Fe32, Fe32IterExt, Hrp};
+use bitcoin::bip32::{ChildNumber, DerivationPath, Xpriv};
+use bitcoin::consensus::encode;
+use bitcoin::hashes::{hash160, Hash};
+use bitcoin::key::TweakedPublicKey;
+use bitcoin::{
+    Block, BlockHash, CompressedPublicKey, Network, NetworkKind, OutPoint, Script, ScriptBuf,
+    Transaction, TxOut, Txid,
+};
+use bitcoincore_rpc::RpcApi;
+use esplora_client::AsyncClient;
+use secp256k1::{Keypair, Parity, PublicKey, Scalar, SecretKey, XOnlyPublicKey};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+
+// BIP-341 NUMS point `H`: script path spends with this internal key don't count as inputs.
+const UNSPENDABLE_KEY: &str = "50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0";
+
+// Versions above 0 may append data after the keys, 31 is reserved for a breaking change.
+const MAX_ADDRESS_VERSION: u8 = 30;
+
+fn hash_to_scalar(tag: &str, chunks: &[&[u8]]) -> anyhow::Result<Scalar> {
+    Scalar::from_be_bytes(tagged_hash(tag, chunks))
+        .map_err(|_| anyhow!("Fail to hash {} into a scalar", tag))
+}
+
+fn label_tweak(scan_key: &SecretKey, m: u32) -> anyhow::Result<Scalar> {
+    hash_to_scalar(
+        "BIP0352/Label",
+        &[&scan_key.secret_bytes(), &m.to_be_bytes()],
+    )
+}
+
+fn shared_secret_tweak(ecdh_shared_secret: &PublicKey, k: u32) -> anyhow::Result<Scalar> {
+    hash_to_scalar(
+        "BIP0352/SharedSecret",
+        &[&ecdh_shared_secret.serialize(), &k.to_be_bytes()],
+    )
+}
+
+/// `hash(outpoint_L || A)` with `outpoint_L` the smallest serialized outpoint of the transaction.
+fn input_hash<'a>(
+    out_points: impl Iterator<Item = &'a OutPoint>,
+    input_key_sum: &PublicKey,
+) -> anyhow::Result<Scalar> {
+    let smallest = out_points
+        .map(encode::serialize)
+        .min()
+        .ok_or_else(|| anyhow!("Fail to hash inputs: no input"))?;
+    hash_to_scalar("BIP0352/Inputs", &[&smallest, &input_key_sum.serialize()])
+}
+
+// Spending rules of segwit v2 and up are unknown, transactions spending them are left out.
+fn is_future_segwit(script_pubkey: &Script) -> bool {
+    script_pubkey
+        .witness_version()
+        .is_some_and(|version| version.to_num() > 1)
+}
+
+fn taproot_output_key(script_pubkey: &Script) -> Option<XOnlyPublicKey> {
+    if !script_pubkey.is_p2tr() {
+        return None;
+    }
+    XOnlyPublicKey::from_slice(&script_pubkey.as_bytes()[2..34]).ok()
+}
+
+/// A silent payment address: a scan key to find payments and a spend key to spend them,
+/// optionally with a label tweak added to the spend key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SilentPaymentAddress {
+    pub scan_key: PublicKey,
+    pub spend_key: PublicKey,
+    pub network: AddressNetwork,
+}
+
+impl SilentPaymentAddress {
+    pub fn new(scan_key: PublicKey, spend_key: PublicKey, network: Network) -> Self {
+        Self {
+            scan_key,
+            spend_key,
+            network: AddressNetwork::from(network),
+        }
+    }
+
+    /// The address, if it can be used on `network`.
+    pub fn require_network(self, network: Network) -> anyhow::Result<Self> {
+        if !self.network.contains(network) {
+            bail!(
+                "Refusing {} silent payment address on {}",
+                self.network,
+                network
+            );
+        }
+        Ok(self)
+    }
+
+    fn hrp(&self) -> Hrp {
+        match self.network {
+            AddressNetwork::Mainnet => Hrp::parse_unchecked("sp"),
+            AddressNetwork::Regtest => Hrp::parse_unchecked("sprt"),
+            AddressNetwork::Testnet | AddressNetwork::AnyTest => Hrp::parse_unchecked("tsp"),
+        }
+    }
+}
+
+impl fmt::Display for SilentPaymentAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let data = [self.scan_key.serialize(), self.spend_key.serialize()].concat();
+        for c in data
+            .into_iter()
+            .bytes_to_fes()
+            .with_checksum::<Bech32m>(&self.hrp())
+            .with_witness_version(Fe32::Q)
+            .chars()
+        {
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SilentPaymentAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut checked = CheckedHrpstring::new::<Bech32m>(s)
+            .map_err(|e| anyhow!("Invalid silent payment address {}: {}", s, e))?;
+        let network = match checked.hrp().to_lowercase().as_str() {
+            "sp" => AddressNetwork::Mainnet,
+            "tsp" => AddressNetwork::Testnet,
+            "sprt" => AddressNetwork::Regtest,
+            hrp => bail!("Invalid silent payment address {}: unknown hrp {}", s, hrp),
+        };
+        let version = checked
+            .remove_witness_version()
+            .ok_or_else(|| anyhow!("Invalid silent payment address {}: no version", s))?
+            .to_u8();
+        let data: Vec<u8> = checked.byte_iter().collect();
+        match (version, data.len()) {
+            (0, 66) => {}
+            (1..=MAX_ADDRESS_VERSION, len) if len >= 66 => {}
+            _ => bail!(
+                "Invalid silent payment address {}: version {} with {} bytes",
+                s,
+                version,
+                data.len()
+            ),
+        }
+        Ok(Self {
+            scan_key: PublicKey::from_slice(&data[..33])?,
+            spend_key: PublicKey::from_slice(&data[33..66])?,
+            network,
+        })
+    }
+}
+
+/// The scan and spend secret keys behind a silent payment address.
+pub struct SilentPaymentKeys {
+    pub scan_key: SecretKey,
+    pub spend_key: SecretKey,
+}
+
+impl SilentPaymentKeys {
+    /// Keys of `account` at `m/352'/coin_type'/account'/1'/0` (scan) and `../0'/0` (spend).
+    pub fn from_xpriv(master: &Xpriv, account: u32) -> anyhow::Result<Self> {
+        let coin_type = match master.network {
+            NetworkKind::Main => 0,
+            NetworkKind::Test => 1,
+        };
+        let derive = |branch: u32| -> anyhow::Result<SecretKey> {
+            let path = DerivationPath::from(vec![
+                ChildNumber::from_hardened_idx(352)?,
+                ChildNumber::from_hardened_idx(coin_type)?,
+                ChildNumber::from_hardened_idx(account)?,
+                ChildNumber::from_hardened_idx(branch)?,
+                ChildNumber::from_normal_idx(0)?,
+            ]);
+            Ok(master.derive_priv(&SECP, &path)?.private_key)
+        };
+        Ok(Self {
+            scan_key: derive(1)?,
+            spend_key: derive(0)?,
+        })
+    }
+
+    pub fn receiver(&self, network: Network) -> SilentPaymentReceiver {
+        SilentPaymentReceiver::new(self.scan_key, self.spend_key.public_key(&SECP), network)
+    }
+}
+
+// Wipe the secrets once the keys go out of scope.
+impl Drop for SilentPaymentKeys {
+    fn drop(&mut self) {
+        self.scan_key.non_secure_erase();
+        self.spend_key.non_secure_erase();
+    }
+}
+
+/// An input of the transaction being built, one per transaction input.
+pub struct SenderInput {
+    pub out_point: OutPoint,
+    pub prevout: TxOut,
+    /// The key signing the input: the output key for p2tr, the public key's secret otherwise.
+    /// `None` for inputs that don't count (p2sh other than p2sh-p2wpkh, p2wsh, p2tr script path
+    /// spends with the NUMS internal key, ...). It must be the key of `prevout`, a p2pkh key
+    /// that is uncompressed doesn't count either.
+    pub secret_key: Option<SecretKey>,
+}
+
+/// Output keys paying `recipients`, in order. Each becomes a p2tr output as is.
+pub fn output_keys(
+    inputs: &[SenderInput],
+    recipients: &[SilentPaymentAddress],
+) -> anyhow::Result<Vec<XOnlyPublicKey>> {
+    if let Some(index) = inputs
+        .iter()
+        .position(|input| is_future_segwit(&input.prevout.script_pubkey))
+    {
+        bail!(
+            "Fail to send silent payment: input {} spends a segwit v2+ output",
+            index
+        );
+    }
+    let mut keys = vec![];
+    for (index, input) in inputs.iter().enumerate() {
+        let script_pubkey = &input.prevout.script_pubkey;
+        let Some(secret_key) = input.secret_key else {
+            if script_pubkey.is_p2wpkh() || script_pubkey.is_p2pkh() {
+                bail!("Fail to send silent payment: input {} needs its key", index);
+            }
+            continue;
+        };
+        let key = match taproot_output_key(script_pubkey) {
+            Some(output_key) => {
+                let (x_only, parity) = secret_key.x_only_public_key(&SECP);
+                if x_only != output_key {
+                    bail!(
+                        "Fail to send silent payment: key of input {} isn't its p2tr key",
+                        index
+                    );
+                }
+                match parity {
+                    Parity::Even => secret_key,
+                    Parity::Odd => secret_key.negate(),
+                }
+            }
+            None => {
+                let public_key = secret_key.public_key(&SECP);
+                let compressed = CompressedPublicKey(public_key);
+                let p2wpkh = ScriptBuf::new_p2wpkh(&compressed.wpubkey_hash());
+                let eligible = [
+                    ScriptBuf::new_p2pkh(&compressed.pubkey_hash()),
+                    ScriptBuf::new_p2sh(&p2wpkh.script_hash()),
+                    p2wpkh,
+                ];
+                let uncompressed_p2pkh = ScriptBuf::new_p2pkh(
+                    &bitcoin::PublicKey::new_uncompressed(public_key).pubkey_hash(),
+                );
+                if *script_pubkey == uncompressed_p2pkh {
+                    continue;
+                }
+                if !eligible.contains(script_pubkey) {
+                    bail!(
+                        "Fail to send silent payment: key of input {} doesn't match its {}",
+                        index,
+                        script_pubkey.to_asm_string()
+                    );
+                }
+                secret_key
+            }
+        };
+        keys.push(Scalar::from(key));
+    }
+    let (first, rest) = keys
+        .split_first()
+        .ok_or_else(|| anyhow!("Fail to send silent payment: no eligible input"))?;
+    let mut input_key = SecretKey::from_slice(&first.to_be_bytes())?;
+    for key in rest {
+        input_key = input_key
+            .add_tweak(key)
+            .map_err(|_| anyhow!("Fail to send silent payment: input keys sum to zero"))?;
+    }
+    let input_hash = input_hash(
+        inputs.iter().map(|input| &input.out_point),
+        &input_key.public_key(&SECP),
+    )?;
+    let tweaked_input_key = input_key.mul_tweak(&input_hash)?;
+
+    // `k` counts the outputs per scan key.
+    let mut counters: HashMap<PublicKey, (PublicKey, u32)> = HashMap::new();
+    let mut output_keys = vec![];
+    for recipient in recipients {
+        let (ecdh_shared_secret, k) = match counters.entry(recipient.scan_key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let ecdh_shared_secret = recipient
+                    .scan_key
+                    .mul_tweak(&SECP, &Scalar::from(tweaked_input_key))?;
+                entry.insert((ecdh_shared_secret, 0))
+            }
+        };
+        let tweak = shared_secret_tweak(ecdh_shared_secret, *k)?;
+        *k += 1;
+        let output_key = recipient.spend_key.add_exp_tweak(&SECP, &tweak)?;
+        output_keys.push(output_key.x_only_public_key().0);
+    }
+    Ok(output_keys)
+}
+
+/// `ScriptBuf` of the p2tr output with output key `key`, as returned by `output_keys`.
+pub fn output_script(key: XOnlyPublicKey) -> ScriptBuf {
+    ScriptBuf::new_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(key))
+}
+
+/// Public key an input contributes to the shared secret, `None` for inputs that don't count.
+fn input_public_key(
+    script_sig: &Script,
+    witness: &bitcoin::Witness,
+    prevout: &TxOut,
+) -> Option<PublicKey> {
+    let script_pubkey = &prevout.script_pubkey;
+    let compressed = |bytes: &[u8]| match bytes.len() {
+        33 => PublicKey::from_slice(bytes).ok(),
+        _ => None,
+    };
+    if let Some(output_key) = taproot_output_key(script_pubkey) {
+        let mut stack: Vec<&[u8]> = witness.iter().collect();
+        if stack.len() > 1 && stack.last().is_some_and(|item| item.first() == Some(&0x50)) {
+            stack.pop(); // annex
+        }
+        if stack.len() > 1 {
+            let control_block = stack.last()?;
+            let internal_key = control_block.get(1..33)?;
+            if internal_key == XOnlyPublicKey::from_str(UNSPENDABLE_KEY).ok()?.serialize() {
+                return None;
+            }
+        }
+        return Some(output_key.public_key(Parity::Even));
+    }
+    if script_pubkey.is_p2wpkh() {
+        return compressed(witness.last()?);
+    }
+    if script_pubkey.is_p2sh() {
+        let redeem_script = Script::from_bytes(script_sig.as_bytes().get(1..)?);
+        if redeem_script.is_p2wpkh() {
+            return compressed(witness.last()?);
+        }
+        return None;
+    }
+    if script_pubkey.is_p2pkh() {
+        // The last 33 bytes of the script sig hashing to the p2pkh hash.
+        let hash = &script_pubkey.as_bytes()[3..23];
+        let script_sig = script_sig.as_bytes();
+        return (33..=script_sig.len())
+            .rev()
+            .map(|end| &script_sig[end - 33..end])
+            .find(|key| hash160::Hash::hash(key)[..] == *hash)
+            .and_then(compressed);
+    }
+    None
+}
+
+/// An output paying us.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReceivedOutput {
+    pub out_point: OutPoint,
+    pub txout: TxOut,
+    /// Added to the spend secret key to get the output key's secret.
+    pub tweak: Scalar,
+    pub label: Option<u32>,
+}
+
+impl ReceivedOutput {
+    /// Keypair of the output key, to sign its key path spends with (without taproot tweak).
+    pub fn keypair(&self, spend_key: &SecretKey) -> anyhow::Result<Keypair> {
+        Ok(Keypair::from_secret_key(
+            &SECP,
+            &spend_key.add_tweak(&self.tweak)?,
+        ))
+    }
+}
+
+/// Where `SilentPaymentReceiver` fetches blocks and the transactions their inputs spend.
+pub trait ScanSource {
+    fn block(&self, hash: &BlockHash) -> impl Future<Output = anyhow::Result<Block>>;
+
+    fn transaction(&self, txid: &Txid) -> impl Future<Output = anyhow::Result<Transaction>>;
+}
+
+// Spent transactions are looked up with `getrawtransaction`, which needs `-txindex`.
+impl ScanSource for bitcoincore_rpc::Client {
+    async fn block(&self, hash: &BlockHash) -> anyhow::Result<Block> {
+        Ok(self.get_block(hash)?)
+    }
+
+    async fn transaction(&self, txid: &Txid) -> anyhow::Result<Transaction> {
+        Ok(self.get_raw_transaction(txid, None)?)
+    }
+}
+
+impl ScanSource for AsyncClient {
+    async fn block(&self, hash: &BlockHash) -> anyhow::Result<Block> {
+        self.get_block_by_hash(hash)
+            .await?
+            .ok_or_else(|| anyhow!("Fail to get block {}", hash))
+    }
+
+    async fn transaction(&self, txid: &Txid) -> anyhow::Result<Transaction> {
+        self.get_tx(txid)
+            .await?
+            .ok_or_else(|| anyhow!("Fail to get transaction {}", txid))
+    }
+}
+
+/// Finds the outputs paying a silent payment address. Only needs the scan secret key, the
+/// spend secret key can stay offline until the outputs get spent.
+pub struct SilentPaymentReceiver {
+    scan_key: SecretKey,
+    spend_key: PublicKey,
+    network: Network,
+    // label point `hash(b_scan || m)·G` -> (m, tweak)
+    labels: HashMap<PublicKey, (u32, Scalar)>,
+}
+
+impl SilentPaymentReceiver {
+    pub fn new(scan_key: SecretKey, spend_key: PublicKey, network: Network) -> Self {
+        Self {
+            scan_key,
+            spend_key,
+            network,
+            labels: HashMap::new(),
+        }
+    }
+
+    pub fn address(&self) -> SilentPaymentAddress {
+        SilentPaymentAddress::new(
+            self.scan_key.public_key(&SECP),
+            self.spend_key,
+            self.network,
+        )
+    }
+
+    /// Address with label `m` and start looking for payments to it. Label 0 is for change.
+    pub fn labeled_address(&mut self, m: u32) -> anyhow::Result<SilentPaymentAddress> {
+        let tweak = label_tweak(&self.scan_key, m)?;
+        let label = SecretKey::from_slice(&tweak.to_be_bytes())?.public_key(&SECP);
+        self.labels.insert(label, (m, tweak));
+        Ok(SilentPaymentAddress::new(
+            self.scan_key.public_key(&SECP),
+            self.spend_key.combine(&label)?,
+            self.network,
+        ))
+    }
+
+    /// Outputs of `tx` paying us. `prevouts` are the outputs its inputs spend, in order.
+    pub fn scan_transaction(
+        &self,
+        tx: &Transaction,
+        prevouts: &[TxOut],
+    ) -> anyhow::Result<Vec<ReceivedOutput>> {
+        if prevouts.len() != tx.input.len() {
+            bail!(
+                "Fail to scan {}: {} prevouts for {} inputs",
+                tx.compute_txid(),
+                prevouts.len(),
+                tx.input.len()
+            );
+        }
+        let mut outputs: Vec<(usize, XOnlyPublicKey)> = tx
+            .output
+            .iter()
+            .enumerate()
+            .filter_map(|(vout, txout)| Some((vout, taproot_output_key(&txout.script_pubkey)?)))
+            .collect();
+        let input_keys: Vec<PublicKey> = tx
+            .input
+            .iter()
+            .zip(prevouts)
+            .filter_map(|(input, prevout)| {
+                input_public_key(&input.script_sig, &input.witness, prevout)
+            })
+            .collect();
+        let spends_future_segwit = prevouts
+            .iter()
+            .any(|prevout| is_future_segwit(&prevout.script_pubkey));
+        if tx.is_coinbase() || spends_future_segwit || outputs.is_empty() || input_keys.is_empty() {
+            return Ok(vec![]);
+        }
+        let Ok(input_key_sum) = PublicKey::combine_keys(&input_keys.iter().collect::<Vec<_>>())
+        else {
+            return Ok(vec![]); // keys cancel out
+        };
+        let input_hash = input_hash(
+            tx.input.iter().map(|input| &input.previous_output),
+            &input_key_sum,
+        )?;
+        let ecdh_shared_secret = input_key_sum
+            .mul_tweak(&SECP, &input_hash)?
+            .mul_tweak(&SECP, &Scalar::from(self.scan_key))?;
+
+        let txid = tx.compute_txid();
+        let mut received = vec![];
+        for k in 0.. {
+            let tweak = shared_secret_tweak(&ecdh_shared_secret, k)?;
+            let base = self.spend_key.add_exp_tweak(&SECP, &tweak)?;
+            let Some((position, label)) = outputs
+                .iter()
+                .enumerate()
+                .find_map(|(position, (_, key))| Some((position, self.match_output(&base, key)?)))
+            else {
+                break;
+            };
+            let (vout, _) = outputs.remove(position);
+            let (tweak, label) = match label {
+                Some((m, label_tweak)) => (
+                    Scalar::from(
+                        SecretKey::from_slice(&tweak.to_be_bytes())?.add_tweak(&label_tweak)?,
+                    ),
+                    Some(m),
+                ),
+                None => (tweak, None),
+            };
+            received.push(ReceivedOutput {
+                out_point: OutPoint {
+                    txid,
+                    vout: vout as u32,
+                },
+                txout: tx.output[vout].clone(),
+                tweak,
+                label,
+            });
+        }
+        Ok(received)
+    }
+
+    /// Whether `output` is `base`, or `base` plus one of our labels. `Some(None)` if unlabeled.
+    fn match_output(
+        &self,
+        base: &PublicKey,
+        output: &XOnlyPublicKey,
+    ) -> Option<Option<(u32, Scalar)>> {
+        if base.x_only_public_key().0 == *output {
+            return Some(None);
+        }
+        let output = output.public_key(Parity::Even);
+        let negated_base = base.negate(&SECP);
+        [output, output.negate(&SECP)]
+            .iter()
+            .filter_map(|output| output.combine(&negated_base).ok())
+            .find_map(|label| self.labels.get(&label).copied())
+            .map(Some)
+    }
+
+    /// Outputs paying us in `txid`, which may still be in the mempool.
+    pub async fn scan_txid<S: ScanSource>(
+        &self,
+        source: &S,
+        txid: &Txid,
+    ) -> anyhow::Result<Vec<ReceivedOutput>> {
+        let tx = source.transaction(txid).await?;
+        self.scan_with(source, &tx).await
+    }
+
+    /// Outputs paying us in block `hash`.
+    pub async fn scan_block<S: ScanSource>(
+        &self,
+        source: &S,
+        hash: &BlockHash,
+    ) -> anyhow::Result<Vec<ReceivedOutput>> {
+        let block = source.block(hash).await?;
+        let mut received = vec![];
+        for tx in &block.txdata {
+            received.extend(self.scan_with(source, tx).await?);
+        }
+        Ok(received)
+    }
+
+    async fn scan_with<S: ScanSource>(
+        &self,
+        source: &S,
+        tx: &Transaction,
+    ) -> anyhow::Result<Vec<ReceivedOutput>> {
+        // Skip the prevout lookups for transactions that can't pay us.
+        let has_taproot_output = tx.output.iter().any(|txout| txout.script_pubkey.is_p2tr());
+        if tx.is_coinbase() || !has_taproot_output {
+            return Ok(vec![]);
+        }
+        let mut prevouts = vec![];
+        for input in &tx.input {
+            let out_point = input.previous_output;
+            let spent = source.transaction(&out_point.txid).await?;
+            let prevout = spent
+                .output
+                .get(out_point.vout as usize)
+                .ok_or_else(|| anyhow!("Fail to find prevout {}", out_point))?;
+            prevouts.push(prevout.clone());
+        }
+        self.scan_transaction(tx, &prevouts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin_node::account::BitcoinAccount;
+    use crate::bitcoin_node::tx::USER_A_PRIVATE_KEY;
+    use bitcoin::hashes::Hash;
+    use bitcoin::script::PushBytesBuf;
+    use bitcoin::{absolute, transaction, Amount, Sequence, TxIn, Witness};
+
+    fn secret_key(hex: &str) -> SecretKey {
+        SecretKey::from_str(hex).unwrap()
+    }
+
+    fn p2pkh(key: &SecretKey) -> ScriptBuf {
+        ScriptBuf::new_p2pkh(&bitcoin::PublicKey::new(key.public_key(&SECP)).pubkey_hash())
+    }
+
+    #[test]
+    fn test_address_encoding() -> anyhow::Result<()> {
+        // Receiver keys and address of the BIP-352 test vectors.
+        let scan_key =
+            secret_key("0f694e068028a717f8af6b9411f9a133dd3565258714cc226594b34db90c1f2c");
+        let spend_key =
+            secret_key("9d6ad855ce3417ef84e836892e5a56392bfba05fa5d97ccea30e266f540e08b3");
+        let receiver =
+            SilentPaymentReceiver::new(scan_key, spend_key.public_key(&SECP), Network::Bitcoin);
+        let address = "sp1qqgste7k9hx0qftg6qmwlkqtwuy6cycyavzmzj85c6qdfhjdpdjtdgqjuexzk6murw56suy3e0rd2cgqvycxttddwsvgxe2usfpxumr70xc9pkqwv";
+        assert_eq!(receiver.address().to_string(), address);
+        let parsed = SilentPaymentAddress::from_str(address)?;
+        assert_eq!(parsed, receiver.address());
+        assert!(parsed.require_network(Network::Signet).is_err());
+
+        let regtest =
+            SilentPaymentReceiver::new(scan_key, spend_key.public_key(&SECP), Network::Regtest);
+        let address = regtest.address().to_string();
+        assert!(address.starts_with("sprt1q"));
+        assert_eq!(SilentPaymentAddress::from_str(&address)?, regtest.address());
+        assert!(SilentPaymentAddress::from_str(&address.replace("sprt1q", "sprt1p")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_and_receive_with_labels() -> anyhow::Result<()> {
+        let keys: Vec<SecretKey> = (1..=4u8)
+            .map(|i| SecretKey::from_slice(&[i; 32]).unwrap())
+            .collect();
+        let signature = [1u8; 71].to_vec();
+        let compressed = |key: &SecretKey| CompressedPublicKey(key.public_key(&SECP));
+        let push = |bytes: &[u8]| PushBytesBuf::try_from(bytes.to_vec()).unwrap();
+
+        // p2tr key path, p2wpkh, p2sh-p2wpkh and p2pkh inputs. A p2tr key has to have an even
+        // key or be negated, the first one is picked to be odd.
+        let taproot_key = if keys[0].x_only_public_key(&SECP).1 == Parity::Odd {
+            keys[0]
+        } else {
+            keys[0].negate()
+        };
+        let p2sh_p2wpkh = ScriptBuf::new_p2wpkh(&compressed(&keys[2]).wpubkey_hash());
+        let prevouts = vec![
+            TxOut {
+                value: Amount::from_sat(50_000),
+                script_pubkey: output_script(taproot_key.x_only_public_key(&SECP).0),
+            },
+            TxOut {
+                value: Amount::from_sat(50_000),
+                script_pubkey: ScriptBuf::new_p2wpkh(&compressed(&keys[1]).wpubkey_hash()),
+            },
+            TxOut {
+                value: Amount::from_sat(50_000),
+                script_pubkey: ScriptBuf::new_p2sh(&p2sh_p2wpkh.script_hash()),
+            },
+            TxOut {
+                value: Amount::from_sat(50_000),
+                script_pubkey: p2pkh(&keys[3]),
+            },
+        ];
+        let secret_keys = [taproot_key, keys[1], keys[2], keys[3]];
+        let inputs: Vec<SenderInput> = prevouts
+            .iter()
+            .zip(secret_keys)
+            .enumerate()
+            .map(|(vout, (prevout, key))| SenderInput {
+                out_point: OutPoint {
+                    txid: Txid::all_zeros(),
+                    vout: vout as u32,
+                },
+                prevout: prevout.clone(),
+                secret_key: Some(key),
+            })
+            .collect();
+
+        let mut receiver = SilentPaymentReceiver::new(
+            secret_key("0f694e068028a717f8af6b9411f9a133dd3565258714cc226594b34db90c1f2c"),
+            keys[3].public_key(&SECP),
+            Network::Regtest,
+        );
+        let spend_key = keys[3];
+        let address = receiver.address();
+        let labeled = receiver.labeled_address(7)?;
+        let payment_keys = output_keys(&inputs, &[address, labeled, address])?;
+
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![
+                TxIn {
+                    previous_output: inputs[0].out_point,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::from_slice(&[[2u8; 64].to_vec()]),
+                },
+                TxIn {
+                    previous_output: inputs[1].out_point,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::from_slice(&[
+                        signature.clone(),
+                        compressed(&keys[1]).to_bytes().to_vec(),
+                    ]),
+                },
+                TxIn {
+                    previous_output: inputs[2].out_point,
+                    script_sig: bitcoin::script::Builder::new()
+                        .push_slice(push(p2sh_p2wpkh.as_bytes()))
+                        .into_script(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::from_slice(&[
+                        signature.clone(),
+                        compressed(&keys[2]).to_bytes().to_vec(),
+                    ]),
+                },
+                TxIn {
+                    previous_output: inputs[3].out_point,
+                    script_sig: bitcoin::script::Builder::new()
+                        .push_slice(push(&signature))
+                        .push_slice(push(&compressed(&keys[3]).to_bytes()))
+                        .into_script(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::default(),
+                },
+            ],
+            output: payment_keys
+                .iter()
+                .map(|key| TxOut {
+                    value: Amount::from_sat(40_000),
+                    script_pubkey: output_script(*key),
+                })
+                .collect(),
+        };
+
+        let received = receiver.scan_transaction(&tx, &prevouts)?;
+        assert_eq!(received.len(), 3);
+        for output in &received {
+            let keypair = output.keypair(&spend_key)?;
+            assert_eq!(
+                keypair.x_only_public_key().0,
+                payment_keys[output.out_point.vout as usize]
+            );
+            let expected_label = (output.out_point.vout == 1).then_some(7);
+            assert_eq!(output.label, expected_label);
+        }
+
+        // Someone else finds nothing, and a missing key is refused.
+        let other =
+            SilentPaymentReceiver::new(keys[0], keys[1].public_key(&SECP), Network::Regtest);
+        assert!(other.scan_transaction(&tx, &prevouts)?.is_empty());
+        let mut inputs = inputs;
+        inputs[1].secret_key = None;
+        assert!(output_keys(&inputs, &[address]).is_err());
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct FakeSource {
+        transactions: HashMap<Txid, Transaction>,
+        blocks: HashMap<BlockHash, Block>,
+    }
+
+    impl ScanSource for FakeSource {
+        async fn block(&self, hash: &BlockHash) -> anyhow::Result<Block> {
+            self.blocks
+                .get(hash)
+                .cloned()
+                .ok_or_else(|| anyhow!("unknown block"))
+        }
+
+        async fn transaction(&self, txid: &Txid) -> anyhow::Result<Transaction> {
+            self.transactions
+                .get(txid)
+                .cloned()
+                .ok_or_else(|| anyhow!("unknown transaction"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scan_block_with_account_keys() -> anyhow::Result<()> {
+        let account = BitcoinAccount::from_xpriv(
+            Xpriv::from_str(USER_A_PRIVATE_KEY)?,
+            DerivationPath::from_str("m/86'/1'/0'/0/0")?,
+        )?;
+        let keys = account.silent_payment_keys()?;
+        let receiver = keys.receiver(Network::Regtest);
+        assert_eq!(
+            account.silent_payment_address(Network::Regtest)?,
+            receiver.address()
+        );
+        assert_ne!(keys.scan_key, keys.spend_key);
+
+        // The sender spends a p2wpkh output of a funding transaction.
+        let sender_key = SecretKey::from_slice(&[5; 32])?;
+        let sender_pk = CompressedPublicKey(sender_key.public_key(&SECP));
+        let funding = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![],
+            output: vec![TxOut {
+                value: Amount::from_sat(100_000),
+                script_pubkey: ScriptBuf::new_p2wpkh(&sender_pk.wpubkey_hash()),
+            }],
+        };
+        let input = SenderInput {
+            out_point: OutPoint {
+                txid: funding.compute_txid(),
+                vout: 0,
+            },
+            prevout: funding.output[0].clone(),
+            secret_key: Some(sender_key),
+        };
+        let output_key = output_keys(std::slice::from_ref(&input), &[receiver.address()])?[0];
+        let payment = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: input.out_point,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::from_slice(&[[1u8; 71].to_vec(), sender_pk.to_bytes().to_vec()]),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(99_000),
+                script_pubkey: output_script(output_key),
+            }],
+        };
+        let coinbase = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: Amount::from_sat(50_000),
+                script_pubkey: output_script(output_key),
+            }],
+        };
+        let block = Block {
+            header: bitcoin::block::Header {
+                version: bitcoin::block::Version::TWO,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: bitcoin::TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: bitcoin::CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+            },
+            txdata: vec![coinbase, payment.clone()],
+        };
+        let mut source = FakeSource::default();
+        source.transactions.insert(funding.compute_txid(), funding);
+        source
+            .transactions
+            .insert(payment.compute_txid(), payment.clone());
+        source.blocks.insert(block.block_hash(), block.clone());
+
+        let received = receiver.scan_block(&source, &block.block_hash()).await?;
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].out_point.txid, payment.compute_txid());
+        assert_eq!(
+            receiver.scan_txid(&source, &payment.compute_txid()).await?,
+            received
+        );
+
+        // The derived key signs for the output key.
+        let keypair = received[0].keypair(&keys.spend_key)?;
+        let msg = secp256k1::Message::from_digest([7; 32]);
+        let signature = SECP.sign_schnorr(&msg, &keypair);
+        SECP.verify_schnorr(&signature, &msg, &output_key)?;
+        Ok(())
+    }
+
+    // Keys, outpoints and outputs of the BIP-352 `send_and_receive_test_vectors.json`, the
+    // witnesses and script sigs are built here with dummy signatures.
+    const SCAN_KEY: &str = "0f694e068028a717f8af6b9411f9a133dd3565258714cc226594b34db90c1f2c";
+    const SPEND_KEY: &str = "9d6ad855ce3417ef84e836892e5a56392bfba05fa5d97ccea30e266f540e08b3";
+    const LABEL_2_ADDRESS: &str = "sp1qqgste7k9hx0qftg6qmwlkqtwuy6cycyavzmzj85c6qdfhjdpdjtdgqjex54dmqmmv6rw353tsuqhs99ydvadxzrsy9nuvk74epvee55drs734pqq";
+    const TXID_1: &str = "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16";
+    const TXID_2: &str = "a1075db55d416d3ca199f55b6084e2115b9345e16c5cf302fc80e9d5fbf5d48d";
+    const KEY_1: &str = "eadc78165ff1f8ea94ad7cfdc54990738a4c53f6e0507b42154201b8e5dff3b1";
+    const KEY_2: &str = "93f5ed907ad5b2bdbbdcb5d9116ebc0a4e1f92f910d5260237fa45a9408aad16";
+    const KEY_3: &str = "8d4751f6e8a3586880fb66c19ae277969bd5aa06f61c4ee2f1e2486efdf666d3";
+    const TAPROOT_EVEN_KEY: &str =
+        "fc8716a97a48ba9a05a98ae47b5cd201a25a7fd5d8b73c203c5f7b6b6b3b6ad7";
+    const TAPROOT_ODD_KEY: &str =
+        "1d37787c2b7116ee983e9f9c13269df29091b391c04db94239e0d2bc2182c3bf";
+
+    #[derive(Clone, Copy)]
+    enum VectorInput {
+        P2wpkh,
+        P2pkh,
+        // The key is preceded by another key the script sig drops.
+        P2pkhMalleated,
+        P2pkhUncompressed,
+        P2trKeyPath,
+        // Script path spend with the NUMS point as internal key.
+        P2trNums,
+        SegwitV2,
+    }
+
+    // kind, secret key, txid and vout.
+    type VectorIn<'a> = (VectorInput, &'a str, &'a str, u32);
+
+    fn vector_input(kind: VectorInput, key: &str, txid: &str, vout: u32) -> (SenderInput, TxIn) {
+        let key = secret_key(key);
+        let public_key = key.public_key(&SECP);
+        let compressed = CompressedPublicKey(public_key);
+        let signature = [1u8; 71];
+        let push = |bytes: &[u8]| PushBytesBuf::try_from(bytes.to_vec()).unwrap();
+        let mut script_sig = ScriptBuf::new();
+        let mut witness = Witness::new();
+        let mut secret_key = Some(key);
+        let script_pubkey = match kind {
+            VectorInput::P2wpkh => {
+                witness.push(signature);
+                witness.push(compressed.to_bytes());
+                ScriptBuf::new_p2wpkh(&compressed.wpubkey_hash())
+            }
+            VectorInput::P2pkh | VectorInput::P2pkhMalleated => {
+                let mut builder = bitcoin::script::Builder::new();
+                if matches!(kind, VectorInput::P2pkhMalleated) {
+                    let decoy = SECP.generate_keypair(&mut rand::thread_rng()).1;
+                    builder = builder
+                        .push_slice(decoy.serialize())
+                        .push_opcode(bitcoin::opcodes::all::OP_DROP);
+                }
+                script_sig = builder
+                    .push_slice(push(&signature))
+                    .push_slice(compressed.to_bytes())
+                    .into_script();
+                ScriptBuf::new_p2pkh(&compressed.pubkey_hash())
+            }
+            VectorInput::P2pkhUncompressed => {
+                let uncompressed = bitcoin::PublicKey::new_uncompressed(public_key);
+                script_sig = bitcoin::script::Builder::new()
+                    .push_slice(push(&signature))
+                    .push_slice(push(&uncompressed.to_bytes()))
+                    .into_script();
+                ScriptBuf::new_p2pkh(&uncompressed.pubkey_hash())
+            }
+            VectorInput::P2trKeyPath => {
+                witness.push([2u8; 64]);
+                output_script(public_key.x_only_public_key().0)
+            }
+            VectorInput::P2trNums => {
+                let leaf = bitcoin::script::Builder::new()
+                    .push_x_only_key(&public_key.x_only_public_key().0)
+                    .push_opcode(bitcoin::opcodes::all::OP_CHECKSIG)
+                    .into_script();
+                let spend_info = bitcoin::taproot::TaprootBuilder::new()
+                    .add_leaf(0, leaf.clone())
+                    .unwrap()
+                    .finalize(&SECP, XOnlyPublicKey::from_str(UNSPENDABLE_KEY).unwrap())
+                    .unwrap();
+                let control_block = spend_info
+                    .control_block(&(leaf.clone(), bitcoin::taproot::LeafVersion::TapScript))
+                    .unwrap();
+                witness.push([2u8; 64]);
+                witness.push(leaf.as_bytes());
+                witness.push(control_block.serialize());
+                secret_key = None;
+                ScriptBuf::new_p2tr_tweaked(spend_info.output_key())
+            }
+            VectorInput::SegwitV2 => {
+                witness.push([2u8; 64]);
+                let program =
+                    bitcoin::WitnessProgram::new(bitcoin::WitnessVersion::V2, &[3u8; 32]).unwrap();
+                ScriptBuf::new_witness_program(&program)
+            }
+        };
+        let out_point = OutPoint::new(Txid::from_str(txid).unwrap(), vout);
+        let sender_input = SenderInput {
+            out_point,
+            prevout: TxOut {
+                value: Amount::from_sat(100_000),
+                script_pubkey,
+            },
+            secret_key,
+        };
+        let tx_in = TxIn {
+            previous_output: out_point,
+            script_sig,
+            sequence: Sequence::MAX,
+            witness,
+        };
+        (sender_input, tx_in)
+    }
+
+    fn vector_inputs(vin: &[VectorIn]) -> (Vec<SenderInput>, Vec<TxIn>) {
+        vin.iter()
+            .map(|(kind, key, txid, vout)| vector_input(*kind, key, txid, *vout))
+            .unzip()
+    }
+
+    fn prevouts(inputs: &[SenderInput]) -> Vec<TxOut> {
+        inputs.iter().map(|input| input.prevout.clone()).collect()
+    }
+
+    fn vector_tx(tx_ins: Vec<TxIn>, output_keys: &[XOnlyPublicKey]) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: tx_ins,
+            output: output_keys
+                .iter()
+                .map(|key| TxOut {
+                    value: Amount::from_sat(50_000),
+                    script_pubkey: output_script(*key),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_bip352_vectors() -> anyhow::Result<()> {
+        use VectorInput::*;
+        let spend_key = secret_key(SPEND_KEY);
+        let mut receiver = SilentPaymentReceiver::new(
+            secret_key(SCAN_KEY),
+            spend_key.public_key(&SECP),
+            Network::Bitcoin,
+        );
+        let address = receiver.address();
+        let cases: Vec<(&str, Vec<VectorIn>, &str)> = vec![
+            (
+                "simple send: two inputs",
+                vec![(P2wpkh, KEY_1, TXID_1, 0), (P2pkh, KEY_2, TXID_2, 0)],
+                "3e9fce73d4e77a4809908e3c3a2e54ee147b9312dc5044a193d1fc85de46e3c1",
+            ),
+            (
+                "outpoint ordering byte-lexicographically vs. vout-integer",
+                vec![(P2wpkh, KEY_1, TXID_1, 1), (P2wpkh, KEY_2, TXID_1, 256)],
+                "a85ef8701394b517a4b35217c4bd37ac01ebeed4b008f8d0879f9e09ba95319c",
+            ),
+            (
+                "multiple UTXOs from the same public key",
+                vec![(P2wpkh, KEY_1, TXID_1, 0), (P2wpkh, KEY_1, TXID_2, 0)],
+                "548ae55c8eec1e736e8d3e520f011f1f42a56d166116ad210b3937599f87f566",
+            ),
+            (
+                "taproot only inputs with even y-values",
+                vec![
+                    (P2trKeyPath, KEY_1, TXID_1, 0),
+                    (P2trKeyPath, TAPROOT_EVEN_KEY, TXID_2, 0),
+                ],
+                "de88bea8e7ffc9ce1af30d1132f910323c505185aec8eae361670421e749a1fb",
+            ),
+            (
+                "taproot only with mixed even/odd y-values",
+                vec![
+                    (P2trKeyPath, KEY_1, TXID_1, 0),
+                    (P2trKeyPath, TAPROOT_ODD_KEY, TXID_2, 0),
+                ],
+                "77cab7dd12b10259ee82c6ea4b509774e33e7078e7138f568092241bf26b99f1",
+            ),
+            (
+                "taproot input with even y-value and non-taproot input",
+                vec![(P2trKeyPath, KEY_1, TXID_1, 0), (P2pkh, KEY_3, TXID_2, 0)],
+                "30523cca96b2a9ae3c98beb5e60f7d190ec5bc79b2d11a0b2d4d09a608c448f0",
+            ),
+            (
+                "taproot input with odd y-value and non-taproot input",
+                vec![
+                    (P2trKeyPath, TAPROOT_ODD_KEY, TXID_1, 0),
+                    (P2pkh, KEY_3, TXID_2, 0),
+                ],
+                "359358f59ee9e9eec3f00bdf4882570fd5c182e451aa2650b788544aff012a3a",
+            ),
+            (
+                "taproot input with NUMS point",
+                vec![
+                    (P2trKeyPath, TAPROOT_EVEN_KEY, TXID_1, 0),
+                    (P2trNums, KEY_1, TXID_2, 0),
+                    (P2trNums, KEY_2, TXID_2, 1),
+                ],
+                "79e79897c52935bfd97fc6e076a6431a0c7543ca8c31e0fc3cf719bb572c842d",
+            ),
+            (
+                "pubkey extraction from malleated p2pkh",
+                vec![
+                    (P2wpkh, KEY_1, TXID_1, 0),
+                    (P2pkhMalleated, KEY_2, TXID_2, 0),
+                ],
+                "3e9fce73d4e77a4809908e3c3a2e54ee147b9312dc5044a193d1fc85de46e3c1",
+            ),
+            (
+                "p2pkh uncompressed keys are skipped",
+                vec![
+                    (P2wpkh, KEY_1, TXID_1, 0),
+                    (P2pkhUncompressed, KEY_2, TXID_2, 0),
+                ],
+                "67fee277da9e8542b5d2e6f32d660a9bbd3f0e107c2d53638ab1d869088882d6",
+            ),
+        ];
+        for (comment, vin, expected) in cases {
+            let (inputs, tx_ins) = vector_inputs(&vin);
+            let keys = output_keys(&inputs, &[address])?;
+            assert_eq!(
+                keys,
+                vec![XOnlyPublicKey::from_str(expected)?],
+                "{}",
+                comment
+            );
+
+            let tx = vector_tx(tx_ins, &keys);
+            let received = receiver.scan_transaction(&tx, &prevouts(&inputs))?;
+            assert_eq!(received.len(), 1, "{}", comment);
+            assert_eq!(
+                received[0].keypair(&spend_key)?.x_only_public_key().0,
+                keys[0],
+                "{}",
+                comment
+            );
+        }
+
+        // labels: the address with label 2, found back with its label.
+        let labeled = receiver.labeled_address(2)?;
+        assert_eq!(labeled.to_string(), LABEL_2_ADDRESS);
+        let (inputs, tx_ins) =
+            vector_inputs(&[(P2wpkh, KEY_1, TXID_1, 0), (P2wpkh, KEY_2, TXID_2, 0)]);
+        let keys = output_keys(&inputs, &[labeled])?;
+        let tx = vector_tx(tx_ins, &keys);
+        let received = receiver.scan_transaction(&tx, &prevouts(&inputs))?;
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].label, Some(2));
+        assert_eq!(
+            received[0].keypair(&spend_key)?.x_only_public_key().0,
+            keys[0]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_refused_inputs() -> anyhow::Result<()> {
+        use VectorInput::*;
+        let receiver = SilentPaymentReceiver::new(
+            secret_key(SCAN_KEY),
+            secret_key(SPEND_KEY).public_key(&SECP),
+            Network::Bitcoin,
+        );
+        let address = receiver.address();
+
+        // a key that isn't the prevout's, or a prevout that takes no key.
+        let (mut inputs, _) =
+            vector_inputs(&[(P2wpkh, KEY_1, TXID_1, 0), (P2wpkh, KEY_2, TXID_2, 0)]);
+        inputs[1].secret_key = Some(secret_key(KEY_3));
+        assert!(output_keys(&inputs, &[address]).is_err());
+        inputs[1].secret_key = Some(secret_key(KEY_2));
+        inputs[1].prevout.script_pubkey = ScriptBuf::new_p2wsh(&bitcoin::WScriptHash::all_zeros());
+        assert!(output_keys(&inputs, &[address]).is_err());
+
+        // spending a segwit v2 output: nothing is sent, nor found.
+        let (inputs, tx_ins) = vector_inputs(&[
+            (P2wpkh, KEY_1, TXID_1, 0),
+            (P2wpkh, KEY_2, TXID_2, 0),
+            (SegwitV2, KEY_3, TXID_2, 1),
+        ]);
+        assert!(output_keys(&inputs, &[address]).is_err());
+        let keys = output_keys(&inputs[..2], &[address])?;
+        let tx = vector_tx(tx_ins, &keys);
+        assert!(receiver
+            .scan_transaction(&tx, &prevouts(&inputs))?
+            .is_empty());
+        Ok(())
+    }
+}